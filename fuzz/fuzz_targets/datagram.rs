@@ -0,0 +1,8 @@
+//! Feed the server datagrams made up by libFuzzer, run with `cargo fuzz run datagram fuzz/corpus/server`
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|bytes: &[u8]| {
+    game::network::fuzz::fuzz_datagram(bytes);
+});
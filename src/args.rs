@@ -3,7 +3,10 @@ use std::path::PathBuf;
 
 use clap::{Args, Parser};
 
-use crate::{network, save};
+use crate::{
+    network, save,
+    world::{valid_terrain_scale, CHUNK_HEIGHT, CHUNK_WIDTH},
+};
 
 pub fn get_args() -> GameArgs {
     GameArgs::parse()
@@ -16,18 +19,82 @@ pub enum GameArgs {
 
     /// Client mode
     Client(ClientArgs),
+
+    /// Print a summary of a save file and exit
+    Inspect(InspectArgs),
+
+    /// Print every packet in a replay log (compressed or not) and exit
+    Replay(ReplayArgs),
+
+    /// Connect lots of fake clients to a server, print how it held up and exit
+    LoadTest(LoadTestArgs),
+
+    /// Give every file in a fuzz corpus to the server as a datagram, print what happened and exit
+    Fuzz(FuzzArgs),
 }
 
 #[derive(Args, Debug)]
 // #[command(arg_required_else_help(true))]
 pub struct ServerArgs {
-    /// File to load and save to
-    #[arg(short = 'f', long = "file", default_value_os_t = save::default_save_path())]
-    pub save_file: PathBuf,
+    /// TOML file with server settings, the flags below override it
+    #[arg(short = 'c', long)]
+    pub config: Option<PathBuf>,
 
-    /// Port to open server on
-    #[arg(short = 'p', long, default_value_t = network::DEFAULT_SERVER_PORT)]
-    pub port: u16,
+    /// File to load and save to [default: ./savedata/server.sav]
+    #[arg(short = 'f', long = "file")]
+    pub save_file: Option<PathBuf>,
+
+    /// Port to open server on [default: 8888]
+    #[arg(short = 'p', long)]
+    pub port: Option<u16>,
+
+    /// Only use this network interface, its ip address or (on Linux) its name, e.g. eth0
+    #[arg(long)]
+    pub interface: Option<String>,
+
+    /// Only accept clients on this machine
+    #[arg(long)]
+    pub localhost_only: bool,
+
+    /// Send terrain changes to clients once every this many network ticks [default: 1]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub terrain_interval: Option<u64>,
+
+    /// Spread sends to clients out over each network tick instead of sending all at once
+    #[arg(long)]
+    pub pacing: bool,
+
+    /// Record every incoming packet to this replay log
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+
+    /// Gzip the replay log
+    #[arg(long, requires = "record")]
+    pub compress_record: bool,
+
+    /// Log every client's bandwidth, round trip time and loss once a second
+    #[arg(long)]
+    pub log_bandwidth: bool,
+
+    /// Trace every message the server gets or sends, shown with RUST_LOG=trace
+    #[arg(long)]
+    pub trace_messages: bool,
+
+    /// Find out how big a packet can get to each client and keep packets under that
+    #[arg(long)]
+    pub probe_mtu: bool,
+
+    /// Append a row of server metrics to this CSV file every so often
+    #[arg(long)]
+    pub metrics_csv: Option<PathBuf>,
+
+    /// Seconds between rows of the metrics CSV [default: 10]
+    #[arg(long, requires = "metrics_csv", value_parser = clap::value_parser!(u64).range(1..))]
+    pub metrics_csv_interval: Option<u64>,
+
+    /// Check the config, the port and the save file, print what's wrong and exit without starting
+    #[arg(long)]
+    pub check: bool,
 }
 
 #[derive(Args, Debug)]
@@ -40,4 +107,100 @@ pub struct ClientArgs {
     /// Port of server
     #[arg(short = 'p', long, default_value_t = network::DEFAULT_SERVER_PORT)]
     pub server_port: u16,
+
+    /// Only watch the game, the server ignores our inputs and doesn't give us a player
+    #[arg(long)]
+    pub observe: bool,
+
+    /// Get the terrain at 1/N the resolution each way, for slow connections
+    #[arg(long, default_value_t = 1, value_parser = parse_terrain_scale)]
+    pub terrain_scale: u8,
+
+    /// Name to go by on the server
+    #[arg(short = 'n', long)]
+    pub name: Option<String>,
+
+    /// Sample and send inputs N times as often as everything else
+    #[arg(long, default_value_t = 1)]
+    pub input_rate: u64,
+}
+
+/// A scale the server would take, see world::valid_terrain_scale
+fn parse_terrain_scale(arg: &str) -> Result<u8, String> {
+    let scale: u8 = arg.parse().map_err(|e| format!("{}", e))?;
+    if !valid_terrain_scale(scale) {
+        return Err(format!(
+            "chunks are {}x{} blocks, the scale has to split them evenly",
+            CHUNK_WIDTH, CHUNK_HEIGHT
+        ));
+    }
+    Ok(scale)
+}
+
+#[derive(Args, Debug)]
+pub struct InspectArgs {
+    /// Save file to look at
+    #[arg(default_value_os_t = save::default_save_path())]
+    pub save_file: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct ReplayArgs {
+    /// Replay log to read
+    pub replay_file: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct FuzzArgs {
+    /// Directory with one datagram per file
+    #[arg(default_value = "fuzz/corpus/server")]
+    pub corpus: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct LoadTestArgs {
+    /// Address of server
+    #[arg(short = 'i', long = "ip", default_value_t = network::DEFAULT_SERVER_IP.into())]
+    pub server_ip: IpAddr,
+
+    /// Port of server
+    #[arg(short = 'p', long, default_value_t = network::DEFAULT_SERVER_PORT)]
+    pub server_port: u16,
+
+    /// Fake clients to connect, each gets its own socket
+    #[arg(short = 'n', long, default_value_t = 16)]
+    pub clients: usize,
+
+    /// Packets every client sends a second
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    pub rate: u64,
+
+    /// Seconds to keep sending for
+    #[arg(long, default_value_t = 10.)]
+    pub seconds: f64,
+
+    /// Send inputs along with the pings
+    #[arg(long)]
+    pub inputs: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn terrain_scale_checked_when_parsed() {
+        let scale = |arg: &str| {
+            GameArgs::try_parse_from(["game", "client", "--terrain-scale", arg]).map(|args| {
+                match args {
+                    GameArgs::Client(c) => c.terrain_scale,
+                    other => panic!("expected client args, got {:?}", other),
+                }
+            })
+        };
+        assert_eq!(scale("4").unwrap(), 4);
+        for bad in ["0", "3", "255", "300", "half"] {
+            assert!(scale(bad).is_err(), "{}", bad);
+        }
+    }
 }
@@ -0,0 +1,19 @@
+//! Everything but main, so that the fuzz targets in fuzz/ can get at the server too
+use bevy::prelude::*;
+
+pub mod args;
+pub mod credit_image;
+pub mod menu;
+pub mod network;
+pub mod player;
+pub mod procedural_functions;
+pub mod save;
+pub mod states;
+pub mod world;
+
+pub const TITLE: &str = "The Krusty Krabs";
+pub const WIN_W: f32 = 1280.;
+pub const WIN_H: f32 = 720.;
+
+#[derive(Component)]
+pub struct CharacterCamera;
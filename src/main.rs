@@ -1,25 +1,68 @@
 use bevy::{prelude::*, render::render_resource::Texture};
 
-mod args;
-mod credit_image;
-mod menu;
-mod network;
-mod player;
-mod procedural_functions;
-mod save;
-mod states;
-mod world;
-
-const TITLE: &str = "The Krusty Krabs";
-const WIN_W: f32 = 1280.;
-const WIN_H: f32 = 720.;
-
-#[derive(Component)]
-pub struct CharacterCamera;
+use game::{
+    args, credit_image, menu, network, player, save, states, world, CharacterCamera, TITLE, WIN_H,
+    WIN_W,
+};
 
 fn main() {
     let args = args::get_args();
     warn!("game arguments: {:?}", args);
+
+    // looking at a save file doesn't need the rest of the game
+    if let args::GameArgs::Inspect(i) = &args {
+        match save::inspect_save_file(&i.save_file) {
+            Ok(info) => println!("{}", info),
+            Err(e) => {
+                eprintln!("{}: {}", i.save_file.display(), e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let args::GameArgs::Replay(r) = &args {
+        if let Err(e) = network::replay::print_replay(&r.replay_file) {
+            eprintln!("{}: {}", r.replay_file.display(), e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let args::GameArgs::LoadTest(l) = &args {
+        let config = network::load_test::LoadTestConfig {
+            clients: l.clients,
+            send_hz: l.rate,
+            duration: std::time::Duration::from_secs_f64(l.seconds.max(0.)),
+            inputs: l.inputs,
+        };
+        let server = std::net::SocketAddr::new(l.server_ip, l.server_port);
+        match network::load_test::run_load_test(server, config) {
+            Ok(report) => println!("{}", report),
+            Err(e) => {
+                eprintln!("load test against {}: {}", server, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let args::GameArgs::Fuzz(f) = &args {
+        match network::fuzz::run_fuzz_corpus(&f.corpus) {
+            Ok(report) => {
+                println!("{}", report);
+                if !report.panicked.is_empty() {
+                    std::process::exit(1);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}: {}", f.corpus.display(), e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     let mut app = App::new();
 
     match args {
@@ -39,11 +82,64 @@ fn main() {
 
             app.add_plugin(states::server::StatePlugin);
 
+            // config file first, then anything given on the command line
+            let mut config = match &s.config {
+                Some(path) => match network::config::ServerConfig::load(path) {
+                    Ok(config) => config.into_builder(),
+                    Err(e) => {
+                        eprintln!("{}: {}", path.display(), e);
+                        std::process::exit(1);
+                    }
+                },
+                None => network::config::ServerConfig::builder(),
+            };
+            if let Some(port) = s.port {
+                config = config.port(port);
+            }
+            if let Some(save_file) = s.save_file {
+                config = config.save_file(save_file);
+            }
+            if let Some(ticks) = s.terrain_interval {
+                config = config.terrain_interval_ticks(ticks);
+            }
+            if s.interface.is_some() {
+                config = config.interface(s.interface.map(network::config::Interface::from));
+            }
+            if s.localhost_only {
+                config = config.localhost_only(true);
+            }
+            if s.pacing {
+                config = config.pacing(true);
+            }
+            if s.log_bandwidth {
+                config = config.log_bandwidth(true);
+            }
+            if s.trace_messages {
+                config = config.trace_messages(true);
+            }
+            if s.probe_mtu {
+                config = config.probe_mtu(true);
+            }
+            if s.record.is_some() {
+                config = config.record(s.record, s.compress_record);
+            }
+            if s.metrics_csv.is_some() {
+                let interval = s.metrics_csv_interval.map_or(
+                    network::config::DEFAULT_METRICS_CSV_INTERVAL,
+                    std::time::Duration::from_secs,
+                );
+                config = config.metrics_csv(s.metrics_csv, interval);
+            }
+
+            let config = config.build();
+            if s.check {
+                let report = config.check();
+                println!("{}", report);
+                std::process::exit(report.exit_code());
+            }
+
             // server network plugin
-            app.add_plugin(network::server::ServerPlugin {
-                port: s.port,
-                save_file: s.save_file,
-            });
+            app.add_plugin(network::server::ServerPlugin::new(config));
 
             app.add_plugin(world::server::WorldPlugin);
 
@@ -83,8 +179,19 @@ fn main() {
             app.add_plugin(network::client::ClientPlugin {
                 server_address: c.server_ip.into(),
                 server_port: c.server_port,
+                observer: c.observe,
+                terrain_scale: c.terrain_scale,
+                name: c.name,
+                input_rate: c.input_rate,
             });
         }
+
+        args::GameArgs::Inspect(_)
+        | args::GameArgs::Replay(_)
+        | args::GameArgs::LoadTest(_)
+        | args::GameArgs::Fuzz(_) => {
+            unreachable!("handled before the app is built")
+        }
     }
 
     app.run();
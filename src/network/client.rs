@@ -1,21 +1,36 @@
-use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 
 use super::*;
-use crate::player::{self, CameraBoundsBox, Player};
+use crate::player::{self, CameraBoundsBox, Player, PLAYER_ASSET, PLAYER_SIZE, PLAYER_Z};
 use crate::states;
 use crate::world::derender_chunk;
-use crate::world::Terrain;
+use crate::world::{BlockBounds, Chunk, Terrain};
 use crate::{WIN_H, WIN_W};
 use bevy::prelude::*;
 
 /// TODO: move to iyes_loopless
 const NETWORK_TICK_DELAY: u64 = 60;
 
+/// Codecs this client can decompress, offered to the server when connecting
+const SUPPORTED_CODECS: &[Codec] = &[Codec::Deflate];
+
+/// Pings that haven't been answered in this long are forgotten
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most entity snapshots kept around for the server to send deltas against
+const ENTITY_HISTORY: usize = 32;
+
+/// Render this many of the server's send intervals behind its latest snapshot
+/// so there's usually a newer snapshot to interpolate towards
+const INTERPOLATION_DELAY_INTERVALS: u64 = 2;
+
 /// Should be used as a global resource on the client
 #[derive(Debug)]
 struct Client {
-    /// UDP socket that should be used for everything
-    socket: UdpSocket,
+    /// Transport (normally a UDP socket) that should be used for everything
+    transport: Box<dyn Transport>,
     /// There is only ever one server we care about
     server: SocketAddr,
     /// Our current sequence number
@@ -24,6 +39,49 @@ struct Client {
     last_received_sequence: u64,
     /// Which bodies should be sent in the next outgoing packet
     bodies: Vec<ClientBodyElem>,
+    /// Id of the last reliable body we handled, anything at or below it is a duplicate
+    last_reliable_id: u64,
+    /// Token the next ping will use
+    next_ping_token: u64,
+    /// When each unanswered ping was sent, by token
+    pings_in_flight: HashMap<u64, Instant>,
+    /// Newest entity snapshot that hasn't been applied yet
+    entity_snapshot: Option<Vec<EntityState>>,
+    /// Snapshots we've had, by id, so deltas have something to apply to
+    entity_history: BTreeMap<u64, Vec<EntityState>>,
+    /// Server tick the newest snapshot is from
+    snapshot_tick: u64,
+    /// Server's game ticks per second
+    server_tick_hz: u64,
+    /// How often the server says it's sending to us
+    server_send_interval: Duration,
+    /// The match's settings, None until the server sends them
+    settings: Option<GameSettings>,
+    /// Our half of the key exchange, offered to the server when connecting
+    key_pair: KeyPair,
+    /// Key agreed with the server, None until it answers our key exchange (it might never)
+    session: Option<SessionKey>,
+    /// Added to our clock to get the server's, in microseconds
+    /// TODO: 0 (trusting the system clocks) until clocks are synced with the server
+    clock_offset_micros: i64,
+    /// Only watching, we tell the server when connecting and don't send inputs
+    observer: bool,
+    /// Ask for terrain at 1/this the resolution each way, 1 for all of it
+    terrain_scale: u8,
+    /// What we'd like to be called, told to the server when connecting
+    name: Option<String>,
+    /// Sample and send inputs this many times every NETWORK_TICK_DELAY frames, see ClientPlugin::input_rate
+    input_rate: u64,
+    /// Version the server picked out of the ones we speak, None until it answers
+    protocol_version: Option<u32>,
+    /// Newest terrain version we've got, acked again when connecting so the server can send only what changed
+    terrain_version: Option<u64>,
+    /// Token the server gave us before restarting and how long to wait for it, see ServerBodyElem::Restarting
+    restart: Option<(u64, Duration)>,
+    /// When to start connecting to the restarted server
+    resume_at: Option<Instant>,
+    /// Where the TerrainChunks right after it changed, see ServerBodyElem::ChangedBounds
+    changed_bounds: Option<Vec<BlockBounds>>,
     /// Debugging pause: drop all packets in and out, stop any processing
     debug_paused: bool,
     /// TODO: replace this with iyes_loopless fixedtimestep
@@ -36,38 +94,77 @@ impl Client {
     fn new(server_address: SocketAddr) -> Result<Self, std::io::Error> {
         // port 0 means we let the OS decide
         let addr = SocketAddr::from(([0, 0, 0, 0], 0));
-        let sock = UdpSocket::bind(addr)?;
-
-        // we want nonblocking sockets!
-        sock.set_nonblocking(true)?;
+        Ok(Self::with_transport(
+            Box::new(UdpTransport::bind(addr)?),
+            server_address,
+        ))
+    }
 
-        Ok(Self {
-            socket: sock,
+    /// Creates a client on top of an already set up transport
+    fn with_transport(transport: Box<dyn Transport>, server_address: SocketAddr) -> Self {
+        Self {
+            transport,
             server: server_address,
             last_received_sequence: 0,
             current_sequence: 0,
             bodies: Vec::with_capacity(DEFAULT_BODIES_VEC_CAPACITY),
+            last_reliable_id: 0,
+            next_ping_token: 0,
+            pings_in_flight: HashMap::new(),
+            entity_snapshot: None,
+            entity_history: BTreeMap::new(),
+            snapshot_tick: 0,
+            server_tick_hz: 0,
+            server_send_interval: Duration::ZERO,
+            settings: None,
+            key_pair: KeyPair::generate(),
+            session: None,
+            clock_offset_micros: 0,
+            observer: false,
+            terrain_scale: 1,
+            name: None,
+            input_rate: 1,
+            protocol_version: None,
+            terrain_version: None,
+            restart: None,
+            resume_at: None,
+            changed_bounds: None,
             debug_paused: true, // TODO: remove
             real_tick_count: 0,
             buffer: [0u8; BUFFER_SIZE],
-        })
+        }
     }
 
-    /// Send a message to the server
-    fn send_message(&self, message: ClientToServer) -> Result<(), SendError> {
-        send_message(&self.socket, self.server, message)?;
+    /// Talk to the server from scratch, as if we'd just started, e.g. once it has restarted
+    fn start_over(&mut self) {
+        self.last_received_sequence = 0;
+        self.last_reliable_id = 0;
+        self.session = None;
+        self.protocol_version = None;
+        self.bodies.clear();
+    }
+
+    /// Send a message to the server, encrypting its bodies if we agreed on a key
+    fn send_message(&mut self, mut message: ClientToServer) -> Result<(), SendError> {
+        if let Some(key) = &mut self.session {
+            let sealed = seal_bodies(key, Toward::Server, &message.header.aad(), &message.bodies)
+                .map_err(SendError::EncodeError)?;
+            message.bodies = vec![ClientBodyElem::Encrypted(sealed)];
+        }
+        send_message(self.transport.as_ref(), self.server, message)?;
         Ok(())
     }
 
     /// Non-blocking way to get one message from the socket
     fn get_one_message(&mut self) -> Result<ServerToClient, ReceiveError> {
         // read from socket
-        let (_size, sender_addr) = self.socket.recv_from(&mut self.buffer).map_err(|e| match e
-            .kind()
-        {
-            std::io::ErrorKind::WouldBlock => ReceiveError::NoMessage,
-            _ => ReceiveError::IoError(e),
-        })?;
+        let (size, sender_addr) =
+            self.transport
+                .recv_from(&mut self.buffer)
+                .map_err(|e| match e.kind() {
+                    std::io::ErrorKind::WouldBlock => ReceiveError::NoMessage,
+                    _ => ReceiveError::IoError(e),
+                })?;
 
         // check if it's actually from the server
         if sender_addr != self.server {
@@ -75,17 +172,137 @@ impl Client {
         }
 
         // decode message
-        let (message, _size) = bincode::decode_from_slice(&self.buffer, BINCODE_CONFIG)
-            .map_err(ReceiveError::DecodeError)?;
+        let mut message: ServerToClient =
+            decode_into_owned(&self.buffer[..size]).map_err(ReceiveError::DecodeError)?;
+
+        if let [ServerBodyElem::Encrypted(sealed)] = &message.bodies[..] {
+            let key = self
+                .session
+                .as_mut()
+                .ok_or(ReceiveError::Decrypt(CryptoError::NoSession))?;
+            let (bodies, reliable) =
+                open_bodies(key, Toward::Client, &message.header.aad(), sealed)?;
+            message.bodies = bodies;
+            message.reliable = reliable;
+        }
 
         Ok(message)
     }
 
+    /// Whether inputs get sampled (and sent) this frame, see ClientPlugin::input_rate
+    fn input_due(&self) -> bool {
+        self.real_tick_count
+            .is_multiple_of(NETWORK_TICK_DELAY / self.input_rate)
+    }
+
+    /// Send the inputs queued so far in a packet of their own, everything else waits for the next full send
+    fn send_inputs(&mut self) {
+        let (inputs, rest): (Vec<ClientBodyElem>, Vec<ClientBodyElem>) =
+            std::mem::take(&mut self.bodies)
+                .into_iter()
+                .partition(|body| {
+                    matches!(
+                        body,
+                        ClientBodyElem::Input(_) | ClientBodyElem::PackedInput(_)
+                    )
+                });
+        self.bodies = rest;
+        if inputs.is_empty() {
+            return;
+        }
+        let message = ClientToServer::builder()
+            .ack(self.last_received_sequence)
+            .sequence(self.current_sequence)
+            .bodies(inputs)
+            .build();
+        if let Err(e) = self.send_message(message) {
+            error!("failed to send inputs to server: {:?}", e);
+        }
+    }
+
     /// Push a body that will be sent to the server
     fn enqueue_body(&mut self, body: ClientBodyElem) {
         self.bodies.push(body);
     }
 
+    /// Tell the server when its packet sent at sent_at_micros got here, so it can work out the one-way delay
+    fn report_receive_time(&mut self, sent_at_micros: u64) {
+        // older packets aren't packets from 0, e.g. from servers that don't stamp them
+        if sent_at_micros == 0 {
+            return;
+        }
+        let received_at_micros = unix_micros().saturating_add_signed(self.clock_offset_micros);
+        // only the newest one matters
+        self.bodies
+            .retain(|b| !matches!(b, ClientBodyElem::ReceiveTime { .. }));
+        self.enqueue_body(ClientBodyElem::ReceiveTime {
+            sent_at_micros,
+            received_at_micros,
+        });
+    }
+
+    /// Queue a ping with a fresh token, remembering when it was sent
+    fn enqueue_ping(&mut self, now: Instant) {
+        self.pings_in_flight
+            .retain(|_, sent| now.saturating_duration_since(*sent) < PING_TIMEOUT);
+        let token = self.next_ping_token;
+        self.next_ping_token = self.next_ping_token.wrapping_add(1);
+        self.pings_in_flight.insert(token, now);
+        self.enqueue_body(ClientBodyElem::TokenPing(token));
+    }
+
+    /// Keep track of how far behind the server to render, from a packet's header
+    fn update_timing(&mut self, header: &ServerHeader) {
+        self.snapshot_tick = header.snapshot_tick;
+        self.server_tick_hz = header.game_tick_hz;
+        // 0 means the server isn't going to send another one, the old interval still holds
+        if header.send_interval_micros == 0 {
+            return;
+        }
+        let interval = Duration::from_micros(header.send_interval_micros);
+        if interval != self.server_send_interval {
+            self.server_send_interval = interval;
+            info!(
+                "server sending every {:?}, rendering {} ticks behind",
+                interval,
+                self.interpolation_delay_ticks()
+            );
+        }
+    }
+
+    /// Ticks between the newest snapshot and what gets rendered
+    fn interpolation_delay_ticks(&self) -> u64 {
+        let interval_ticks =
+            (self.server_send_interval.as_secs_f64() * self.server_tick_hz as f64).ceil() as u64;
+        interval_ticks * INTERPOLATION_DELAY_INTERVALS
+    }
+
+    /// Server tick that should be rendered right now
+    fn render_tick(&self) -> u64 {
+        self.snapshot_tick
+            .saturating_sub(self.interpolation_delay_ticks())
+    }
+
+    /// Remember a snapshot, make it the next one applied and tell the server we have it
+    fn store_entity_snapshot(&mut self, snapshot: u64, entities: Vec<EntityState>) {
+        self.entity_history.insert(snapshot, entities.clone());
+        while self.entity_history.len() > ENTITY_HISTORY {
+            self.entity_history.pop_first();
+        }
+        self.entity_snapshot = Some(entities);
+        // the newest ack covers any older ones still waiting
+        self.bodies
+            .retain(|b| !matches!(b, ClientBodyElem::EntityAck(_)));
+        self.enqueue_body(ClientBodyElem::EntityAck(snapshot));
+    }
+
+    /// Round trip time of the ping with this token, None if we never sent it or already got its pong
+    fn take_ping_rtt(&mut self, token: u64, now: Instant) -> Option<Duration> {
+        self.pings_in_flight
+            .remove(&token)
+            .map(|sent| now.saturating_duration_since(sent))
+    }
+
     /// Client logic for handling bodies received from the server
     /// TODO: improve performance by avoiding copies
     fn handle_body(
@@ -94,37 +311,309 @@ impl Client {
         commands: &mut Commands,
         terrain: &mut Terrain,
     ) {
+        debug!("client handling {}", body);
+        // only good for the body right after it
+        let changed_bounds = self.changed_bounds.take();
         match body {
             ServerBodyElem::Pong(pong) => info!("got pong for seqnum: {}", pong),
+            ServerBodyElem::Ping(token) => self.enqueue_body(ClientBodyElem::Pong(token)),
+            ServerBodyElem::MtuProbe { size, .. } => {
+                self.enqueue_body(ClientBodyElem::MtuAck(size))
+            }
+            ServerBodyElem::Restarting {
+                reconnect_token,
+                after_seconds,
+            } => {
+                self.restart = Some((reconnect_token, Duration::from_secs(after_seconds)));
+            }
+            ServerBodyElem::ProtocolVersion(version) => {
+                if self.protocol_version != Some(version) {
+                    info!("server speaking protocol version {} with us", version);
+                }
+                self.protocol_version = Some(version);
+                // the restarted server has answered, the token has done its job
+                if self.resume_at.is_none() {
+                    self.restart = None;
+                }
+            }
+            ServerBodyElem::TokenPong { token, server_seq } => {
+                match self.take_ping_rtt(token, Instant::now()) {
+                    Some(rtt) => info!("ping {} took {:?}, server at {}", token, rtt, server_seq),
+                    // resent pong, or an answer to a ping we gave up on
+                    None => debug!("ignoring pong for unknown ping {}", token),
+                }
+            }
             ServerBodyElem::Terrain(t) => {
                 // overwrite
                 info!("got terrain, overwriting!");
-
-                // de-render all old chunks
-                for chunk in &mut terrain.chunks {
-                    derender_chunk(commands, chunk);
+                overwrite_terrain(commands, terrain, t);
+                info!("done with terrain overwrite");
+            }
+            ServerBodyElem::MapChange(t) => {
+                info!("server changed the map");
+                self.terrain_version = None;
+                // anything queued up was meant for the old map
+                self.bodies.clear();
+                overwrite_terrain(commands, terrain, t);
+            }
+            ServerBodyElem::TerrainChunks(chunks) => {
+                for chunk in chunks {
+                    match &changed_bounds {
+                        Some(bounds) => patch_chunk(commands, terrain, chunk, bounds),
+                        None => replace_chunk(commands, terrain, chunk),
+                    }
+                }
+                // a gap below the newest chunk means some got lost on the way, ask for just those
+                let missing = missing_chunks(terrain);
+                if !missing.is_empty() {
+                    warn!("missing {} chunk(s), asking for them again", missing.len());
+                    self.enqueue_body(ClientBodyElem::RequestChunks { ids: missing });
                 }
+            }
+            ServerBodyElem::TerrainVersion(version) => {
+                self.terrain_version = Some(version);
+                self.enqueue_body(ClientBodyElem::TerrainAck(version));
+            }
+            ServerBodyElem::ChangedBounds(bounds) => self.changed_bounds = Some(bounds),
+            ServerBodyElem::Disconnect(reason) => {
+                error!("disconnected from server: {}", reason);
+                // TODO: go back to the menu and show the reason
+                self.bodies.clear();
+                if let Some((_, after)) = self.restart {
+                    info!("server is restarting, connecting again in {:?}", after);
+                    self.resume_at = Some(Instant::now() + after);
+                }
+            }
+            ServerBodyElem::Coarse { kind, chunks } => {
+                // rendered blocky, at whatever resolution the server sent
+                match ServerBodyElem::upsample(kind, &chunks) {
+                    Some(body) => self.handle_body(body, commands, terrain),
+                    None => error!("got malformed coarse {:?} terrain", kind),
+                }
+            }
+            ServerBodyElem::Compressed { codec, data } => {
+                match ServerBodyElem::decompress(codec, &data) {
+                    Ok(body) => {
+                        // still right before what was compressed
+                        self.changed_bounds = changed_bounds;
+                        self.handle_body(body, commands, terrain)
+                    }
+                    Err(e) => error!("unable to decompress {:?} body: {}", codec, e),
+                }
+            }
+            ServerBodyElem::EntitySnapshot { snapshot, entities } => {
+                // applied by apply_entity_snapshot, only the newest one matters
+                self.store_entity_snapshot(snapshot, entities);
+            }
+            ServerBodyElem::EntityDelta {
+                snapshot,
+                baseline,
+                changed,
+                removed,
+            } => match self.entity_history.get(&baseline) {
+                Some(base) => {
+                    let entities = apply_entity_delta(base, &changed, &removed);
+                    // the server won't use anything older than the baseline again
+                    self.entity_history.retain(|id, _| *id >= baseline);
+                    self.store_entity_snapshot(snapshot, entities);
+                }
+                // it'll fall back to a full snapshot once it forgets the baseline we last acked
+                None => warn!("entity delta against unknown snapshot {}", baseline),
+            },
+            ServerBodyElem::KeyExchange(server_public) => {
+                // the server keeps sending it until our first encrypted packet gets there
+                if self.session.is_none() {
+                    info!("agreed on a key with the server, encrypting from now on");
+                    self.session = Some(self.key_pair.session_key(server_public));
+                }
+            }
+            ServerBodyElem::Encrypted(_) => warn!("encrypted body inside an encrypted packet"),
+            ServerBodyElem::GameSettings(settings) => {
+                let newer = self
+                    .settings
+                    .as_ref()
+                    .is_none_or(|current| settings.version > current.version);
+                if newer {
+                    // TODO: show these in the UI once there is one for the match
+                    info!("game settings v{}: {:?}", settings.version, settings.values);
+                    self.settings = Some(settings);
+                }
+            }
+            ServerBodyElem::PlayerList(players) => {
+                let names: Vec<String> = players.iter().map(|p| p.to_string()).collect();
+                info!(
+                    "{} player(s) connected: {}",
+                    players.len(),
+                    names.join(", ")
+                );
+            }
+            // TODO: show these in the UI
+            ServerBodyElem::Announcement(text) => info!("server says: {}", text),
+            ServerBodyElem::Redirect { addr } => {
+                info!("server is full, moving to {}", addr);
+                // start over with the new server
+                self.server = addr;
+                self.last_received_sequence = 0;
+                self.bodies.clear();
+            }
+        }
+    }
+}
 
-                // overwrite the terrain
-                *terrain = t;
+/// Move, spawn and despawn networked entities to match the latest snapshot from the server
+fn apply_entity_snapshot(
+    mut client: ResMut<Client>,
+    mut commands: Commands,
+    assets: Res<AssetServer>,
+    mut query: Query<(Entity, &NetworkId, &mut Transform)>,
+) {
+    let snapshot = match client.entity_snapshot.take() {
+        Some(snapshot) => snapshot,
+        None => return,
+    };
+    let mut states: HashMap<u64, EntityState> = snapshot.into_iter().map(|s| (s.id, s)).collect();
+
+    for (entity, id, mut transform) in query.iter_mut() {
+        match states.remove(&id.0) {
+            Some(state) => {
+                transform.translation.x = state.x;
+                transform.translation.y = state.y;
+            }
+            None => commands.entity(entity).despawn(),
+        }
+    }
 
-                // terrain will be re-rendered as necessary
+    // whatever is left is new to us
+    for state in states.into_values() {
+        commands
+            .spawn_bundle(SpriteBundle {
+                transform: Transform::from_xyz(state.x, state.y, PLAYER_Z),
+                texture: assets.load(PLAYER_ASSET),
+                sprite: Sprite {
+                    custom_size: Some(Vec2::splat(PLAYER_SIZE)),
+                    ..default()
+                },
+                ..default()
+            })
+            .insert(NetworkId(state.id));
+    }
+}
 
-                info!("done with terrain overwrite");
+/// Swap out our terrain for a new one from the server
+fn overwrite_terrain(commands: &mut Commands, terrain: &mut Terrain, new_terrain: Terrain) {
+    // de-render all old chunks
+    for chunk in &mut terrain.chunks {
+        derender_chunk(commands, chunk);
+    }
+
+    // overwrite the terrain
+    *terrain = new_terrain;
+
+    // terrain will be re-rendered as necessary
+}
+
+/// Swap out one of our chunks for one from the server
+fn replace_chunk(commands: &mut Commands, terrain: &mut Terrain, new_chunk: Chunk) {
+    match terrain
+        .chunks
+        .iter_mut()
+        .find(|c| c.chunk_number == new_chunk.chunk_number)
+    {
+        Some(chunk) => {
+            derender_chunk(commands, chunk);
+            *chunk = new_chunk;
+        }
+        None => {
+            // a chunk we haven't generated yet, keep chunks in order
+            let index = terrain
+                .chunks
+                .partition_point(|c| c.chunk_number < new_chunk.chunk_number);
+            terrain.chunks.insert(index, new_chunk);
+        }
+    }
+
+    // chunk will be re-rendered as necessary
+}
+
+/// Copy over just the blocks in bounds from new_chunk, leaving the rest of ours and its sprites alone
+/// that only works for blocks being taken away, anything needing a new sprite replaces the whole chunk
+fn patch_chunk(
+    commands: &mut Commands,
+    terrain: &mut Terrain,
+    mut new_chunk: Chunk,
+    bounds: &[BlockBounds],
+) {
+    let chunk_number = new_chunk.chunk_number;
+    let chunk = match terrain
+        .chunks
+        .iter_mut()
+        .find(|c| c.chunk_number == chunk_number)
+    {
+        Some(chunk) => chunk,
+        None => return replace_chunk(commands, terrain, new_chunk),
+    };
+    let cells = || {
+        bounds
+            .iter()
+            .flat_map(move |bounds| bounds.cells_in_chunk(chunk_number))
+    };
+    let new_sprites = cells().any(
+        |(x, y)| match (&chunk.blocks[y][x], &new_chunk.blocks[y][x]) {
+            (_, None) => false,
+            (Some(old), Some(new)) => old.block_type != new.block_type,
+            (None, Some(_)) => true,
+        },
+    );
+    if new_sprites {
+        return replace_chunk(commands, terrain, new_chunk);
+    }
+    for (x, y) in cells() {
+        if new_chunk.blocks[y][x].take().is_none() {
+            if let Some(entity) = chunk.blocks[y][x].take().and_then(|block| block.entity) {
+                commands.entity(entity).despawn();
             }
         }
     }
 }
 
+/// Chunks below the newest one we have that we don't have, at most MAX_REQUESTED_CHUNKS of them
+fn missing_chunks(terrain: &Terrain) -> Vec<ChunkId> {
+    // chunks are kept in order, see replace_chunk
+    let mut missing = Vec::new();
+    let mut expected = 0;
+    for chunk in &terrain.chunks {
+        let room = MAX_REQUESTED_CHUNKS - missing.len();
+        missing.extend((expected..chunk.chunk_number).take(room));
+        expected = chunk.chunk_number + 1;
+    }
+    missing
+}
+
 pub struct ClientPlugin {
     pub server_address: IpAddr,
     pub server_port: u16,
+    /// Connect as an observer, see ServerConfig::max_observers
+    pub observer: bool,
+    /// Get terrain at 1/this the resolution each way, saves bandwidth at the cost of detail
+    pub terrain_scale: u8,
+    /// Name to go by, see ServerConfig::name_collision for what happens if it's taken
+    pub name: Option<String>,
+    /// Sample inputs and send them this many times as often as usual, each in its own packet
+    /// inputs are small, so this gets them to the server sooner without anything else being sent more
+    pub input_rate: u64,
 }
 
 impl Plugin for ClientPlugin {
     fn build(&self, app: &mut App) {
+        let (observer, terrain_scale, input_rate) =
+            (self.observer, self.terrain_scale, self.input_rate);
+        let name = self.name.clone();
         app.add_system_set(
-            SystemSet::on_enter(states::client::GameState::InGame).with_system(create_client),
+            SystemSet::on_enter(states::client::GameState::InGame).with_system(
+                move |commands: Commands| {
+                    create_client(commands, observer, terrain_scale, name.clone(), input_rate)
+                },
+            ),
         )
         .add_system_set(
             SystemSet::on_update(states::client::GameState::InGame)
@@ -134,7 +623,12 @@ impl Plugin for ClientPlugin {
                 .with_system(p_queues_ping.after(increase_tick))
                 .with_system(queue_inputs.after(increase_tick))
                 .with_system(client_handle_messages.after(p_queues_ping))
-                .with_system(send_bodies.after(client_handle_messages)),
+                .with_system(apply_entity_snapshot.after(client_handle_messages))
+                .with_system(
+                    send_bodies
+                        .after(client_handle_messages)
+                        .after(queue_inputs),
+                ),
         )
         .add_system_set(
             SystemSet::on_exit(states::client::GameState::InGame).with_system(destroy_client),
@@ -142,11 +636,22 @@ impl Plugin for ClientPlugin {
     }
 }
 
-fn create_client(mut commands: Commands) {
-    let client = match Client::new(SocketAddr::from((DEFAULT_SERVER_IP, DEFAULT_SERVER_PORT))) {
+fn create_client(
+    mut commands: Commands,
+    observer: bool,
+    terrain_scale: u8,
+    name: Option<String>,
+    input_rate: u64,
+) {
+    let mut client = match Client::new(SocketAddr::from((DEFAULT_SERVER_IP, DEFAULT_SERVER_PORT))) {
         Ok(s) => s,
         Err(e) => panic!("Unable to create client: {}", e),
     };
+    client.observer = observer;
+    client.terrain_scale = terrain_scale;
+    client.name = name;
+    client.input_rate = input_rate.clamp(1, NETWORK_TICK_DELAY);
+    info!("client speaking protocol version {}", PROTOCOL_VERSION);
     commands.insert_resource(client);
 }
 
@@ -191,14 +696,34 @@ fn p_queues_ping(mut client: ResMut<Client>, input: Res<Input<KeyCode>>) {
         .iter()
         .filter(|b| match b {
             ClientBodyElem::Ping => true,
+            ClientBodyElem::TokenPing(_) => true,
+            ClientBodyElem::EntityAck(_) => false,
             ClientBodyElem::Input(_) => false,
+            ClientBodyElem::Codecs(_) => false,
+            ClientBodyElem::TerrainAck(_) => false,
+            ClientBodyElem::PredictionError { .. } => false,
+            ClientBodyElem::AdminCommand(_) => false,
+            ClientBodyElem::ReceiveTime { .. } => false,
+            ClientBodyElem::KeyExchange(_) => false,
+            ClientBodyElem::Encrypted(_) => false,
+            ClientBodyElem::RequestChunks { .. } => false,
+            ClientBodyElem::Observe => false,
+            ClientBodyElem::DesyncReport { .. } => false,
+            ClientBodyElem::TerrainScale(_) => false,
+            ClientBodyElem::Pong(_) => false,
+            ClientBodyElem::ProtocolVersions { .. } => false,
+            ClientBodyElem::PackedInput(_) => false,
+            ClientBodyElem::MtuAck(_) => false,
+            ClientBodyElem::ReconnectToken(_) => false,
+            ClientBodyElem::Subscribe { .. } => false,
+            ClientBodyElem::Name(_) => false,
         })
         .count();
 
     // only allow one ping per network cycle
     if num_ping_bodies == 0 {
         info!("client queueing a ping");
-        client.enqueue_body(ClientBodyElem::Ping);
+        client.enqueue_ping(Instant::now());
     }
 }
 
@@ -210,13 +735,12 @@ fn queue_inputs(
     mut windows: ResMut<Windows>,
     mut query: Query<(&mut Transform, &mut CameraBoundsBox, With<Player>)>,
 ) {
-    // TODO: remove
-    // only send out once every x frames
-    if client.real_tick_count % NETWORK_TICK_DELAY != 0 {
+    if !client.input_due() {
         return;
     }
 
-    if client.debug_paused {
+    // observers only watch, the server would throw them away anyway
+    if client.debug_paused || client.observer {
         return;
     }
 
@@ -261,7 +785,14 @@ fn queue_inputs(
 
     // TODO: add block mining attempts
 
-    client.enqueue_body(ClientBodyElem::Input(input));
+    // servers new enough get the smaller form
+    let body = match client.protocol_version {
+        Some(version) if version >= PACKED_INPUT_PROTOCOL_VERSION => {
+            ClientBodyElem::PackedInput(PackedInput::pack(&input))
+        }
+        _ => ClientBodyElem::Input(input),
+    };
+    client.enqueue_body(body);
 }
 
 /// Get and handle all messages from server
@@ -273,7 +804,7 @@ fn client_handle_messages(
     if client.debug_paused {
         // eat all the messages
         let mut void = [0u8; 0];
-        while client.socket.recv_from(&mut void).is_ok() {}
+        while client.transport.recv_from(&mut void).is_ok() {}
         return;
     }
 
@@ -284,13 +815,38 @@ fn client_handle_messages(
                     "client received message with {} bodies",
                     message.bodies.len()
                 );
+                // probes don't count as the newest message, they're only answered
+                if let [ServerBodyElem::MtuProbe { .. }] = &message.bodies[..] {
+                    for body in message.bodies {
+                        client.handle_body(body, &mut commands, &mut terrain);
+                    }
+                    continue;
+                }
                 // only process newer messages, ignore old ones that arrive out of orders
                 if message.header.sequence > client.last_received_sequence {
+                    // handle reliable bodies we haven't seen yet, they are always in order
+                    for reliable in message.reliable {
+                        if reliable.id > client.last_reliable_id {
+                            client.last_reliable_id = reliable.id;
+                            client.handle_body(reliable.body, &mut commands, &mut terrain);
+                        }
+                    }
+
                     // handle all bodies sent from the server
                     for body in message.bodies {
                         client.handle_body(body, &mut commands, &mut terrain);
                     }
 
+                    // with everything applied our world should match the server's
+                    if let Some(report) = desync_report(&message.header, terrain.as_ref()) {
+                        warn!("world doesn't match the server's, reporting a desync");
+                        // only the newest one matters
+                        client
+                            .bodies
+                            .retain(|b| !matches!(b, ClientBodyElem::DesyncReport { .. }));
+                        client.enqueue_body(report);
+                    }
+
                     // if we are desync'd
                     if client.current_sequence != message.header.sequence {
                         let ticks_ahead =
@@ -306,6 +862,10 @@ fn client_handle_messages(
                         client.current_sequence = message.header.sequence;
                     }
 
+                    client.update_timing(&message.header);
+                    client.report_receive_time(message.header.sent_at_micros);
+                    debug!("client rendering tick {}", client.render_tick());
+
                     // remember the last sequence that we received
                     client.last_received_sequence = message.header.sequence;
                 }
@@ -330,18 +890,52 @@ fn send_bodies(mut client: ResMut<Client>) {
     }
 
     // TODO: remove
-    // only send out once every x frames
+    // only send out once every x frames, or more often for inputs
     if client.real_tick_count % NETWORK_TICK_DELAY != 0 {
+        if client.input_due() && !client.observer {
+            client.send_inputs();
+        }
         return;
     }
 
-    let message = ClientToServer {
-        header: ClientHeader {
-            current_sequence: client.current_sequence,
-            last_received_sequence: client.last_received_sequence,
-        },
-        bodies: client.bodies.clone(),
-    };
+    // the restarted server should be up by now
+    if client.resume_at.is_some_and(|at| Instant::now() >= at) {
+        client.resume_at = None;
+        client.start_over();
+    }
+
+    // let the server know what we can decompress, and our key, until we hear back from it
+    if client.last_received_sequence == 0 {
+        client.enqueue_body(ClientBodyElem::Codecs(SUPPORTED_CODECS.to_vec()));
+        client.enqueue_body(ClientBodyElem::ProtocolVersions {
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        });
+        let public_key = client.key_pair.public_key();
+        client.enqueue_body(ClientBodyElem::KeyExchange(public_key));
+        if client.observer {
+            client.enqueue_body(ClientBodyElem::Observe);
+        }
+        if client.terrain_scale != 1 {
+            let scale = client.terrain_scale;
+            client.enqueue_body(ClientBodyElem::TerrainScale(scale));
+        }
+        if let Some((token, _)) = client.restart {
+            client.enqueue_body(ClientBodyElem::ReconnectToken(token));
+        }
+        if let Some(name) = client.name.clone() {
+            client.enqueue_body(ClientBodyElem::Name(name));
+        }
+        if let Some(version) = client.terrain_version {
+            client.enqueue_body(ClientBodyElem::TerrainAck(version));
+        }
+    }
+
+    let message = ClientToServer::builder()
+        .ack(client.last_received_sequence)
+        .sequence(client.current_sequence)
+        .bodies(client.bodies.iter().cloned())
+        .build();
     let success_str = format!("client sent message to server: {:?}", message);
     match client.send_message(message) {
         Ok(_) => info!("{}", success_str),
@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+/// Where the server gets the time from, so timeouts can be tested without sleeping
+pub(super) trait Clock: Send + Sync + std::fmt::Debug {
+    /// The current time, never goes backwards
+    fn now(&self) -> Instant;
+
+    /// Wait until by has gone by on this clock
+    fn sleep(&self, by: Duration);
+}
+
+/// The real monotonic clock
+#[derive(Debug, Default, Clone, Copy)]
+pub(super) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, by: Duration) {
+        std::thread::sleep(by);
+    }
+}
+
+/// A clock that only moves when advance is called
+/// Cloning gives another handle to the same time, so a test can keep one and give the server the other
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub(super) struct FakeClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+#[cfg(test)]
+impl FakeClock {
+    /// Starts at the real time, so it can be mixed with Instants from before it was made
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    /// Doesn't wait at all, the time just moves on
+    fn sleep(&self, by: Duration) {
+        self.advance(by);
+    }
+}
@@ -1,8 +1,20 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    ops::RangeInclusive,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use bevy::prelude::Component;
 use bincode::{Decode, Encode};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::Deserialize;
 
-use crate::{player::PlayerInput, world::Terrain};
+use super::{CryptoError, SessionKey, Toward, Transport, PUBLIC_KEY_LEN};
+use crate::{
+    player::PlayerInput,
+    world::{BlockBounds, Chunk, CoarseChunk, Terrain, WorldState},
+};
 
 /// This is the bincode config that we should use everywhere
 pub const BINCODE_CONFIG: bincode::config::Configuration = bincode::config::standard()
@@ -15,10 +27,84 @@ pub const BINCODE_CONFIG: bincode::config::Configuration = bincode::config::stan
 pub const DEFAULT_SERVER_PORT: u16 = 8888u16;
 pub const DEFAULT_SERVER_IP: [u8; 4] = [127, 0, 0, 1];
 
+/// Version of the messages below, new bodies only ever go at the end of their enum
+/// so clients that are a version behind can still talk to the server
+/// 1: Ping gets Pong(sequence)
+/// 2: TokenPing(token) gets TokenPong, which echoes the token
+/// 3: KeyExchange, after which bodies can be Encrypted
+/// 4: RequestChunks gets those chunks sent again
+/// 5: Observe, for clients that only watch
+/// 6: PlayerList and Announcement, sent to every client when it connects
+/// 7: world_checksum in the header, clients that don't match answer with DesyncReport
+/// 8: TerrainScale gets terrain sent as Coarse bodies
+/// 9: the server's own Ping, which clients echo with Pong
+/// 10: ProtocolVersions, the server answers with the version it picked
+/// 11: PackedInput, a smaller Input
+/// 12: MtuProbe, padded out to a size, which clients answer with MtuAck
+/// 13: Restarting hands out a token, which clients bring back with ReconnectToken
+/// 14: Subscribe, for clients that only want some bodies
+/// 15: Name, and the NameTaken and Replaced disconnects
+/// 16: ChangedBounds, where in the terrain changes blocks actually changed
+pub(super) const PROTOCOL_VERSION: u32 = 16;
+
+/// Oldest version the server still talks to, older clients can't decode the header since world_checksum
+/// clients that don't say which versions they speak are taken to only speak this one
+pub(super) const MIN_PROTOCOL_VERSION: u32 = 7;
+
+/// Every version the server can speak, the highest one it shares with a client gets used
+pub(super) const SUPPORTED_PROTOCOL_VERSIONS: RangeInclusive<u32> =
+    MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION;
+
+/// Version Coarse came in, older clients can't decode it
+pub(super) const COARSE_PROTOCOL_VERSION: u32 = 8;
+
+/// Version the server's own Ping came in, older clients can't decode it
+pub(super) const PROBE_PROTOCOL_VERSION: u32 = 9;
+
+/// Version ProtocolVersion came in, older clients can't decode it
+pub(super) const NEGOTIATION_PROTOCOL_VERSION: u32 = 10;
+
+/// Version PackedInput came in, clients only send it once the server has picked this or newer
+pub(super) const PACKED_INPUT_PROTOCOL_VERSION: u32 = 11;
+
+/// Version MtuProbe came in, older clients can't decode it
+pub(super) const MTU_PROBE_PROTOCOL_VERSION: u32 = 12;
+
+/// Version Restarting came in, older clients can't decode it
+pub(super) const RESTART_PROTOCOL_VERSION: u32 = 13;
+
+/// Version the NameTaken and Replaced disconnects came in, older clients can't decode them
+pub(super) const NAME_PROTOCOL_VERSION: u32 = 15;
+
+/// Version ChangedBounds came in, older clients can't decode it
+pub(super) const CHANGED_BOUNDS_PROTOCOL_VERSION: u32 = 16;
+
+/// Longest name a client may ask for, in characters
+pub(super) const MAX_NAME_LEN: usize = 32;
+
+/// Highest version both ranges include, None if they don't overlap
+pub(super) fn negotiate_version(
+    ours: RangeInclusive<u32>,
+    theirs: RangeInclusive<u32>,
+) -> Option<u32> {
+    let highest = *ours.end().min(theirs.end());
+    (highest >= *ours.start().max(theirs.start())).then_some(highest)
+}
+
+/// Which chunk of the terrain, see Chunk::chunk_number
+pub type ChunkId = u64;
+
+/// Most chunks a client can have asked for at once, anything past that is ignored
+pub(super) const MAX_REQUESTED_CHUNKS: usize = 32;
+
 /// incoming buffer size for networking
 /// TODO: reduce whenever delta compression is implemented
 pub(super) const BUFFER_SIZE: usize = 65536;
 
+/// Most memory decoding one datagram may claim, far more than any real message needs
+/// stops a made up length prefix from making us try to allocate exabytes, which aborts rather than erroring
+pub(super) const MAX_DECODED_SIZE: usize = 16 << 20;
+
 /// Default size of allocated bodies vec, larger numbers may help reduce reallocation
 pub(super) const DEFAULT_BODIES_VEC_CAPACITY: usize = 10;
 
@@ -33,13 +119,41 @@ pub(super) trait NetworkMessage: Encode + Decode {}
 pub(super) struct ServerToClient {
     pub header: ServerHeader,
     pub bodies: Vec<ServerBodyElem>,
+    /// Bodies that get resent until the client acknowledges them, in the order they were queued
+    pub reliable: Vec<ReliableElem>,
 }
 
 /// Header for ServerToClient message
-#[derive(Encode, Decode, Debug)]
+#[derive(Encode, Decode, Debug, Default)]
 pub(super) struct ServerHeader {
     /// Sequence/tick number
     pub sequence: u64,
+    /// Game tick the entities and terrain in this packet are from
+    /// clients interpolate by rendering a little behind this
+    pub snapshot_tick: u64,
+    /// Game ticks per second, for turning ticks into time
+    pub game_tick_hz: u64,
+    /// How long until the server sends this client its next packet, in microseconds
+    /// 0 when there won't be one, e.g. disconnects
+    pub send_interval_micros: u64,
+    /// Server's clock when the packet went out, in microseconds since the unix epoch
+    pub sent_at_micros: u64,
+    /// WorldState::checksum of the server's world at snapshot_tick, see ServerConfig::checksum_interval_ticks
+    /// only sent when the client should have exactly the same world, see desync_report
+    pub world_checksum: Option<u64>,
+}
+
+impl ServerHeader {
+    /// What of the header encrypted bodies are bound to, see seal_bodies
+    /// sent_at_micros is filled in after sealing, when the packet actually goes out, so it's left out
+    pub fn aad(&self) -> Vec<u8> {
+        let header = ServerHeader {
+            sent_at_micros: 0,
+            ..*self
+        };
+        // unwrap OK because a header is only ever integers
+        bincode::encode_to_vec(header, BINCODE_CONFIG).unwrap()
+    }
 }
 
 /// One element (message) for the body of a ServerToClient message
@@ -52,6 +166,623 @@ pub(super) enum ServerBodyElem {
     /// TODO: separate into baseline and delta
     /// TODO: use ref instead
     Terrain(Terrain),
+    /// replace the whole world with a new map, should be sent reliably
+    MapChange(Terrain),
+    /// chunks that changed since they were last sent, replace the client's copies
+    TerrainChunks(Vec<Chunk>),
+    /// terrain bodies before this one bring the client up to this version, ack it with TerrainAck
+    TerrainVersion(u64),
+    /// last body a client gets before the server forgets about it
+    Disconnect(DisconnectReason),
+    /// this server has no room, connect to the one at addr instead
+    Redirect { addr: SocketAddr },
+    /// another body, bincode encoded and then compressed, see ServerBodyElem::compress
+    Compressed { codec: Codec, data: Vec<u8> },
+    /// answer to a TokenPing, token is exactly what the client sent
+    TokenPong { token: u64, server_seq: u64 },
+    /// every networked entity, anything the client has that isn't in here is gone
+    /// ack it with EntityAck so the server can send deltas against it
+    EntitySnapshot {
+        snapshot: u64,
+        entities: Vec<EntityState>,
+    },
+    /// how snapshot differs from baseline, a snapshot the client acked earlier
+    EntityDelta {
+        snapshot: u64,
+        baseline: u64,
+        changed: Vec<EntityState>,
+        removed: Vec<u64>,
+    },
+    /// the match's settings, sent reliably on connect and whenever they change
+    GameSettings(GameSettings),
+    /// the server's public key, answering the client's KeyExchange, sent until the client starts encrypting
+    KeyExchange([u8; PUBLIC_KEY_LEN]),
+    /// every other body and reliable body in the packet, see seal_bodies
+    Encrypted(Vec<u8>),
+    /// everyone connected when it was sent, the client included
+    PlayerList(Vec<PlayerListEntry>),
+    /// a message from the server for the player to read, e.g. the welcome message
+    Announcement(String),
+    /// a Terrain, MapChange or TerrainChunks body at a lower resolution, see ServerBodyElem::downsample
+    Coarse {
+        kind: CoarseKind,
+        chunks: Vec<CoarseChunk>,
+    },
+    /// the server timing the round trip, answer with a Pong with the same token
+    Ping(u64),
+    /// the protocol version the server picked out of the client's ProtocolVersions
+    ProtocolVersion(u32),
+    /// padding to make the whole datagram size bytes, answer with an MtuAck with the same size
+    /// always sent on its own and outside the usual sequence, see Server::probe_mtus
+    MtuProbe { size: u16, padding: Vec<u8> },
+    /// the server is about to restart, connect again after_seconds after it goes and send reconnect_token
+    /// to pick up where we left off, always sent reliably
+    Restarting {
+        reconnect_token: u64,
+        after_seconds: u64,
+    },
+    /// where blocks changed in the TerrainChunks right after this, one bounds per chunk or one for all of them
+    /// so they can be copied over and re-rendered without touching the rest of each chunk
+    ChangedBounds(Vec<BlockBounds>),
+}
+
+/// Which terrain body a Coarse body stands in for
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum CoarseKind {
+    Terrain,
+    MapChange,
+    TerrainChunks,
+}
+
+/// Match settings clients need to know about, e.g. game mode or whether friendly fire is on
+/// kept as strings so new settings don't need a new protocol version
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub struct GameSettings {
+    /// Goes up by one every time a setting changes, older versions can be ignored
+    pub version: u64,
+    pub values: BTreeMap<String, String>,
+}
+
+impl Default for GameSettings {
+    fn default() -> Self {
+        let values = [
+            ("mode", "sandbox"),
+            ("time_limit", "none"),
+            ("friendly_fire", "false"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        Self { version: 1, values }
+    }
+}
+
+impl GameSettings {
+    /// Change one setting, bumping the version if it's actually different
+    /// returns whether anything changed
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        if self.values.get(key).map(String::as_str) == Some(value) {
+            return false;
+        }
+        self.values.insert(key.to_string(), value.to_string());
+        self.version += 1;
+        true
+    }
+}
+
+/// One connected client, as other clients get to see it
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub struct PlayerListEntry {
+    /// Clients that joined earlier have lower numbers
+    pub join_number: u64,
+    pub name: Option<String>,
+    /// Only watching, see ClientBodyElem::Observe
+    pub observer: bool,
+}
+
+impl std::fmt::Display for PlayerListEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => f.write_str(name)?,
+            None => write!(f, "player #{}", self.join_number)?,
+        }
+        if self.observer {
+            f.write_str(" (observing)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Marks an entity whose state gets sent to clients, the id is the same on every machine
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NetworkId(pub u64);
+
+/// Where one networked entity is
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub struct EntityState {
+    /// See NetworkId
+    pub id: u64,
+    pub x: f32,
+    pub y: f32,
+}
+
+/// Entities in current that aren't the same in base, and ids in base that current doesn't have
+/// both need to be sorted by id
+pub fn entity_delta(base: &[EntityState], current: &[EntityState]) -> (Vec<EntityState>, Vec<u64>) {
+    let changed = current
+        .iter()
+        .filter(
+            |state| match base.binary_search_by_key(&state.id, |b| b.id) {
+                Ok(i) => base[i] != **state,
+                Err(_) => true,
+            },
+        )
+        .copied()
+        .collect();
+    let removed = base
+        .iter()
+        .filter(|b| current.binary_search_by_key(&b.id, |s| s.id).is_err())
+        .map(|b| b.id)
+        .collect();
+    (changed, removed)
+}
+
+/// Put the changes from entity_delta back on top of base, the result is sorted by id
+pub fn apply_entity_delta(
+    base: &[EntityState],
+    changed: &[EntityState],
+    removed: &[u64],
+) -> Vec<EntityState> {
+    let mut states: BTreeMap<u64, EntityState> = base.iter().map(|s| (s.id, *s)).collect();
+    for id in removed {
+        states.remove(id);
+    }
+    for state in changed {
+        states.insert(state.id, *state);
+    }
+    states.into_values().collect()
+}
+
+/// Ways bodies can be compressed, the server only uses one the client said it supports
+#[derive(Encode, Decode, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Uncompressed,
+    Deflate,
+}
+
+impl Codec {
+    /// This codec if the client offered it, otherwise uncompressed, which works for everyone
+    pub fn negotiate(self, offered: &[Codec]) -> Codec {
+        if offered.contains(&self) {
+            self
+        } else {
+            Codec::Uncompressed
+        }
+    }
+}
+
+/// Kinds of bodies that get compressed on their own terms, terrain is big and compresses well, entities don't
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Payload {
+    Terrain,
+    Entities,
+}
+
+/// Which codec each payload gets compressed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadCodecs {
+    pub terrain: Codec,
+    pub entities: Codec,
+}
+
+impl Default for PayloadCodecs {
+    fn default() -> Self {
+        Self {
+            terrain: Codec::Uncompressed,
+            entities: Codec::Uncompressed,
+        }
+    }
+}
+
+impl PayloadCodecs {
+    /// What a client that offered these codecs gets, each payload is negotiated on its own
+    pub fn negotiate(self, offered: &[Codec]) -> PayloadCodecs {
+        PayloadCodecs {
+            terrain: self.terrain.negotiate(offered),
+            entities: self.entities.negotiate(offered),
+        }
+    }
+
+    pub fn codec(&self, payload: Payload) -> Codec {
+        match payload {
+            Payload::Terrain => self.terrain,
+            Payload::Entities => self.entities,
+        }
+    }
+}
+
+/// Why the server stopped talking to a client
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client didn't respond for too long
+    Timeout,
+    /// An admin removed the client
+    Kicked,
+    /// The server has no room for another client
+    ServerFull,
+    /// The server is going away
+    ServerShutdown,
+    /// The client and server don't speak any of the same protocol versions
+    UnsupportedVersion,
+    /// Another client already has the name the client asked for, see NameCollision::Reject
+    NameTaken,
+    /// Another client asked for the client's name and got it, see NameCollision::Kick
+    Replaced,
+}
+
+impl DisconnectReason {
+    /// Oldest protocol version that can decode this reason
+    pub(super) fn min_version(&self) -> u32 {
+        match self {
+            DisconnectReason::NameTaken | DisconnectReason::Replaced => NAME_PROTOCOL_VERSION,
+            DisconnectReason::Timeout
+            | DisconnectReason::Kicked
+            | DisconnectReason::ServerFull
+            | DisconnectReason::ServerShutdown
+            | DisconnectReason::UnsupportedVersion => MIN_PROTOCOL_VERSION,
+        }
+    }
+
+    /// This reason if a client speaking version can decode it, otherwise being kicked, which is close enough
+    pub(super) fn for_version(self, version: u32) -> DisconnectReason {
+        if version >= self.min_version() {
+            self
+        } else {
+            DisconnectReason::Kicked
+        }
+    }
+}
+
+/// Message that can be shown to the player
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DisconnectReason::Timeout => "timed out",
+            DisconnectReason::Kicked => "kicked by the server",
+            DisconnectReason::ServerFull => "server is full",
+            DisconnectReason::ServerShutdown => "server shut down",
+            DisconnectReason::UnsupportedVersion => "client and server versions aren't compatible",
+            DisconnectReason::NameTaken => "someone else is already using that name",
+            DisconnectReason::Replaced => "someone else joined with your name",
+        })
+    }
+}
+
+/// How much a body matters when there isn't room to send everything
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BodyPriority {
+    /// Big and can wait, e.g. terrain, which gets resent until it's acked anyway
+    Low,
+    /// Goes stale quickly, e.g. entity snapshots
+    Normal,
+    /// Small and needed to keep the connection working, e.g. pongs
+    High,
+}
+
+/// A group of bodies a client can choose not to get, see ClientBodyElem::Subscribe
+/// the numbers are bits of the mask, and must never change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum BodyCategory {
+    Pongs = 0,
+    Terrain = 1,
+    Entities = 2,
+    Settings = 3,
+    Players = 4,
+    Announcements = 5,
+}
+
+impl BodyCategory {
+    /// The category's bit in a subscription mask
+    pub fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// Subscription mask with every category in it, what clients get unless they say otherwise
+pub(super) const SUBSCRIBE_ALL: u32 = u32::MAX;
+
+impl ServerBodyElem {
+    /// Oldest protocol version that can decode this body, clients speaking an older one never get it
+    pub(super) fn min_version(&self) -> u32 {
+        match self {
+            ServerBodyElem::Coarse { .. } => COARSE_PROTOCOL_VERSION,
+            ServerBodyElem::Ping(_) => PROBE_PROTOCOL_VERSION,
+            ServerBodyElem::ProtocolVersion(_) => NEGOTIATION_PROTOCOL_VERSION,
+            ServerBodyElem::MtuProbe { .. } => MTU_PROBE_PROTOCOL_VERSION,
+            ServerBodyElem::Restarting { .. } => RESTART_PROTOCOL_VERSION,
+            ServerBodyElem::ChangedBounds(_) => CHANGED_BOUNDS_PROTOCOL_VERSION,
+            ServerBodyElem::Disconnect(reason) => reason.min_version(),
+            ServerBodyElem::Pong(_)
+            | ServerBodyElem::Terrain(_)
+            | ServerBodyElem::MapChange(_)
+            | ServerBodyElem::TerrainChunks(_)
+            | ServerBodyElem::TerrainVersion(_)
+            | ServerBodyElem::Redirect { .. }
+            | ServerBodyElem::Compressed { .. }
+            | ServerBodyElem::TokenPong { .. }
+            | ServerBodyElem::EntitySnapshot { .. }
+            | ServerBodyElem::EntityDelta { .. }
+            | ServerBodyElem::GameSettings(_)
+            | ServerBodyElem::KeyExchange(_)
+            | ServerBodyElem::Encrypted(_)
+            | ServerBodyElem::PlayerList(_)
+            | ServerBodyElem::Announcement(_) => MIN_PROTOCOL_VERSION,
+        }
+    }
+
+    /// Which category the body is in, None for bodies every client gets since the connection needs them
+    pub fn category(&self) -> Option<BodyCategory> {
+        match self {
+            ServerBodyElem::Pong(_) | ServerBodyElem::TokenPong { .. } => Some(BodyCategory::Pongs),
+            ServerBodyElem::Terrain(_)
+            | ServerBodyElem::MapChange(_)
+            | ServerBodyElem::TerrainChunks(_)
+            | ServerBodyElem::TerrainVersion(_)
+            | ServerBodyElem::ChangedBounds(_)
+            | ServerBodyElem::Coarse { .. } => Some(BodyCategory::Terrain),
+            ServerBodyElem::EntitySnapshot { .. } | ServerBodyElem::EntityDelta { .. } => {
+                Some(BodyCategory::Entities)
+            }
+            ServerBodyElem::GameSettings(_) => Some(BodyCategory::Settings),
+            ServerBodyElem::PlayerList(_) => Some(BodyCategory::Players),
+            ServerBodyElem::Announcement(_) => Some(BodyCategory::Announcements),
+            ServerBodyElem::Disconnect(_)
+            | ServerBodyElem::Redirect { .. }
+            | ServerBodyElem::Compressed { .. }
+            | ServerBodyElem::KeyExchange(_)
+            | ServerBodyElem::Encrypted(_)
+            | ServerBodyElem::Ping(_)
+            | ServerBodyElem::ProtocolVersion(_)
+            | ServerBodyElem::MtuProbe { .. }
+            | ServerBodyElem::Restarting { .. } => None,
+        }
+    }
+
+    /// Whether a client with this subscription mask gets the body
+    pub fn subscribed(&self, mask: u32) -> bool {
+        self.category()
+            .is_none_or(|category| mask & category.bit() != 0)
+    }
+
+    /// What gets cut first when the server is short on bandwidth, see ServerConfig::max_bytes_per_second
+    pub fn priority(&self) -> BodyPriority {
+        match self {
+            ServerBodyElem::Pong(_) => BodyPriority::High,
+            ServerBodyElem::Ping(_) => BodyPriority::High,
+            ServerBodyElem::ProtocolVersion(_) => BodyPriority::High,
+            ServerBodyElem::MtuProbe { .. } => BodyPriority::High,
+            ServerBodyElem::Restarting { .. } => BodyPriority::High,
+            ServerBodyElem::TokenPong { .. } => BodyPriority::High,
+            ServerBodyElem::Disconnect(_) => BodyPriority::High,
+            ServerBodyElem::Redirect { .. } => BodyPriority::High,
+            ServerBodyElem::KeyExchange(_) => BodyPriority::High,
+            ServerBodyElem::EntitySnapshot { .. } => BodyPriority::Normal,
+            ServerBodyElem::EntityDelta { .. } => BodyPriority::Normal,
+            ServerBodyElem::GameSettings(_) => BodyPriority::Normal,
+            ServerBodyElem::PlayerList(_) => BodyPriority::Normal,
+            ServerBodyElem::Announcement(_) => BodyPriority::Normal,
+            // whatever is inside is mostly terrain
+            ServerBodyElem::Encrypted(_) => BodyPriority::Normal,
+            ServerBodyElem::Terrain(_) => BodyPriority::Low,
+            ServerBodyElem::MapChange(_) => BodyPriority::Low,
+            ServerBodyElem::TerrainChunks(_) => BodyPriority::Low,
+            ServerBodyElem::TerrainVersion(_) => BodyPriority::Low,
+            ServerBodyElem::ChangedBounds(_) => BodyPriority::Low,
+            ServerBodyElem::Compressed { .. } => BodyPriority::Low,
+            ServerBodyElem::Coarse { .. } => BodyPriority::Low,
+        }
+    }
+
+    /// Short name of the variant, without any of its contents
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerBodyElem::Pong(_) => "pong",
+            ServerBodyElem::Terrain(_) => "terrain",
+            ServerBodyElem::MapChange(_) => "map_change",
+            ServerBodyElem::TerrainChunks(_) => "terrain_chunks",
+            ServerBodyElem::TerrainVersion(_) => "terrain_version",
+            ServerBodyElem::ChangedBounds(_) => "changed_bounds",
+            ServerBodyElem::Disconnect(_) => "disconnect",
+            ServerBodyElem::Redirect { .. } => "redirect",
+            ServerBodyElem::Compressed { .. } => "compressed",
+            ServerBodyElem::TokenPong { .. } => "token_pong",
+            ServerBodyElem::EntitySnapshot { .. } => "entity_snapshot",
+            ServerBodyElem::EntityDelta { .. } => "entity_delta",
+            ServerBodyElem::GameSettings(_) => "game_settings",
+            ServerBodyElem::KeyExchange(_) => "key_exchange",
+            ServerBodyElem::Encrypted(_) => "encrypted",
+            ServerBodyElem::PlayerList(_) => "player_list",
+            ServerBodyElem::Announcement(_) => "announcement",
+            ServerBodyElem::Coarse { .. } => "coarse",
+            ServerBodyElem::Ping(_) => "ping",
+            ServerBodyElem::ProtocolVersion(_) => "protocol_version",
+            ServerBodyElem::MtuProbe { .. } => "mtu_probe",
+            ServerBodyElem::Restarting { .. } => "restarting",
+        }
+    }
+
+    /// Which payload the body is part of, None for anything too small to ever be worth compressing
+    pub fn payload(&self) -> Option<Payload> {
+        match self {
+            ServerBodyElem::Terrain(_)
+            | ServerBodyElem::MapChange(_)
+            | ServerBodyElem::TerrainChunks(_)
+            | ServerBodyElem::Coarse { .. } => Some(Payload::Terrain),
+            ServerBodyElem::EntitySnapshot { .. } | ServerBodyElem::EntityDelta { .. } => {
+                Some(Payload::Entities)
+            }
+            _ => None,
+        }
+    }
+
+    /// Compress terrain and entity bodies with the codec for their payload, everything else is sent as is
+    pub fn compress(&self, codecs: PayloadCodecs) -> ServerBodyElem {
+        let codec = match self.payload() {
+            Some(payload) => codecs.codec(payload),
+            None => Codec::Uncompressed,
+        };
+        if codec == Codec::Uncompressed {
+            return self.clone();
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        let data = bincode::encode_into_std_write(self, &mut encoder, BINCODE_CONFIG)
+            .ok()
+            .and_then(|_| encoder.finish().ok());
+        match data {
+            Some(data) => ServerBodyElem::Compressed { codec, data },
+            // sending it uncompressed is better than not sending it
+            None => self.clone(),
+        }
+    }
+
+    /// Terrain bodies with every chunk at 1/scale the resolution, None for anything else or a scale of 1
+    /// scale has to be valid_terrain_scale
+    pub fn downsample(&self, scale: u8) -> Option<ServerBodyElem> {
+        if scale == 1 {
+            return None;
+        }
+        let (kind, chunks) = match self {
+            ServerBodyElem::Terrain(t) => (CoarseKind::Terrain, &t.chunks),
+            ServerBodyElem::MapChange(t) => (CoarseKind::MapChange, &t.chunks),
+            ServerBodyElem::TerrainChunks(chunks) => (CoarseKind::TerrainChunks, chunks),
+            _ => return None,
+        };
+        Some(ServerBodyElem::Coarse {
+            kind,
+            chunks: chunks.iter().map(|c| c.downsample(scale)).collect(),
+        })
+    }
+
+    /// Get back a full resolution body from a Coarse one, None if any of its chunks are malformed
+    pub fn upsample(kind: CoarseKind, chunks: &[CoarseChunk]) -> Option<ServerBodyElem> {
+        let chunks = chunks
+            .iter()
+            .map(CoarseChunk::upsample)
+            .collect::<Option<Vec<Chunk>>>()?;
+        Some(match kind {
+            CoarseKind::Terrain => ServerBodyElem::Terrain(Terrain::from_chunks(chunks)),
+            CoarseKind::MapChange => ServerBodyElem::MapChange(Terrain::from_chunks(chunks)),
+            CoarseKind::TerrainChunks => ServerBodyElem::TerrainChunks(chunks),
+        })
+    }
+
+    /// What the body goes out as for a client, downsampled to its terrain scale and then compressed
+    pub fn shrink(&self, scale: u8, codecs: PayloadCodecs) -> ServerBodyElem {
+        match self.downsample(scale) {
+            Some(coarse) => coarse.compress(codecs),
+            None => self.compress(codecs),
+        }
+    }
+
+    /// Get back the body that was compressed into data
+    pub fn decompress(
+        codec: Codec,
+        data: &[u8],
+    ) -> Result<ServerBodyElem, bincode::error::DecodeError> {
+        match codec {
+            Codec::Uncompressed => {
+                bincode::decode_from_slice(data, BINCODE_CONFIG).map(|(body, _size)| body)
+            }
+            Codec::Deflate => {
+                bincode::decode_from_std_read(&mut DeflateDecoder::new(data), BINCODE_CONFIG)
+            }
+        }
+    }
+}
+
+/// Summary of a body, doesn't print out whole terrains like Debug does
+impl std::fmt::Display for ServerBodyElem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerBodyElem::Pong(seq) => write!(f, "pong({})", seq),
+            ServerBodyElem::Ping(token) => write!(f, "ping({})", token),
+            ServerBodyElem::ProtocolVersion(version) => write!(f, "protocol_version({})", version),
+            ServerBodyElem::MtuProbe { size, .. } => write!(f, "mtu_probe({})", size),
+            ServerBodyElem::Restarting { after_seconds, .. } => {
+                write!(f, "restarting(back after {}s)", after_seconds)
+            }
+            ServerBodyElem::Terrain(t) | ServerBodyElem::MapChange(t) => {
+                write!(f, "{}({} chunks)", self.kind(), t.chunks.len())
+            }
+            ServerBodyElem::TerrainChunks(chunks) => {
+                let numbers: Vec<u64> = chunks.iter().map(|c| c.chunk_number).collect();
+                write!(f, "{}({:?})", self.kind(), numbers)
+            }
+            ServerBodyElem::TerrainVersion(version) => write!(f, "terrain_version({})", version),
+            ServerBodyElem::ChangedBounds(bounds) => write!(f, "changed_bounds({})", bounds.len()),
+            ServerBodyElem::Disconnect(reason) => write!(f, "disconnect({})", reason),
+            ServerBodyElem::Redirect { addr } => write!(f, "redirect({})", addr),
+            ServerBodyElem::Compressed { codec, data } => {
+                write!(f, "compressed({:?}, {} bytes)", codec, data.len())
+            }
+            ServerBodyElem::Coarse { kind, chunks } => {
+                let scale = chunks.first().map_or(1, |c| c.scale);
+                write!(
+                    f,
+                    "coarse({:?}, {} chunks at 1/{})",
+                    kind,
+                    chunks.len(),
+                    scale
+                )
+            }
+            ServerBodyElem::TokenPong { token, server_seq } => {
+                write!(f, "token_pong({}, seq {})", token, server_seq)
+            }
+            ServerBodyElem::EntitySnapshot { snapshot, entities } => {
+                write!(
+                    f,
+                    "{}({}, {} entities)",
+                    self.kind(),
+                    snapshot,
+                    entities.len()
+                )
+            }
+            ServerBodyElem::GameSettings(settings) => {
+                write!(f, "{}(v{})", self.kind(), settings.version)
+            }
+            ServerBodyElem::KeyExchange(_) => f.write_str(self.kind()),
+            ServerBodyElem::Encrypted(sealed) => {
+                write!(f, "{}({} bytes)", self.kind(), sealed.len())
+            }
+            ServerBodyElem::PlayerList(players) => {
+                write!(f, "{}({} players)", self.kind(), players.len())
+            }
+            ServerBodyElem::Announcement(text) => write!(f, "{}({:?})", self.kind(), text),
+            ServerBodyElem::EntityDelta {
+                snapshot,
+                baseline,
+                changed,
+                removed,
+            } => write!(
+                f,
+                "{}({} from {}, {} changed, {} removed)",
+                self.kind(),
+                snapshot,
+                baseline,
+                changed.len(),
+                removed.len()
+            ),
+        }
+    }
+}
+
+/// A body that is sent reliably
+#[derive(Encode, Decode, Debug, Clone)]
+pub(super) struct ReliableElem {
+    /// Increases by one for every reliable body sent to a client, used to ignore duplicates
+    pub id: u64,
+    pub body: ServerBodyElem,
 }
 
 impl NetworkMessage for ServerToClient {}
@@ -73,6 +804,58 @@ pub(super) struct ClientHeader {
     pub last_received_sequence: u64,
 }
 
+impl ClientHeader {
+    /// What of the header encrypted bodies are bound to, see seal_bodies
+    pub fn aad(&self) -> Vec<u8> {
+        // unwrap OK because a header is only ever integers
+        bincode::encode_to_vec(self, BINCODE_CONFIG).unwrap()
+    }
+}
+
+/// A PlayerInput in as few bytes as it fits in, the keys are one bit each
+/// and the block only goes along while mining, since it means nothing otherwise
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct PackedInput {
+    keys: u8,
+    block: Option<(usize, usize)>,
+}
+
+impl PackedInput {
+    const LEFT: u8 = 1 << 0;
+    const RIGHT: u8 = 1 << 1;
+    const JUMP: u8 = 1 << 2;
+    const MINE: u8 = 1 << 3;
+
+    pub fn pack(input: &PlayerInput) -> Self {
+        let keys = [
+            (input.left, Self::LEFT),
+            (input.right, Self::RIGHT),
+            (input.jump, Self::JUMP),
+            (input.mine, Self::MINE),
+        ]
+        .iter()
+        .filter(|(pressed, _)| *pressed)
+        .fold(0, |keys, (_, bit)| keys | bit);
+        Self {
+            keys,
+            block: input.mine.then_some((input.block_x, input.block_y)),
+        }
+    }
+
+    /// The input this was packed from, the block is 0, 0 when not mining
+    pub fn unpack(&self) -> PlayerInput {
+        let (block_x, block_y) = self.block.unwrap_or_default();
+        PlayerInput {
+            left: self.keys & Self::LEFT != 0,
+            right: self.keys & Self::RIGHT != 0,
+            jump: self.keys & Self::JUMP != 0,
+            mine: self.keys & Self::MINE != 0,
+            block_x,
+            block_y,
+        }
+    }
+}
+
 /// One element (message) for the body of a ClientToServer message
 #[derive(Encode, Decode, Debug, Clone)]
 pub(super) enum ClientBodyElem {
@@ -81,10 +864,119 @@ pub(super) enum ClientBodyElem {
     Ping,
     /// sends entire input
     Input(PlayerInput),
+    /// codecs the client can decompress, sent until the server answers
+    Codecs(Vec<Codec>),
+    /// the client's terrain is up to date with this version
+    TerrainAck(u64),
+    /// how far (in pixels) the client's predicted player was from where the server put it
+    PredictionError { magnitude: f32 },
+    /// asks server to send a TokenPong with the same token back
+    /// the client picks the token, so it can match pongs to pings even with several in flight
+    TokenPing(u64),
+    /// the client has applied this entity snapshot, see EntitySnapshot
+    EntityAck(u64),
+    /// a line of server console input, e.g. `kick <address>`, only the host's get run
+    AdminCommand(String),
+    /// when the client got the server packet sent at sent_at_micros, by the server's clock
+    ReceiveTime {
+        sent_at_micros: u64,
+        received_at_micros: u64,
+    },
+    /// the client's public key, sent until the server answers with its own
+    KeyExchange([u8; PUBLIC_KEY_LEN]),
+    /// every other body in the packet, see seal_bodies
+    Encrypted(Vec<u8>),
+    /// the client is missing these chunks and wants them sent again, instead of the whole terrain
+    RequestChunks { ids: Vec<ChunkId> },
+    /// the client only wants to watch, sent along with its first packets, see ServerConfig::max_observers
+    Observe,
+    /// the client's world didn't match the world_checksum the server sent for tick
+    DesyncReport { tick: u64, client_checksum: u64 },
+    /// the client wants terrain at 1/scale the resolution each way, sent along with its codecs
+    TerrainScale(u8),
+    /// echoes a Ping from the server, with its token
+    Pong(u64),
+    /// the protocol versions the client can speak, sent along with its codecs
+    ProtocolVersions { min: u32, max: u32 },
+    /// same as Input, but smaller, see PACKED_INPUT_PROTOCOL_VERSION
+    PackedInput(PackedInput),
+    /// the MtuProbe of this size got through
+    MtuAck(u16),
+    /// the token from a Restarting, sent along with the codecs when connecting to the restarted server
+    ReconnectToken(u64),
+    /// only send bodies in these categories from now on, one bit per BodyCategory, SUBSCRIBE_ALL to get everything again
+    Subscribe { mask: u32 },
+    /// the name the player wants to go by, sent along with the codecs when connecting
+    Name(String),
 }
 
 impl NetworkMessage for ClientToServer {}
 
+impl ClientToServer {
+    /// Build a message one body at a time, the ack has to be given before anything else
+    /// e.g. `ClientToServer::builder().ack(seq).token_ping(token).input(input).build()`
+    pub fn builder() -> ClientToServerAck {
+        ClientToServerAck
+    }
+}
+
+/// A ClientToServer builder still waiting on the last received sequence, see ClientToServer::builder
+#[derive(Debug)]
+pub(super) struct ClientToServerAck;
+
+impl ClientToServerAck {
+    /// The last server sequence the client got
+    pub fn ack(self, last_received_sequence: u64) -> ClientToServerBuilder {
+        ClientToServerBuilder {
+            header: ClientHeader {
+                current_sequence: 0,
+                last_received_sequence,
+            },
+            bodies: Vec::new(),
+        }
+    }
+}
+
+/// Builds a ClientToServer with its header filled in, see ClientToServer::builder
+#[derive(Debug)]
+pub(super) struct ClientToServerBuilder {
+    header: ClientHeader,
+    bodies: Vec<ClientBodyElem>,
+}
+
+impl ClientToServerBuilder {
+    /// The client's own sequence, 0 if it's never set
+    pub fn sequence(mut self, current_sequence: u64) -> Self {
+        self.header.current_sequence = current_sequence;
+        self
+    }
+
+    pub fn body(mut self, body: ClientBodyElem) -> Self {
+        self.bodies.push(body);
+        self
+    }
+
+    pub fn bodies(mut self, bodies: impl IntoIterator<Item = ClientBodyElem>) -> Self {
+        self.bodies.extend(bodies);
+        self
+    }
+
+    pub fn token_ping(self, token: u64) -> Self {
+        self.body(ClientBodyElem::TokenPing(token))
+    }
+
+    pub fn input(self, input: PlayerInput) -> Self {
+        self.body(ClientBodyElem::Input(input))
+    }
+
+    pub fn build(self) -> ClientToServer {
+        ClientToServer {
+            header: self.header,
+            bodies: self.bodies,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(super) enum SendError {
     IoError(std::io::Error),
@@ -97,20 +989,105 @@ pub(super) enum ReceiveError {
     IoError(std::io::Error),
     DecodeError(bincode::error::DecodeError),
     UnknownSender,
+    /// Server was full so the sender got sent to another server
+    Redirected,
+    /// Sender isn't allowed to talk to us, e.g. non-loopback when in localhost-only mode
+    AddressNotAllowed,
+    /// Sender was kicked and its ban hasn't run out yet
+    Banned,
+    /// Datagram had nothing in it, usually someone probing the port
+    EmptyDatagram,
+    /// Message claimed more bodies than the server is willing to handle, holds how many
+    TooManyBodies(usize),
+    /// Datagram was bigger than ServerConfig::max_message_size
+    Oversized {
+        from: SocketAddr,
+        size: usize,
+    },
+    /// Encrypted bodies that couldn't be opened
+    Decrypt(CryptoError),
+    /// Bodies sent in the clear after agreeing to encrypt them
+    Unencrypted,
+    /// A client we already have started connecting again, and ServerConfig::duplicate_connect says no
+    DuplicateConnect,
+    /// Middleware dropped the message before it was handled
+    Vetoed,
     NoMessage,
 }
 
+/// Encrypt bodies with key, the result goes out as the packet's only body
+/// headers stay readable so packets can still be routed and acked, but header (see ServerHeader::aad)
+/// has to arrive unchanged for them to open
+pub(super) fn seal_bodies<T: Encode>(
+    key: &mut SessionKey,
+    toward: Toward,
+    header: &[u8],
+    bodies: T,
+) -> Result<Vec<u8>, bincode::error::EncodeError> {
+    let plaintext = bincode::encode_to_vec(bodies, BINCODE_CONFIG)?;
+    Ok(key.seal(toward, header, &plaintext))
+}
+
+/// Decode a message out of a receive buffer that the next packet is going to overwrite
+/// the 'static bound means the message owns everything in it, so nothing decoded can still point into the buffer
+pub(super) fn decode_into_owned<M: Decode + 'static>(
+    bytes: &[u8],
+) -> Result<M, bincode::error::DecodeError> {
+    bincode::decode_from_slice(bytes, BINCODE_CONFIG.with_limit::<MAX_DECODED_SIZE>())
+        .map(|(message, _size)| message)
+}
+
+/// Get back the bodies seal_bodies put in sealed
+pub(super) fn open_bodies<T: Decode + 'static>(
+    key: &mut SessionKey,
+    toward: Toward,
+    header: &[u8],
+    sealed: &[u8],
+) -> Result<T, ReceiveError> {
+    let plaintext = key
+        .open(toward, header, sealed)
+        .map_err(ReceiveError::Decrypt)?;
+    decode_into_owned(&plaintext).map_err(ReceiveError::DecodeError)
+}
+
+/// What a client should tell the server after applying a packet with header, None if its world matches
+/// or the server didn't send a checksum
+pub(super) fn desync_report(
+    header: &ServerHeader,
+    world: &dyn WorldState,
+) -> Option<ClientBodyElem> {
+    let expected = header.world_checksum?;
+    let client_checksum = world.checksum();
+    (client_checksum != expected).then_some(ClientBodyElem::DesyncReport {
+        tick: header.snapshot_tick,
+        client_checksum,
+    })
+}
+
+/// Microseconds since the unix epoch by this machine's clock, 0 if the clock is before 1970
+pub(super) fn unix_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64)
+}
+
+/// How many bytes value takes up once encoded, 0 if it can't be
+pub(super) fn encoded_len<T: Encode>(value: &T) -> usize {
+    bincode::encode_to_vec(value, BINCODE_CONFIG).map_or(0, |encoded| encoded.len())
+}
+
 /// Helper method for sending a message
+/// returns how many bytes were sent
 pub(super) fn send_message<M: NetworkMessage>(
-    socket: &UdpSocket,
+    transport: &dyn Transport,
     target: SocketAddr,
     message: M,
-) -> Result<(), SendError> {
+) -> Result<usize, SendError> {
     // TODO: use a buffer instead of allocating into vector
     let encoded =
         bincode::encode_to_vec(message, BINCODE_CONFIG).map_err(|e| SendError::EncodeError(e))?;
-    socket
+    transport
         .send_to(&encoded, target)
         .map_err(|e| SendError::IoError(e))?;
-    Ok(())
+    Ok(encoded.len())
 }
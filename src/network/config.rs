@@ -0,0 +1,940 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use super::{
+    Codec, PayloadCodecs, UdpTransport, BUFFER_SIZE, DEFAULT_SERVER_IP, DEFAULT_SERVER_PORT,
+    FRAME_DIFFERENCE_BEFORE_DISCONNECT,
+};
+use crate::{
+    save::{default_save_path_server, inspect_save_file},
+    world::MAX_TERRAIN_CHUNKS,
+};
+
+/// how many times per second the network tick occurs by default
+pub const DEFAULT_NETWORK_TICK_HZ: u64 = 1;
+
+/// how many times per second the game tick occurs by default
+pub const DEFAULT_GAME_TICK_HZ: u64 = 60;
+
+/// network ticks further apart than this mean the server stalled
+pub const DEFAULT_TICK_WATCHDOG_THRESHOLD: Duration =
+    Duration::from_millis(2 * 1000 / DEFAULT_NETWORK_TICK_HZ);
+
+// maximum number of clients (final goal = 2, strech goal = 4)
+pub const DEFAULT_MAX_CLIENTS: usize = 2;
+
+/// observers (casters, coaches) that can watch on top of max_clients players
+pub const DEFAULT_MAX_OBSERVERS: usize = 2;
+
+/// most datagrams handled in one game tick, the rest wait in the socket's receive buffer until the next tick
+pub const DEFAULT_MAX_MESSAGES_PER_TICK: usize = 64;
+
+/// high enough that normal play never gets near it
+pub const DEFAULT_MAX_PACKETS_PER_SECOND: u64 = 120;
+
+/// clients that haven't sent any input for this long are treated as idle
+pub const DEFAULT_IDLE_AFTER: Duration = Duration::from_secs(30);
+
+/// kicked clients are refused for this long, so they can't just rejoin straight away
+pub const DEFAULT_KICK_BAN: Duration = Duration::from_secs(30);
+
+/// a client restarting a few times in a minute is unlucky, more than that is someone churning on purpose
+pub const DEFAULT_MAX_RECONNECTS: u32 = 5;
+
+/// reconnects older than this don't count towards max_reconnects
+pub const DEFAULT_RECONNECT_WINDOW: Duration = Duration::from_secs(60);
+
+/// new clients aren't dropped for this long even if they haven't acked anything yet
+pub const DEFAULT_CONNECT_GRACE: Duration = Duration::from_secs(15);
+
+/// how many seconds of packets are kept in memory by default, in case they need to be dumped
+pub const DEFAULT_REPLAY_WINDOW: Duration = Duration::from_secs(10);
+
+/// datagrams the receive thread holds on to before dropping new ones
+pub const DEFAULT_RECEIVE_QUEUE_CAPACITY: usize = 1024;
+
+/// binding again this many times covers a port that's still held by a server that just stopped
+pub const DEFAULT_BIND_RETRIES: u32 = 4;
+
+/// wait before the first bind retry, doubled for every retry after it
+pub const DEFAULT_BIND_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// clients get a packet every network tick they're due one, even when there's nothing in it
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::ZERO;
+
+/// far more bodies than a real client ever sends in one message
+pub const DEFAULT_MAX_BODIES_PER_MESSAGE: usize = 256;
+
+/// a few times the biggest datagram that gets anywhere unfragmented, real clients stay well under it
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4096;
+
+/// physics runs once per game tick by default
+pub const DEFAULT_PHYSICS_SUBSTEPS: u64 = 1;
+
+/// by default terrain changes go out on every network tick
+pub const DEFAULT_TERRAIN_INTERVAL_TICKS: u64 = 1;
+
+/// the world checksum goes out every 10 network ticks
+pub const DEFAULT_CHECKSUM_INTERVAL_TICKS: u64 = 10;
+
+/// a row of metrics every 10 seconds is plenty for graphing a long run
+pub const DEFAULT_METRICS_CSV_INTERVAL: Duration = Duration::from_secs(10);
+
+/// a second of world snapshots at the default game tick rate, enough to rewind to what a laggy client saw
+pub const DEFAULT_SNAPSHOT_HISTORY: usize = DEFAULT_GAME_TICK_HZ as usize;
+
+/// long enough for a couple of round trips on a bad connection, short enough that stopping doesn't drag
+pub const DEFAULT_SHUTDOWN_DRAIN: Duration = Duration::from_secs(1);
+
+/// shown to every client when the server stops
+pub const DEFAULT_SHUTDOWN_MESSAGE: &str = "The server is shutting down";
+
+/// shown to every client when it connects
+pub const DEFAULT_WELCOME_MESSAGE: &str = "Welcome to the server!";
+
+/// What to do when a client we already have starts connecting again from the same address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateConnect {
+    /// Start it over as a new client, it most likely restarted without disconnecting
+    #[default]
+    Reconnect,
+    /// Ignore it until the old client times out
+    Reject,
+}
+
+/// What to do when a client asks for a name another client already has
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NameCollision {
+    /// Give the newcomer the name with a number after it, e.g. "miner (2)"
+    #[default]
+    Suffix,
+    /// Turn the newcomer away, the name stays with whoever had it first
+    Reject,
+    /// Disconnect whoever had the name and give it to the newcomer, for a player reconnecting from somewhere else
+    /// only if the newcomer has the reconnect token the other one connected with, otherwise same as Suffix
+    Kick,
+}
+
+/// What to do with inputs from a client whose player entity is gone, e.g. it died and hasn't respawned
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingPlayerInputs {
+    /// Throw them away, a respawned player starts out standing still
+    #[default]
+    Ignore,
+    /// Hold on to the latest one and apply it once the player is back
+    Keep,
+}
+
+/// Network interface the server's socket is tied to, for machines with more than one
+/// anything that parses as an ip address is an address, anything else a device name
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "String")]
+pub enum Interface {
+    /// The interface that has this address, the socket binds to it instead of bind_addr's ip
+    Address(IpAddr),
+    /// The interface called this, e.g. eth0, Linux only
+    Device(String),
+}
+
+impl From<String> for Interface {
+    fn from(name: String) -> Self {
+        match name.parse() {
+            Ok(ip) => Interface::Address(ip),
+            Err(_) => Interface::Device(name),
+        }
+    }
+}
+
+/// Every server tunable in one place
+/// Config files only need the fields that differ from the defaults, durations are in seconds
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address the server's socket binds to
+    pub bind_addr: SocketAddr,
+    /// Only use this network interface, None to use whatever bind_addr ends up on
+    pub interface: Option<Interface>,
+    /// Where the world gets saved
+    pub save_file: PathBuf,
+    /// Save the world to save_file every few seconds and when stopping, false to never touch it
+    pub persist: bool,
+    /// Network ticks (sending out messages) per second
+    pub network_tick_hz: u64,
+    /// Game ticks (handling messages and inputs) per second
+    pub game_tick_hz: u64,
+    /// Most clients connected at once, not counting observers
+    pub max_clients: usize,
+    /// What happens when a known client connects again from the same address, e.g. after a crash
+    pub duplicate_connect: DuplicateConnect,
+    /// What happens when a client asks for a name another client already has
+    pub name_collision: NameCollision,
+    /// Most times one address gets started over within reconnect_window, after that its reconnects are
+    /// ignored like DuplicateConnect::Reject until the old reconnects age out, None for no limit
+    pub max_reconnects: Option<u32>,
+    /// How far back reconnects count towards max_reconnects
+    #[serde(with = "duration_secs")]
+    pub reconnect_window: Duration,
+    /// Most observers connected at once, on top of max_clients, 0 to turn observers away
+    /// observers get sent everything but their inputs are ignored
+    pub max_observers: usize,
+    /// Most datagrams handled in one game tick
+    pub max_messages_per_tick: usize,
+    /// Size of the incoming buffer, bigger datagrams get cut off
+    pub buffer_size: usize,
+    /// Datagrams bigger than this are thrown away without being decoded, should be under buffer_size
+    pub max_message_size: usize,
+    /// Only accept clients with a loopback address
+    pub localhost_only: bool,
+    /// Send terrain changes once every this many network ticks, pongs still go out every tick
+    pub terrain_interval_ticks: u64,
+    /// Spread sends to clients out over the network tick
+    pub pacing: bool,
+    /// Hold back packets to a client that would go out early, so they stay evenly spaced however unevenly ticks run
+    pub smooth_sends: bool,
+    /// Warn when network ticks are further apart than this
+    #[serde(with = "duration_secs")]
+    pub tick_watchdog_threshold: Duration,
+    /// Record every incoming packet to a replay log here
+    pub record_path: Option<PathBuf>,
+    /// Gzip the replay log
+    pub compress_record: bool,
+    /// Most packets sent to one client in any second, anything over waits
+    pub max_packets_per_second: u64,
+    /// Send less often to clients that haven't sent input for this long
+    #[serde(with = "duration_secs")]
+    pub idle_after: Duration,
+    /// Refuse kicked clients for this long, zero to not ban at all
+    #[serde(with = "duration_secs")]
+    pub kick_ban: Duration,
+    /// When full, send new clients to this server instead of turning them away
+    pub redirect_when_full: Option<SocketAddr>,
+    /// Keep this long of incoming packets in memory for the dump command, zero to keep none
+    #[serde(with = "duration_secs")]
+    pub replay_window: Duration,
+    /// Newly connected clients get this long before the normal timeout applies
+    #[serde(with = "duration_secs")]
+    pub connect_grace: Duration,
+    /// Log every client's traffic once a second, for looking into one player's connection
+    pub log_bandwidth: bool,
+    /// Trace every whole message in and out, see Middleware::tracing, off so nothing runs for them by default
+    pub trace_messages: bool,
+    /// Find out how big a datagram can get to each new client, and keep its packets under that
+    pub probe_mtu: bool,
+    /// Append a row of metrics to this CSV file every metrics_csv_interval, None to not write one
+    pub metrics_csv: Option<PathBuf>,
+    /// How often a row goes into metrics_csv
+    #[serde(with = "duration_secs")]
+    pub metrics_csv_interval: Duration,
+    /// Most datagrams waiting between the receive thread and the game tick
+    pub receive_queue_capacity: usize,
+    /// Clients only get entities within this many pixels of their player, None sends everything
+    pub entity_view_radius: Option<u32>,
+    /// Physics steps run in every game tick, more is smoother but costs more
+    pub physics_substeps: u64,
+    /// Apply inputs before every physics step instead of once per game tick
+    pub inputs_every_substep: bool,
+    /// What happens to inputs from clients whose player entity doesn't exist right now
+    pub missing_player_inputs: MissingPlayerInputs,
+    /// Snapshots of where every entity was, one per game tick, kept for lag compensation, 0 to keep none
+    pub snapshot_history: usize,
+    /// Messages with more bodies than this are thrown away before they're handled
+    pub max_bodies_per_message: usize,
+    /// Slow the network tick down to this rate as the server fills up, None always runs at network_tick_hz
+    pub min_network_tick_hz: Option<u64>,
+    /// Let the host (the longest connected client) run admin commands, for peer-hosted games
+    pub host_authority: bool,
+    /// Times to try binding the socket again before giving up
+    pub bind_retries: u32,
+    /// Wait before the first bind retry, each retry after it waits twice as long
+    #[serde(with = "duration_secs")]
+    pub bind_retry_delay: Duration,
+    /// Agree on a key with clients that offer one and encrypt their bodies, headers stay readable
+    pub encryption: bool,
+    /// Clients with nothing queued only get an empty packet this often, zero sends one every tick they're due
+    #[serde(with = "duration_secs")]
+    pub keepalive_interval: Duration,
+    /// Send every client a ping of our own this often and time its echo, zero to only time acks
+    #[serde(with = "duration_secs")]
+    pub probe_interval: Duration,
+    /// Also take admin commands over UDP on this address, kept apart from player traffic, None to only use the terminal
+    pub admin_addr: Option<SocketAddr>,
+    /// Admin datagrams have to start with this and a space, None to accept any datagram sent from this machine
+    pub admin_token: Option<String>,
+    /// Most bytes a second sent to all clients together, once it's reached only high priority bodies go out
+    /// until the next second, checked every network tick so it can go over by about one tick's worth
+    /// None to not cap it
+    pub max_bytes_per_second: Option<u64>,
+    /// Announced to every client once it has the terrain, settings and player list, empty to not announce anything
+    pub welcome_message: String,
+    /// Announced to every client when the server stops, empty to not announce anything
+    pub shutdown_message: String,
+    /// When stopping, keep resending reliable bodies (like shutdown_message) this long or until they're acked
+    /// the server's frame doesn't finish until then, so keep it short
+    #[serde(with = "duration_secs")]
+    pub shutdown_drain: Duration,
+    /// Put the world checksum in the header once every this many network ticks, so clients can report desyncs, 0 to never
+    pub checksum_interval_ticks: u64,
+    /// Send the whole terrain again to a client that reports a desync, otherwise it's only logged and counted
+    pub resync_on_desync: bool,
+    /// Keep using inputs from a client that is marked for drop until it's actually removed,
+    /// otherwise they're thrown away as soon as the drop is decided
+    pub inputs_while_dropping: bool,
+    /// Compress terrain with this, for clients that can decompress it
+    pub terrain_codec: Codec,
+    /// Compress entity snapshots and deltas with this, for clients that can decompress it
+    /// they're small and change every tick, so by default they aren't worth the CPU
+    pub entity_codec: Codec,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: SocketAddr::from((DEFAULT_SERVER_IP, DEFAULT_SERVER_PORT)),
+            interface: None,
+            save_file: default_save_path_server(),
+            persist: true,
+            network_tick_hz: DEFAULT_NETWORK_TICK_HZ,
+            game_tick_hz: DEFAULT_GAME_TICK_HZ,
+            max_clients: DEFAULT_MAX_CLIENTS,
+            max_observers: DEFAULT_MAX_OBSERVERS,
+            duplicate_connect: DuplicateConnect::default(),
+            name_collision: NameCollision::default(),
+            max_reconnects: Some(DEFAULT_MAX_RECONNECTS),
+            reconnect_window: DEFAULT_RECONNECT_WINDOW,
+            max_messages_per_tick: DEFAULT_MAX_MESSAGES_PER_TICK,
+            buffer_size: BUFFER_SIZE,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            localhost_only: false,
+            terrain_interval_ticks: DEFAULT_TERRAIN_INTERVAL_TICKS,
+            pacing: false,
+            smooth_sends: false,
+            tick_watchdog_threshold: DEFAULT_TICK_WATCHDOG_THRESHOLD,
+            record_path: None,
+            compress_record: false,
+            max_packets_per_second: DEFAULT_MAX_PACKETS_PER_SECOND,
+            idle_after: DEFAULT_IDLE_AFTER,
+            kick_ban: DEFAULT_KICK_BAN,
+            redirect_when_full: None,
+            replay_window: DEFAULT_REPLAY_WINDOW,
+            connect_grace: DEFAULT_CONNECT_GRACE,
+            log_bandwidth: false,
+            trace_messages: false,
+            probe_mtu: false,
+            metrics_csv: None,
+            metrics_csv_interval: DEFAULT_METRICS_CSV_INTERVAL,
+            receive_queue_capacity: DEFAULT_RECEIVE_QUEUE_CAPACITY,
+            entity_view_radius: None,
+            physics_substeps: DEFAULT_PHYSICS_SUBSTEPS,
+            inputs_every_substep: false,
+            missing_player_inputs: MissingPlayerInputs::default(),
+            snapshot_history: DEFAULT_SNAPSHOT_HISTORY,
+            max_bodies_per_message: DEFAULT_MAX_BODIES_PER_MESSAGE,
+            min_network_tick_hz: None,
+            host_authority: false,
+            bind_retries: DEFAULT_BIND_RETRIES,
+            bind_retry_delay: DEFAULT_BIND_RETRY_DELAY,
+            encryption: false,
+            keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+            probe_interval: Duration::ZERO,
+            admin_addr: None,
+            admin_token: None,
+            max_bytes_per_second: None,
+            welcome_message: DEFAULT_WELCOME_MESSAGE.to_string(),
+            shutdown_message: DEFAULT_SHUTDOWN_MESSAGE.to_string(),
+            shutdown_drain: DEFAULT_SHUTDOWN_DRAIN,
+            checksum_interval_ticks: DEFAULT_CHECKSUM_INTERVAL_TICKS,
+            resync_on_desync: false,
+            inputs_while_dropping: false,
+            terrain_codec: Codec::Deflate,
+            entity_codec: Codec::Uncompressed,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Unable to read the file
+    Io(std::io::Error),
+    /// The file isn't valid TOML or has fields of the wrong type
+    Parse(toml::de::Error),
+    /// A field has a value the server can't run with
+    Invalid { field: &'static str, reason: String },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "could not read config file, {}", e),
+            ConfigError::Parse(e) => write!(f, "unable to parse config file: {}", e),
+            ConfigError::Invalid { field, reason } => write!(f, "invalid `{}`: {}", field, reason),
+        }
+    }
+}
+
+/// Lets durations be written as seconds, e.g. `idle_after = 30` or `kick_ban = 0.5`
+mod duration_secs {
+    use serde::{Deserialize, Deserializer};
+    use std::time::Duration;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let secs = f64::deserialize(deserializer)?;
+        Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom)
+    }
+}
+
+impl ServerConfig {
+    /// Start from the defaults and change what's needed
+    pub fn builder() -> ServerConfigBuilder {
+        Self::default().into_builder()
+    }
+
+    /// Change what's needed starting from this config
+    pub fn into_builder(self) -> ServerConfigBuilder {
+        ServerConfigBuilder { config: self }
+    }
+
+    /// Read a TOML config file on top of the defaults
+    /// a missing file isn't an error, the server just runs with the defaults
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!("{} not found, using default config", path.display());
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(ConfigError::Io(e)),
+        };
+        Self::parse(&contents)
+    }
+
+    /// Parse and validate the contents of a config file
+    fn parse(contents: &str) -> Result<Self, ConfigError> {
+        let mut config: Self = toml::from_str(contents).map_err(ConfigError::Parse)?;
+        // left out, it shrinks to fit whatever buffer_size is
+        let fields: toml::value::Table = toml::from_str(contents).map_err(ConfigError::Parse)?;
+        if !fields.contains_key("max_message_size") {
+            config.max_message_size =
+                DEFAULT_MAX_MESSAGE_SIZE.min(config.buffer_size.saturating_sub(1));
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Everything `server --check` looks at: the config itself, the sockets it binds and the save file
+    /// nothing is kept, the sockets are closed again straight away
+    pub fn check(&self) -> CheckReport {
+        let mut report = CheckReport::default();
+        report.add("config", self.validate().map(|_| "valid".to_string()));
+        let bind = |addr: SocketAddr| {
+            std::net::UdpSocket::bind(addr)
+                .map(|_| format!("{} is free", addr))
+                .map_err(|e| format!("unable to bind {}: {}", addr, e))
+        };
+        let socket = self
+            .open_socket()
+            .map(|_| format!("{} is free", self.socket_addr()))
+            .map_err(|e| format!("unable to bind {}: {}", self.socket_addr(), e));
+        report.add("bind_addr", socket);
+        if let Some(addr) = self.admin_addr {
+            report.add("admin_addr", bind(addr));
+        }
+        let save = match inspect_save_file(&self.save_file) {
+            Ok(info) if info.chunks() == 0 => Err("terrain has no chunks".to_string()),
+            Ok(info) if info.chunks() as u64 > MAX_TERRAIN_CHUNKS => Err(format!(
+                "terrain has {} chunks, clients take at most {}",
+                info.chunks(),
+                MAX_TERRAIN_CHUNKS
+            )),
+            Ok(info) => Ok(format!("{} chunk(s)", info.chunks())),
+            Err(crate::save::LoadError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok("not found, a new world gets made".to_string())
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        report.add(&format!("save_file {}", self.save_file.display()), save);
+        report
+    }
+
+    /// Where the socket binds, bind_addr moved onto the interface's address if it has one
+    pub fn socket_addr(&self) -> SocketAddr {
+        match &self.interface {
+            Some(Interface::Address(ip)) => SocketAddr::new(*ip, self.bind_addr.port()),
+            _ => self.bind_addr,
+        }
+    }
+
+    /// Bind the server's socket, on the interface if there is one
+    pub(super) fn open_socket(&self) -> std::io::Result<UdpTransport> {
+        let device = match &self.interface {
+            Some(Interface::Device(name)) => Some(name.as_str()),
+            _ => None,
+        };
+        UdpTransport::bind_on(self.socket_addr(), device)
+    }
+
+    /// Make sure the server can actually run with this config
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |field, reason: &str| {
+            Err(ConfigError::Invalid {
+                field,
+                reason: reason.to_string(),
+            })
+        };
+        if let Some(Interface::Device(name)) = &self.interface {
+            if !cfg!(target_os = "linux") {
+                return invalid(
+                    "interface",
+                    "device names only work on Linux, use the interface's address instead",
+                );
+            }
+            // IFNAMSIZ, counting the nul
+            if name.is_empty() || name.len() >= 16 {
+                return invalid("interface", "device names are 1 to 15 characters");
+            }
+        }
+        if self.network_tick_hz == 0 {
+            return invalid("network_tick_hz", "must be at least 1");
+        }
+        if self.game_tick_hz == 0 {
+            return invalid("game_tick_hz", "must be at least 1");
+        }
+        // messages are handled on the game tick, so sending faster than that makes no sense
+        if self.network_tick_hz > self.game_tick_hz {
+            return Err(ConfigError::Invalid {
+                field: "network_tick_hz",
+                reason: format!(
+                    "{} is faster than game_tick_hz ({})",
+                    self.network_tick_hz, self.game_tick_hz
+                ),
+            });
+        }
+        if self.max_clients == 0 {
+            return invalid("max_clients", "must be at least 1");
+        }
+        if self.max_messages_per_tick == 0 {
+            return invalid("max_messages_per_tick", "must be at least 1");
+        }
+        if self.buffer_size == 0 {
+            return invalid("buffer_size", "must be at least 1");
+        }
+        if self.max_message_size == 0 {
+            return invalid("max_message_size", "must be at least 1");
+        }
+        // a datagram cut off at the end of the buffer would look like one that fits
+        if self.max_message_size >= self.buffer_size {
+            return invalid("max_message_size", "must be under buffer_size");
+        }
+        if self.terrain_interval_ticks == 0 {
+            return invalid("terrain_interval_ticks", "must be at least 1");
+        }
+        if self.max_packets_per_second == 0 {
+            return invalid("max_packets_per_second", "must be at least 1");
+        }
+        if self.receive_queue_capacity == 0 {
+            return invalid("receive_queue_capacity", "must be at least 1");
+        }
+        if self.physics_substeps == 0 {
+            return invalid("physics_substeps", "must be at least 1");
+        }
+        if self.max_bodies_per_message == 0 {
+            return invalid("max_bodies_per_message", "must be at least 1");
+        }
+        if self.max_bytes_per_second == Some(0) {
+            return invalid("max_bytes_per_second", "must be at least 1");
+        }
+        if let Some(min) = self.min_network_tick_hz {
+            if min == 0 {
+                return invalid("min_network_tick_hz", "must be at least 1");
+            }
+            if min > self.network_tick_hz {
+                return Err(ConfigError::Invalid {
+                    field: "min_network_tick_hz",
+                    reason: format!(
+                        "{} is faster than network_tick_hz ({})",
+                        min, self.network_tick_hz
+                    ),
+                });
+            }
+        }
+        // clients time out after not hearing from us for this long
+        let client_timeout = Duration::from_secs_f64(
+            FRAME_DIFFERENCE_BEFORE_DISCONNECT as f64 / self.game_tick_hz as f64,
+        );
+        if self.keepalive_interval >= client_timeout {
+            return Err(ConfigError::Invalid {
+                field: "keepalive_interval",
+                reason: format!(
+                    "{:?} is longer than clients wait before timing out ({:?})",
+                    self.keepalive_interval, client_timeout
+                ),
+            });
+        }
+        if self.metrics_csv.is_some() && self.metrics_csv_interval.is_zero() {
+            return invalid("metrics_csv_interval", "must be more than 0");
+        }
+        if self.compress_record && self.record_path.is_none() {
+            return invalid("compress_record", "needs record_path to be set");
+        }
+        if self.admin_addr.is_some_and(|addr| !addr.ip().is_loopback()) {
+            return invalid("admin_addr", "must be on localhost");
+        }
+        if self.admin_token.is_some() && self.admin_addr.is_none() {
+            return invalid("admin_token", "needs admin_addr to be set");
+        }
+        if self
+            .admin_token
+            .as_ref()
+            .is_some_and(|token| token.is_empty() || token.contains(char::is_whitespace))
+        {
+            return invalid("admin_token", "must be one word");
+        }
+        Ok(())
+    }
+
+    /// Game ticks that go by between network ticks at this network tick rate, at least 1
+    pub fn game_ticks_per_network_tick(&self, network_tick_hz: u64) -> u64 {
+        (self.game_tick_hz / network_tick_hz.max(1)).max(1)
+    }
+
+    /// Network tick rate to run at with this many clients connected
+    /// goes from network_tick_hz with one client down to min_network_tick_hz when full,
+    /// so the total bandwidth stays about the same as clients join
+    pub fn tuned_network_tick_hz(&self, clients: usize) -> u64 {
+        let max = self.network_tick_hz;
+        let min = match self.min_network_tick_hz {
+            Some(min) if min < max && self.max_clients > 1 => min,
+            _ => return max,
+        };
+        let extra_clients = clients.clamp(1, self.max_clients) as u64 - 1;
+        max - (max - min) * extra_clients / (self.max_clients as u64 - 1)
+    }
+}
+
+/// What ServerConfig::check found, one line per thing checked
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// What was checked, and how it went or what's wrong with it
+    checks: Vec<(String, Result<String, String>)>,
+}
+
+impl CheckReport {
+    fn add<E: std::fmt::Display>(&mut self, what: &str, result: Result<String, E>) {
+        self.checks
+            .push((what.to_string(), result.map_err(|e| e.to_string())));
+    }
+
+    /// Nothing failed, the server should start fine
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|(_, result)| result.is_ok())
+    }
+
+    /// What `server --check` exits with, 0 if everything passed and 1 if anything failed
+    pub fn exit_code(&self) -> i32 {
+        if self.passed() {
+            0
+        } else {
+            1
+        }
+    }
+}
+
+impl std::fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (what, result) in &self.checks {
+            match result {
+                Ok(detail) => writeln!(f, "ok   {}: {}", what, detail)?,
+                Err(reason) => writeln!(f, "FAIL {}: {}", what, reason)?,
+            }
+        }
+        let failed = self.checks.iter().filter(|(_, r)| r.is_err()).count();
+        match failed {
+            0 => write!(f, "all {} check(s) passed", self.checks.len()),
+            _ => write!(f, "{} of {} check(s) failed", failed, self.checks.len()),
+        }
+    }
+}
+
+/// Builds a ServerConfig, see ServerConfig::builder
+#[derive(Debug, Clone)]
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+}
+
+impl ServerConfigBuilder {
+    /// Bind to this port on the default ip
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.bind_addr.set_port(port);
+        self
+    }
+
+    pub fn save_file(mut self, save_file: PathBuf) -> Self {
+        self.config.save_file = save_file;
+        self
+    }
+
+    pub fn interface(mut self, interface: Option<Interface>) -> Self {
+        self.config.interface = interface;
+        self
+    }
+
+    pub fn localhost_only(mut self, localhost_only: bool) -> Self {
+        self.config.localhost_only = localhost_only;
+        self
+    }
+
+    pub fn terrain_interval_ticks(mut self, ticks: u64) -> Self {
+        self.config.terrain_interval_ticks = ticks;
+        self
+    }
+
+    pub fn pacing(mut self, pacing: bool) -> Self {
+        self.config.pacing = pacing;
+        self
+    }
+
+    pub fn log_bandwidth(mut self, log_bandwidth: bool) -> Self {
+        self.config.log_bandwidth = log_bandwidth;
+        self
+    }
+
+    pub fn trace_messages(mut self, trace_messages: bool) -> Self {
+        self.config.trace_messages = trace_messages;
+        self
+    }
+
+    pub fn probe_mtu(mut self, probe_mtu: bool) -> Self {
+        self.config.probe_mtu = probe_mtu;
+        self
+    }
+
+    /// Write metrics to path every interval if there is one
+    pub fn metrics_csv(mut self, path: Option<PathBuf>, interval: Duration) -> Self {
+        self.config.metrics_csv = path;
+        self.config.metrics_csv_interval = interval;
+        self
+    }
+
+    /// Record incoming packets to path if there is one, gzipped if compressed
+    pub fn record(mut self, path: Option<PathBuf>, compressed: bool) -> Self {
+        self.config.record_path = path;
+        self.config.compress_record = compressed;
+        self
+    }
+
+    /// Finish up, values that would stop the server from working get bumped up to 1
+    pub fn build(self) -> ServerConfig {
+        let mut config = self.config;
+        // an interval of 0 would mean never sending terrain
+        config.terrain_interval_ticks = config.terrain_interval_ticks.max(1);
+        config.network_tick_hz = config.network_tick_hz.max(1);
+        config.game_tick_hz = config.game_tick_hz.max(1);
+        config.max_messages_per_tick = config.max_messages_per_tick.max(1);
+        config.receive_queue_capacity = config.receive_queue_capacity.max(1);
+        config.physics_substeps = config.physics_substeps.max(1);
+        config.max_bodies_per_message = config.max_bodies_per_message.max(1);
+        config.max_message_size = config.max_message_size.max(1);
+        config.metrics_csv_interval = config.metrics_csv_interval.max(Duration::from_secs(1));
+        config.min_network_tick_hz = config
+            .min_network_tick_hz
+            .map(|min| min.clamp(1, config.network_tick_hz));
+        config
+    }
+}
+
+impl ServerConfig {
+    /// Codecs clients get for each payload, if they offer them
+    pub fn payload_codecs(&self) -> PayloadCodecs {
+        PayloadCodecs {
+            terrain: self.terrain_codec,
+            entities: self.entity_codec,
+        }
+    }
+}
+
+/// unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Transport;
+
+    #[test]
+    fn defaults_match_previous_behavior() {
+        let config = ServerConfig::default();
+        assert_eq!(config, ServerConfig::builder().build());
+        assert_eq!(config.bind_addr.port(), DEFAULT_SERVER_PORT);
+        assert_eq!(config.network_tick_hz, 1);
+        assert_eq!(config.game_tick_hz, 60);
+        assert_eq!(
+            config.game_ticks_per_network_tick(config.network_tick_hz),
+            60
+        );
+        assert_eq!(config.max_clients, 2);
+        assert_eq!(config.max_messages_per_tick, 64);
+        assert_eq!(config.buffer_size, BUFFER_SIZE);
+        assert_eq!(config.tick_watchdog_threshold, Duration::from_secs(2));
+        assert_eq!(config.terrain_interval_ticks, 1);
+        assert!(!config.localhost_only && !config.pacing);
+        assert_eq!(config.record_path, None);
+    }
+
+    #[test]
+    fn builder_keeps_intervals_usable() {
+        let config = ServerConfig::builder()
+            .port(9001)
+            .terrain_interval_ticks(0)
+            .build();
+        assert_eq!(config.bind_addr.port(), 9001);
+        assert_eq!(config.terrain_interval_ticks, 1);
+    }
+
+    #[test]
+    fn file_overrides_defaults() {
+        let config = ServerConfig::parse(
+            r#"
+            bind_addr = "127.0.0.1:9100"
+            max_clients = 4
+            network_tick_hz = 10
+            pacing = true
+            idle_after = 2.5
+            record_path = "packets.log"
+            duplicate_connect = "reject"
+            name_collision = "kick"
+            welcome_message = "no griefing"
+            entity_codec = "deflate"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.bind_addr, SocketAddr::from(([127, 0, 0, 1], 9100)));
+        assert_eq!(config.max_clients, 4);
+        assert_eq!(config.network_tick_hz, 10);
+        assert!(config.pacing);
+        assert_eq!(config.idle_after, Duration::from_millis(2500));
+        assert_eq!(config.record_path, Some(PathBuf::from("packets.log")));
+        assert_eq!(config.duplicate_connect, DuplicateConnect::Reject);
+        assert_eq!(config.name_collision, NameCollision::Kick);
+        assert_eq!(config.welcome_message, "no griefing");
+        assert_eq!(config.entity_codec, Codec::Deflate);
+        assert_eq!(config.terrain_codec, Codec::Deflate);
+        // everything else is left alone
+        assert_eq!(config.game_tick_hz, DEFAULT_GAME_TICK_HZ);
+        assert_eq!(config.kick_ban, DEFAULT_KICK_BAN);
+        assert_eq!(config.max_message_size, DEFAULT_MAX_MESSAGE_SIZE);
+
+        // unless it has to make room for a smaller buffer
+        let config = ServerConfig::parse("buffer_size = 1024").unwrap();
+        assert_eq!(config.max_message_size, 1023);
+    }
+
+    #[test]
+    fn invalid_files_name_the_field() {
+        let error = |contents| match ServerConfig::parse(contents) {
+            Err(ConfigError::Invalid { field, .. }) => field,
+            other => panic!("expected an invalid field, got {:?}", other),
+        };
+        assert_eq!(error("max_clients = 0"), "max_clients");
+        assert_eq!(error("network_tick_hz = 120"), "network_tick_hz");
+        assert_eq!(error("compress_record = true"), "compress_record");
+        assert_eq!(error(r#"admin_addr = "10.0.0.1:9200""#), "admin_addr");
+        assert_eq!(
+            error("buffer_size = 4096\nmax_message_size = 4096"),
+            "max_message_size"
+        );
+
+        // typos and wrong types get caught by the parser
+        assert!(matches!(
+            ServerConfig::parse("max_client = 3"),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            ServerConfig::parse("pacing = 1"),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn missing_file_uses_defaults() {
+        let path = std::env::temp_dir().join("game_missing_config.toml");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(ServerConfig::load(&path).unwrap(), ServerConfig::default());
+    }
+
+    #[test]
+    fn check_reports_what_is_wrong() {
+        let dir = std::env::temp_dir();
+        let good = ServerConfig {
+            bind_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            save_file: dir.join("game_check_test_missing.sav"),
+            ..ServerConfig::default()
+        };
+        let report = good.check();
+        assert_eq!(report.exit_code(), 0, "{}", report);
+
+        let garbage = dir.join("game_check_test_garbage.sav");
+        std::fs::write(&garbage, b"not a save").unwrap();
+        let bad = ServerConfig {
+            max_clients: 0,
+            save_file: garbage,
+            ..good
+        };
+        let report = bad.check();
+        assert_eq!(report.exit_code(), 1);
+        let text = report.to_string();
+        assert!(
+            text.contains("FAIL config: invalid `max_clients`"),
+            "{}",
+            text
+        );
+        assert!(text.contains("unable to decode save file"), "{}", text);
+        assert!(text.ends_with("2 of 3 check(s) failed"), "{}", text);
+    }
+
+    #[test]
+    fn interface_picks_the_address_to_bind() {
+        let config = ServerConfig::parse(r#"interface = "127.0.0.1""#).unwrap();
+        assert_eq!(
+            config.interface,
+            Some(Interface::Address([127, 0, 0, 1].into()))
+        );
+        let config = ServerConfig {
+            bind_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            ..config
+        };
+        let socket = config.open_socket().unwrap();
+        assert_eq!(
+            socket.local_addr().unwrap().ip(),
+            IpAddr::from([127, 0, 0, 1])
+        );
+
+        let config = ServerConfig::parse(r#"interface = "eth0""#);
+        if cfg!(target_os = "linux") {
+            assert_eq!(
+                config.unwrap().interface,
+                Some(Interface::Device("eth0".to_string()))
+            );
+        } else {
+            assert!(matches!(config, Err(ConfigError::Invalid { .. })));
+        }
+        assert!(ServerConfig::parse(r#"interface = "much_too_long_a_name""#).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn missing_device_is_an_error() {
+        let config = ServerConfig {
+            bind_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            interface: Some(Interface::Device("nosuchnic0".to_string())),
+            ..ServerConfig::default()
+        };
+        let error = config.open_socket().unwrap_err();
+        assert!(error.to_string().contains("nosuchnic0"));
+    }
+}
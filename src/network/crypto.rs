@@ -0,0 +1,340 @@
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Bytes in an X25519 public key
+pub(super) const PUBLIC_KEY_LEN: usize = 32;
+
+/// Every sealed payload starts with the counter its nonce was made from
+const COUNTER_LEN: usize = 8;
+
+/// How far behind the highest counter opened so far a payload can be and still open, packets do arrive out of order
+const REPLAY_WINDOW: u64 = 64;
+
+/// Mixed into the session key so it can't be confused with a key for anything else
+const KEY_CONTEXT: &[u8] = b"game session key v1";
+
+/// Which way a payload is going, part of the nonce so both sides can count from 0 with the same key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Toward {
+    Server,
+    Client,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum CryptoError {
+    /// Too short to even hold a counter and a tag
+    TooShort,
+    /// Wrong key, or the payload was changed on the way
+    Rejected,
+    /// Got an encrypted payload before agreeing on a key
+    NoSession,
+    /// A payload that was already opened, or one too old to tell, see REPLAY_WINDOW
+    Replayed,
+}
+
+impl std::fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CryptoError::TooShort => "encrypted payload is too short",
+            CryptoError::Rejected => "unable to decrypt payload, wrong key or tampered with",
+            CryptoError::NoSession => "encrypted payload before a key exchange",
+            CryptoError::Replayed => "encrypted payload was already received or is too old",
+        })
+    }
+}
+
+/// One side's half of a key exchange, kept until the other side's public key shows up
+pub(super) struct KeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    /// What gets sent to the other side
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+        self.public.to_bytes()
+    }
+
+    /// The key for talking to whoever has their_public
+    /// both public keys go into it in a fixed order, so both sides end up with the same key
+    pub fn session_key(&self, their_public: [u8; PUBLIC_KEY_LEN]) -> SessionKey {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(their_public));
+        let ours = self.public_key();
+        let (first, second) = if ours <= their_public {
+            (ours, their_public)
+        } else {
+            (their_public, ours)
+        };
+        let key = Sha256::new()
+            .chain_update(KEY_CONTEXT)
+            .chain_update(shared.as_bytes())
+            .chain_update(first)
+            .chain_update(second)
+            .finalize();
+        SessionKey {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            sent: 0,
+            toward_server: ReplayWindow::default(),
+            toward_client: ReplayWindow::default(),
+        }
+    }
+}
+
+/// Nothing secret gets printed
+impl std::fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("public", &self.public_key())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Counters opened so far going one way, so a payload captured and sent again doesn't open twice
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    /// Highest counter opened, None until the first one
+    highest: Option<u64>,
+    /// Bit n set if highest - n was opened
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Whether counter hasn't been opened yet and isn't too old to tell
+    fn fresh(&self, counter: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if counter > highest => true,
+            Some(highest) => {
+                let behind = highest - counter;
+                behind < REPLAY_WINDOW && self.seen & (1 << behind) == 0
+            }
+        }
+    }
+
+    /// Remember counter was opened, only once it's known to be genuine
+    fn mark(&mut self, counter: u64) {
+        match self.highest {
+            Some(highest) if counter <= highest => self.seen |= 1 << (highest - counter),
+            Some(highest) => {
+                let ahead = counter - highest;
+                self.seen = match ahead {
+                    a if a < REPLAY_WINDOW => self.seen << a,
+                    _ => 0,
+                } | 1;
+                self.highest = Some(counter);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(counter);
+            }
+        }
+    }
+}
+
+/// Symmetric key agreed on by a key exchange, encrypts payloads one way and decrypts them the other
+pub(super) struct SessionKey {
+    cipher: ChaCha20Poly1305,
+    /// Payloads sealed so far, makes every nonce different
+    sent: u64,
+    /// Counters opened going each way
+    toward_server: ReplayWindow,
+    toward_client: ReplayWindow,
+}
+
+impl SessionKey {
+    fn nonce(toward: Toward, counter: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[0] = match toward {
+            Toward::Server => 0,
+            Toward::Client => 1,
+        };
+        nonce[4..].copy_from_slice(&counter.to_le_bytes());
+        nonce
+    }
+
+    /// Encrypt plaintext going toward, the counter goes in front so the other side knows the nonce
+    /// aad isn't encrypted but has to be the same when it's opened, e.g. the readable header
+    pub fn seal(&mut self, toward: Toward, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let counter = self.sent;
+        self.sent += 1;
+        let nonce = Self::nonce(toward, counter);
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .expect("chacha20poly1305 only fails for payloads far bigger than a datagram");
+        let mut sealed = Vec::with_capacity(COUNTER_LEN + ciphertext.len());
+        sealed.extend_from_slice(&counter.to_le_bytes());
+        sealed.extend(ciphertext);
+        sealed
+    }
+
+    /// Decrypt a payload sealed by the other side going toward, with the same aad
+    /// each one only opens once
+    pub fn open(
+        &mut self,
+        toward: Toward,
+        aad: &[u8],
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        if sealed.len() < COUNTER_LEN {
+            return Err(CryptoError::TooShort);
+        }
+        let (counter, ciphertext) = sealed.split_at(COUNTER_LEN);
+        // unwrap OK because split_at gave us exactly COUNTER_LEN bytes
+        let counter = u64::from_le_bytes(counter.try_into().unwrap());
+        let window = match toward {
+            Toward::Server => &mut self.toward_server,
+            Toward::Client => &mut self.toward_client,
+        };
+        if !window.fresh(counter) {
+            return Err(CryptoError::Replayed);
+        }
+        let nonce = Self::nonce(toward, counter);
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| CryptoError::Rejected)?;
+        window.mark(counter);
+        Ok(plaintext)
+    }
+}
+
+impl std::fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionKey")
+            .field("sent", &self.sent)
+            .finish_non_exhaustive()
+    }
+}
+
+/// unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &[u8] = b"header";
+
+    #[test]
+    fn both_sides_agree_and_round_trip() {
+        let client = KeyPair::generate();
+        let server = KeyPair::generate();
+        let mut client_key = client.session_key(server.public_key());
+        let mut server_key = server.session_key(client.public_key());
+
+        let sealed = client_key.seal(Toward::Server, HEADER, b"inputs");
+        assert_ne!(sealed[COUNTER_LEN..], b"inputs"[..]);
+        assert_eq!(
+            server_key.open(Toward::Server, HEADER, &sealed).unwrap(),
+            b"inputs"
+        );
+
+        // the same plaintext never looks the same twice
+        let again = client_key.seal(Toward::Server, HEADER, b"inputs");
+        assert_ne!(sealed, again);
+
+        let sealed = server_key.seal(Toward::Client, HEADER, b"terrain");
+        assert_eq!(
+            client_key.open(Toward::Client, HEADER, &sealed).unwrap(),
+            b"terrain"
+        );
+        // a payload bounced back the other way doesn't open
+        let mut bounced_to = server.session_key(client.public_key());
+        assert_eq!(
+            bounced_to.open(Toward::Server, HEADER, &sealed),
+            Err(CryptoError::Rejected)
+        );
+    }
+
+    #[test]
+    fn wrong_key_or_tampering_is_an_error() {
+        let client = KeyPair::generate();
+        let server = KeyPair::generate();
+        let mut client_key = client.session_key(server.public_key());
+        let sealed = client_key.seal(Toward::Server, HEADER, b"chat");
+
+        // someone who only saw the public keys
+        let mut snooper = KeyPair::generate().session_key(server.public_key());
+        assert_eq!(
+            snooper.open(Toward::Server, HEADER, &sealed),
+            Err(CryptoError::Rejected)
+        );
+
+        let mut server_key = server.session_key(client.public_key());
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            server_key.open(Toward::Server, HEADER, &tampered),
+            Err(CryptoError::Rejected)
+        );
+        // so is the readable header it went out with
+        assert_eq!(
+            server_key.open(Toward::Server, b"headed", &sealed),
+            Err(CryptoError::Rejected)
+        );
+        assert_eq!(
+            server_key.open(Toward::Server, HEADER, &sealed[..4]),
+            Err(CryptoError::TooShort)
+        );
+        assert_eq!(
+            server_key.open(Toward::Server, HEADER, &sealed[..COUNTER_LEN]),
+            Err(CryptoError::Rejected)
+        );
+        // none of that used up the counter
+        assert!(server_key.open(Toward::Server, HEADER, &sealed).is_ok());
+    }
+
+    #[test]
+    fn payloads_only_open_once() {
+        let client = KeyPair::generate();
+        let server = KeyPair::generate();
+        let mut client_key = client.session_key(server.public_key());
+        let mut server_key = server.session_key(client.public_key());
+        let sealed: Vec<Vec<u8>> = (0..REPLAY_WINDOW + 2)
+            .map(|_| client_key.seal(Toward::Server, HEADER, b"input"))
+            .collect();
+
+        assert!(server_key.open(Toward::Server, HEADER, &sealed[1]).is_ok());
+        assert_eq!(
+            server_key.open(Toward::Server, HEADER, &sealed[1]),
+            Err(CryptoError::Replayed)
+        );
+        // late but not seen yet is fine
+        assert!(server_key.open(Toward::Server, HEADER, &sealed[0]).is_ok());
+        assert_eq!(
+            server_key.open(Toward::Server, HEADER, &sealed[0]),
+            Err(CryptoError::Replayed)
+        );
+
+        // far enough ahead that the first ones can't be told apart from replays anymore
+        let last = sealed.last().unwrap();
+        assert!(server_key.open(Toward::Server, HEADER, last).is_ok());
+        assert!(server_key.open(Toward::Server, HEADER, &sealed[2]).is_ok());
+        assert_eq!(
+            server_key.open(Toward::Server, HEADER, &sealed[1]),
+            Err(CryptoError::Replayed)
+        );
+
+        // the other way has its own counters
+        let sealed = server_key.seal(Toward::Client, HEADER, b"terrain");
+        assert!(client_key.open(Toward::Client, HEADER, &sealed).is_ok());
+    }
+}
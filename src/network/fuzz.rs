@@ -0,0 +1,123 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+};
+
+use super::{config::ServerConfig, server::Server, *};
+
+/// Where every fuzzed datagram claims to come from
+const FUZZ_SENDER: SocketAddr = SocketAddr::new(
+    std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST),
+    DEFAULT_SERVER_PORT + 1,
+);
+
+/// Run bytes through everything the server does with a datagram, as the first one a fresh server gets
+/// this is what a fuzz target should call (see fuzz/fuzz_targets), it must not panic whatever bytes are
+/// returns whether the server took it as a message
+pub fn fuzz_datagram(bytes: &[u8]) -> bool {
+    receive_datagram(bytes).is_ok()
+}
+
+/// fuzz_datagram, but saying why the bytes were turned away
+fn receive_datagram(bytes: &[u8]) -> Result<Vec<u64>, ReceiveError> {
+    let mut server = Server::with_transport(Box::new(NullTransport), ServerConfig::default());
+    server.inject_datagram(FUZZ_SENDER, bytes)
+}
+
+/// What happened to every file in a fuzz corpus
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FuzzReport {
+    pub files: usize,
+    /// Handled as a message
+    pub accepted: usize,
+    /// Turned away, how many times for each kind of ReceiveError
+    pub rejected: BTreeMap<String, usize>,
+    /// Files that made the server panic, every one of these is a bug
+    pub panicked: Vec<PathBuf>,
+}
+
+impl std::fmt::Display for FuzzReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} file(s), {} accepted, {} panicked",
+            self.files,
+            self.accepted,
+            self.panicked.len()
+        )?;
+        for (kind, count) in &self.rejected {
+            write!(f, "\n{} rejected as {}", count, kind)?;
+        }
+        for path in &self.panicked {
+            write!(f, "\npanicked on {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Give every file in dir to receive_datagram, one datagram per file, in name order
+/// for checking a corpus (or a crash a fuzzer found) without the fuzzer
+pub fn run_fuzz_corpus(dir: &Path) -> io::Result<FuzzReport> {
+    let mut paths = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    paths.retain(|path| path.is_file());
+    paths.sort();
+
+    let mut report = FuzzReport::default();
+    for path in paths {
+        let bytes = std::fs::read(&path)?;
+        report.files += 1;
+        match panic::catch_unwind(AssertUnwindSafe(|| receive_datagram(&bytes))) {
+            Ok(Ok(_)) => report.accepted += 1,
+            Ok(Err(e)) => {
+                // just the variant, not what's in it
+                let kind = format!("{:?}", e);
+                let kind = kind.split('(').next().unwrap_or_default().to_string();
+                *report.rejected.entry(kind).or_default() += 1;
+            }
+            Err(_) => report.panicked.push(path),
+        }
+    }
+    Ok(report)
+}
+
+/// unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seed_corpus_is_all_accepted() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/server");
+        let report = run_fuzz_corpus(&dir).unwrap();
+        assert!(report.files > 0);
+        assert_eq!(report.accepted, report.files, "{}", report);
+    }
+
+    #[test]
+    fn made_up_length_is_refused_instead_of_allocated() {
+        let mut bytes = bincode::encode_to_vec(
+            ClientToServer::builder()
+                .ack(0)
+                .body(ClientBodyElem::AdminCommand(String::new()))
+                .build(),
+            BINCODE_CONFIG,
+        )
+        .unwrap();
+        // the empty string's length is the last byte, claim exabytes instead
+        // this used to abort the whole server when it tried to allocate them
+        bytes.pop();
+        bytes.push(253);
+        bytes.extend_from_slice(&(u64::MAX / 4).to_le_bytes());
+        assert!(matches!(
+            receive_datagram(&bytes),
+            Err(ReceiveError::DecodeError(
+                bincode::error::DecodeError::LimitExceeded
+            ))
+        ));
+    }
+}
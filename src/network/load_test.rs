@@ -0,0 +1,329 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use super::*;
+use crate::player::PlayerInput;
+use bevy::prelude::*;
+
+/// How long to keep listening after the last ping goes out, so its pong isn't counted as lost
+const LINGER: Duration = Duration::from_millis(500);
+
+/// Settings for a load test, see run_load_test
+#[derive(Debug, Clone)]
+pub struct LoadTestConfig {
+    /// Fake clients to connect, more than the server's max_clients tests turning clients away
+    pub clients: usize,
+    /// Packets every client sends a second
+    pub send_hz: u64,
+    /// How long to keep sending for
+    pub duration: Duration,
+    /// Send a made up input along with every ping, so the server has inputs to handle too
+    pub inputs: bool,
+}
+
+/// What happened during a load test
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LoadReport {
+    pub elapsed: Duration,
+    pub clients: usize,
+    /// Clients the server turned away or dropped
+    pub refused: usize,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Pings that got a pong back
+    pub pongs: u64,
+    /// Pings that never got a pong back, not counting those to refused clients
+    pub lost: u64,
+    /// Time between sending a ping and getting its pong, added up over every pong
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+}
+
+impl LoadReport {
+    pub fn average_latency(&self) -> Option<Duration> {
+        (self.pongs > 0).then(|| self.total_latency / self.pongs as u32)
+    }
+
+    /// Pings the server handled a second
+    pub fn pongs_per_second(&self) -> f64 {
+        self.pongs as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl std::fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let pings = self.pongs + self.lost;
+        let lost_percent = match pings {
+            0 => 0.,
+            _ => 100. * self.lost as f64 / pings as f64,
+        };
+        writeln!(
+            f,
+            "{} fake client(s) for {:.1?}, {} turned away",
+            self.clients, self.elapsed, self.refused
+        )?;
+        writeln!(
+            f,
+            "{} packets sent, {} received, {:.0} pings handled a second",
+            self.packets_sent,
+            self.packets_received,
+            self.pongs_per_second()
+        )?;
+        write!(
+            f,
+            "{} pongs, {} lost ({:.1}%), latency avg {:.1?} max {:.1?}",
+            self.pongs,
+            self.lost,
+            lost_percent,
+            self.average_latency().unwrap_or_default(),
+            self.max_latency
+        )
+    }
+}
+
+/// One fake client, it only pings (and maybe sends inputs) and listens for pongs
+#[derive(Debug)]
+struct FakeClient {
+    transport: Box<dyn Transport>,
+    /// Newest server sequence we got, acked in every packet
+    last_received_sequence: u64,
+    /// Packets sent so far, also the token of the next ping
+    sent: u64,
+    /// Pings still waiting on a pong, and when they went out
+    outstanding: HashMap<u64, Instant>,
+    /// The server told us to go away, we stop sending then
+    disconnected: Option<DisconnectReason>,
+}
+
+/// A load test in progress, run_load_test drives it over UDP, tests drive it over the channel transport
+#[derive(Debug)]
+pub(super) struct LoadTest {
+    server: SocketAddr,
+    config: LoadTestConfig,
+    clients: Vec<FakeClient>,
+    report: LoadReport,
+    buffer: Vec<u8>,
+}
+
+impl LoadTest {
+    /// One fake client for every transport
+    pub fn new(
+        server: SocketAddr,
+        config: LoadTestConfig,
+        transports: Vec<Box<dyn Transport>>,
+    ) -> Self {
+        let clients = transports
+            .into_iter()
+            .map(|transport| FakeClient {
+                transport,
+                last_received_sequence: 0,
+                sent: 0,
+                outstanding: HashMap::new(),
+                disconnected: None,
+            })
+            .collect::<Vec<FakeClient>>();
+        Self {
+            server,
+            report: LoadReport {
+                clients: clients.len(),
+                ..LoadReport::default()
+            },
+            config,
+            clients,
+            buffer: vec![0; BUFFER_SIZE],
+        }
+    }
+
+    /// Every client that is still connected sends one packet, at now
+    pub fn send_round(&mut self, now: Instant) {
+        for client in self.clients.iter_mut().filter(|c| c.disconnected.is_none()) {
+            let token = client.sent;
+            let mut message = ClientToServer::builder()
+                .ack(client.last_received_sequence)
+                .sequence(token)
+                .token_ping(token);
+            if self.config.inputs {
+                // walk back and forth
+                message = message.input(PlayerInput {
+                    left: token % 2 == 0,
+                    right: token % 2 == 1,
+                    jump: false,
+                    mine: false,
+                    block_x: 0,
+                    block_y: 0,
+                });
+            }
+            let message = message.build();
+            client.sent += 1;
+            match send_message(client.transport.as_ref(), self.server, message) {
+                Ok(_) => {
+                    client.outstanding.insert(token, now);
+                    self.report.packets_sent += 1;
+                }
+                Err(e) => warn!("load test client unable to send: {:?}", e),
+            }
+        }
+    }
+
+    /// Take everything waiting off every client's socket, now is when it arrived
+    pub fn receive(&mut self, now: Instant) {
+        for client in &mut self.clients {
+            while let Ok((size, _)) = client.transport.recv_from(&mut self.buffer) {
+                self.report.packets_received += 1;
+                let message: ServerToClient = match decode_into_owned(&self.buffer[..size]) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        warn!("load test client got an invalid message: {}", e);
+                        continue;
+                    }
+                };
+                client.last_received_sequence =
+                    client.last_received_sequence.max(message.header.sequence);
+                for body in &message.bodies {
+                    match body {
+                        // pongs get resent until acked, only the first one counts
+                        ServerBodyElem::TokenPong { token, .. } => {
+                            if let Some(sent) = client.outstanding.remove(token) {
+                                let latency = now.saturating_duration_since(sent);
+                                self.report.pongs += 1;
+                                self.report.total_latency += latency;
+                                self.report.max_latency = self.report.max_latency.max(latency);
+                            }
+                        }
+                        ServerBodyElem::Disconnect(reason) => {
+                            if client.disconnected.is_none() {
+                                self.report.refused += 1;
+                            }
+                            client.disconnected = Some(*reason);
+                            // those pings were never going to be answered
+                            client.outstanding.clear();
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stop the test, anything still waiting on a pong is lost
+    pub fn finish(mut self, elapsed: Duration) -> LoadReport {
+        self.report.elapsed = elapsed;
+        self.report.lost = self
+            .clients
+            .iter()
+            .map(|c| c.outstanding.len() as u64)
+            .sum();
+        self.report
+    }
+}
+
+/// Connect config.clients fake clients to the server at addr over UDP, each on its own socket,
+/// and have them ping it config.send_hz times a second for config.duration
+pub fn run_load_test(server: SocketAddr, config: LoadTestConfig) -> io::Result<LoadReport> {
+    let ip = if server.ip().is_loopback() {
+        Ipv4Addr::LOCALHOST
+    } else {
+        Ipv4Addr::UNSPECIFIED
+    };
+    let transports = (0..config.clients)
+        .map(|_| {
+            UdpTransport::bind(SocketAddr::from((ip, 0)))
+                .map(|transport| Box::new(transport) as Box<dyn Transport>)
+        })
+        .collect::<io::Result<Vec<Box<dyn Transport>>>>()?;
+
+    let interval = Duration::from_secs_f64(1. / config.send_hz.max(1) as f64);
+    let duration = config.duration;
+    let mut test = LoadTest::new(server, config, transports);
+    let start = Instant::now();
+    let mut next_round = start;
+    while start.elapsed() < duration {
+        let now = Instant::now();
+        if now >= next_round {
+            test.send_round(now);
+            next_round += interval;
+        }
+        test.receive(now);
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    let elapsed = start.elapsed();
+
+    let stop_listening = Instant::now() + LINGER;
+    while Instant::now() < stop_listening {
+        test.receive(Instant::now());
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    Ok(test.finish(elapsed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{
+        config::ServerConfig,
+        server::{
+            send_all_messages, server_handle_messages,
+            tests::{capped_sends, run_system, test_client, test_server_with, test_world},
+            Server,
+        },
+    };
+
+    #[test]
+    fn load_test_counts_pongs_and_refused_clients() {
+        let run = |config: ServerConfig, rounds| {
+            let server_addr = SocketAddr::from((DEFAULT_SERVER_IP, DEFAULT_SERVER_PORT));
+            let (server, network) = test_server_with(config);
+            let mut world = test_world(server);
+            // one more than the server has room for
+            let transports = (0..3)
+                .map(|i| Box::new(test_client(&network, 9000 + i)) as Box<dyn Transport>)
+                .collect();
+            let config = LoadTestConfig {
+                clients: 3,
+                send_hz: 30,
+                duration: Duration::ZERO,
+                inputs: true,
+            };
+            let mut load = LoadTest::new(server_addr, config, transports);
+            for _ in 0..rounds {
+                load.send_round(Instant::now());
+                run_system(&mut world, server_handle_messages);
+                run_system(&mut world, send_all_messages);
+                load.receive(Instant::now());
+            }
+            let inputs = world.resource::<HashMap<SocketAddr, PlayerInput>>().len();
+            let capped_sends = capped_sends(world.resource::<Server>());
+            (load.finish(Duration::from_secs(1)), inputs, capped_sends)
+        };
+
+        let config = ServerConfig {
+            max_clients: 2,
+            ..ServerConfig::default()
+        };
+        let (report, inputs, _) = run(config.clone(), 3);
+        assert_eq!(report.refused, 1);
+        // the refused client gives up after its first packet
+        assert_eq!(report.packets_sent, 3 + 2 + 2);
+        assert_eq!(report.pongs, 6);
+        assert_eq!(report.lost, 0);
+        assert_eq!(inputs, 2);
+        assert!(report.to_string().contains("6 pings handled a second"));
+
+        // the packets per second cap holds pongs back until the test is over
+        let (report, _, capped_sends) = run(
+            ServerConfig {
+                max_packets_per_second: 2,
+                ..config
+            },
+            4,
+        );
+        assert_eq!(capped_sends, 4);
+        assert_eq!(report.pongs, 4);
+        assert_eq!(report.lost, 4);
+    }
+}
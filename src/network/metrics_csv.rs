@@ -0,0 +1,137 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+/// First line of every metrics CSV, the columns of MetricsRow in order
+pub(super) const METRICS_CSV_HEADER: &str = "timestamp,clients,bytes_in,bytes_out,packets_in,packets_out,lost,drops,decode_errors,missed_ticks,deferred_ticks";
+
+/// One line of a metrics CSV, everything but clients counts up from when the server started
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(super) struct MetricsRow {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub clients: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    /// Client sequence numbers we never saw
+    pub lost: u64,
+    /// Datagrams the receive queue had no room for
+    pub drops: u64,
+    /// Datagrams that weren't a valid message
+    pub decode_errors: u64,
+    /// Network ticks that came late
+    pub missed_ticks: u64,
+    /// Game ticks that ran out of message budget
+    pub deferred_ticks: u64,
+}
+
+impl std::fmt::Display for MetricsRow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{},{},{},{},{},{},{},{},{},{},{}",
+            self.timestamp,
+            self.clients,
+            self.bytes_in,
+            self.bytes_out,
+            self.packets_in,
+            self.packets_out,
+            self.lost,
+            self.drops,
+            self.decode_errors,
+            self.missed_ticks,
+            self.deferred_ticks
+        )
+    }
+}
+
+/// Appends a MetricsRow to a CSV file every so often, for graphing a server over a long run
+#[derive(Debug)]
+pub(super) struct MetricsCsv {
+    path: PathBuf,
+    file: File,
+}
+
+impl MetricsCsv {
+    /// Open path to append to, writing the header if it's new or empty
+    /// a file with different columns (from an older server) is moved to path.old first, so rows never get mixed up
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Ok(existing) = File::open(path) {
+            let mut header = String::new();
+            BufReader::new(existing).read_line(&mut header)?;
+            if !header.is_empty() && header.trim_end() != METRICS_CSV_HEADER {
+                let mut old = path.as_os_str().to_owned();
+                old.push(".old");
+                std::fs::rename(path, &old)?;
+            }
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "{}", METRICS_CSV_HEADER)?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Add row to the end of the file, straight to disk so nothing is lost if the server dies
+    pub fn append(&mut self, row: &MetricsRow) -> io::Result<()> {
+        writeln!(self.file, "{}", row)?;
+        self.file.flush()
+    }
+}
+
+/// unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_written_once_and_other_schemas_moved_aside() {
+        let dir = std::env::temp_dir().join(format!("game_metrics_csv_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metrics.csv");
+        let old = dir.join("metrics.csv.old");
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&old);
+
+        let row = MetricsRow {
+            clients: 2,
+            ..MetricsRow::default()
+        };
+        MetricsCsv::open(&path).unwrap().append(&row).unwrap();
+        // opening again carries on where it left off
+        MetricsCsv::open(&path).unwrap().append(&row).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                METRICS_CSV_HEADER,
+                "0,2,0,0,0,0,0,0,0,0,0",
+                "0,2,0,0,0,0,0,0,0,0,0"
+            ]
+        );
+
+        std::fs::write(&path, "timestamp,clients\n1,1\n").unwrap();
+        MetricsCsv::open(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&old).unwrap(),
+            "timestamp,clients\n1,1\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", METRICS_CSV_HEADER)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use bevy::{log::Level, prelude::*, utils::tracing::enabled};
+
+use super::{ClientToServer, ServerToClient};
+
+/// Sees a message from a client before the server handles it, returns false to drop it
+pub(super) type InboundHook = Arc<dyn Fn(SocketAddr, &ClientToServer) -> bool + Send + Sync>;
+
+/// Sees a message to a client right before it's sent, returns false to not send it
+pub(super) type OutboundHook = Arc<dyn Fn(SocketAddr, &ServerToClient) -> bool + Send + Sync>;
+
+/// Hooks that see every message the server gets or sends, for logging, counting or vetoing them
+/// they run in the order they were added, once one vetoes a message the ones after it don't see it
+/// there are none unless some are added, see ServerPlugin::add_inbound
+#[derive(Default, Clone)]
+pub(super) struct Middleware {
+    inbound: Vec<InboundHook>,
+    outbound: Vec<OutboundHook>,
+}
+
+/// Inbound hook that traces every whole message, see ServerConfig::trace_messages
+/// formatting a whole message is slow, so it's only done when someone is looking
+pub(super) fn trace_inbound(from: SocketAddr, message: &ClientToServer) -> bool {
+    if enabled!(Level::TRACE) {
+        trace!("server got {:?} from {}", message, from);
+    }
+    true
+}
+
+/// Outbound hook that traces every whole message, like trace_inbound
+pub(super) fn trace_outbound(to: SocketAddr, message: &ServerToClient) -> bool {
+    if enabled!(Level::TRACE) {
+        trace!("server sending {:?} to {}", message, to);
+    }
+    true
+}
+
+impl Middleware {
+    /// Every hook in other after the ones already here
+    pub fn extend(&mut self, other: &Middleware) {
+        self.inbound.extend(other.inbound.iter().cloned());
+        self.outbound.extend(other.outbound.iter().cloned());
+    }
+
+    pub fn add_inbound(
+        &mut self,
+        hook: impl Fn(SocketAddr, &ClientToServer) -> bool + Send + Sync + 'static,
+    ) {
+        self.inbound.push(Arc::new(hook));
+    }
+
+    pub fn add_outbound(
+        &mut self,
+        hook: impl Fn(SocketAddr, &ServerToClient) -> bool + Send + Sync + 'static,
+    ) {
+        self.outbound.push(Arc::new(hook));
+    }
+
+    /// Whether every inbound hook let message from addr through, true straight away if there are none
+    pub fn allow_inbound(&self, from: SocketAddr, message: &ClientToServer) -> bool {
+        self.inbound.iter().all(|hook| hook(from, message))
+    }
+
+    /// Whether every outbound hook let message to addr through, true straight away if there are none
+    pub fn allow_outbound(&self, to: SocketAddr, message: &ServerToClient) -> bool {
+        self.outbound.iter().all(|hook| hook(to, message))
+    }
+}
+
+/// unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::ClientBodyElem;
+    use std::sync::Mutex;
+
+    #[test]
+    fn hooks_run_in_order_until_one_vetoes() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut middleware = Middleware::default();
+        for (name, allow) in [("first", true), ("veto", false), ("never", true)] {
+            let seen = seen.clone();
+            middleware.add_inbound(move |_, _| {
+                seen.lock().unwrap().push(name);
+                allow
+            });
+        }
+        let message = ClientToServer::builder()
+            .ack(0)
+            .sequence(1)
+            .body(ClientBodyElem::Ping)
+            .build();
+        let from = SocketAddr::from(([127, 0, 0, 1], 9000));
+        assert!(!middleware.allow_inbound(from, &message));
+        assert_eq!(*seen.lock().unwrap(), ["first", "veto"]);
+
+        // nothing registered lets everything through
+        assert!(Middleware::default().allow_inbound(from, &message));
+    }
+}
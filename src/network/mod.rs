@@ -4,8 +4,50 @@ pub mod server;
 /// Module for client-specific network code
 pub mod client;
 
+/// Module for the server's tunables
+pub mod config;
+
 /// Module for network code common between server and client
 mod common;
 
+/// Module for recording packets to replay logs and reading them back
+pub mod replay;
+
+/// Module for the transports (UDP or in-process) that messages are sent over
+mod transport;
+
+/// Module for load testing a server with lots of fake clients
+pub mod load_test;
+
+/// Module for feeding made up datagrams to the server, for fuzzing its receive path
+pub mod fuzz;
+
+/// Module for the key exchange and encryption of bodies
+mod crypto;
+
+/// Module for hooks that see (and can veto) every message the server gets or sends
+mod middleware;
+
+/// Module for writing server metrics to a CSV file
+mod metrics_csv;
+
+/// Module for the clock the server tells time with, real or faked for tests
+mod clock;
+
 /// Re-export everything in common as if it was here
 pub use common::*;
+
+/// Bring everything in transport in as if it was here
+use transport::*;
+
+/// Same for crypto
+use crypto::*;
+
+/// And middleware
+use middleware::*;
+
+/// And metrics_csv
+use metrics_csv::*;
+
+/// And clock
+use clock::*;
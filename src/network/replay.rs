@@ -0,0 +1,315 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use bincode::{Decode, Encode};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+use super::{ClientToServer, BINCODE_CONFIG};
+
+/// First two bytes of every gzip stream, used to tell compressed logs apart when reading
+/// a plain log can't start with these, its second byte is the SocketAddr variant (0 or 1)
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// One recorded packet
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub struct ReplayFrame {
+    /// Server sequence/tick when the packet arrived
+    pub sequence: u64,
+    /// Who sent the packet
+    pub from: SocketAddr,
+    /// The packet exactly as it came off the socket
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    Encode(bincode::error::EncodeError),
+    Decode(bincode::error::DecodeError),
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayError::Io(e) => write!(f, "replay log io error: {}", e),
+            ReplayError::Encode(e) => write!(f, "unable to encode replay frame: {}", e),
+            ReplayError::Decode(e) => write!(f, "unable to decode replay frame: {}", e),
+        }
+    }
+}
+
+/// Where frames end up, either straight to the file or through gzip
+enum ReplaySink<W: Write> {
+    Plain(W),
+    Gzip(GzEncoder<W>),
+}
+
+/// Writes packets to a replay log, one bincode-encoded ReplayFrame after another
+/// Uncompressed logs are easy to poke at by hand, compressed ones are a lot smaller
+pub struct ReplayWriter<W: Write = BufWriter<File>> {
+    /// None once finished
+    sink: Option<ReplaySink<W>>,
+}
+
+impl ReplayWriter {
+    /// Create (or overwrite) a replay log at path
+    pub fn create(path: &Path, compressed: bool) -> Result<Self, ReplayError> {
+        let file = File::create(path).map_err(ReplayError::Io)?;
+        Ok(Self::new(BufWriter::new(file), compressed))
+    }
+}
+
+impl<W: Write> ReplayWriter<W> {
+    pub fn new(inner: W, compressed: bool) -> Self {
+        let sink = if compressed {
+            ReplaySink::Gzip(GzEncoder::new(inner, Compression::default()))
+        } else {
+            ReplaySink::Plain(inner)
+        };
+        Self { sink: Some(sink) }
+    }
+
+    /// Append one frame to the log
+    pub fn write_frame(&mut self, frame: &ReplayFrame) -> Result<(), ReplayError> {
+        let result = match &mut self.sink {
+            Some(ReplaySink::Plain(w)) => bincode::encode_into_std_write(frame, w, BINCODE_CONFIG),
+            Some(ReplaySink::Gzip(w)) => bincode::encode_into_std_write(frame, w, BINCODE_CONFIG),
+            None => return Ok(()),
+        };
+        result.map(|_| ()).map_err(ReplayError::Encode)
+    }
+
+    /// Flush everything out, writing the gzip trailer if compressed
+    /// without this a compressed log is truncated and can't be read back
+    pub fn finish(&mut self) -> Result<W, ReplayError> {
+        let mut inner = match self.sink.take() {
+            Some(ReplaySink::Plain(w)) => w,
+            Some(ReplaySink::Gzip(w)) => w.finish().map_err(ReplayError::Io)?,
+            None => {
+                return Err(ReplayError::Io(io::Error::other(
+                    "replay log already finished",
+                )))
+            }
+        };
+        inner.flush().map_err(ReplayError::Io)?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Drop for ReplayWriter<W> {
+    fn drop(&mut self) {
+        if self.sink.is_some() {
+            let _ = self.finish();
+        }
+    }
+}
+
+impl<W: Write> std::fmt::Debug for ReplayWriter<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = match self.sink {
+            Some(ReplaySink::Plain(_)) => "plain",
+            Some(ReplaySink::Gzip(_)) => "gzip",
+            None => "finished",
+        };
+        f.debug_struct("ReplayWriter")
+            .field("sink", &state)
+            .finish()
+    }
+}
+
+/// Reads frames back out of a replay log, compressed or not
+pub struct ReplayReader {
+    inner: Box<dyn Read>,
+}
+
+impl ReplayReader {
+    /// Open a replay log at path
+    pub fn open(path: &Path) -> Result<Self, ReplayError> {
+        let file = File::open(path).map_err(ReplayError::Io)?;
+        Self::new(BufReader::new(file))
+    }
+
+    /// Works out whether the log is compressed from its first bytes
+    pub fn new<R: Read + 'static>(mut inner: R) -> Result<Self, ReplayError> {
+        let mut start = Vec::with_capacity(GZIP_MAGIC.len());
+        inner
+            .by_ref()
+            .take(GZIP_MAGIC.len() as u64)
+            .read_to_end(&mut start)
+            .map_err(ReplayError::Io)?;
+
+        // put the bytes we peeked at back in front
+        let whole = io::Cursor::new(start.clone()).chain(inner);
+        let inner: Box<dyn Read> = if start == GZIP_MAGIC {
+            Box::new(GzDecoder::new(whole))
+        } else {
+            Box::new(whole)
+        };
+        Ok(Self { inner })
+    }
+
+    /// Read the next frame, None at the end of the log
+    pub fn next_frame(&mut self) -> Result<Option<ReplayFrame>, ReplayError> {
+        match bincode::decode_from_std_read(&mut self.inner, BINCODE_CONFIG) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(bincode::error::DecodeError::Io { inner, .. })
+                if inner.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(ReplayError::Decode(e)),
+        }
+    }
+}
+
+/// The last few seconds of packets, kept in memory so they can be written out after something goes wrong
+/// cheaper than always recording to disk
+#[derive(Debug)]
+pub struct ReplayRing {
+    /// Frames and when they arrived, oldest first
+    frames: VecDeque<(Instant, ReplayFrame)>,
+    /// Frames older than this get dropped
+    window: Duration,
+    /// Most frames kept no matter how recent, so a flood of packets can't eat all our memory
+    capacity: usize,
+}
+
+impl ReplayRing {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            frames: VecDeque::with_capacity(capacity),
+            window,
+            capacity,
+        }
+    }
+
+    /// Add a frame that arrived at now, dropping whatever is too old or doesn't fit
+    pub fn push(&mut self, now: Instant, frame: ReplayFrame) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.frames.len() >= self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames.push_back((now, frame));
+        while let Some((arrived, _)) = self.frames.front() {
+            if now.saturating_duration_since(*arrived) <= self.window {
+                break;
+            }
+            self.frames.pop_front();
+        }
+    }
+
+    /// Write every frame in the ring to a replay log at path, oldest first
+    /// returns how many frames were written, the ring itself is left alone
+    pub fn dump_replay(&self, path: &Path) -> Result<usize, ReplayError> {
+        let mut writer = ReplayWriter::create(path, false)?;
+        for (_, frame) in &self.frames {
+            writer.write_frame(frame)?;
+        }
+        writer.finish()?;
+        Ok(self.frames.len())
+    }
+}
+
+/// Print out every frame in a replay log, for looking at one without running the game
+pub fn print_replay(path: &Path) -> Result<(), ReplayError> {
+    let mut reader = ReplayReader::open(path)?;
+    let mut count = 0;
+    while let Some(frame) = reader.next_frame()? {
+        count += 1;
+        let contents =
+            match bincode::decode_from_slice::<ClientToServer, _>(&frame.data, BINCODE_CONFIG) {
+                Ok((message, _size)) => format!(
+                    "ack {}, {} bodies",
+                    message.header.last_received_sequence,
+                    message.bodies.len()
+                ),
+                Err(_) => "not a valid message".to_string(),
+            };
+        println!(
+            "seq {}: {} bytes from {}, {}",
+            frame.sequence,
+            frame.data.len(),
+            frame.from,
+            contents
+        );
+    }
+    println!("{} frame(s)", count);
+    Ok(())
+}
+
+/// unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_frames() -> Vec<ReplayFrame> {
+        (0..5)
+            .map(|i| ReplayFrame {
+                sequence: i * 10,
+                from: SocketAddr::from(([127, 0, 0, 1], 9000 + i as u16)),
+                data: vec![i as u8; 100 * i as usize],
+            })
+            .collect()
+    }
+
+    fn round_trip(compressed: bool) -> (Vec<u8>, Vec<ReplayFrame>) {
+        let mut writer = ReplayWriter::new(Vec::new(), compressed);
+        for frame in &test_frames() {
+            writer.write_frame(frame).unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+
+        let mut reader = ReplayReader::new(io::Cursor::new(bytes.clone())).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = reader.next_frame().unwrap() {
+            frames.push(frame);
+        }
+        (bytes, frames)
+    }
+
+    #[test]
+    fn compressed_log_round_trips() {
+        let (compressed, frames) = round_trip(true);
+        assert_eq!(frames, test_frames());
+        assert_eq!(compressed[..2], GZIP_MAGIC);
+
+        // plain logs still work, and are bigger
+        let (plain, frames) = round_trip(false);
+        assert_eq!(frames, test_frames());
+        assert!(compressed.len() < plain.len());
+    }
+
+    #[test]
+    fn ring_keeps_recent_frames_in_order() {
+        let start = Instant::now();
+        let mut ring = ReplayRing::new(Duration::from_secs(10), 3);
+        for (i, frame) in test_frames().into_iter().enumerate() {
+            ring.push(start + Duration::from_secs(i as u64), frame);
+        }
+        // only room for the last three
+        let path = std::env::temp_dir().join("game_replay_ring_test.log");
+        assert_eq!(ring.dump_replay(&path).unwrap(), 3);
+        let mut reader = ReplayReader::open(&path).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = reader.next_frame().unwrap() {
+            frames.push(frame);
+        }
+        assert_eq!(frames, test_frames()[2..]);
+
+        // anything older than the window goes too
+        ring.push(
+            start + Duration::from_millis(13500),
+            test_frames().remove(0),
+        );
+        let sequences: Vec<u64> = ring.frames.iter().map(|(_, f)| f.sequence).collect();
+        assert_eq!(sequences, [40, 0]);
+    }
+}
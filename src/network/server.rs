@@ -1,38 +1,465 @@
-use super::*;
-use crate::{player::PlayerInput, states, world::Terrain};
-use bevy::prelude::*;
+use super::{config::*, replay::*, *};
+use crate::{
+    player::{PlayerInput, PLAYER_SPEED},
+    save, states,
+    world::{
+        create_surface_chunk, remove_block, valid_terrain_scale, BlockBounds, Chunk, Terrain,
+        TerrainChanged, WorldState,
+    },
+};
+use bevy::{app::AppExit, prelude::*};
 use iyes_loopless::prelude::*;
 use std::{
-    collections::HashMap,
-    net::{SocketAddr, UdpSocket},
-    path::PathBuf,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    io::BufRead,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+    time::{Duration, Instant},
 };
 
-/// how many times per second will the network tick occur
-const NETWORK_TICK_HZ: u64 = 1;
-
 /// timestep for sending out network messages
 pub const NETWORK_TICK_LABEL: &str = "NETWORK_TICK";
 
-/// how many times per second will the game tick occur
-const GAME_TICK_HZ: u64 = 60;
-
 /// timestep for doing world calculations
 pub const GAME_TICK_LABEL: &str = "GAME_TICK";
 
-// maximum number of clients (final goal = 2, strech goal = 4)
-const MAX_CLIENTS: usize = 2;
+/// most clients that may be turned into spectators on top of the players
+const MAX_SPECTATORS: usize = 2;
+
+/// room for clients that are on their way out while their replacement is already connecting
+const RECONNECT_BUFFER: usize = 2;
+
+/// most packets the in-memory replay ring holds, however short the window
+const REPLAY_RING_CAPACITY: usize = 4096;
+
+/// most inputs kept for each client for dump_input_history, at 60 inputs a second that's the last 10 seconds
+const INPUT_HISTORY_LEN: usize = 600;
+
+/// most inputs waiting for apply_inputs per client, a client sending far faster than the game tick loses its oldest
+const MAX_QUEUED_INPUTS: usize = 32;
+
+/// reported prediction errors are clamped to this many pixels, anything bigger is garbage anyway
+const MAX_PREDICTION_ERROR: f32 = 32. * 16.;
+
+/// reported one-way delays are clamped to this, anything longer and the clocks are way off
+const MAX_ONE_WAY_DELAY: Duration = Duration::from_secs(10);
+
+/// while draining on shutdown, unacked reliable bodies go out again this often
+const DRAIN_RESEND_INTERVAL: Duration = Duration::from_millis(100);
+/// and this is how long it waits between looking for acks
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// room the clients map needs on top of max_clients, reserved up front so it never rehashes mid-game
+const EXTRA_CLIENTS_CAPACITY: usize = MAX_SPECTATORS + RECONNECT_BUFFER;
+
+/// timestep for logging how many bodies are queued for each client
+#[cfg(debug_assertions)]
+const QUEUE_LOG_LABEL: &str = "QUEUE_LOG";
+
+/// timestep for spreading out sends when pacing is on
+const PACING_LABEL: &str = "PACING";
+
+/// how many pieces each network tick gets split into when pacing is on
+const PACING_STEPS: u64 = 4;
+
+/// players get a packet every network tick
+const PLAYER_SEND_INTERVAL_TICKS: u64 = 1;
+
+/// spectators don't need to be as up to date
+const SPECTATOR_SEND_INTERVAL_TICKS: u64 = 5;
+
+/// idle clients get a packet at most once every this many network ticks
+const IDLE_SEND_INTERVAL_TICKS: u64 = 4;
+
+/// timestep for the per-client bandwidth log
+const BANDWIDTH_LOG_LABEL: &str = "BANDWIDTH_LOG";
+
+/// timestep for the metrics CSV, see ServerConfig::metrics_csv
+const METRICS_CSV_LABEL: &str = "METRICS_CSV";
+
+/// timestep for giving back memory left over from clients that have gone, see Server::compact
+const COMPACT_LABEL: &str = "COMPACT";
+
+/// how often Server::compact runs, rarely since shrinking a map copies everything in it
+const COMPACT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// datagram sizes new clients are probed with, see ServerConfig::probe_mtu
+/// 1472 is as big as a UDP payload gets over ethernet without being fragmented
+const MTU_PROBE_SIZES: [u16; 3] = [1200, 1400, 1472];
+
+/// MTU a client gets when none of its probes come back, small enough to get through nearly anywhere
+const FALLBACK_MTU: usize = 1200;
+
+/// how long a client's MTU probes have to come back before it settles on one
+const MTU_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// room kept under a client's MTU for the header, the reliable bodies' lengths, encryption and a key exchange
+const MTU_OVERHEAD: usize = 128;
+
+/// how long clients get to come back with their reconnect token after a restart
+const RESTART_TICKET_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// how long clients wait before connecting to a restarting server again, if the admin doesn't say
+const DEFAULT_RESTART_AFTER_SECONDS: u64 = 5;
+
+/// most entity snapshots kept around per client to send deltas against, clients further behind get a full one
+const ENTITY_SNAPSHOT_HISTORY: usize = 32;
+
+/// socket errors in a row (that weren't transient) before the socket counts as broken and gets bound again
+const SOCKET_ERRORS_BEFORE_REBIND: u32 = 16;
+
+/// most unacked packets remembered per client for working out round trip times
+const RTT_SAMPLE_CAPACITY: usize = 64;
+
+/// most versions a chunk's changed bounds are kept for, older ones get folded into the oldest one kept
+const CHANGED_BOUNDS_HISTORY: usize = 8;
+
+/// most bounds in a ChangedBounds, any more and they're sent as one big one instead
+const MAX_CHANGED_BOUNDS: usize = 16;
 
 /// Should be used as a global resource on the server
-struct Server {
-    /// UDP socket that should be used for everything
-    socket: UdpSocket,
+pub(crate) struct Server {
+    /// Transport (normally a UDP socket) that should be used for everything
+    transport: Box<dyn Transport>,
+    /// Hooks every message in and out goes through, see Middleware
+    middleware: Middleware,
+    /// Where the time comes from, only tests use anything but SystemClock
+    clock: Box<dyn Clock>,
     /// HashMap of clients using the socket address as the key
     clients: HashMap<SocketAddr, ClientInfo>,
     /// The current sequence/tick number
     sequence: u64,
-    /// Incoming buffer
-    buffer: [u8; BUFFER_SIZE],
+    /// Incoming buffer, every packet is read into it so it only holds the last one
+    /// decode out of it with decode_into_owned, so nothing still refers to it at the next read
+    buffer: Vec<u8>,
+    /// Counters for keeping an eye on the server
+    metrics: ServerMetrics,
+    /// Tunables the server was started with
+    config: ServerConfig,
+    /// Network ticks since the server started, used to space out terrain changes
+    terrain_tick_counter: u64,
+    /// Network ticks since the server started, used to space out packets to slow clients
+    network_tick: u64,
+    /// Messages waiting for their piece of the network tick, see send_paced_messages
+    paced: VecDeque<(SocketAddr, ServerToClient)>,
+    /// How many paced messages go out each piece of the network tick
+    paced_per_step: usize,
+    /// Messages held back until their turn, see ServerConfig::smooth_sends
+    smoothed: Vec<(Instant, SocketAddr, ServerToClient)>,
+    /// When the last network tick sent out messages
+    last_network_tick_at: Option<Instant>,
+    /// When ServerMetrics::bytes_this_second started counting, see ServerConfig::max_bytes_per_second
+    bandwidth_second_start: Instant,
+    /// Join number the next new client gets
+    next_join_number: u64,
+    /// NetworkId the next player gets, unless something in the world already has it, see spawn_players
+    next_player_id: u64,
+    /// Every incoming packet gets written here, if set
+    recorder: Option<ReplayWriter>,
+    /// A row of metrics gets written here every ServerConfig::metrics_csv_interval, if set
+    metrics_csv: Option<MetricsCsv>,
+    /// The last few seconds of incoming packets, if set, see ReplayRing
+    replay_ring: Option<ReplayRing>,
+    /// Chunks that changed since the terrain version was last bumped, and where in them
+    dirty_chunks: BTreeMap<u64, BlockBounds>,
+    /// Goes up by one every time changed chunks are sent out, or the map changes
+    terrain_version: u64,
+    /// Newest terrain that passed check_terrain and its version, sent to new clients instead of one that didn't
+    /// it's the copy check_terrain decoded, so exactly what clients will end up with
+    last_good_terrain: Option<(Terrain, u64)>,
+    /// How big the last terrain check_terrain passed was encoded, for AdminCommand::Status
+    last_good_terrain_size: Option<usize>,
+    /// Checksum of the world as it is now, None until update_world_checksum first runs
+    world_checksum: Option<WorldChecksum>,
+    /// Version each chunk last changed in, chunks that haven't changed since the map loaded aren't in here
+    chunk_versions: BTreeMap<u64, u64>,
+    /// Where each chunk changed in the last few versions it did, oldest first, see changed_bounds
+    chunk_bounds: BTreeMap<u64, VecDeque<(u64, BlockBounds)>>,
+    /// Kicked addresses and when they're allowed back
+    bans: HashMap<SocketAddr, Instant>,
+    /// Banned addresses already reminded why, everything else from them is dropped without an answer
+    /// so spoofing a banned address can't turn the server into a reflector
+    reminded_bans: HashSet<SocketAddr>,
+    /// When each address was started over recently, oldest first, see ServerConfig::max_reconnects
+    reconnects: HashMap<SocketAddr, VecDeque<Instant>>,
+    /// Id and entities (sorted by entity id) of the newest entity snapshot
+    latest_snapshot: Option<(u64, Vec<EntityState>)>,
+    /// Entities over the last few game ticks, for lag compensation
+    snapshot_history: SnapshotHistory,
+    /// Id the next entity snapshot will get
+    next_snapshot_id: u64,
+    /// The match's current settings, see AdminCommand::SetSetting
+    settings: GameSettings,
+    /// Client allowed to run admin commands when host_authority is on
+    host: Option<SocketAddr>,
+    /// Changes to the clients map waiting for apply_client_mutations, oldest first
+    mutations: Vec<ClientMutation>,
+    /// Socket errors in a row that weren't transient, see SOCKET_ERRORS_BEFORE_REBIND
+    socket_errors: u32,
+    /// Times the socket has been bound again since it broke, see check_socket
+    rebind_attempts: u32,
+    /// Don't try binding the socket again before this
+    next_rebind_at: Option<Instant>,
+    /// Gave up on the socket, the server is on its way out
+    socket_failed: bool,
+    /// Clients from before a restart, by reconnect token, see Server::prepare_restart
+    restart_tickets: HashMap<u64, RestartTicket>,
+    /// Tickets left after this never get used, see RESTART_TICKET_LIFETIME
+    restart_tickets_until: Option<Instant>,
+}
+
+/// What a client had before a restart, for when it comes back, see Server::prepare_restart
+#[derive(bincode::Encode, bincode::Decode, Debug, Clone, PartialEq)]
+struct RestartTicket {
+    token: u64,
+    name: Option<String>,
+    player_id: Option<u64>,
+    /// Where its player was
+    position: Option<(f32, f32)>,
+}
+
+/// Why a restart couldn't be set up, see Server::prepare_restart
+#[derive(Debug)]
+enum RestartError {
+    /// The world is never saved, so there is nothing to restart from
+    NoSaveFile,
+    IoError(std::io::Error),
+    EncodeError(bincode::error::EncodeError),
+}
+
+impl std::fmt::Display for RestartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RestartError::NoSaveFile => write!(f, "the world isn't saved, nothing would be kept"),
+            RestartError::IoError(e) => write!(f, "could not write restart file, {}", e),
+            RestartError::EncodeError(e) => write!(f, "unable to encode restart tickets, {}", e),
+        }
+    }
+}
+
+/// A change to which clients there are or what they're called, see Server::queue_mutation
+#[derive(Debug)]
+enum ClientMutation {
+    /// Give a new client a slot, replacing whatever was at its address
+    Add(Box<ClientInfo>),
+    /// Take a client's slot away, letting it know why
+    Remove(SocketAddr, DisconnectReason),
+    /// Change the name a client goes by
+    UpdateName(SocketAddr, String),
+    /// A client asked to go by a name, see Server::claim_name
+    ClaimName(SocketAddr, String),
+}
+
+/// Counters describing how the server is doing, for operators
+#[derive(Debug, Default, Clone)]
+struct ServerMetrics {
+    /// Datagrams taken off the socket, whether or not they were valid
+    datagrams_received: u64,
+    /// Game ticks that ran out of message budget, leaving messages for the next tick
+    /// if this keeps going up the server is falling behind
+    deferred_ticks: u64,
+    /// Most messages sent back-to-back in one go, pacing should keep this down
+    largest_send_burst: u64,
+    /// Messages that were held back by pacing to a later piece of the network tick
+    paced_messages: u64,
+    /// Messages held back by smooth_sends because they were ready early
+    smoothed_sends: u64,
+    /// Network ticks that came late because the server stalled
+    missed_ticks: u64,
+    /// Packets not sent because the client was at its packets per second cap
+    capped_sends: u64,
+    /// Datagrams thrown away because the receive queue was full, see ThreadedTransport
+    receive_queue_drops: u64,
+    /// Physics steps run, physics_substeps for every game tick
+    physics_steps: u64,
+    /// Datagrams with nothing in them, ignored without a slot or a log line
+    empty_datagrams: u64,
+    /// Messages thrown away for having more than max_bodies_per_message bodies
+    too_many_bodies: u64,
+    /// Datagrams thrown away for being bigger than max_message_size
+    oversized_messages: u64,
+    /// Rate the network tick is running at right now, see ServerConfig::tuned_network_tick_hz
+    network_tick_hz: u64,
+    /// Packets sent with nothing in them, just to let clients know we're still here
+    keepalives: u64,
+    /// Empty packets not sent, because of keepalive_interval or because the client already got one that tick
+    skipped_keepalives: u64,
+    /// Bytes sent to clients since the current second started
+    bytes_this_second: u64,
+    /// Network ticks that only sent high priority bodies because of max_bytes_per_second
+    bandwidth_capped_ticks: u64,
+    /// How long the busiest systems take to run
+    system_times: SystemTimes,
+    /// Packets that went out with a world checksum in the header
+    checksums_sent: u64,
+    /// Times a client said its world didn't match the checksum
+    desyncs: u64,
+    /// Datagrams that didn't decode into a message
+    decode_errors: u64,
+    /// Traffic to and from every client since the server started, lost isn't counted here
+    traffic: BandwidthSample,
+}
+
+/// Where every entity was over the last few game ticks, so the server can rewind to what a client saw
+/// when it acted, see ServerConfig::snapshot_history
+#[derive(Debug, Default)]
+struct SnapshotHistory {
+    /// Game tick and entities (sorted by entity id), oldest first
+    snapshots: VecDeque<(u64, Vec<EntityState>)>,
+    /// Most snapshots kept, older ones are thrown away
+    depth: usize,
+}
+
+impl SnapshotHistory {
+    fn new(depth: usize) -> Self {
+        Self {
+            snapshots: VecDeque::with_capacity(depth),
+            depth,
+        }
+    }
+
+    /// Remember the entities as of tick, dropping the oldest snapshot if there's no room
+    fn push(&mut self, tick: u64, entities: Vec<EntityState>) {
+        if self.depth == 0 {
+            return;
+        }
+        while self.snapshots.len() >= self.depth {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back((tick, entities));
+    }
+
+    /// The newest snapshot taken at or before tick, with the tick it was taken on
+    /// None if tick is older than anything still kept
+    fn at_tick(&self, tick: u64) -> Option<(u64, &[EntityState])> {
+        self.snapshots
+            .iter()
+            .rev()
+            .find(|(taken, _)| *taken <= tick)
+            .map(|(taken, entities)| (*taken, entities.as_slice()))
+    }
+}
+
+/// WorldState::checksum of the server's world, see update_world_checksum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct WorldChecksum {
+    checksum: u64,
+    /// Game tick the checksum last changed on
+    changed_at: u64,
+}
+
+/// How long one system has taken to run, over every run
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct SystemTiming {
+    runs: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl SystemTiming {
+    /// Count one run that started at start and just finished
+    fn record(&mut self, start: Instant) {
+        let took = start.elapsed();
+        self.runs += 1;
+        self.total += took;
+        self.max = self.max.max(took);
+    }
+
+    /// Average time per run, None if it hasn't run yet
+    fn average(&self) -> Option<Duration> {
+        (self.runs > 0).then(|| self.total / self.runs as u32)
+    }
+}
+
+/// SystemTiming for each of the systems that do the most work, to see which one grows with the clients
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct SystemTimes {
+    handle_messages: SystemTiming,
+    enqueue_terrain: SystemTiming,
+    send_messages: SystemTiming,
+    drop_clients: SystemTiming,
+}
+
+impl std::fmt::Display for SystemTimes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let systems = [
+            ("handle messages", self.handle_messages),
+            ("enqueue terrain", self.enqueue_terrain),
+            ("send messages", self.send_messages),
+            ("drop clients", self.drop_clients),
+        ];
+        for (i, (name, timing)) in systems.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(
+                f,
+                "{} avg {:.1?} max {:.1?}",
+                name,
+                timing.average().unwrap_or_default(),
+                timing.max
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// How fast a simulated entity is moving, in pixels per second
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Velocity {
+    pub x: f32,
+    pub y: f32,
+}
+
+/// Where a client is in its lifecycle, a new slot starts Connecting (or Reconnecting, see ClientInfo::returning)
+/// and from then on it's only ever changed through ClientInfo::transition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionState {
+    /// Just got a slot and hasn't acked anything from us yet, its handshake bodies are handled but not its inputs
+    Connecting,
+    /// Has acked something, so it hears us and everything it sends is handled
+    Connected,
+    /// Connected before and started its handshake over from the same address, see DuplicateConnect::Reconnect
+    /// treated like Connecting until it acks something again
+    Reconnecting,
+    /// Going to be removed (kicked, refused, shutting down), keep_inputs is ServerConfig::inputs_while_dropping
+    Disconnecting { keep_inputs: bool },
+}
+
+impl ConnectionState {
+    /// Whether a client can go from this state straight to next
+    /// a client only ever comes back from Disconnecting by getting a new slot
+    fn can_become(self, next: ConnectionState) -> bool {
+        use ConnectionState::*;
+        matches!(
+            (self, next),
+            (Connecting | Reconnecting, Connected)
+                | (Connected, Reconnecting)
+                | (Connecting | Connected | Reconnecting, Disconnecting { .. })
+        )
+    }
+
+    /// Whether inputs sent in this state get stored
+    fn takes_inputs(self) -> bool {
+        matches!(
+            self,
+            ConnectionState::Connected | ConnectionState::Disconnecting { keep_inputs: true }
+        )
+    }
+}
+
+/// A client was asked to go to a state it can't get to from the one it's in, see ConnectionState::can_become
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct InvalidTransition {
+    from: ConnectionState,
+    to: ConnectionState,
+}
+
+impl std::fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "can't go from {:?} to {:?}", self.from, self.to)
+    }
 }
 
 /// Information about a client
@@ -44,327 +471,9519 @@ struct ClientInfo {
     last_ack: u64,
     /// Body elements that we build up
     bodies: Vec<ServerBodyElem>,
+    /// How many bodies at the front of bodies were left out of the last packet, see send_all_messages
+    /// acking it doesn't mean they got there
+    waiting: usize,
     /// How many frames until we drop it
     until_drop: u64,
+    /// Where the client is in its lifecycle, see ConnectionState
+    state: ConnectionState,
+    /// Terrain goes out at 1/this the resolution each way, 1 for all of it
+    terrain_scale: u8,
+    /// Version we speak with the client, see negotiate_version
+    protocol_version: u32,
+    /// Versions the client offered that we don't share any of, it gets disconnected
+    version_mismatch: Option<(u32, u32)>,
+    /// Reliable bodies that the client has not acknowledged yet, oldest first
+    reliable: Vec<PendingReliable>,
+    /// Id that the next reliable body will get
+    next_reliable_id: u64,
+    /// When the client first connected
+    connected_at: Instant,
+    /// Not dropped before this even without any acks, two-way traffic can take a while to get going
+    grace_until: Instant,
+    /// What the client is doing in the game
+    role: ClientRole,
+    /// Only watching, asked for when connecting, its inputs are ignored and it doesn't count against max_clients
+    observer: bool,
+    /// Only send to this client once every this many network ticks
+    send_interval_ticks: u64,
+    /// Clients that joined earlier have lower numbers, used to order their inputs
+    join_number: u64,
+    /// What the client goes by, None until it's given one, see AdminCommand::SetName
+    name: Option<String>,
+    /// Token the client came back from a restart with, until restore_returning_players looks it up
+    reconnect_token: Option<u64>,
+    /// The same token, kept for as long as the client is connected
+    /// another client with it is the same player connecting from somewhere else, see Server::claim_name
+    player_token: Option<u64>,
+    /// Categories of bodies the client wants, see ClientBodyElem::Subscribe
+    subscriptions: u32,
+    /// Blocks this client has mined since joining
+    blocks_mined: u64,
+    /// When packets were sent to the client over the last second, oldest first
+    recent_sends: VecDeque<Instant>,
+    /// Terrain versions the client has or is about to have, changes are sent relative to the oldest
+    /// empty until the whole terrain has been queued, an ack frees every version older than it
+    terrain_baselines: BTreeSet<u64>,
+    /// terrain_baselines (and the scale they were at) from before the client connected again, see DuplicateConnect::Reconnect
+    /// if it acks one of them it still has that terrain, and gets changes since instead of all of it
+    returning_baselines: Option<(BTreeSet<u64>, u8)>,
+    /// Chunks the client asked for again, sent by the next enqueue_terrain, at most MAX_REQUESTED_CHUNKS
+    requested_chunks: BTreeSet<ChunkId>,
+    /// Newest entity snapshot the client has acked, deltas are sent against it
+    entity_baseline: Option<u64>,
+    /// What the client was sent for each recent snapshot, after culling, so deltas match what it has
+    entity_views: BTreeMap<u64, Vec<EntityState>>,
+    /// Version of the game settings last queued for this client
+    settings_version: Option<u64>,
+    /// Got everything it needs on connect but the terrain, see enqueue_welcome
+    welcomed: bool,
+    /// Got the whole terrain, or only what changed since it left if it came back still having it, see queue_terrain
+    terrain_welcomed: bool,
+    /// Admin command lines from the client, waiting to be checked against host authority
+    admin_commands: Vec<String>,
+    /// Name the client asked for, waiting on Server::claim_name
+    name_claim: Option<String>,
+    /// Tick and checksum of the newest desync the client reported, waiting on Server::handle_desync
+    desync_report: Option<(u64, u64)>,
+    /// Times the client reported that its world didn't match ours
+    desyncs: u64,
+    /// NetworkId of the entity this client plays as, its view is centered on it
+    /// spawned right after the client is let in, see spawn_players, observers never get one and see everything
+    player_id: Option<u64>,
+    /// Reliable id and terrain version of a whole terrain (or map) still on its way to the client
+    pending_baseline: Option<(u64, u64)>,
+    /// When the client last sent an input (or joined)
+    last_input_time: Instant,
+    /// Inputs the server took from this client and the client sequence each came in, oldest first
+    /// at most INPUT_HISTORY_LEN, for looking into suspected cheating, see Server::dump_input_history
+    input_history: VecDeque<(u64, PlayerInput)>,
+    /// Inputs that came in since apply_inputs last ran, with the sequence of the message they came in, oldest first
+    queued_inputs: VecDeque<(u64, PlayerInput)>,
+    /// Which way this game tick's inputs steer the player, from -1 (all left) through 0 to 1 (all right)
+    /// set by apply_inputs and used up by simulate_physics
+    tick_direction: Option<f32>,
+    /// Client hasn't sent any input in a while, so it gets fewer packets
+    idle: bool,
+    /// How bodies get compressed for this client, agreed on from the codecs it offered
+    codecs: PayloadCodecs,
+    /// Codecs the client just said it can decompress, waiting on negotiate_codecs
+    offered_codecs: Option<Vec<Codec>>,
+    /// Highest sequence the client has sent us, unlike last_ack this is the client's own numbering
+    last_seen_client_seq: Option<u64>,
+    /// Sequence numbers skipped by the client's packets, each one possibly a lost packet
+    detected_loss: u64,
+    /// What the client has told us about how well its prediction is doing
+    prediction_error: PredictionErrorStats,
+    /// Traffic since the bandwidth log last rolled it over
+    bandwidth: BandwidthSample,
+    /// Sequence and send time of recent packets that haven't been acked, oldest first
+    unacked_sends: VecDeque<(u64, Instant)>,
+    /// Time between sending the last acked packet and getting its ack
+    rtt: Option<Duration>,
+    /// Every rtt we've worked out, so not just the last one
+    ack_latency: AckLatencyStats,
+    /// Tokens of our own pings that haven't been echoed yet, and when they were queued, oldest first
+    probes: VecDeque<(u64, Instant)>,
+    /// Token the next probe gets
+    next_probe_token: u64,
+    /// When the last probe was queued, None if there hasn't been one
+    last_probe_at: Option<Instant>,
+    /// Biggest datagram that gets to the client, None if it hasn't been probed, see ServerConfig::probe_mtu
+    mtu: Option<usize>,
+    /// MTU probes still waiting to come back
+    mtu_probe: Option<MtuProbing>,
+    /// When the last packet went to the client, None if nothing has yet
+    last_sent_at: Option<Instant>,
+    /// How evenly packets go out to the client
+    smoothing: SendSmoothing,
+    /// How long our packets take to get to the client, from the receive times it reports
+    one_way_delay: OneWayDelayStats,
+    /// Key agreed with the client, None if it didn't offer one or encryption is off
+    session: Option<ClientSession>,
 }
 
-impl ClientInfo {
-    fn new(addr: SocketAddr) -> Self {
-        ClientInfo {
-            addr,
-            last_ack: 0,
-            bodies: Vec::with_capacity(DEFAULT_BODIES_VEC_CAPACITY),
-            until_drop: FRAME_DIFFERENCE_BEFORE_DISCONNECT,
-        }
-    }
+/// Encryption agreed with one client, see ServerConfig::encryption
+#[derive(Debug)]
+struct ClientSession {
+    key: SessionKey,
+    /// Our half of the key exchange, sent to the client until it starts encrypting
+    server_public: [u8; PUBLIC_KEY_LEN],
+    /// The client has sent us something encrypted, so it's safe to encrypt everything we send it
+    confirmed: bool,
+    /// Make every seal fail, since nothing we send can really fail to encode
+    #[cfg(test)]
+    fail_seals: bool,
 }
 
-impl Server {
-    /// Binds the socket
-    fn new(port: u16) -> Result<Self, std::io::Error> {
-        let addr = SocketAddr::from((DEFAULT_SERVER_IP, port));
-        let sock = UdpSocket::bind(addr)?;
-
-        // we want nonblocking sockets!
-        sock.set_nonblocking(true)?;
+impl ClientInfo {
+    /// Answer a key exchange if there is one, then decrypt the message's bodies if they're encrypted
+    /// once the client has encrypted something, bodies sent in the clear are refused
+    fn decrypt(
+        &mut self,
+        mut message: ClientToServer,
+        encryption: bool,
+    ) -> Result<ClientToServer, ReceiveError> {
+        if encryption && self.session.is_none() {
+            let offered = message.bodies.iter().find_map(|body| match body {
+                ClientBodyElem::KeyExchange(client_public) => Some(*client_public),
+                _ => None,
+            });
+            if let Some(client_public) = offered {
+                let pair = KeyPair::generate();
+                self.session = Some(ClientSession {
+                    key: pair.session_key(client_public),
+                    server_public: pair.public_key(),
+                    confirmed: false,
+                    #[cfg(test)]
+                    fail_seals: false,
+                });
+                info!("agreed on a key with {}", self.addr);
+            }
+        }
 
-        Ok(Server {
-            socket: sock,
-            clients: HashMap::with_capacity(MAX_CLIENTS * 2), // avoid resizing (default capacity is 16).,
-            sequence: 1u64,
-            buffer: [0u8; BUFFER_SIZE],
-        })
+        if let [ClientBodyElem::Encrypted(sealed)] = &message.bodies[..] {
+            let session = self
+                .session
+                .as_mut()
+                .ok_or(ReceiveError::Decrypt(CryptoError::NoSession))?;
+            let header = message.header.aad();
+            let bodies = open_bodies(&mut session.key, Toward::Server, &header, sealed)?;
+            message.bodies = bodies;
+            session.confirmed = true;
+        } else if self.session.as_ref().is_some_and(|s| s.confirmed) && !message.bodies.is_empty() {
+            return Err(ReceiveError::Unencrypted);
+        }
+        Ok(message)
     }
 
-    /// Send message to a specific client
-    fn send_message(
-        &self,
-        client_addr: SocketAddr,
-        message: ServerToClient,
-    ) -> Result<(), SendError> {
-        match &self.clients.get(&client_addr) {
-            Some(client) => {
-                send_message(&self.socket, client.addr, message)?;
-                Ok(())
+    /// Encrypt a message going to the client if it has confirmed its key,
+    /// or tack our half of the key exchange on if it hasn't yet
+    /// None if it couldn't be encrypted, then it mustn't be sent or count as sent so everything goes out next time
+    fn encrypt(&mut self, mut message: ServerToClient) -> Option<ServerToClient> {
+        let session = match &mut self.session {
+            Some(session) => session,
+            None => return Some(message),
+        };
+        if !session.confirmed {
+            message
+                .bodies
+                .push(ServerBodyElem::KeyExchange(session.server_public));
+            return Some(message);
+        }
+        let sealed = seal_bodies(
+            &mut session.key,
+            Toward::Client,
+            &message.header.aad(),
+            (&message.bodies, &message.reliable),
+        );
+        #[cfg(test)]
+        let sealed = sealed.and_then(|sealed| match session.fail_seals {
+            true => Err(bincode::error::EncodeError::Other("fail_seals")),
+            false => Ok(sealed),
+        });
+        match sealed {
+            Ok(sealed) => {
+                message.bodies = vec![ServerBodyElem::Encrypted(sealed)];
+                message.reliable = Vec::new();
+                Some(message)
+            }
+            Err(e) => {
+                error!("unable to encrypt message for {}: {}", self.addr, e);
+                None
             }
-            None => Err(SendError::NoSuchPeer),
         }
     }
+}
 
-    /// Non-blocking way to get one message from the socket
-    /// TODO: loop over all clients whenever more than one is supported
-    fn get_one_message(&mut self) -> Result<(&mut ClientInfo, ClientToServer), ReceiveError> {
-        // read from socket
-        let (_size, sender_addr) = self.socket.recv_from(&mut self.buffer).map_err(|e| match e
-            .kind()
-        {
-            std::io::ErrorKind::WouldBlock => ReceiveError::NoMessage,
-            _ => ReceiveError::IoError(e),
-        })?;
+/// MTU probes sent to a client that haven't all come back yet, see Server::probe_mtus
+#[derive(Debug, Clone, Copy)]
+struct MtuProbing {
+    sent_at: Instant,
+    largest_acked: Option<usize>,
+}
 
-        // decode
-        let (message, _size) = bincode::decode_from_slice(&self.buffer, BINCODE_CONFIG)
-            .map_err(ReceiveError::DecodeError)?;
+/// Traffic to and from one client over some stretch of time
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct BandwidthSample {
+    bytes_sent: u64,
+    bytes_received: u64,
+    packets_sent: u64,
+    packets_received: u64,
+    /// Client sequence numbers we never saw
+    lost: u64,
+}
 
-        // if the server recieves a msg from a new client
-        if !self.clients.contains_key(&sender_addr) {
-            // if at max clients, return error
-            if self.clients.len() == MAX_CLIENTS {
-                return Err(ReceiveError::UnknownSender);
-            }
-            // add the new client
-            self.clients
-                .insert(sender_addr, ClientInfo::new(sender_addr));
+/// Running totals of the prediction errors one client reported
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct PredictionErrorStats {
+    reports: u64,
+    total: f64,
+    max: f32,
+}
+
+impl PredictionErrorStats {
+    /// Add one report, clamping it so a bad client can't skew the numbers
+    fn add(&mut self, magnitude: f32) {
+        if magnitude.is_nan() {
+            return;
         }
+        let magnitude = magnitude.clamp(0., MAX_PREDICTION_ERROR);
+        self.reports += 1;
+        self.total += magnitude as f64;
+        self.max = self.max.max(magnitude);
+    }
 
-        // unwrap OK because we just guaranteed the client is in our HashMap
-        Ok((self.clients.get_mut(&sender_addr).unwrap(), message))
+    /// Average error over every report, 0 if there weren't any
+    fn average(&self) -> f64 {
+        if self.reports == 0 {
+            0.
+        } else {
+            self.total / self.reports as f64
+        }
     }
 }
 
-/// Bevy plugin that implements server logic
-pub struct ServerPlugin {
-    pub port: u16,
-    pub save_file: PathBuf,
+/// Running totals of the one-way delays worked out from one client's receive times
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct OneWayDelayStats {
+    reports: u64,
+    total: Duration,
+    max: Duration,
+    /// Reports that said the packet arrived before it was sent, so the clocks disagree
+    skewed: u64,
 }
 
-impl Plugin for ServerPlugin {
-    fn build(&self, app: &mut App) {
-        // add game tick
-        app.add_fixed_timestep(
-            std::time::Duration::from_secs_f64(1. / GAME_TICK_HZ as f64),
-            GAME_TICK_LABEL,
-        );
+impl OneWayDelayStats {
+    /// Add one report of a packet sent at sent_at_micros (our clock) arriving at received_at_micros (the client's)
+    fn add(&mut self, sent_at_micros: u64, received_at_micros: u64) {
+        let delay = match received_at_micros.checked_sub(sent_at_micros) {
+            Some(micros) => Duration::from_micros(micros).min(MAX_ONE_WAY_DELAY),
+            None => {
+                self.skewed += 1;
+                return;
+            }
+        };
+        self.reports += 1;
+        self.total += delay;
+        self.max = self.max.max(delay);
+    }
 
-        // add network tick
-        app.add_fixed_timestep(
-            std::time::Duration::from_secs_f64(1. / NETWORK_TICK_HZ as f64),
-            NETWORK_TICK_LABEL,
-        );
+    /// Average delay over every report, None if there weren't any
+    fn average(&self) -> Option<Duration> {
+        (self.reports > 0).then(|| self.total / self.reports as u32)
+    }
+}
 
-        // enter systems
-        app.add_enter_system(states::server::GameState::Running, create_server);
+/// Running totals of how long one client took to ack packets, every packet and not just pings
+/// only packets still in unacked_sends when their ack shows up count, see RTT_SAMPLE_CAPACITY
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct AckLatencyStats {
+    acks: u64,
+    total: Duration,
+    min: Duration,
+    max: Duration,
+}
 
-        // exit systems
-        app.add_exit_system(states::server::GameState::Running, destroy_server);
+impl AckLatencyStats {
+    fn add(&mut self, latency: Duration) {
+        self.min = if self.acks == 0 {
+            latency
+        } else {
+            self.min.min(latency)
+        };
+        self.acks += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
 
-        // game tick systems
-        app.add_fixed_timestep_system(
-            GAME_TICK_LABEL,
-            0,
-            increase_tick
-                .run_in_state(states::server::GameState::Running)
-                .label("increase_tick"),
-        )
-        .add_fixed_timestep_system(
-            GAME_TICK_LABEL,
-            0,
-            server_handle_messages
-                .run_in_state(states::server::GameState::Running)
-                .after("increase_tick")
-                .label("handle_messages"),
-        );
+    /// Both sets of acks together
+    fn merge(self, other: Self) -> Self {
+        if self.acks == 0 || other.acks == 0 {
+            return if self.acks == 0 { other } else { self };
+        }
+        Self {
+            acks: self.acks + other.acks,
+            total: self.total + other.total,
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
 
-        // network tick systems
-        app.add_fixed_timestep_system(
-            NETWORK_TICK_LABEL,
-            0,
-            enqueue_terrain
-                .run_in_state(states::server::GameState::Running)
-                .label("enqueue_terrain"),
-        )
-        .add_fixed_timestep_system(
-            NETWORK_TICK_LABEL,
-            0,
-            send_all_messages
-                .run_in_state(states::server::GameState::Running)
-                .after("enqueue_terrain")
-                .label("send_messages"),
-        )
-        .add_fixed_timestep_system(
-            NETWORK_TICK_LABEL,
-            0,
-            drop_disconnected_clients
-                .run_in_state(states::server::GameState::Running)
-                .after("send_messages")
-                .label("drop_disconnected"),
-        );
+    /// Average latency over every ack, None if there weren't any
+    fn average(&self) -> Option<Duration> {
+        (self.acks > 0).then(|| self.total / self.acks as u32)
     }
 }
 
-fn create_server(mut commands: Commands) {
-    // TODO: use command line arguments for port and handle failure better
-    let server = match Server::new(DEFAULT_SERVER_PORT) {
-        Ok(s) => s,
-        Err(e) => panic!("Unable to create server: {}", e),
-    };
-
-    commands.insert_resource(server);
+/// How evenly packets go out to one client, and when the next one should to keep them even
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct SendSmoothing {
+    /// Time there should be between packets, the client's send interval at the current network tick rate
+    interval: Duration,
+    /// When the next packet would go out if every one was on time, None before the first
+    next_due: Option<Instant>,
+    /// Moving average of how far the time between packets is from interval
+    jitter: Duration,
+}
 
-    let input_map: HashMap<SocketAddr, PlayerInput> = HashMap::new();
+impl SendSmoothing {
+    /// When a packet ready at now should go out, see ServerConfig::smooth_sends
+    /// early packets wait for their turn, late ones go straight away and the next one's turn comes that much sooner
+    /// a packet a whole interval off starts the schedule over from now
+    fn schedule(&mut self, now: Instant) -> Instant {
+        let due = self
+            .next_due
+            .filter(|due| {
+                due.saturating_duration_since(now) <= self.interval
+                    && now.saturating_duration_since(*due) <= self.interval
+            })
+            .unwrap_or(now);
+        self.next_due = Some(due + self.interval);
+        due.max(now)
+    }
 
-    commands.insert_resource(input_map);
+    /// A packet went out gap after the one before it
+    fn note_gap(&mut self, gap: Duration) {
+        let off = gap.abs_diff(self.interval);
+        self.jitter = (self.jitter * 7 + off) / 8;
+    }
+}
 
-    info!("server created");
+/// What a client is doing, decides how often it gets sent packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientRole {
+    Player,
+    Spectator,
 }
 
-fn destroy_server(mut commands: Commands) {
-    commands.remove_resource::<Server>();
+impl ClientRole {
+    /// How many network ticks between packets for this role
+    fn send_interval_ticks(self) -> u64 {
+        match self {
+            ClientRole::Player => PLAYER_SEND_INTERVAL_TICKS,
+            ClientRole::Spectator => SPECTATOR_SEND_INTERVAL_TICKS,
+        }
+    }
 }
 
-/// Server increase tick count
-fn increase_tick(mut server: ResMut<Server>) {
-    server.sequence += 1;
+/// A reliable body waiting to be acknowledged
+#[derive(Debug)]
+struct PendingReliable {
+    elem: ReliableElem,
+    /// Sequence of the first packet it was sent in, None if it hasn't been sent yet
+    /// every packet from then on also carries it, so any ack at or after this sequence confirms it
+    first_sent: Option<u64>,
 }
 
-/// Server system
-fn server_handle_messages(
-    mut server: ResMut<Server>,
-    mut input_map: ResMut<HashMap<SocketAddr, PlayerInput>>,
-) {
-    loop {
-        // handle all messages on our socket
-        match server.get_one_message() {
-            Ok((client, message)) => {
-                compute_new_bodies(client, message, &mut input_map);
-            }
-            Err(ReceiveError::NoMessage) => {
-                // break whenever we run out of messages
+impl ClientInfo {
+    fn new(addr: SocketAddr, join_number: u64, now: Instant) -> Self {
+        ClientInfo {
+            addr,
+            last_ack: 0,
+            bodies: Vec::with_capacity(DEFAULT_BODIES_VEC_CAPACITY),
+            waiting: 0,
+            until_drop: FRAME_DIFFERENCE_BEFORE_DISCONNECT,
+            state: ConnectionState::Connecting,
+            terrain_scale: 1,
+            protocol_version: MIN_PROTOCOL_VERSION,
+            version_mismatch: None,
+            reliable: Vec::new(),
+            next_reliable_id: 1,
+            connected_at: now,
+            grace_until: now,
+            role: ClientRole::Player,
+            observer: false,
+            send_interval_ticks: ClientRole::Player.send_interval_ticks(),
+            join_number,
+            name: None,
+            reconnect_token: None,
+            player_token: None,
+            subscriptions: SUBSCRIBE_ALL,
+            blocks_mined: 0,
+            recent_sends: VecDeque::new(),
+            terrain_baselines: BTreeSet::new(),
+            returning_baselines: None,
+            requested_chunks: BTreeSet::new(),
+            entity_baseline: None,
+            entity_views: BTreeMap::new(),
+            settings_version: None,
+            welcomed: false,
+            terrain_welcomed: false,
+            admin_commands: Vec::new(),
+            name_claim: None,
+            desync_report: None,
+            desyncs: 0,
+            player_id: None,
+            pending_baseline: None,
+            last_input_time: now,
+            input_history: VecDeque::new(),
+            queued_inputs: VecDeque::new(),
+            tick_direction: None,
+            idle: false,
+            codecs: PayloadCodecs::default(),
+            offered_codecs: None,
+            last_seen_client_seq: None,
+            detected_loss: 0,
+            prediction_error: PredictionErrorStats::default(),
+            one_way_delay: OneWayDelayStats::default(),
+            ack_latency: AckLatencyStats::default(),
+            probes: VecDeque::new(),
+            next_probe_token: 0,
+            last_probe_at: None,
+            mtu: None,
+            mtu_probe: None,
+            session: None,
+            bandwidth: BandwidthSample::default(),
+            unacked_sends: VecDeque::new(),
+            rtt: None,
+            last_sent_at: None,
+            smoothing: SendSmoothing::default(),
+        }
+    }
+
+    /// A new slot for a client that was Connected at this address and started its handshake over
+    /// it starts Reconnecting rather than moving there, since nothing moves from Connecting to Reconnecting
+    fn returning(addr: SocketAddr, join_number: u64, now: Instant) -> Self {
+        ClientInfo {
+            state: ConnectionState::Reconnecting,
+            ..ClientInfo::new(addr, join_number, now)
+        }
+    }
+
+    /// Count a packet of bytes that went out with sequence at now
+    fn note_sent(&mut self, sequence: u64, bytes: usize, now: Instant) {
+        if let Some(last) = self.last_sent_at {
+            self.smoothing.note_gap(now.saturating_duration_since(last));
+        }
+        self.last_sent_at = Some(now);
+        self.bandwidth.bytes_sent += bytes as u64;
+        self.bandwidth.packets_sent += 1;
+        if self.unacked_sends.len() >= RTT_SAMPLE_CAPACITY {
+            self.unacked_sends.pop_front();
+        }
+        self.unacked_sends.push_back((sequence, now));
+    }
+
+    /// Count a packet of bytes that came in
+    fn note_received(&mut self, bytes: usize) {
+        self.bandwidth.bytes_received += bytes as u64;
+        self.bandwidth.packets_received += 1;
+    }
+
+    /// The client acked sequence at now, if we still know when it went out that's our rtt
+    fn note_acked(&mut self, sequence: u64, now: Instant) {
+        while let Some(&(sent_sequence, sent_at)) = self.unacked_sends.front() {
+            if sent_sequence > sequence {
                 break;
             }
-            Err(ReceiveError::UnknownSender) => {
-                warn!("server recieve error: server is full!");
-            }
-            Err(e) => {
-                // anything else is a "real" error that we should complain about
-                error!("server receive error: {:?}", e);
+            if sent_sequence == sequence {
+                let latency = now.saturating_duration_since(sent_at);
+                debug!("{} acked {} after {:.1?}", self.addr, sequence, latency);
+                self.rtt = Some(latency);
+                self.ack_latency.add(latency);
             }
+            self.unacked_sends.pop_front();
         }
     }
-}
+
+    /// Whether it's been interval since the last probe, never if interval is zero
+    fn probe_due(&self, now: Instant, interval: Duration) -> bool {
+        !interval.is_zero()
+            && self
+                .last_probe_at
+                .is_none_or(|at| now.saturating_duration_since(at) >= interval)
+    }
+
+    /// Queue a ping of our own for the client to echo, see note_probe_echo
+    fn enqueue_probe(&mut self, now: Instant) {
+        if self.probes.len() >= RTT_SAMPLE_CAPACITY {
+            self.probes.pop_front();
+        }
+        let token = self.next_probe_token;
+        self.next_probe_token += 1;
+        self.probes.push_back((token, now));
+        self.last_probe_at = Some(now);
+        self.bodies.push(ServerBodyElem::Ping(token));
+    }
+
+    /// The client echoed probe token at now, its round trip counts the same as an ack's
+    fn note_probe_echo(&mut self, token: u64, now: Instant) {
+        let sent_at = match self.probes.iter().position(|(t, _)| *t == token) {
+            Some(index) => self.probes.remove(index).map(|(_, sent_at)| sent_at),
+            None => None,
+        };
+        match sent_at {
+            Some(sent_at) => {
+                let latency = now.saturating_duration_since(sent_at);
+                debug!("{} echoed probe {} after {:.1?}", self.addr, token, latency);
+                self.rtt = Some(latency);
+                self.ack_latency.add(latency);
+            }
+            // a duplicate, or one we gave up on
+            None => debug!(
+                "ignoring echo of unknown probe {} from {}",
+                token, self.addr
+            ),
+        }
+    }
+
+    /// The MtuProbe of size got to the client
+    fn note_mtu_ack(&mut self, size: u16) {
+        match &mut self.mtu_probe {
+            Some(probing) => {
+                probing.largest_acked = probing.largest_acked.max(Some(size as usize));
+            }
+            // a duplicate, or too late to count
+            None => debug!("ignoring mtu ack {} from {}", size, self.addr),
+        }
+    }
+
+    /// Traffic since the last roll, starting the count over
+    fn roll_bandwidth(&mut self) -> BandwidthSample {
+        std::mem::take(&mut self.bandwidth)
+    }
+
+    /// Change the client's role along with how often it gets packets
+    fn set_role(&mut self, role: ClientRole) {
+        self.role = role;
+        self.update_send_interval();
+    }
+
+    /// Work out how often the client gets packets from its role and whether it's idle
+    fn update_send_interval(&mut self) {
+        let interval = self.role.send_interval_ticks();
+        self.send_interval_ticks = if self.idle {
+            interval.max(IDLE_SEND_INTERVAL_TICKS)
+        } else {
+            interval
+        };
+        // acks will come in at the new rate
+        self.reset_drop_timer();
+    }
+
+    /// Slow down packets to the client if it hasn't sent input for idle_after
+    fn check_idle(&mut self, now: Instant, idle_after: Duration) {
+        let idle = now.saturating_duration_since(self.last_input_time) > idle_after;
+        if idle != self.idle {
+            self.idle = idle;
+            self.update_send_interval();
+            if idle {
+                info!("{} is idle, sending less often", self.addr);
+            }
+        }
+    }
+
+    /// Remember an input the server is going to use, forgetting the oldest once there are too many
+    fn record_input(&mut self, sequence: u64, input: &PlayerInput) {
+        if self.input_history.len() == INPUT_HISTORY_LEN {
+            self.input_history.pop_front();
+        }
+        self.input_history.push_back((sequence, input.clone()));
+    }
+
+    /// Queue an input for apply_inputs, in sequence order even if its message came in late
+    fn queue_input(&mut self, sequence: u64, input: PlayerInput) {
+        let at = self
+            .queued_inputs
+            .partition_point(|(queued, _)| *queued <= sequence);
+        self.queued_inputs.insert(at, (sequence, input));
+        if self.queued_inputs.len() > MAX_QUEUED_INPUTS {
+            debug!("{} is sending inputs faster than they're used", self.addr);
+            self.queued_inputs.pop_front();
+        }
+    }
+
+    /// The client did something, back to its full rate if it was idle
+    fn note_input(&mut self, now: Instant) {
+        self.last_input_time = now;
+        if self.idle {
+            self.idle = false;
+            self.update_send_interval();
+            info!("{} is back", self.addr);
+        }
+    }
+
+    /// Whether the client should be sent a packet on this network tick
+    fn is_due(&self, network_tick: u64) -> bool {
+        network_tick.is_multiple_of(self.send_interval_ticks)
+    }
+
+    /// Whether another packet can go to the client at now without going over max_per_second
+    /// if it can, the packet is counted
+    fn take_send_slot(&mut self, now: Instant, max_per_second: u64) -> bool {
+        // forget about sends that are out of the window
+        while let Some(sent) = self.recent_sends.front() {
+            if now.saturating_duration_since(*sent) < Duration::from_secs(1) {
+                break;
+            }
+            self.recent_sends.pop_front();
+        }
+
+        if self.recent_sends.len() as u64 >= max_per_second {
+            return false;
+        }
+        self.recent_sends.push_back(now);
+        true
+    }
+
+    /// Whether there's anything at all to send the client
+    /// an unconfirmed key exchange counts, it gets added on while sending
+    fn has_queued(&self) -> bool {
+        !self.bodies.is_empty()
+            || !self.reliable.is_empty()
+            || self.session.as_ref().is_some_and(|s| !s.confirmed)
+    }
+
+    /// Whether a packet with nothing in it should go to the client on the network tick that started at now
+    /// not if it already got something this tick (e.g. a late paced message), otherwise once every interval
+    fn keepalive_due(&self, now: Instant, interval: Duration) -> bool {
+        match self.last_sent_at {
+            Some(sent) if sent >= now => false,
+            Some(sent) => now.saturating_duration_since(sent) >= interval,
+            None => true,
+        }
+    }
+
+    /// Move the client to state next, leaving it where it was if it can't get there from here
+    fn transition(&mut self, next: ConnectionState) -> Result<(), InvalidTransition> {
+        if !self.state.can_become(next) {
+            return Err(InvalidTransition {
+                from: self.state,
+                to: next,
+            });
+        }
+        debug!("{} is now {:?}", self.addr, next);
+        self.state = next;
+        Ok(())
+    }
+
+    /// Give the client its full time before being dropped
+    /// slower clients ack less often, so they get longer
+    fn reset_drop_timer(&mut self) {
+        self.until_drop = FRAME_DIFFERENCE_BEFORE_DISCONNECT * self.send_interval_ticks;
+    }
+
+    /// Queue the whole terrain reliably, it becomes the client's only baseline
+    fn enqueue_baseline(&mut self, body: ServerBodyElem, version: u64) {
+        let id = self.enqueue_reliable(body);
+        self.pending_baseline = Some((id, version));
+        self.terrain_baselines.clear();
+        self.terrain_baselines.insert(version);
+    }
+
+    /// Queue the chunks changed since the client's oldest baseline, replacing any changes still waiting to go out
+    /// those were since the same baseline or an older one, so the new ones have everything they did
+    /// changed_bounds go right before the chunks, for clients that can decode them
+    fn enqueue_terrain_delta(
+        &mut self,
+        chunks: Vec<Chunk>,
+        changed_bounds: Vec<BlockBounds>,
+        version: u64,
+    ) {
+        if let Some(i) = self
+            .bodies
+            .iter()
+            .position(|b| matches!(b, ServerBodyElem::TerrainVersion(_)))
+        {
+            // always queued right after its chunks
+            debug_assert!(i > 0 && matches!(self.bodies[i - 1], ServerBodyElem::TerrainChunks(_)));
+            let start = match i.checked_sub(2).map(|j| &self.bodies[j]) {
+                Some(ServerBodyElem::ChangedBounds(_)) => i - 2,
+                _ => i - 1,
+            };
+            debug!(
+                "{} hadn't been sent its terrain changes yet, replacing them",
+                self.addr
+            );
+            self.bodies.drain(start..=i);
+        }
+        if self.protocol_version >= CHANGED_BOUNDS_PROTOCOL_VERSION {
+            self.bodies
+                .push(ServerBodyElem::ChangedBounds(changed_bounds));
+        }
+        self.bodies.push(ServerBodyElem::TerrainChunks(chunks));
+        self.bodies.push(ServerBodyElem::TerrainVersion(version));
+    }
+
+    /// Queue everything a client needs on connect but the terrain reliably, always in this order:
+    /// the game settings, who is connected and then the welcome message if there is one
+    /// the terrain follows from queue_terrain once there is one
+    fn enqueue_welcome(
+        &mut self,
+        settings: &GameSettings,
+        players: &[PlayerListEntry],
+        message: &str,
+    ) {
+        self.enqueue_reliable(ServerBodyElem::GameSettings(settings.clone()));
+        self.settings_version = Some(settings.version);
+        self.enqueue_reliable(ServerBodyElem::PlayerList(players.to_vec()));
+        if !message.is_empty() {
+            self.enqueue_reliable(ServerBodyElem::Announcement(message.to_string()));
+        }
+        self.welcomed = true;
+    }
+
+    /// Pick the codec for each payload out of the ones the client offered, preferred is what the server would like to use
+    fn negotiate_codecs(&mut self, preferred: PayloadCodecs) {
+        let offered = match self.offered_codecs.take() {
+            Some(offered) => offered,
+            None => return,
+        };
+        let codecs = preferred.negotiate(&offered);
+        if codecs != self.codecs {
+            info!(
+                "compressing terrain for {} with {:?} and entities with {:?}",
+                self.addr, codecs.terrain, codecs.entities
+            );
+            self.codecs = codecs;
+        }
+    }
+
+    /// Whether the client has acked terrain version and has nothing else of the terrain on its way
+    /// only then should its world match ours
+    /// a client with less than the full resolution never matches
+    fn terrain_synced(&self, version: u64) -> bool {
+        self.terrain_scale == 1
+            && self.pending_baseline.is_none()
+            && self.requested_chunks.is_empty()
+            && self.terrain_baselines.iter().eq([&version])
+    }
+
+    /// Send terrain at 1/scale the resolution from now on
+    /// whatever the client already has is at the old one, so it gets a new baseline to apply deltas to
+    fn set_terrain_scale(&mut self, scale: u8) {
+        if !valid_terrain_scale(scale) {
+            warn!(
+                "{} asked for terrain at an invalid scale {}",
+                self.addr, scale
+            );
+            return;
+        }
+        if scale == self.terrain_scale {
+            return;
+        }
+        info!("sending terrain to {} at 1/{} resolution", self.addr, scale);
+        self.terrain_scale = scale;
+        // enqueue_terrain sends a fresh baseline to anyone without one
+        self.terrain_baselines.clear();
+        self.pending_baseline = None;
+    }
+
+    /// Client has terrain up to version, so older baselines can go
+    /// versions we don't know about are from before a map change and get ignored
+    fn ack_terrain(&mut self, version: u64) {
+        // connected again still having its terrain, queue_terrain decides whether changes since are worth it
+        if self.terrain_baselines.is_empty() {
+            if let Some((baselines, scale)) = self.returning_baselines.take() {
+                if scale == self.terrain_scale && baselines.contains(&version) {
+                    self.terrain_baselines.insert(version);
+                }
+            }
+            return;
+        }
+        if self.terrain_baselines.contains(&version) {
+            self.terrain_baselines.retain(|v| *v >= version);
+        }
+    }
+
+    /// How long the client has been connected for
+    fn session_duration(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.connected_at)
+    }
+
+    /// Queue a body that will be resent every packet until the client acknowledges it
+    /// returns its id, see ReliableDelivered
+    fn enqueue_reliable(&mut self, body: ServerBodyElem) -> u64 {
+        let id = self.next_reliable_id;
+        self.reliable.push(PendingReliable {
+            elem: ReliableElem {
+                id: self.next_reliable_id,
+                body,
+            },
+            first_sent: None,
+        });
+        self.next_reliable_id += 1;
+        id
+    }
+}
+
+impl Server {
+    /// Where the world gets saved, None if it isn't, see ServerConfig::persist
+    pub fn save_file(&self) -> Option<&Path> {
+        self.config
+            .persist
+            .then_some(self.config.save_file.as_path())
+    }
+
+    /// Where the reconnect tokens go for the server to find when it comes back, next to the save file
+    fn restart_file(&self) -> Option<PathBuf> {
+        let mut path = self.save_file()?.as_os_str().to_owned();
+        path.push(".restart");
+        Some(PathBuf::from(path))
+    }
+
+    /// Hand every client a reconnect token along with how long to wait, and write them down for after the restart
+    /// positions are where every entity is, their player's gets restored too, returns how many tokens went out
+    fn prepare_restart(
+        &mut self,
+        after_seconds: u64,
+        positions: &[EntityState],
+    ) -> Result<usize, RestartError> {
+        let path = self.restart_file().ok_or(RestartError::NoSaveFile)?;
+        let mut tickets = Vec::with_capacity(self.clients.len());
+        for client in self.clients.values_mut() {
+            let ticket = RestartTicket {
+                token: rand::random(),
+                name: client.name.clone(),
+                player_id: client.player_id,
+                position: positions
+                    .iter()
+                    .find(|state| Some(state.id) == client.player_id)
+                    .map(|state| (state.x, state.y)),
+            };
+            // older clients only get the shutdown message
+            if client.protocol_version >= RESTART_PROTOCOL_VERSION {
+                client.enqueue_reliable(ServerBodyElem::Restarting {
+                    reconnect_token: ticket.token,
+                    after_seconds,
+                });
+            }
+            tickets.push(ticket);
+        }
+        let encoded =
+            bincode::encode_to_vec(&tickets, BINCODE_CONFIG).map_err(RestartError::EncodeError)?;
+        std::fs::write(path, encoded).map_err(RestartError::IoError)?;
+        Ok(tickets.len())
+    }
+
+    /// Pick up the tokens prepare_restart wrote down, if the server is coming back from a restart
+    /// the file is removed so they only get picked up once, returns whether there was one
+    fn resume_restart(&mut self, now: Instant) -> bool {
+        let path = match self.restart_file().filter(|path| path.exists()) {
+            Some(path) => path,
+            None => return false,
+        };
+        let decoded = std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|encoded| {
+                bincode::decode_from_slice::<Vec<RestartTicket>, _>(&encoded, BINCODE_CONFIG)
+                    .map_err(|e| e.to_string())
+            });
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("unable to remove {}: {}", path.display(), e);
+        }
+        match decoded {
+            Ok((tickets, _)) => {
+                info!(
+                    "back from a restart, {} client(s) can reconnect",
+                    tickets.len()
+                );
+                // players that come back get their old ids, nobody new can have them in the meantime
+                let reserved = tickets.iter().filter_map(|t| t.player_id).map(|id| id + 1);
+                self.next_player_id = reserved.fold(self.next_player_id, u64::max);
+                self.restart_tickets = tickets.into_iter().map(|t| (t.token, t)).collect();
+                self.restart_tickets_until = Some(now + RESTART_TICKET_LIFETIME);
+                true
+            }
+            Err(e) => {
+                error!("unable to read {}: {}", path.display(), e);
+                false
+            }
+        }
+    }
+
+    /// Binds the socket to the configured address
+    fn new(config: ServerConfig) -> Result<Self, std::io::Error> {
+        let transport = Self::open_transport(&config)?;
+        Ok(Self::with_transport(transport, config))
+    }
+
+    /// Bind the socket at config's bind_addr, on its interface if it has one
+    fn open_transport(config: &ServerConfig) -> std::io::Result<Box<dyn Transport>> {
+        let socket = config.open_socket()?;
+        // the socket is drained on its own thread, so a slow tick doesn't overflow its buffer
+        let transport = ThreadedTransport::spawn(
+            Box::new(socket),
+            config.receive_queue_capacity,
+            config.buffer_size,
+        )?;
+        Ok(Box::new(transport))
+    }
+
+    /// Count an error from the socket, transient ones don't say anything about the socket
+    fn note_socket_error(&mut self, error: &std::io::Error) {
+        if !is_transient(error) {
+            self.socket_errors += 1;
+        }
+    }
+
+    /// The time according to the server's clock, use this rather than Instant::now so tests can fake it
+    fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Too many socket errors in a row, the socket itself is broken, see check_socket
+    fn socket_down(&self) -> bool {
+        self.socket_errors >= SOCKET_ERRORS_BEFORE_REBIND
+    }
+
+    /// Creates a server on top of an already set up transport
+    pub(super) fn with_transport(transport: Box<dyn Transport>, config: ServerConfig) -> Self {
+        let clock = SystemClock;
+        Server {
+            transport,
+            middleware: Middleware::default(),
+            clock: Box::new(clock),
+            clients: HashMap::with_capacity(
+                config.max_clients + config.max_observers + EXTRA_CLIENTS_CAPACITY,
+            ),
+            sequence: 1u64,
+            buffer: vec![0u8; config.buffer_size],
+            metrics: ServerMetrics {
+                network_tick_hz: config.network_tick_hz,
+                ..ServerMetrics::default()
+            },
+            terrain_tick_counter: 0,
+            network_tick: 0,
+            paced: VecDeque::new(),
+            paced_per_step: 1,
+            smoothed: Vec::new(),
+            last_network_tick_at: None,
+            bandwidth_second_start: clock.now(),
+            next_join_number: 0,
+            next_player_id: 0,
+            recorder: None,
+            metrics_csv: None,
+            replay_ring: (!config.replay_window.is_zero())
+                .then(|| ReplayRing::new(config.replay_window, REPLAY_RING_CAPACITY)),
+            dirty_chunks: BTreeMap::new(),
+            terrain_version: 0,
+            last_good_terrain: None,
+            last_good_terrain_size: None,
+            world_checksum: None,
+            chunk_versions: BTreeMap::new(),
+            chunk_bounds: BTreeMap::new(),
+            bans: HashMap::new(),
+            reminded_bans: HashSet::new(),
+            reconnects: HashMap::new(),
+            latest_snapshot: None,
+            snapshot_history: SnapshotHistory::new(config.snapshot_history),
+            next_snapshot_id: 1,
+            settings: GameSettings::default(),
+            host: None,
+            mutations: Vec::new(),
+            socket_errors: 0,
+            rebind_attempts: 0,
+            next_rebind_at: None,
+            socket_failed: false,
+            restart_tickets: HashMap::new(),
+            restart_tickets_until: None,
+            config,
+        }
+    }
+
+    /// Send message to a specific client
+    fn send_message(
+        &mut self,
+        client_addr: SocketAddr,
+        mut message: ServerToClient,
+    ) -> Result<(), SendError> {
+        let sequence = message.header.sequence;
+        match self.clients.get_mut(&client_addr) {
+            Some(client) => {
+                // stamped here rather than when built, paced messages can sit around for a while
+                message.header.sent_at_micros = unix_micros();
+                let bytes = match send_message(self.transport.as_ref(), client_addr, message) {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        if let SendError::IoError(error) = &e {
+                            if !is_transient(error) {
+                                self.socket_errors += 1;
+                            }
+                        }
+                        return Err(e);
+                    }
+                };
+                self.socket_errors = 0;
+                client.note_sent(sequence, bytes, self.clock.now());
+                self.metrics.bytes_this_second += bytes as u64;
+                self.metrics.traffic.bytes_sent += bytes as u64;
+                self.metrics.traffic.packets_sent += 1;
+                Ok(())
+            }
+            None => Err(SendError::NoSuchPeer),
+        }
+    }
+
+    /// Send message to addr whether or not it's a client, e.g. turning away a sender that never got a slot
+    /// nothing is tracked for it, use send_message for connected clients
+    /// returns how many bytes were sent
+    fn send_to_addr(
+        &self,
+        addr: SocketAddr,
+        mut message: ServerToClient,
+    ) -> Result<usize, SendError> {
+        message.header.sent_at_micros = unix_micros();
+        send_message(self.transport.as_ref(), addr, message)
+    }
+
+    /// Write the packet sitting in our buffer to the replay log and the replay ring
+    fn record(&mut self, from: SocketAddr, size: usize) {
+        if self.recorder.is_none() && self.replay_ring.is_none() {
+            return;
+        }
+        let frame = ReplayFrame {
+            sequence: self.sequence,
+            from,
+            data: self.buffer[..size].to_vec(),
+        };
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.write_frame(&frame) {
+                // stop instead of spamming errors every packet
+                error!("{}, recording stopped", e);
+                self.recorder = None;
+            }
+        }
+        if let Some(ring) = &mut self.replay_ring {
+            ring.push(self.clock.now(), frame);
+        }
+    }
+
+    /// Write the packets in the replay ring out to a replay log at path
+    fn dump_replay(&self, path: &Path) -> Result<usize, ReplayError> {
+        match &self.replay_ring {
+            Some(ring) => ring.dump_replay(path),
+            None => Err(ReplayError::Io(std::io::Error::other(
+                "replay ring is turned off",
+            ))),
+        }
+    }
+
+    /// Write the inputs recently taken from the client at addr to a text file at path, one per line, oldest first
+    /// returns how many were written
+    fn dump_input_history(&self, addr: SocketAddr, path: &Path) -> std::io::Result<usize> {
+        let client = self.clients.get(&addr).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no client at {}", addr),
+            )
+        })?;
+        let mut text = format!(
+            "# inputs from {}\n# sequence left right jump mine block_x block_y\n",
+            addr
+        );
+        for (sequence, input) in &client.input_history {
+            text += &format!(
+                "{} {} {} {} {} {} {}\n",
+                sequence,
+                input.left as u8,
+                input.right as u8,
+                input.jump as u8,
+                input.mine as u8,
+                input.block_x,
+                input.block_y
+            );
+        }
+        std::fs::write(path, text)?;
+        Ok(client.input_history.len())
+    }
+
+    /// Complain if the network tick at now came too long after the previous one
+    fn check_tick_watchdog(&mut self, now: Instant) {
+        if let Some(last) = self.last_network_tick_at {
+            let gap = now.saturating_duration_since(last);
+            if gap > self.config.tick_watchdog_threshold {
+                warn!(
+                    "network tick late: {:.1?} since the last one, expected at most {:.1?}",
+                    gap, self.config.tick_watchdog_threshold
+                );
+                self.metrics.missed_ticks += 1;
+            }
+        }
+        self.last_network_tick_at = Some(now);
+    }
+
+    /// How long the systems of the tick called label may take before they hold up the next one
+    fn tick_budget(&self, label: &str) -> Duration {
+        let hz = match label {
+            NETWORK_TICK_LABEL => self.metrics.network_tick_hz,
+            _ => self.config.game_tick_hz,
+        };
+        Duration::from_secs_f64(1. / hz as f64)
+    }
+
+    /// Send the messages held back by smooth_sends whose turn has come by now
+    fn send_smoothed(&mut self, now: Instant) {
+        if self.smoothed.is_empty() {
+            return;
+        }
+        let (due, waiting) = std::mem::take(&mut self.smoothed)
+            .into_iter()
+            .partition(|(at, _, _)| *at <= now);
+        self.smoothed = waiting;
+        let due: Vec<_> = due;
+        self.send_burst(due.into_iter().map(|(_, addr, message)| (addr, message)));
+    }
+
+    /// Send a batch of messages right away, keeping track of how bursty sends are
+    fn send_burst(&mut self, messages: impl IntoIterator<Item = (SocketAddr, ServerToClient)>) {
+        let mut burst = 0;
+        for (client_addr, message) in messages {
+            match self.send_message(client_addr, message) {
+                Ok(_) => info!("server sent message to {:?}", client_addr),
+                Err(e) => error!("server unable to send message: {:?}", e),
+            }
+            burst += 1;
+        }
+        self.metrics.largest_send_burst = self.metrics.largest_send_burst.max(burst);
+    }
+
+    /// Tell whoever is at addr that we won't talk to them anymore
+    /// also used for senders that never got a slot, so doesn't need addr to be a client
+    fn send_disconnect(&self, addr: SocketAddr, reason: DisconnectReason) {
+        self.send_single_body(addr, ServerBodyElem::Disconnect(reason));
+    }
+
+    /// Send one body straight away, for addresses that aren't (or are no longer) clients
+    fn send_single_body(&self, addr: SocketAddr, body: ServerBodyElem) {
+        let kind = body.kind();
+        let message = self.single_body_message(body);
+        if !self.middleware.allow_outbound(addr, &message) {
+            debug!("middleware vetoed {} to {}", kind, addr);
+            return;
+        }
+        if let Err(e) = self.send_to_addr(addr, message) {
+            error!("server unable to send {} to {}: {:?}", kind, addr, e);
+        }
+    }
+
+    /// A message with only body in it, see send_single_body
+    fn single_body_message(&self, body: ServerBodyElem) -> ServerToClient {
+        ServerToClient {
+            header: ServerHeader {
+                sequence: self.sequence,
+                snapshot_tick: self.sequence,
+                game_tick_hz: self.config.game_tick_hz,
+                send_interval_micros: 0,
+                sent_at_micros: 0,
+                world_checksum: None,
+            },
+            bodies: vec![body],
+            reliable: Vec::new(),
+        }
+    }
+
+    /// Probe clients that can answer and haven't been yet with one datagram of every MTU_PROBE_SIZES,
+    /// and settle on the biggest that came back for clients whose probes have had MTU_PROBE_TIMEOUT
+    fn probe_mtus(&mut self, now: Instant) {
+        let mut to_probe = Vec::new();
+        for client in self.clients.values_mut() {
+            match client.mtu_probe {
+                None if client.mtu.is_none()
+                    && client.protocol_version >= MTU_PROBE_PROTOCOL_VERSION =>
+                {
+                    client.mtu_probe = Some(MtuProbing {
+                        sent_at: now,
+                        largest_acked: None,
+                    });
+                    to_probe.push(client.addr);
+                }
+                Some(probing)
+                    if now.saturating_duration_since(probing.sent_at) >= MTU_PROBE_TIMEOUT =>
+                {
+                    // nothing came back, so nothing was learned
+                    let mtu = probing.largest_acked.unwrap_or(FALLBACK_MTU);
+                    info!("packets to {} kept under {} bytes", client.addr, mtu);
+                    client.mtu = Some(mtu);
+                    client.mtu_probe = None;
+                }
+                _ => {}
+            }
+        }
+        for addr in to_probe {
+            for size in MTU_PROBE_SIZES {
+                let mut empty = self.single_body_message(ServerBodyElem::MtuProbe {
+                    size,
+                    padding: Vec::new(),
+                });
+                // as big as the send time can get, it's filled in as it goes out
+                empty.header.sent_at_micros = u64::MAX;
+                let empty = encoded_len(&empty);
+                // the padding's length takes 2 more bytes once it's past 250
+                let padding = vec![0; (size as usize).saturating_sub(empty + 2)];
+                self.send_single_body(addr, ServerBodyElem::MtuProbe { size, padding });
+            }
+        }
+    }
+
+    /// Remove a client, letting it know why
+    /// returns None if there was no such client
+    fn disconnect(
+        &mut self,
+        addr: SocketAddr,
+        reason: DisconnectReason,
+    ) -> Option<ClientDisconnected> {
+        let client = self.clients.remove(&addr)?;
+        self.send_disconnect(addr, reason.for_version(client.protocol_version));
+        self.pick_host();
+        Some(ClientDisconnected {
+            addr,
+            session_duration: client.session_duration(self.now()),
+            reason,
+            player_id: client.player_id,
+        })
+    }
+
+    /// Change the clients map at the next apply_client_mutations, after sending and dropping clients
+    /// so systems going over the clients never see it change partway through a network tick
+    /// a client that is queued for removal is Disconnecting right away, see inputs_while_dropping
+    fn queue_mutation(&mut self, mutation: ClientMutation) {
+        if let ClientMutation::Remove(addr, _) = &mutation {
+            let keep_inputs = self.config.inputs_while_dropping;
+            if let Some(client) = self.clients.get_mut(addr) {
+                if let Err(e) = client.transition(ConnectionState::Disconnecting { keep_inputs }) {
+                    // already on its way out, e.g. kicked twice in one tick
+                    debug!("{}: {}", addr, e);
+                }
+            }
+        }
+        self.mutations.push(mutation);
+    }
+
+    /// Queue removing the client at addr, forgetting its latest input too unless inputs_while_dropping
+    /// or apply_inputs would keep applying it every game tick until the client is gone
+    fn queue_removal(
+        &mut self,
+        addr: SocketAddr,
+        reason: DisconnectReason,
+        input_map: &mut HashMap<SocketAddr, PlayerInput>,
+    ) {
+        if !self.config.inputs_while_dropping {
+            input_map.remove(&addr);
+        }
+        self.queue_mutation(ClientMutation::Remove(addr, reason));
+    }
+
+    /// Make one change to the clients map right away, returns the disconnect if a client was removed
+    /// mutations for clients that are already gone do nothing
+    fn apply_mutation(&mut self, mutation: ClientMutation) -> Option<ClientDisconnected> {
+        match mutation {
+            ClientMutation::Add(client) => {
+                if let Some(old) = self.clients.insert(client.addr, *client) {
+                    warn!("replaced client #{} at {}", old.join_number, old.addr);
+                }
+                self.pick_host();
+                None
+            }
+            ClientMutation::Remove(addr, reason) => self.disconnect(addr, reason),
+            ClientMutation::UpdateName(addr, name) => {
+                self.rename(addr, name);
+                None
+            }
+            ClientMutation::ClaimName(addr, name) => self.claim_name(addr, name),
+        }
+    }
+
+    fn rename(&mut self, addr: SocketAddr, name: String) {
+        match self.clients.get_mut(&addr) {
+            Some(client) => {
+                info!("{} is now called {}", addr, name);
+                client.name = Some(name);
+            }
+            None => debug!("not renaming {}, it's gone", addr),
+        }
+    }
+
+    /// Whether a client other than addr goes by name, returns its address
+    fn name_holder(&self, name: &str, addr: SocketAddr) -> Option<SocketAddr> {
+        self.clients
+            .values()
+            .find(|client| client.addr != addr && client.name.as_deref() == Some(name))
+            .map(|client| client.addr)
+    }
+
+    /// Name with the lowest number after it that no client other than addr has, e.g. "miner (2)"
+    fn suffixed_name(&self, name: &str, addr: SocketAddr) -> String {
+        (2..)
+            .map(|n| format!("{} ({})", name, n))
+            .find(|suffixed| self.name_holder(suffixed, addr).is_none())
+            .expect("some number isn't taken")
+    }
+
+    /// Give the client at addr the name it asked for, ServerConfig::name_collision says what happens
+    /// if another client already has it, returns the disconnect if a client had to go
+    /// a client with the holder's reconnect token is the same player, and only it can take the name over
+    fn claim_name(&mut self, addr: SocketAddr, name: String) -> Option<ClientDisconnected> {
+        if !self.clients.contains_key(&addr) {
+            debug!("not naming {}, it's gone", addr);
+            return None;
+        }
+        let holder = match self.name_holder(&name, addr) {
+            Some(holder) => holder,
+            None => {
+                self.rename(addr, name);
+                return None;
+            }
+        };
+        let same_player = self.clients[&holder]
+            .player_token
+            .is_some_and(|token| self.clients[&addr].player_token == Some(token));
+        match self.config.name_collision {
+            NameCollision::Reject => {
+                warn!("{} asked to be {}, which {} already is", addr, name, holder);
+                self.disconnect(addr, DisconnectReason::NameTaken)
+            }
+            // the one still holding it is most likely on its way out, it can be the one with the number
+            NameCollision::Suffix if same_player => {
+                info!("{} is {} connecting from {}", addr, name, holder);
+                let suffixed = self.suffixed_name(&name, holder);
+                self.rename(holder, suffixed);
+                self.rename(addr, name);
+                None
+            }
+            NameCollision::Kick if same_player => {
+                info!("{} asked to be {}, disconnecting {}", addr, name, holder);
+                let disconnect = self.disconnect(holder, DisconnectReason::Replaced);
+                self.rename(addr, name);
+                disconnect
+            }
+            // anyone could ask for someone else's name
+            NameCollision::Suffix | NameCollision::Kick => {
+                let name = self.suffixed_name(&name, addr);
+                self.rename(addr, name);
+                None
+            }
+        }
+    }
+
+    /// Hand host authority to the longest connected client if the host is gone (or there never was one)
+    fn pick_host(&mut self) {
+        if !self.config.host_authority || self.host.is_some_and(|h| self.clients.contains_key(&h)) {
+            return;
+        }
+        self.host = self
+            .clients
+            .values()
+            .min_by_key(|client| client.join_number)
+            .map(|client| client.addr);
+        if let Some(host) = self.host {
+            info!("{} is now the host", host);
+        }
+    }
+
+    /// Turn an admin command line sent by the client at addr into a command, if it's allowed to run one
+    fn client_admin_command(&self, addr: SocketAddr, line: &str) -> Result<AdminCommand, String> {
+        if !self.config.host_authority {
+            return Err("clients can't run admin commands on this server".to_string());
+        }
+        if self.host != Some(addr) {
+            return Err("only the host can run admin commands".to_string());
+        }
+        parse_client_admin_command(line)
+    }
+
+    /// Refuse addr for the kick ban window
+    fn ban(&mut self, addr: SocketAddr, now: Instant) {
+        if !self.config.kick_ban.is_zero() {
+            self.bans.insert(addr, now + self.config.kick_ban);
+            self.reminded_bans.remove(&addr);
+        }
+    }
+
+    /// Whether addr was kicked recently enough to still be refused
+    fn is_banned(&self, addr: SocketAddr, now: Instant) -> bool {
+        self.bans.get(&addr).is_some_and(|until| *until > now)
+    }
+
+    /// Game ticks that go by between network ticks at the current network tick rate
+    fn game_ticks_per_network_tick(&self) -> u64 {
+        self.config
+            .game_ticks_per_network_tick(self.metrics.network_tick_hz)
+    }
+
+    /// Forget bans that have run out, and reconnects too old to count any more
+    fn prune_bans(&mut self, now: Instant) {
+        self.bans.retain(|_, until| *until > now);
+        let bans = &self.bans;
+        self.reminded_bans.retain(|addr| bans.contains_key(addr));
+        if self.restart_tickets_until.is_some_and(|until| now >= until) {
+            self.restart_tickets.clear();
+            self.restart_tickets_until = None;
+        }
+        let window = self.config.reconnect_window;
+        self.reconnects.retain(|_, times| {
+            forget_before(times, now, window);
+            !times.is_empty()
+        });
+    }
+
+    /// Give back memory the maps keyed by address grew into and no longer need, e.g. after a full match empties out
+    /// the clients map keeps the room it reserved up front, so it still never rehashes mid-game
+    /// returns how many entries' worth of room was given back
+    fn compact(&mut self, input_map: &mut HashMap<SocketAddr, PlayerInput>) -> usize {
+        let reserved = self.config.max_clients + self.config.max_observers + EXTRA_CLIENTS_CAPACITY;
+        let capacity = |server: &Self, input_map: &HashMap<SocketAddr, PlayerInput>| {
+            server.clients.capacity()
+                + server.bans.capacity()
+                + server.reconnects.capacity()
+                + input_map.capacity()
+        };
+        let before = capacity(self, input_map);
+        shrink_if_sparse(&mut self.clients, reserved);
+        shrink_if_sparse(input_map, reserved);
+        shrink_if_sparse(&mut self.bans, 0);
+        shrink_if_sparse(&mut self.reconnects, 0);
+        before - capacity(self, input_map)
+    }
+
+    /// Count a reconnect from addr at now, false (and not counted) if it has used up max_reconnects
+    fn allow_reconnect(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        let times = self.reconnects.entry(addr).or_default();
+        forget_before(times, now, self.config.reconnect_window);
+        if self
+            .config
+            .max_reconnects
+            .is_some_and(|max| times.len() >= max as usize)
+        {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+
+    /// Non-blocking way to get one message from the socket, from whichever client sent it
+    fn get_one_message(&mut self) -> Result<(&mut ClientInfo, ClientToServer), ReceiveError> {
+        // read from socket
+        let (size, sender_addr) = match self.transport.recv_from(&mut self.buffer) {
+            Ok(received) => received,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                return Err(ReceiveError::NoMessage)
+            }
+            Err(e) => {
+                self.note_socket_error(&e);
+                return Err(ReceiveError::IoError(e));
+            }
+        };
+        self.socket_errors = 0;
+        self.accept_datagram(size, sender_addr)
+    }
+
+    /// Handle bytes as if they had just come in from sender_addr, without a socket, for fuzzing
+    /// goes through all the same checks as a real datagram, one longer than the buffer is cut short like UDP would
+    /// returns the ids of the reliable bodies the message acked
+    pub(super) fn inject_datagram(
+        &mut self,
+        sender_addr: SocketAddr,
+        bytes: &[u8],
+    ) -> Result<Vec<u64>, ReceiveError> {
+        let size = bytes.len().min(self.buffer.len());
+        self.buffer[..size].copy_from_slice(&bytes[..size]);
+        let sequence = self.sequence;
+        let now = self.now();
+        let (client, message) = self.accept_datagram(size, sender_addr)?;
+        Ok(compute_new_bodies(
+            client,
+            message,
+            sequence,
+            &mut HashMap::new(),
+            now,
+        ))
+    }
+
+    /// Everything get_one_message does with a datagram once it's in the buffer
+    fn accept_datagram(
+        &mut self,
+        size: usize,
+        sender_addr: SocketAddr,
+    ) -> Result<(&mut ClientInfo, ClientToServer), ReceiveError> {
+        self.metrics.datagrams_received += 1;
+
+        // port scanners like these, they aren't worth recording or decoding
+        if size == 0 {
+            self.metrics.empty_datagrams += 1;
+            return Err(ReceiveError::EmptyDatagram);
+        }
+
+        // no real client sends this much, so bincode never gets to see it
+        if size > self.config.max_message_size {
+            self.metrics.oversized_messages += 1;
+            return Err(ReceiveError::Oversized {
+                from: sender_addr,
+                size,
+            });
+        }
+        self.record(sender_addr, size);
+
+        // dev servers shouldn't talk to anyone outside this machine
+        if self.config.localhost_only && !sender_addr.ip().is_loopback() {
+            return Err(ReceiveError::AddressNotAllowed);
+        }
+
+        // remind kicked clients why they can't come back yet, once
+        if self.is_banned(sender_addr, self.now()) {
+            if self.reminded_bans.insert(sender_addr) {
+                self.send_disconnect(sender_addr, DisconnectReason::Kicked);
+            }
+            return Err(ReceiveError::Banned);
+        }
+
+        // decode
+        let message: ClientToServer = match decode_into_owned(&self.buffer[..size]) {
+            Ok(message) => message,
+            Err(e) => {
+                self.metrics.decode_errors += 1;
+                return Err(ReceiveError::DecodeError(e));
+            }
+        };
+
+        // tiny bodies can pack a lot into one packet, don't let them make us loop forever
+        if message.bodies.len() > self.config.max_bodies_per_message {
+            self.metrics.too_many_bodies += 1;
+            return Err(ReceiveError::TooManyBodies(message.bodies.len()));
+        }
+
+        // observers say so in their first packets
+        let observer = message
+            .bodies
+            .iter()
+            .any(|b| matches!(b, ClientBodyElem::Observe));
+
+        // a client that restarted on the same port starts its handshake over, as if it never heard from us
+        if let Some(existing) = self.clients.get(&sender_addr) {
+            if existing.state == ConnectionState::Connected && is_fresh_connect(&message) {
+                let policy = self.config.duplicate_connect;
+                // a baseline still on its way might never have got there
+                let baselines = existing
+                    .pending_baseline
+                    .is_none()
+                    .then(|| (existing.terrain_baselines.clone(), existing.terrain_scale))
+                    .filter(|(baselines, _)| !baselines.is_empty());
+                // it's the same player, only the connection starts over
+                let player = (
+                    existing.join_number,
+                    existing.name.clone(),
+                    existing.player_id,
+                    existing.role,
+                );
+                match policy {
+                    DuplicateConnect::Reconnect
+                        if !self.allow_reconnect(sender_addr, self.now()) =>
+                    {
+                        warn!(
+                            "{} reconnected too often, ignoring it until the old client times out",
+                            sender_addr
+                        );
+                        return Err(ReceiveError::DuplicateConnect);
+                    }
+                    DuplicateConnect::Reconnect => {
+                        info!("{} connected again, starting it over", sender_addr);
+                        let client = self.admit_client(sender_addr, observer, true);
+                        (
+                            client.join_number,
+                            client.name,
+                            client.player_id,
+                            client.role,
+                        ) = player;
+                        client.returning_baselines = baselines;
+                    }
+                    DuplicateConnect::Reject => {
+                        warn!(
+                            "{} connected again, ignoring it until the old client times out",
+                            sender_addr
+                        );
+                        return Err(ReceiveError::DuplicateConnect);
+                    }
+                }
+            }
+        }
+
+        // if the server recieves a msg from a new client
+        if !self.clients.contains_key(&sender_addr) {
+            // observers have their own limit
+            let observers = self.clients.values().filter(|c| c.observer).count();
+            let full = if observer {
+                observers >= self.config.max_observers
+            } else {
+                self.clients.len() - observers >= self.config.max_clients
+            };
+            // if at max clients, point them at another server if there is one, else return error
+            if full {
+                if let Some(addr) = self.config.redirect_when_full {
+                    self.send_single_body(sender_addr, ServerBodyElem::Redirect { addr });
+                    return Err(ReceiveError::Redirected);
+                }
+                self.send_disconnect(sender_addr, DisconnectReason::ServerFull);
+                return Err(ReceiveError::UnknownSender);
+            }
+            if observer {
+                info!("{} is joining as an observer", sender_addr);
+            }
+            self.admit_client(sender_addr, observer, false);
+        }
+
+        // whether or not there's terrain to send it yet
+        if !self.clients[&sender_addr].welcomed {
+            let players = self.player_list();
+            let Server {
+                clients,
+                settings,
+                config,
+                ..
+            } = &mut *self;
+            clients.get_mut(&sender_addr).unwrap().enqueue_welcome(
+                settings,
+                &players,
+                &config.welcome_message,
+            );
+        }
+
+        // unwrap OK because we just guaranteed the client is in our HashMap
+        let client = self.clients.get_mut(&sender_addr).unwrap();
+        client.note_received(size);
+        self.metrics.traffic.bytes_received += size as u64;
+        self.metrics.traffic.packets_received += 1;
+        let message = client.decrypt(message, self.config.encryption)?;
+        // encrypted bodies only get counted once they're opened
+        if message.bodies.len() > self.config.max_bodies_per_message {
+            self.metrics.too_many_bodies += 1;
+            return Err(ReceiveError::TooManyBodies(message.bodies.len()));
+        }
+        if !self.middleware.allow_inbound(sender_addr, &message) {
+            return Err(ReceiveError::Vetoed);
+        }
+        Ok((client, message))
+    }
+
+    /// Give addr a brand new slot, Reconnecting if it's returning and Connecting otherwise, replacing any old one
+    /// straight away rather than queued, since the message that got it in is handled against the slot
+    fn admit_client(
+        &mut self,
+        addr: SocketAddr,
+        observer: bool,
+        returning: bool,
+    ) -> &mut ClientInfo {
+        let mut client = if returning {
+            ClientInfo::returning(addr, self.next_join_number, self.now())
+        } else {
+            ClientInfo::new(addr, self.next_join_number, self.now())
+        };
+        client.grace_until = client.connected_at + self.config.connect_grace;
+        client.observer = observer;
+        self.apply_mutation(ClientMutation::Add(Box::new(client)));
+        self.next_join_number += 1;
+        // unwrap OK because it was just added
+        self.clients.get_mut(&addr).unwrap()
+    }
+
+    /// Count up what is queued for every client, without cloning any bodies
+    #[cfg(debug_assertions)]
+    fn queue_summaries(&self) -> Vec<QueueSummary> {
+        let mut summaries: Vec<QueueSummary> = self
+            .clients
+            .values()
+            .map(|client| {
+                let mut bodies = BTreeMap::new();
+                for body in &client.bodies {
+                    *bodies.entry(body.kind()).or_insert(0) += 1;
+                }
+                let mut reliable = BTreeMap::new();
+                for pending in &client.reliable {
+                    *reliable.entry(pending.elem.body.kind()).or_insert(0) += 1;
+                }
+                QueueSummary {
+                    addr: client.addr,
+                    bodies,
+                    reliable,
+                }
+            })
+            .collect();
+        summaries.sort_by_key(|summary| summary.addr);
+        summaries
+    }
+
+    /// Log and count a desync the client at addr reported, and send it the whole terrain again if resync_on_desync
+    fn handle_desync(&mut self, addr: SocketAddr, tick: u64, client_checksum: u64) {
+        self.metrics.desyncs += 1;
+        let ours = self.world_checksum.map(|c| c.checksum);
+        let resync = self.config.resync_on_desync;
+        let client = match self.clients.get_mut(&addr) {
+            Some(client) => client,
+            None => return,
+        };
+        client.desyncs += 1;
+        warn!(
+            "{} desynced at tick {}: its world checksum is {:016x}, ours is {:016x?} ({} desync(s) so far)",
+            addr, tick, client_checksum, ours, client.desyncs
+        );
+        if resync {
+            info!("sending {} the whole terrain again", addr);
+            // enqueue_terrain sends a fresh baseline to anyone without one
+            client.terrain_baselines.clear();
+            client.pending_baseline = None;
+            client.returning_baselines = None;
+        }
+    }
+
+    /// Every connected client as other clients see it, ordered by when they joined
+    fn player_list(&self) -> Vec<PlayerListEntry> {
+        let mut players: Vec<PlayerListEntry> = self
+            .clients
+            .values()
+            .map(|client| PlayerListEntry {
+                join_number: client.join_number,
+                name: client.name.clone(),
+                observer: client.observer,
+            })
+            .collect();
+        players.sort_by_key(|player| player.join_number);
+        players
+    }
+
+    /// Every connected client's inputs, ordered by when the client joined
+    /// HashMap order changes from run to run, this doesn't
+    fn inputs_in_join_order<'a>(
+        &self,
+        input_map: &'a HashMap<SocketAddr, PlayerInput>,
+    ) -> Vec<(SocketAddr, &'a PlayerInput)> {
+        let mut inputs: Vec<(u64, SocketAddr, &PlayerInput)> = input_map
+            .iter()
+            .filter_map(|(addr, input)| {
+                self.clients
+                    .get(addr)
+                    .map(|client| (client.join_number, *addr, input))
+            })
+            .collect();
+        inputs.sort_by_key(|(join_number, _, _)| *join_number);
+        inputs
+            .into_iter()
+            .map(|(_, addr, input)| (addr, input))
+            .collect()
+    }
+
+    /// Keep resending every client's unacked reliable bodies, and taking in acks, until they're all acked
+    /// or window runs out on the server's clock, blocking the whole time, for stopping without losing the last words
+    /// returns how many clients still had something unacked at the end
+    fn drain_reliable(&mut self, window: Duration) -> usize {
+        let deadline = self.now() + window;
+        let mut next_send = self.now();
+        let sequence = self.sequence;
+        let game_tick_hz = self.config.game_tick_hz;
+        loop {
+            let now = self.now();
+            // only acks matter now, inputs and the like go nowhere
+            loop {
+                match self.get_one_message() {
+                    Ok((client, message)) => {
+                        compute_new_bodies(client, message, sequence, &mut HashMap::new(), now);
+                    }
+                    Err(ReceiveError::NoMessage) => break,
+                    Err(_) => {}
+                }
+            }
+
+            let waiting: Vec<SocketAddr> = self
+                .clients
+                .values()
+                .filter(|client| !client.reliable.is_empty())
+                .map(|client| client.addr)
+                .collect();
+            if waiting.is_empty() || now >= deadline {
+                return waiting.len();
+            }
+            if now >= next_send {
+                next_send = now + DRAIN_RESEND_INTERVAL;
+                for addr in waiting {
+                    let client = match self.clients.get_mut(&addr) {
+                        Some(client) => client,
+                        None => continue,
+                    };
+                    let (scale, codecs) = (client.terrain_scale, client.codecs);
+                    let message = ServerToClient {
+                        header: ServerHeader {
+                            sequence,
+                            snapshot_tick: sequence,
+                            game_tick_hz,
+                            send_interval_micros: DRAIN_RESEND_INTERVAL.as_micros() as u64,
+                            sent_at_micros: 0,
+                            world_checksum: None,
+                        },
+                        bodies: Vec::new(),
+                        reliable: client
+                            .reliable
+                            .iter()
+                            .map(|r| ReliableElem {
+                                id: r.elem.id,
+                                body: r.elem.body.shrink(scale, codecs),
+                            })
+                            .collect(),
+                    };
+                    let message = match client.encrypt(message) {
+                        Some(message) => message,
+                        None => continue,
+                    };
+                    for pending in &mut client.reliable {
+                        pending.first_sent.get_or_insert(sequence);
+                    }
+                    if let Err(e) = self.send_message(addr, message) {
+                        debug!("unable to resend to {} while stopping: {:?}", addr, e);
+                    }
+                }
+            }
+            self.clock.sleep(DRAIN_POLL_INTERVAL);
+        }
+    }
+
+    /// Totals for the metrics CSV, as of timestamp (seconds since the unix epoch)
+    fn metrics_row(&self, timestamp: u64) -> MetricsRow {
+        let traffic = self.metrics.traffic;
+        MetricsRow {
+            timestamp,
+            clients: self.clients.len(),
+            bytes_in: traffic.bytes_received,
+            bytes_out: traffic.bytes_sent,
+            packets_in: traffic.packets_received,
+            packets_out: traffic.packets_sent,
+            lost: self.clients.values().map(|c| c.detected_loss).sum(),
+            drops: self.transport.dropped_datagrams(),
+            decode_errors: self.metrics.decode_errors,
+            missed_ticks: self.metrics.missed_ticks,
+            deferred_ticks: self.metrics.deferred_ticks,
+        }
+    }
+
+    /// Append a row to the metrics CSV, if there is one
+    fn write_metrics_row(&mut self) {
+        let row = self.metrics_row(unix_micros() / 1_000_000);
+        if let Some(csv) = &mut self.metrics_csv {
+            if let Err(e) = csv.append(&row) {
+                // stop instead of failing every interval
+                error!(
+                    "unable to write metrics to {}: {}, metrics CSV stopped",
+                    csv.path().display(),
+                    e
+                );
+                self.metrics_csv = None;
+            }
+        }
+    }
+
+    /// Clients with inputs in input_map that should have a player but don't, none of alive is theirs
+    /// clients without a player_id aren't simulated yet, so they're never missing one
+    fn inputs_without_player(
+        &self,
+        input_map: &HashMap<SocketAddr, PlayerInput>,
+        alive: &HashSet<u64>,
+    ) -> Vec<SocketAddr> {
+        input_map
+            .keys()
+            .filter(|addr| {
+                self.clients
+                    .get(addr)
+                    .and_then(|client| client.player_id)
+                    .is_some_and(|id| !alive.contains(&id))
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Take a snapshot of the server's current state
+    fn status(&self) -> ServerStatus {
+        let mut clients: Vec<ClientStatus> = self
+            .clients
+            .values()
+            .map(|client| ClientStatus {
+                addr: client.addr,
+                join_number: client.join_number,
+                name: client.name.clone(),
+                session_duration: client.session_duration(self.now()),
+                rtt: client.rtt,
+                last_ack: client.last_ack,
+                role: client.role,
+                observer: client.observer,
+                idle: client.idle,
+                blocks_mined: client.blocks_mined,
+                queued_bodies: client.bodies.len() + client.reliable.len(),
+                detected_loss: client.detected_loss,
+                prediction_error: client.prediction_error,
+                one_way_delay: client.one_way_delay,
+                ack_latency: client.ack_latency,
+                send_jitter: client.smoothing.jitter,
+            })
+            .collect();
+
+        // longest connected first
+        clients.sort_by_key(|client| std::cmp::Reverse(client.session_duration));
+
+        let mut metrics = self.metrics.clone();
+        metrics.receive_queue_drops = self.transport.dropped_datagrams();
+        ServerStatus {
+            sequence: self.sequence,
+            clients,
+            metrics,
+        }
+    }
+
+    /// Every connected client, for operators
+    fn list_clients(&self) -> ClientListReport {
+        let mut clients: Vec<ClientListEntry> = self
+            .status()
+            .clients
+            .into_iter()
+            .map(|client| ClientListEntry {
+                addr: client.addr,
+                id: client.join_number,
+                name: client.name,
+                observer: client.observer,
+                rtt: client.rtt,
+                loss: client.detected_loss,
+                session_duration: client.session_duration,
+                queued_bodies: client.queued_bodies,
+            })
+            .collect();
+        clients.sort_by_key(|client| client.id);
+        ClientListReport { clients }
+    }
+
+    /// Tell every client to switch over to a new map
+    fn change_map(&mut self, terrain: &Terrain) {
+        // changes were to the old map
+        self.dirty_chunks.clear();
+        self.chunk_versions.clear();
+        self.chunk_bounds.clear();
+        self.terrain_version += 1;
+        let version = self.terrain_version;
+        for client in self.clients.values_mut() {
+            // inputs were meant for the old map
+            client.queued_inputs.clear();
+            // terrains still waiting to go out are for the old map
+            client.bodies.retain(|b| {
+                !matches!(
+                    b,
+                    ServerBodyElem::Terrain(_)
+                        | ServerBodyElem::TerrainChunks(_)
+                        | ServerBodyElem::TerrainVersion(_)
+                        | ServerBodyElem::ChangedBounds(_)
+                )
+            });
+            // and an older map (or baseline) that hasn't been confirmed yet is pointless now
+            client.reliable.retain(|r| {
+                !matches!(
+                    r.elem.body,
+                    ServerBodyElem::MapChange(_) | ServerBodyElem::Terrain(_)
+                )
+            });
+
+            client.enqueue_baseline(ServerBodyElem::MapChange(terrain.clone()), version);
+        }
+    }
+}
+
+/// Snapshot of the server's state, owns all of its data so it doesn't borrow the server
+#[derive(Debug)]
+struct ServerStatus {
+    /// The current sequence/tick number
+    sequence: u64,
+    clients: Vec<ClientStatus>,
+    metrics: ServerMetrics,
+}
+
+/// Snapshot of one client's state, see ServerStatus
+#[derive(Debug)]
+struct ClientStatus {
+    addr: SocketAddr,
+    join_number: u64,
+    name: Option<String>,
+    session_duration: Duration,
+    rtt: Option<Duration>,
+    last_ack: u64,
+    role: ClientRole,
+    observer: bool,
+    idle: bool,
+    blocks_mined: u64,
+    /// Bodies (reliable or not) waiting to be sent
+    queued_bodies: usize,
+    detected_loss: u64,
+    prediction_error: PredictionErrorStats,
+    one_way_delay: OneWayDelayStats,
+    ack_latency: AckLatencyStats,
+    /// How far the time between packets to the client usually is from its send interval
+    send_jitter: Duration,
+}
+
+/// List of the connected clients, see AdminCommand::ListClients
+#[derive(Debug)]
+struct ClientListReport {
+    /// Oldest client first
+    clients: Vec<ClientListEntry>,
+}
+
+/// One line of a ClientListReport
+#[derive(Debug)]
+struct ClientListEntry {
+    addr: SocketAddr,
+    /// Goes up by one for every client that joins, unlike addresses these are never reused
+    id: u64,
+    name: Option<String>,
+    observer: bool,
+    rtt: Option<Duration>,
+    /// Client packets that never arrived
+    loss: u64,
+    session_duration: Duration,
+    /// Bodies (reliable or not) waiting to be sent
+    queued_bodies: usize,
+}
+
+impl std::fmt::Display for ClientListReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} client(s)", self.clients.len())?;
+        for client in &self.clients {
+            let rtt = match client.rtt {
+                Some(rtt) => format!("{:.1?}", rtt),
+                None => "unknown".to_string(),
+            };
+            let name = match &client.name {
+                Some(name) => format!(" ({})", name),
+                None => String::new(),
+            };
+            let observer = if client.observer { " observer" } else { "" };
+            write!(
+                f,
+                "\n    #{} {}{}{}: rtt {}, {} lost, connected for {:.1?}, {} queued bodies",
+                client.id,
+                client.addr,
+                name,
+                observer,
+                rtt,
+                client.loss,
+                client.session_duration,
+                client.queued_bodies
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// What is queued up for one client, counted by body kind
+#[cfg(debug_assertions)]
+#[derive(Debug)]
+struct QueueSummary {
+    addr: SocketAddr,
+    /// Unreliable bodies, by ServerBodyElem::kind
+    bodies: BTreeMap<&'static str, usize>,
+    /// Reliable bodies, by ServerBodyElem::kind
+    reliable: BTreeMap<&'static str, usize>,
+}
+
+#[cfg(debug_assertions)]
+impl QueueSummary {
+    /// Total bodies waiting for this client
+    fn depth(&self) -> usize {
+        self.bodies.values().sum::<usize>() + self.reliable.values().sum::<usize>()
+    }
+}
+
+#[cfg(debug_assertions)]
+impl std::fmt::Display for QueueSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} queued, bodies {:?}",
+            self.addr,
+            self.depth(),
+            self.bodies
+        )?;
+        if !self.reliable.is_empty() {
+            write!(f, ", reliable {:?}", self.reliable)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sent when the server's socket broke and binding it again didn't help, the server stops after this
+pub struct SocketFailed {
+    pub addr: SocketAddr,
+    /// Times binding the socket again was tried
+    pub attempts: u32,
+}
+
+/// Sent when a game or network tick's systems take longer than the time between ticks
+/// the server can't keep up at that point, every late tick pushes the next one back
+pub struct TickOverrun {
+    /// GAME_TICK_LABEL or NETWORK_TICK_LABEL
+    pub label: &'static str,
+    /// How long the tick's systems took, together
+    pub elapsed: Duration,
+    /// Time between ticks, what they had to finish in
+    pub budget: Duration,
+}
+
+/// When the current game and network ticks started, see start_tick and finish_tick
+#[derive(Debug, Default)]
+struct TickTimers(HashMap<&'static str, Instant>);
+
+/// Sent whenever a client gets removed from the server
+pub struct ClientDisconnected {
+    pub addr: SocketAddr,
+    /// How long the client was connected for
+    pub session_duration: Duration,
+    pub reason: DisconnectReason,
+    /// NetworkId of the entity it played as, see despawn_departed_players
+    pub player_id: Option<u64>,
+}
+
+/// Sent once a client has confirmed that it got a reliable body
+pub struct ReliableDelivered {
+    pub client: SocketAddr,
+    /// Id given out by ClientInfo::enqueue_reliable
+    pub message_id: u64,
+}
+
+/// Operator actions, sent to the server as bevy events
+pub enum AdminCommand {
+    /// Replace the world with a new terrain, without disconnecting anyone
+    MapChange(Terrain),
+    /// Log the server's status
+    Status,
+    /// Change how a client takes part in the game
+    SetRole(SocketAddr, ClientRole),
+    /// Remove a client from the server
+    Kick(SocketAddr),
+    /// Write the last few seconds of packets to a replay log
+    DumpReplay(PathBuf),
+    /// Log a ClientListReport
+    ListClients,
+    /// Change one game setting, clients get the new settings on the next network tick
+    SetSetting(String, String),
+    /// Give a client a name, shown in the client list
+    SetName(SocketAddr, String),
+    /// Write the inputs recently taken from a client to a file, see Server::dump_input_history
+    DumpInputs(SocketAddr, PathBuf),
+    /// Let every client know and stop the server, saving the world on the way out
+    Stop,
+    /// Log where every entity was on a recent game tick, see SnapshotHistory
+    Rewind(u64),
+    /// Stop like Stop, but first give every client a token to come back with once the server is started again
+    /// and how many seconds to wait before trying
+    Restart(u64),
+}
+
+/// Lines typed into the server's terminal
+/// stdin gets read on its own thread so that waiting for input never blocks the server
+struct Console {
+    lines: Mutex<mpsc::Receiver<String>>,
+}
+
+impl Console {
+    fn spawn() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+                // stop once the server is gone
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Console {
+            lines: Mutex::new(receiver),
+        }
+    }
+}
+
+/// Parse one line of console input into an admin command, e.g. `map savedata/other.sav`
+fn parse_admin_command(line: &str) -> Result<AdminCommand, String> {
+    let mut words = line.split_whitespace();
+    match words.next() {
+        Some("map") => {
+            let path = words
+                .next()
+                .ok_or_else(|| "usage: map <save file>".to_string())?;
+            save::load_terrain(Path::new(path))
+                .map(AdminCommand::MapChange)
+                .map_err(|e| format!("unable to load map from {}: {}", path, e))
+        }
+        Some("status") => Ok(AdminCommand::Status),
+        Some("stop") => Ok(AdminCommand::Stop),
+        Some("restart") => match words.next() {
+            Some(seconds) => seconds
+                .parse()
+                .map(AdminCommand::Restart)
+                .map_err(|e| format!("invalid number of seconds: {}", e)),
+            None => Ok(AdminCommand::Restart(DEFAULT_RESTART_AFTER_SECONDS)),
+        },
+        Some("clients") => Ok(AdminCommand::ListClients),
+        Some("set") => match (words.next(), words.next()) {
+            (Some(key), Some(value)) => {
+                Ok(AdminCommand::SetSetting(key.to_string(), value.to_string()))
+            }
+            _ => Err("usage: set <setting> <value>".to_string()),
+        },
+        Some("kick") => words
+            .next()
+            .ok_or_else(|| "usage: kick <client address>".to_string())?
+            .parse()
+            .map(AdminCommand::Kick)
+            .map_err(|e| format!("invalid client address: {}", e)),
+        Some("role") => {
+            let usage = || "usage: role <client address> <player|spectator>".to_string();
+            let addr = words
+                .next()
+                .ok_or_else(usage)?
+                .parse()
+                .map_err(|e| format!("invalid client address: {}", e))?;
+            let role = match words.next() {
+                Some("player") => ClientRole::Player,
+                Some("spectator") => ClientRole::Spectator,
+                _ => return Err(usage()),
+            };
+            Ok(AdminCommand::SetRole(addr, role))
+        }
+        Some("name") => {
+            let usage = || "usage: name <client address> <name>".to_string();
+            let addr = words
+                .next()
+                .ok_or_else(usage)?
+                .parse()
+                .map_err(|e| format!("invalid client address: {}", e))?;
+            let name = words.collect::<Vec<&str>>().join(" ");
+            if name.is_empty() {
+                return Err(usage());
+            }
+            Ok(AdminCommand::SetName(addr, name))
+        }
+        Some("inputs") => {
+            let usage = || "usage: inputs <client address> <file>".to_string();
+            let addr = words
+                .next()
+                .ok_or_else(usage)?
+                .parse()
+                .map_err(|e| format!("invalid client address: {}", e))?;
+            let path = words.next().ok_or_else(usage)?;
+            Ok(AdminCommand::DumpInputs(addr, PathBuf::from(path)))
+        }
+        Some("rewind") => words
+            .next()
+            .ok_or_else(|| "usage: rewind <game tick>".to_string())?
+            .parse()
+            .map(AdminCommand::Rewind)
+            .map_err(|e| format!("invalid game tick: {}", e)),
+        Some("dump") => words
+            .next()
+            .map(|path| AdminCommand::DumpReplay(PathBuf::from(path)))
+            .ok_or_else(|| "usage: dump <replay file>".to_string()),
+        Some(other) => Err(format!("unknown command '{}'", other)),
+        None => Err("empty command".to_string()),
+    }
+}
+
+/// How many chunks the `generated` built-in map has
+const GENERATED_MAP_CHUNKS: u64 = 8;
+
+/// Maps that can be switched to by name, for clients who don't get to pick files on the server
+fn builtin_map(name: &str) -> Option<Terrain> {
+    match name {
+        "surface" => {
+            let mut terrain = Terrain::empty();
+            create_surface_chunk(&mut terrain);
+            Some(terrain)
+        }
+        "generated" => Some(Terrain::new(GENERATED_MAP_CHUNKS)),
+        _ => None,
+    }
+}
+
+/// Parse an admin command sent by a client, see ServerConfig::host_authority
+/// only the commands that are safe to take from over the network: nothing that reads or writes files
+/// on the server or stops it, and maps only by built-in name
+fn parse_client_admin_command(line: &str) -> Result<AdminCommand, String> {
+    let mut words = line.split_whitespace();
+    match words.next() {
+        Some("map") => {
+            let name = words
+                .next()
+                .ok_or_else(|| "usage: map <surface|generated>".to_string())?;
+            builtin_map(name)
+                .map(AdminCommand::MapChange)
+                .ok_or_else(|| format!("no built-in map called '{}'", name))
+        }
+        Some("status" | "clients" | "kick" | "role" | "name" | "set") => parse_admin_command(line),
+        Some(other) => Err(format!(
+            "'{}' can only be run from the server console",
+            other
+        )),
+        None => Err("empty command".to_string()),
+    }
+}
+
+/// Bevy plugin that implements server logic
+#[derive(Clone)]
+pub struct ServerPlugin {
+    pub config: ServerConfig,
+    /// Given to the server when it starts, see add_inbound and add_outbound
+    middleware: Middleware,
+}
+
+impl ServerPlugin {
+    /// No middleware unless config traces messages, add any more with add_inbound and add_outbound
+    pub fn new(config: ServerConfig) -> Self {
+        let plugin = Self {
+            middleware: Middleware::default(),
+            config,
+        };
+        if plugin.config.trace_messages {
+            plugin
+                .add_inbound(trace_inbound)
+                .add_outbound(trace_outbound)
+        } else {
+            plugin
+        }
+    }
+
+    /// Run hook on every message from a client before it's handled, after the hooks added before it
+    /// returning false drops the message
+    pub(super) fn add_inbound(
+        mut self,
+        hook: impl Fn(SocketAddr, &ClientToServer) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.middleware.add_inbound(hook);
+        self
+    }
+
+    /// Run hook on every message to a client right before it's sent, after the hooks added before it
+    /// returning false doesn't send it
+    pub(super) fn add_outbound(
+        mut self,
+        hook: impl Fn(SocketAddr, &ServerToClient) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.middleware.add_outbound(hook);
+        self
+    }
+}
+
+impl Plugin for ServerPlugin {
+    fn build(&self, app: &mut App) {
+        // add game tick
+        app.add_fixed_timestep(
+            std::time::Duration::from_secs_f64(1. / self.config.game_tick_hz as f64),
+            GAME_TICK_LABEL,
+        );
+
+        // add network tick
+        app.add_fixed_timestep(
+            std::time::Duration::from_secs_f64(1. / self.config.network_tick_hz as f64),
+            NETWORK_TICK_LABEL,
+        );
+
+        // enter systems
+        let config = self.config.clone();
+        let middleware = self.middleware.clone();
+        app.add_enter_system(
+            states::server::GameState::Running,
+            move |commands: Commands,
+                  exit: EventWriter<AppExit>,
+                  admin_commands: EventWriter<AdminCommand>| {
+                create_server(commands, exit, admin_commands, &config, &middleware)
+            },
+        );
+
+        // admin commands run every frame instead of on a tick so that no events get missed
+        app.add_event::<AdminCommand>()
+            .add_event::<AdminRequest>()
+            .add_event::<ClientDisconnected>()
+            .add_event::<SocketFailed>()
+            .add_event::<TerrainChanged>()
+            .add_event::<ReliableDelivered>()
+            .add_event::<TickOverrun>()
+            .init_resource::<TickTimers>()
+            .insert_resource(Console::spawn())
+            .add_system(
+                read_console
+                    .run_in_state(states::server::GameState::Running)
+                    .label("read_console"),
+            )
+            .add_system(
+                read_admin_socket
+                    .run_in_state(states::server::GameState::Running)
+                    .run_if_resource_exists::<AdminSocket>()
+                    .label("read_admin_socket"),
+            )
+            .add_system(
+                handle_admin_commands
+                    .run_in_state(states::server::GameState::Running)
+                    .after("read_console")
+                    .after("read_admin_socket"),
+            )
+            .add_system(log_disconnects.run_in_state(states::server::GameState::Running))
+            .add_system(despawn_departed_players.run_in_state(states::server::GameState::Running))
+            // the server is already leaving Running by the time this has anything to log
+            .add_system(log_socket_failures)
+            .add_system(log_reliable_deliveries.run_in_state(states::server::GameState::Running))
+            .add_system(log_tick_overruns.run_in_state(states::server::GameState::Running))
+            .add_system(collect_terrain_changes.run_in_state(states::server::GameState::Running));
+
+        // keep an eye on queue depths in debug builds, to spot a client that never catches up
+        #[cfg(debug_assertions)]
+        app.add_fixed_timestep(std::time::Duration::from_secs(1), QUEUE_LOG_LABEL)
+            .add_fixed_timestep_system(
+                QUEUE_LOG_LABEL,
+                0,
+                log_queue_depths.run_in_state(states::server::GameState::Running),
+            );
+
+        // opt-in, this is a lot of log lines with more than a couple of clients
+        if self.config.log_bandwidth {
+            app.add_fixed_timestep(std::time::Duration::from_secs(1), BANDWIDTH_LOG_LABEL)
+                .add_fixed_timestep_system(
+                    BANDWIDTH_LOG_LABEL,
+                    0,
+                    log_client_bandwidth.run_in_state(states::server::GameState::Running),
+                );
+        }
+
+        // opt-in, only runs when there's a file to write to
+        if self.config.metrics_csv.is_some() {
+            app.add_fixed_timestep(self.config.metrics_csv_interval, METRICS_CSV_LABEL)
+                .add_fixed_timestep_system(
+                    METRICS_CSV_LABEL,
+                    0,
+                    write_metrics_csv.run_in_state(states::server::GameState::Running),
+                );
+        }
+
+        // rarely, there's only something to give back after a lot of clients have left
+        app.add_fixed_timestep(COMPACT_INTERVAL, COMPACT_LABEL)
+            .add_fixed_timestep_system(
+                COMPACT_LABEL,
+                0,
+                compact_collections.run_in_state(states::server::GameState::Running),
+            );
+
+        // exit systems
+        app.add_exit_system(states::server::GameState::Running, destroy_server)
+            .add_enter_system(states::server::GameState::Stopped, exit_app);
+
+        // every tick is timed from before its first system to a stage of its own after the rest
+        app.add_fixed_timestep_system(
+            GAME_TICK_LABEL,
+            0,
+            (|timers: ResMut<TickTimers>| start_tick(timers, GAME_TICK_LABEL))
+                .run_in_state(states::server::GameState::Running)
+                .before("increase_tick"),
+        )
+        .add_fixed_timestep_system(
+            NETWORK_TICK_LABEL,
+            0,
+            (|timers: ResMut<TickTimers>| start_tick(timers, NETWORK_TICK_LABEL))
+                .run_in_state(states::server::GameState::Running)
+                .before("enqueue_terrain")
+                .before("enqueue_entities"),
+        );
+        for label in [GAME_TICK_LABEL, NETWORK_TICK_LABEL] {
+            app.add_fixed_timestep_child_stage(label)
+                .add_fixed_timestep_system(
+                    label,
+                    1,
+                    (move |timers: ResMut<TickTimers>,
+                           server: Res<Server>,
+                           overruns: EventWriter<TickOverrun>| {
+                        finish_tick(timers, server, overruns, label)
+                    })
+                    .run_in_state(states::server::GameState::Running),
+                );
+        }
+
+        // game tick systems
+        app.add_fixed_timestep_system(
+            GAME_TICK_LABEL,
+            0,
+            increase_tick
+                .run_in_state(states::server::GameState::Running)
+                .label("increase_tick"),
+        )
+        .add_fixed_timestep_system(
+            GAME_TICK_LABEL,
+            0,
+            server_handle_messages
+                .run_in_state(states::server::GameState::Running)
+                .after("increase_tick")
+                .label("handle_messages"),
+        )
+        .add_fixed_timestep_system(
+            GAME_TICK_LABEL,
+            0,
+            check_socket
+                .run_in_state(states::server::GameState::Running)
+                .after("handle_messages"),
+        )
+        .add_fixed_timestep_system(
+            GAME_TICK_LABEL,
+            0,
+            apply_inputs
+                .run_in_state(states::server::GameState::Running)
+                .after("handle_messages")
+                .label("apply_inputs"),
+        )
+        .add_fixed_timestep_system(
+            GAME_TICK_LABEL,
+            0,
+            simulate_physics
+                .run_in_state(states::server::GameState::Running)
+                .after("apply_inputs")
+                .label("simulate_physics"),
+        )
+        .add_fixed_timestep_system(
+            GAME_TICK_LABEL,
+            0,
+            update_world_checksum
+                .run_in_state(states::server::GameState::Running)
+                .after("simulate_physics"),
+        )
+        .add_fixed_timestep_system(
+            GAME_TICK_LABEL,
+            0,
+            record_snapshot_history
+                .run_in_state(states::server::GameState::Running)
+                .after("simulate_physics"),
+        )
+        .add_fixed_timestep_system(
+            GAME_TICK_LABEL,
+            0,
+            restore_returning_players
+                .run_in_state(states::server::GameState::Running)
+                .after("handle_messages")
+                .before("apply_inputs"),
+        );
+
+        // network tick systems
+        app.add_fixed_timestep_system(
+            NETWORK_TICK_LABEL,
+            0,
+            enqueue_terrain
+                .run_in_state(states::server::GameState::Running)
+                .label("enqueue_terrain"),
+        )
+        .add_fixed_timestep_system(
+            NETWORK_TICK_LABEL,
+            0,
+            enqueue_entity_snapshot
+                .run_in_state(states::server::GameState::Running)
+                .label("enqueue_entities"),
+        )
+        .add_fixed_timestep_system(
+            NETWORK_TICK_LABEL,
+            0,
+            enqueue_settings
+                .run_in_state(states::server::GameState::Running)
+                // new clients get their settings as part of their welcome
+                .after("enqueue_terrain")
+                .label("enqueue_settings"),
+        )
+        .add_fixed_timestep_system(
+            NETWORK_TICK_LABEL,
+            0,
+            send_all_messages
+                .run_in_state(states::server::GameState::Running)
+                .after("enqueue_terrain")
+                .after("enqueue_entities")
+                .after("enqueue_settings")
+                .label("send_messages"),
+        )
+        .add_fixed_timestep_system(
+            NETWORK_TICK_LABEL,
+            0,
+            drop_disconnected_clients
+                .run_in_state(states::server::GameState::Running)
+                .after("send_messages")
+                .label("drop_disconnected"),
+        )
+        .add_fixed_timestep_system(
+            NETWORK_TICK_LABEL,
+            0,
+            apply_client_mutations
+                .run_in_state(states::server::GameState::Running)
+                .after("drop_disconnected")
+                .label("apply_mutations"),
+        );
+
+        // held back messages go out between network ticks
+        if self.config.smooth_sends {
+            app.add_system(send_smoothed_messages.run_in_state(states::server::GameState::Running));
+        }
+
+        // fewer network ticks when the server fills up
+        if self.config.min_network_tick_hz.is_some() {
+            app.add_system(tune_network_tick.run_in_state(states::server::GameState::Running));
+        }
+
+        // pacing splits every network tick into pieces, each sending a share of the messages
+        if self.config.pacing {
+            app.add_fixed_timestep(
+                std::time::Duration::from_secs_f64(
+                    1. / (self.config.network_tick_hz * PACING_STEPS) as f64,
+                ),
+                PACING_LABEL,
+            )
+            .add_fixed_timestep_system(
+                PACING_LABEL,
+                0,
+                send_paced_messages.run_in_state(states::server::GameState::Running),
+            );
+        }
+    }
+}
+
+/// Call bind, and if it fails call it again up to retries more times, waiting twice as long before each one
+/// for ports that are still held by a server that only just stopped
+fn bind_with_retry<T>(
+    mut bind: impl FnMut() -> std::io::Result<T>,
+    retries: u32,
+    base_delay: Duration,
+    mut sleep: impl FnMut(Duration),
+) -> std::io::Result<T> {
+    let mut delay = base_delay;
+    for attempt in 1..=retries {
+        match bind() {
+            Ok(bound) => return Ok(bound),
+            Err(e) => {
+                warn!(
+                    "unable to create server: {}, retrying in {:.1?} ({}/{})",
+                    e, delay, attempt, retries
+                );
+                sleep(delay);
+                delay = delay.saturating_mul(2);
+            }
+        }
+    }
+    bind()
+}
+
+fn create_server(
+    mut commands: Commands,
+    mut exit: EventWriter<AppExit>,
+    mut admin_commands: EventWriter<AdminCommand>,
+    config: &ServerConfig,
+    middleware: &Middleware,
+) {
+    // better to not start than to run a whole session that can't be saved
+    if config.persist {
+        if let Err(e) = save::check_save_path(&config.save_file) {
+            error!("{}, not starting the server", e);
+            commands.insert_resource(NextState(states::server::GameState::Stopped));
+            exit.send(AppExit);
+            return;
+        }
+    }
+    let mut server = match bind_with_retry(
+        || Server::new(config.clone()),
+        config.bind_retries,
+        config.bind_retry_delay,
+        std::thread::sleep,
+    ) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Unable to create server: {}, giving up", e);
+            // leave Running before any of its systems go looking for the server
+            commands.insert_resource(NextState(states::server::GameState::Stopped));
+            exit.send(AppExit);
+            return;
+        }
+    };
+    if let Some(path) = &config.record_path {
+        match ReplayWriter::create(path, config.compress_record) {
+            Ok(recorder) => {
+                info!("recording packets to {}", path.display());
+                server.recorder = Some(recorder);
+            }
+            Err(e) => error!("unable to record to {}: {}", path.display(), e),
+        }
+    }
+    server.middleware.extend(middleware);
+    if let Some(path) = &config.metrics_csv {
+        match MetricsCsv::open(path) {
+            Ok(csv) => {
+                info!("writing metrics to {}", path.display());
+                server.metrics_csv = Some(csv);
+            }
+            Err(e) => error!("unable to write metrics to {}: {}", path.display(), e),
+        }
+    }
+    // back from a restart, carry on with the world as it was saved on the way out
+    let now = server.now();
+    if server.resume_restart(now) {
+        match save::load_terrain(&config.save_file) {
+            Ok(terrain) => admin_commands.send(AdminCommand::MapChange(terrain)),
+            Err(e) => error!("unable to load the world from before the restart: {}", e),
+        }
+    }
+    if config.localhost_only {
+        info!("server only accepting clients on localhost");
+    }
+    // the server runs fine without it, the terminal still works
+    if let Some(addr) = config.admin_addr {
+        match AdminSocket::bind(addr, config.admin_token.clone(), config.buffer_size) {
+            Ok(socket) => {
+                info!("taking admin commands on {}", addr);
+                commands.insert_resource(socket);
+            }
+            Err(e) => error!("unable to open admin socket on {}: {}", addr, e),
+        }
+    }
+
+    match server.transport.local_addr() {
+        Ok(addr) => info!("server created on {}", addr),
+        Err(_) => info!("server created"),
+    }
+
+    commands.insert_resource(server);
+
+    let input_map: HashMap<SocketAddr, PlayerInput> = HashMap::new();
+
+    commands.insert_resource(input_map);
+}
+
+fn destroy_server(mut commands: Commands, server: Option<ResMut<Server>>) {
+    // nothing to clean up if the server never got going
+    let mut server = match server {
+        Some(server) => server,
+        None => return,
+    };
+
+    // say goodbye and give it (and anything else reliable) a moment to arrive, a broken socket can't send it anyway
+    if !server.socket_failed {
+        let message = server.config.shutdown_message.clone();
+        if !message.is_empty() {
+            for client in server.clients.values_mut() {
+                client.enqueue_reliable(ServerBodyElem::Announcement(message.clone()));
+            }
+        }
+        let window = server.config.shutdown_drain;
+        let unconfirmed = server.drain_reliable(window);
+        if unconfirmed > 0 {
+            warn!(
+                "{} client(s) didn't confirm everything within {:?} of stopping",
+                unconfirmed, window
+            );
+        }
+    }
+
+    // let everyone know instead of making them wait for a timeout
+    for addr in server.clients.keys() {
+        server.send_disconnect(*addr, DisconnectReason::ServerShutdown);
+    }
+
+    // the last stretch of the run goes in the metrics CSV too
+    server.write_metrics_row();
+
+    // make sure the replay log is complete
+    if let Some(mut recorder) = server.recorder.take() {
+        if let Err(e) = recorder.finish() {
+            error!("unable to finish replay log: {}", e);
+        }
+    }
+    commands.remove_resource::<Server>();
+    commands.remove_resource::<AdminSocket>();
+}
+
+/// Nothing runs once the server has stopped, so the app might as well go
+fn exit_app(mut exit: EventWriter<AppExit>) {
+    exit.send(AppExit);
+}
+
+/// Second socket that takes admin commands, see ServerConfig::admin_addr
+/// every datagram is one command line, after the token if there is one, and gets a reply saying how it went
+struct AdminSocket {
+    transport: Box<dyn Transport>,
+    token: Option<String>,
+    buffer: Vec<u8>,
+}
+
+/// A command that came in on the admin socket, handle_admin_commands sends its result back to reply_to
+struct AdminRequest {
+    command: AdminCommand,
+    reply_to: SocketAddr,
+}
+
+impl AdminSocket {
+    fn bind(addr: SocketAddr, token: Option<String>, buffer_size: usize) -> std::io::Result<Self> {
+        Ok(Self::with_transport(
+            Box::new(UdpTransport::bind(addr)?),
+            token,
+            buffer_size,
+        ))
+    }
+
+    /// buffer_size is ServerConfig::buffer_size, the same as the player socket
+    fn with_transport(
+        transport: Box<dyn Transport>,
+        token: Option<String>,
+        buffer_size: usize,
+    ) -> Self {
+        Self {
+            transport,
+            token,
+            buffer: vec![0; buffer_size],
+        }
+    }
+
+    /// Check the token on one datagram and parse the command after it, the line is returned without the token
+    fn parse<'a>(&self, datagram: &'a [u8]) -> Result<(AdminCommand, &'a str), String> {
+        let line = std::str::from_utf8(datagram)
+            .map_err(|_| "command isn't valid utf-8".to_string())?
+            .trim();
+        let line = match &self.token {
+            Some(token) => {
+                let (given, rest) = line.split_once(' ').unwrap_or((line, ""));
+                if !constant_time_eq(given.as_bytes(), token.as_bytes()) {
+                    return Err("wrong or missing token".to_string());
+                }
+                rest
+            }
+            None => line,
+        };
+        parse_admin_command(line).map(|command| (command, line))
+    }
+
+    fn reply(&mut self, addr: SocketAddr, reply: &str) {
+        if let Err(e) = self.transport.send_to(reply.as_bytes(), addr) {
+            warn!("admin socket: unable to reply to {}: {}", addr, e);
+        }
+    }
+}
+
+/// Compare two byte strings without stopping at the first difference
+/// so how long a wrong token takes to refuse says nothing about how much of it was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Turn datagrams on the admin socket into admin requests, answering the ones that can't be run
+/// the socket is nonblocking and only read here, so it never holds up the main socket
+fn read_admin_socket(mut socket: ResMut<AdminSocket>, mut requests: EventWriter<AdminRequest>) {
+    let socket = &mut *socket;
+    loop {
+        let (size, sender) = match socket.transport.recv_from(&mut socket.buffer) {
+            Ok(received) => received,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(e) => {
+                warn!("admin socket: {}", e);
+                break;
+            }
+        };
+        match socket.parse(&socket.buffer[..size]) {
+            Ok((command, line)) => {
+                info!("admin socket: '{}' from {}", line, sender);
+                requests.send(AdminRequest {
+                    command,
+                    reply_to: sender,
+                });
+            }
+            Err(e) => {
+                warn!("admin socket: {} from {}", e, sender);
+                socket.reply(sender, &format!("error: {}", e));
+            }
+        }
+    }
+}
+
+/// Turn lines typed into the console into admin commands
+fn read_console(console: Res<Console>, mut admin_commands: EventWriter<AdminCommand>) {
+    let lines = console.lines.lock().unwrap();
+    while let Ok(line) = lines.try_recv() {
+        // ignore blank lines
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match parse_admin_command(&line) {
+            Ok(command) => admin_commands.send(command),
+            Err(e) => warn!("console: {}", e),
+        }
+    }
+}
+
+/// Carry out admin commands, logging how each one went
+/// commands from the admin socket get the same result sent back to whoever sent them
+/// a map change while there's no terrain yet becomes the terrain
+#[allow(clippy::too_many_arguments)]
+fn handle_admin_commands(
+    mut commands: Commands,
+    mut admin_commands: EventReader<AdminCommand>,
+    mut requests: EventReader<AdminRequest>,
+    mut socket: Option<ResMut<AdminSocket>>,
+    mut server: ResMut<Server>,
+    mut terrain: Option<ResMut<Terrain>>,
+    mut input_map: ResMut<HashMap<SocketAddr, PlayerInput>>,
+    entities: Query<(&NetworkId, &Transform)>,
+) {
+    let commands_with_senders = admin_commands.iter().map(|command| (command, None)).chain(
+        requests
+            .iter()
+            .map(|request| (&request.command, Some(request.reply_to))),
+    );
+    for (command, reply_to) in commands_with_senders {
+        let result: Result<String, String> = match command {
+            AdminCommand::MapChange(new_terrain) => {
+                // clients would never get a map that can't be sent, so keep the one we have
+                match check_terrain(new_terrain) {
+                    Err(e) => Err(format!("not changing map: {}", e)),
+                    Ok((_, size)) => {
+                        server.last_good_terrain_size = Some(size);
+                        let reply = match terrain.as_deref_mut() {
+                            Some(terrain) => {
+                                let reply = format!(
+                                    "changing map, {} of {} chunk(s) are different",
+                                    new_terrain.diff(terrain).len(),
+                                    new_terrain.region_count()
+                                );
+                                *terrain = new_terrain.clone();
+                                reply
+                            }
+                            None => {
+                                commands.insert_resource(new_terrain.clone());
+                                "changing map, there wasn't one yet".to_string()
+                            }
+                        };
+                        // inputs were meant for the old map
+                        input_map.clear();
+                        server.change_map(new_terrain);
+                        Ok(reply)
+                    }
+                }
+            }
+            // destroy_server lets the clients know once Running is left
+            AdminCommand::Stop => {
+                commands.insert_resource(NextState(states::server::GameState::Stopped));
+                Ok("stopping the server".to_string())
+            }
+            AdminCommand::Restart(after_seconds) => {
+                match server.prepare_restart(*after_seconds, &entity_states(&entities)) {
+                    Ok(tokens) => {
+                        commands.insert_resource(NextState(states::server::GameState::Stopped));
+                        Ok(format!(
+                            "restarting the server, {} client(s) can come back",
+                            tokens
+                        ))
+                    }
+                    Err(e) => Err(format!("not restarting: {}", e)),
+                }
+            }
+            AdminCommand::Status => {
+                let status = server.status();
+                info!(
+                    "status: tick {}, {} client(s)",
+                    status.sequence,
+                    status.clients.len()
+                );
+                // encoding the whole world just to say how big it is would hold up the frame
+                let encoded = match server.last_good_terrain_size {
+                    Some(size) => format!("{} bytes encoded when last checked", size),
+                    None => "not sent yet".to_string(),
+                };
+                match terrain.as_deref() {
+                    Some(terrain) => {
+                        let world: &dyn WorldState = terrain;
+                        let (width, height) = world.dimensions();
+                        info!(
+                            "    world {}x{} blocks in {} regions, {}",
+                            width,
+                            height,
+                            world.region_count(),
+                            encoded
+                        );
+                    }
+                    None => info!("    no world yet"),
+                }
+                info!(
+                    "    {} datagrams received ({} empty, {} with too many bodies, {} oversized), {} ticks deferred messages",
+                    status.metrics.datagrams_received,
+                    status.metrics.empty_datagrams,
+                    status.metrics.too_many_bodies,
+                    status.metrics.oversized_messages,
+                    status.metrics.deferred_ticks
+                );
+                info!(
+                    "    largest send burst {}, {} messages paced, {} smoothed, {} network ticks missed",
+                    status.metrics.largest_send_burst,
+                    status.metrics.paced_messages,
+                    status.metrics.smoothed_sends,
+                    status.metrics.missed_ticks
+                );
+                info!(
+                    "    {} sends skipped by the packets per second cap, {} datagrams dropped by a full receive queue",
+                    status.metrics.capped_sends,
+                    status.metrics.receive_queue_drops
+                );
+                info!(
+                    "    {} keepalives sent, {} empty packets skipped",
+                    status.metrics.keepalives, status.metrics.skipped_keepalives
+                );
+                info!("    time per system: {}", status.metrics.system_times);
+                info!(
+                    "    {} world checksums sent, {} desyncs reported",
+                    status.metrics.checksums_sent, status.metrics.desyncs
+                );
+                let acks = status
+                    .clients
+                    .iter()
+                    .fold(AckLatencyStats::default(), |acks, client| {
+                        acks.merge(client.ack_latency)
+                    });
+                info!(
+                    "    {} packets acked, ack latency min {:.1?} avg {:?} max {:.1?}",
+                    acks.acks,
+                    acks.min,
+                    acks.average(),
+                    acks.max
+                );
+                let cap = match server.config.max_bytes_per_second {
+                    Some(cap) => cap.to_string(),
+                    None => "uncapped".to_string(),
+                };
+                info!(
+                    "    {} of {} bytes used this second, {} network ticks capped",
+                    status.metrics.bytes_this_second, cap, status.metrics.bandwidth_capped_ticks
+                );
+                info!(
+                    "    {} physics steps, network tick at {} Hz",
+                    status.metrics.physics_steps, status.metrics.network_tick_hz
+                );
+                for client in &status.clients {
+                    info!(
+                        "    {} ({:?}{}) connected for {:.1?}, last ack {}, {} blocks mined, {} queued bodies, {} lost, prediction error avg {:.1} max {:.1}, one-way delay avg {:?} max {:.1?}, ack latency avg {:?} max {:.1?}, send jitter {:.1?}",
+                        client.addr,
+                        client.role,
+                        if client.idle { ", idle" } else { "" },
+                        client.session_duration,
+                        client.last_ack,
+                        client.blocks_mined,
+                        client.queued_bodies,
+                        client.detected_loss,
+                        client.prediction_error.average(),
+                        client.prediction_error.max,
+                        client.one_way_delay.average(),
+                        client.one_way_delay.max,
+                        client.ack_latency.average(),
+                        client.ack_latency.max,
+                        client.send_jitter
+                    );
+                }
+                // whoever sent it probably isn't watching the log
+                Ok(server.list_clients().to_string())
+            }
+            AdminCommand::SetRole(addr, role) => match server.clients.get_mut(addr) {
+                Some(client) => {
+                    client.set_role(*role);
+                    Ok(format!("{} is now a {:?}", addr, role))
+                }
+                None => Err(format!("no client at {}", addr)),
+            },
+            // the client is removed by apply_client_mutations, the ban starts now though
+            AdminCommand::Kick(addr) => {
+                if server.clients.contains_key(addr) {
+                    let now = server.now();
+                    server.ban(*addr, now);
+                    server.queue_removal(*addr, DisconnectReason::Kicked, &mut input_map);
+                    Ok(format!("kicking {}", addr))
+                } else {
+                    Err(format!("no client at {}", addr))
+                }
+            }
+            AdminCommand::SetName(addr, name) => {
+                if server.clients.contains_key(addr) {
+                    server.queue_mutation(ClientMutation::UpdateName(*addr, name.clone()));
+                    Ok(format!("{} is now called {}", addr, name))
+                } else {
+                    Err(format!("no client at {}", addr))
+                }
+            }
+            AdminCommand::DumpReplay(path) => match server.dump_replay(path) {
+                Ok(count) => Ok(format!("dumped {} frame(s) to {}", count, path.display())),
+                Err(e) => Err(format!("unable to dump to {}: {}", path.display(), e)),
+            },
+            AdminCommand::DumpInputs(addr, path) => match server.dump_input_history(*addr, path) {
+                Ok(count) => Ok(format!(
+                    "dumped {} input(s) from {} to {}",
+                    count,
+                    addr,
+                    path.display()
+                )),
+                Err(e) => Err(format!("unable to dump to {}: {}", path.display(), e)),
+            },
+            AdminCommand::Rewind(tick) => match server.snapshot_history.at_tick(*tick) {
+                Some((taken, entities)) => {
+                    for state in entities {
+                        info!("    {} at {:.1}, {:.1}", state.id, state.x, state.y);
+                    }
+                    Ok(format!("{} entities on tick {}", entities.len(), taken))
+                }
+                None => Err(format!("no snapshot kept for tick {}", tick)),
+            },
+            AdminCommand::SetSetting(key, value) => {
+                if server.settings.set(key, value) {
+                    Ok(format!("{} is now {}", key, value))
+                } else {
+                    Ok(format!("{} was already {}", key, value))
+                }
+            }
+            // the report owns its data, the server isn't borrowed while logging
+            AdminCommand::ListClients => Ok(server.list_clients().to_string()),
+        };
+        match &result {
+            // status has already logged everything and the reply is only the client list
+            Ok(_) if matches!(command, AdminCommand::Status) => {}
+            Ok(reply) => info!("admin: {}", reply),
+            Err(e) => warn!("admin: {}", e),
+        }
+        if let (Some(addr), Some(socket)) = (reply_to, socket.as_deref_mut()) {
+            let reply = match result {
+                Ok(reply) => reply,
+                Err(e) => format!("error: {}", e),
+            };
+            socket.reply(addr, &reply);
+        }
+    }
+}
+
+/// Log every client that leaves
+fn log_disconnects(mut disconnects: EventReader<ClientDisconnected>) {
+    for disconnect in disconnects.iter() {
+        info!(
+            addr = %disconnect.addr,
+            session_secs = disconnect.session_duration.as_secs_f64(),
+            reason = %disconnect.reason,
+            "client disconnected"
+        );
+    }
+}
+
+/// Take the entities clients that left played as out of the world
+fn despawn_departed_players(
+    mut commands: Commands,
+    mut disconnects: EventReader<ClientDisconnected>,
+    players: Query<(Entity, &NetworkId)>,
+) {
+    let departed: HashSet<u64> = disconnects.iter().filter_map(|d| d.player_id).collect();
+    for (entity, _) in players.iter().filter(|(_, id)| departed.contains(&id.0)) {
+        commands.entity(entity).despawn();
+    }
+}
+
+/// Log every tick that took too long
+fn log_tick_overruns(mut overruns: EventReader<TickOverrun>) {
+    for overrun in overruns.iter() {
+        warn!(
+            "{} took {:.1?}, more than its {:.1?}",
+            overrun.label, overrun.elapsed, overrun.budget
+        );
+    }
+}
+
+/// Start timing the tick called label, runs before any of its other systems
+/// this is real time whatever the server's clock says, it's the systems themselves being measured
+fn start_tick(mut timers: ResMut<TickTimers>, label: &'static str) {
+    timers.0.insert(label, Instant::now());
+}
+
+/// Stop timing the tick called label, sending a TickOverrun if it took longer than Server::tick_budget
+fn finish_tick(
+    mut timers: ResMut<TickTimers>,
+    server: Res<Server>,
+    mut overruns: EventWriter<TickOverrun>,
+    label: &'static str,
+) {
+    let elapsed = match timers.0.remove(label) {
+        Some(started) => started.elapsed(),
+        None => return,
+    };
+    let budget = server.tick_budget(label);
+    if elapsed > budget {
+        overruns.send(TickOverrun {
+            label,
+            elapsed,
+            budget,
+        });
+    }
+}
+
+/// Log the server giving up on its socket
+fn log_socket_failures(mut failures: EventReader<SocketFailed>) {
+    for failure in failures.iter() {
+        error!(
+            "server socket on {} is broken and {} attempt(s) to bind it again failed, stopping",
+            failure.addr, failure.attempts
+        );
+    }
+}
+
+/// Log every reliable body that made it to its client
+fn log_reliable_deliveries(mut deliveries: EventReader<ReliableDelivered>) {
+    for delivery in deliveries.iter() {
+        debug!(
+            "{} confirmed reliable body {}",
+            delivery.client, delivery.message_id
+        );
+    }
+}
+
+/// Once a second, log what each client sent and got since the last time
+fn log_client_bandwidth(mut server: ResMut<Server>) {
+    for client in server.clients.values_mut() {
+        let sample = client.roll_bandwidth();
+        let rtt = match client.rtt {
+            Some(rtt) => format!("{:.1?}", rtt),
+            None => "unknown".to_string(),
+        };
+        info!(
+            "{}: sent {} bytes in {} packets, received {} bytes in {} packets, rtt {}, {} lost",
+            client.addr,
+            sample.bytes_sent,
+            sample.packets_sent,
+            sample.bytes_received,
+            sample.packets_received,
+            rtt,
+            sample.lost
+        );
+    }
+}
+
+/// Give clients that came back from a restart with a token what they had before, see Server::prepare_restart
+fn restore_returning_players(
+    mut commands: Commands,
+    mut server: ResMut<Server>,
+    mut entities: Query<(Entity, &mut NetworkId, &mut Transform)>,
+) {
+    let mut names = Vec::new();
+    let Server {
+        clients,
+        restart_tickets,
+        ..
+    } = &mut *server;
+    for client in clients.values_mut() {
+        let token = match client.reconnect_token.take() {
+            Some(token) => token,
+            None => continue,
+        };
+        // already used, the token keeps coming until the client hears back from us
+        let ticket = match restart_tickets.remove(&token) {
+            Some(ticket) => ticket,
+            None => {
+                debug!("{} sent an unknown reconnect token", client.addr);
+                continue;
+            }
+        };
+        info!("{} is back from the restart", client.addr);
+        if let Some(id) = ticket.player_id {
+            // the player it got on joining again becomes the old one, unless that one is still around
+            let joined_as = client
+                .player_id
+                .replace(id)
+                .filter(|joined_as| *joined_as != id);
+            let still_around = entities.iter().any(|(_, n, _)| n.0 == id);
+            for (entity, mut network_id, mut transform) in entities.iter_mut() {
+                if Some(network_id.0) == joined_as {
+                    if still_around {
+                        commands.entity(entity).despawn();
+                        continue;
+                    }
+                    network_id.0 = id;
+                } else if network_id.0 != id {
+                    continue;
+                }
+                if let Some((x, y)) = ticket.position {
+                    transform.translation.x = x;
+                    transform.translation.y = y;
+                }
+            }
+        }
+        if let Some(name) = ticket.name {
+            names.push((client.addr, name));
+        }
+    }
+    for (addr, name) in names {
+        server.queue_mutation(ClientMutation::UpdateName(addr, name));
+    }
+}
+
+/// Append a row of metrics to the CSV file
+fn write_metrics_csv(mut server: ResMut<Server>) {
+    server.write_metrics_row();
+}
+
+/// Give back memory left over from clients that have gone
+fn compact_collections(
+    mut server: ResMut<Server>,
+    mut input_map: ResMut<HashMap<SocketAddr, PlayerInput>>,
+) {
+    let freed = server.compact(&mut input_map);
+    if freed > 0 {
+        debug!("compacting gave back room for {} entries", freed);
+    }
+}
+
+/// Server increase tick count
+fn increase_tick(mut server: ResMut<Server>) {
+    server.sequence += 1;
+}
+
+/// Server system
+pub(super) fn server_handle_messages(
+    mut commands: Commands,
+    mut server: ResMut<Server>,
+    mut input_map: ResMut<HashMap<SocketAddr, PlayerInput>>,
+    mut deliveries: EventWriter<ReliableDelivered>,
+    mut admin_commands: EventWriter<AdminCommand>,
+    entities: Query<&NetworkId>,
+) {
+    let start = Instant::now();
+    receive_messages(
+        &mut server,
+        &mut input_map,
+        &mut deliveries,
+        &mut admin_commands,
+    );
+    spawn_players(&mut server, &mut commands, &entities);
+    server.metrics.system_times.handle_messages.record(start);
+}
+
+/// Give every client that was let in something to play as, at the start with a NetworkId nothing else has
+/// observers only watch, so they don't get one
+fn spawn_players(server: &mut Server, commands: &mut Commands, entities: &Query<&NetworkId>) {
+    let mut next_id = entities
+        .iter()
+        .map(|id| id.0 + 1)
+        .fold(server.next_player_id, u64::max);
+    for client in server.clients.values_mut() {
+        if client.player_id.is_some() || client.observer {
+            continue;
+        }
+        commands
+            .spawn()
+            .insert(NetworkId(next_id))
+            .insert(Transform::default())
+            .insert(Velocity::default());
+        debug!("{} plays as {}", client.addr, next_id);
+        client.player_id = Some(next_id);
+        next_id += 1;
+    }
+    server.next_player_id = next_id;
+}
+
+/// Handle this game tick's messages, see server_handle_messages
+fn receive_messages(
+    server: &mut Server,
+    input_map: &mut HashMap<SocketAddr, PlayerInput>,
+    deliveries: &mut EventWriter<ReliableDelivered>,
+    admin_commands: &mut EventWriter<AdminCommand>,
+) {
+    // handle messages on our socket, but only up to our budget so other systems don't get starved
+    let sequence = server.sequence;
+    let preferred_codecs = server.config.payload_codecs();
+    let now = server.now();
+    for _ in 0..server.config.max_messages_per_tick {
+        match server.get_one_message() {
+            Ok((client, message)) => {
+                let addr = client.addr;
+                for message_id in compute_new_bodies(client, message, sequence, input_map, now) {
+                    deliveries.send(ReliableDelivered {
+                        client: addr,
+                        message_id,
+                    });
+                }
+                client.negotiate_codecs(preferred_codecs);
+                let desync = client.desync_report.take();
+                let mismatch = client.version_mismatch.take();
+                let name_claim = client.name_claim.take();
+                for line in std::mem::take(&mut client.admin_commands) {
+                    match server.client_admin_command(addr, &line) {
+                        Ok(command) => admin_commands.send(command),
+                        Err(e) => warn!("refused admin command '{}' from {}: {}", line, addr, e),
+                    }
+                }
+                if let Some((tick, client_checksum)) = desync {
+                    server.handle_desync(addr, tick, client_checksum);
+                }
+                if let Some(name) = name_claim {
+                    server.queue_mutation(ClientMutation::ClaimName(addr, name));
+                }
+                if let Some((min, max)) = mismatch {
+                    warn!(
+                        "{} speaks protocol versions {} to {}, we only speak {:?}",
+                        addr, min, max, SUPPORTED_PROTOCOL_VERSIONS
+                    );
+                    server.queue_removal(addr, DisconnectReason::UnsupportedVersion, input_map);
+                }
+            }
+            Err(ReceiveError::NoMessage) => {
+                // return whenever we run out of messages
+                return;
+            }
+            Err(ReceiveError::UnknownSender) => {
+                warn!("server recieve error: server is full!");
+            }
+            Err(ReceiveError::AddressNotAllowed) => {
+                warn!("server recieve error: refusing non-local client");
+            }
+            Err(ReceiveError::Redirected) => {
+                info!("server full, redirected a new client");
+            }
+            Err(ReceiveError::Banned) => {
+                warn!("server recieve error: refusing recently kicked client");
+            }
+            Err(ReceiveError::Decrypt(e)) => {
+                warn!("server recieve error: {}", e);
+            }
+            Err(ReceiveError::Unencrypted) => {
+                warn!("server recieve error: refusing unencrypted bodies after a key exchange");
+            }
+            Err(ReceiveError::DuplicateConnect) => {
+                // logged when it was refused
+            }
+            Err(ReceiveError::Vetoed) => {
+                debug!("middleware vetoed a message");
+            }
+            Err(ReceiveError::TooManyBodies(count)) => {
+                warn!(
+                    "server recieve error: dropped a message with {} bodies",
+                    count
+                );
+            }
+            Err(ReceiveError::EmptyDatagram) => {
+                // counted in the metrics, logging every probe would drown everything else out
+            }
+            Err(ReceiveError::Oversized { from, size }) => {
+                warn!(
+                    "server recieve error: {} sent a {} byte datagram, over the {} byte limit",
+                    from, size, server.config.max_message_size
+                );
+            }
+            Err(ReceiveError::IoError(e)) if !is_transient(&e) => {
+                // check_socket takes it from here, once it's clearly broken there's no point logging every error
+                if !server.socket_down() {
+                    error!("server socket error: {}", e);
+                }
+                return;
+            }
+            Err(e) => {
+                // anything else is a "real" error that we should complain about
+                error!("server receive error: {:?}", e);
+            }
+        }
+    }
+
+    // out of budget, anything left will be picked up next tick
+    server.metrics.deferred_ticks += 1;
+}
+
+/// Bind the socket again once it has clearly broken (network interface gone, ...)
+/// failed attempts wait longer each time like at startup, see ServerConfig::bind_retries
+/// after that many the server stops, with a SocketFailed
+fn check_socket(
+    mut commands: Commands,
+    mut server: ResMut<Server>,
+    mut failures: EventWriter<SocketFailed>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if !server.socket_down() || server.socket_failed {
+        return;
+    }
+    let now = server.now();
+    if server.next_rebind_at.is_some_and(|at| now < at) {
+        return;
+    }
+
+    let addr = server.config.bind_addr;
+    let retries = server.config.bind_retries;
+    if server.rebind_attempts > retries {
+        server.socket_failed = true;
+        failures.send(SocketFailed {
+            addr,
+            attempts: server.rebind_attempts,
+        });
+        commands.insert_resource(NextState(states::server::GameState::Stopped));
+        exit.send(AppExit);
+        return;
+    }
+
+    server.rebind_attempts += 1;
+    let attempt = server.rebind_attempts;
+    // let go of the old socket first, it's still holding the port
+    server.transport = Box::new(ClosedTransport);
+    match Server::open_transport(&server.config) {
+        Ok(transport) => {
+            info!("server socket on {} bound again", addr);
+            server.transport = transport;
+            server.socket_errors = 0;
+            server.rebind_attempts = 0;
+            server.next_rebind_at = None;
+        }
+        Err(e) => {
+            let delay = server
+                .config
+                .bind_retry_delay
+                .saturating_mul(2u32.saturating_pow(attempt - 1));
+            warn!(
+                "unable to bind server socket on {} again: {}, retrying in {:.1?} ({}/{})",
+                addr,
+                e,
+                delay,
+                attempt,
+                retries + 1
+            );
+            server.next_rebind_at = Some(now + delay);
+        }
+    }
+}
+
+/// Apply every client's inputs to the world
+/// each client's inputs since the last game tick go in the order it sent them, or its latest again if there weren't any
+/// the first of them that mines a block is the only one that does this tick, and the player moves by all of them
+/// clients go one at a time in the order they joined, so when two clients
+/// mine the same block on the same tick the one that joined first gets it
+/// clients whose player is gone (e.g. it died) don't get to do anything, see ServerConfig::missing_player_inputs
+/// TODO: check that the block is actually in reach of the client's player
+fn apply_inputs(
+    mut server: ResMut<Server>,
+    mut input_map: ResMut<HashMap<SocketAddr, PlayerInput>>,
+    mut terrain: Option<ResMut<Terrain>>,
+    mut terrain_changes: EventWriter<TerrainChanged>,
+    players: Query<&NetworkId>,
+) {
+    let alive: HashSet<u64> = players.iter().map(|id| id.0).collect();
+    let missing = server.inputs_without_player(&input_map, &alive);
+    if server.config.missing_player_inputs == MissingPlayerInputs::Ignore {
+        for addr in &missing {
+            debug!("{} has no player right now, ignoring its input", addr);
+            input_map.remove(addr);
+        }
+    }
+
+    let latest: Vec<(SocketAddr, PlayerInput)> = server
+        .inputs_in_join_order(&input_map)
+        .into_iter()
+        .map(|(addr, input)| (addr, input.clone()))
+        .collect();
+    let mut mined = Vec::new();
+    for (addr, latest) in latest {
+        let queued: Vec<PlayerInput> = match server.clients.get_mut(&addr) {
+            Some(client) => client
+                .queued_inputs
+                .drain(..)
+                .map(|(_, input)| input)
+                .collect(),
+            None => Vec::new(),
+        };
+        if missing.contains(&addr) {
+            continue;
+        }
+        let inputs = if queued.is_empty() {
+            vec![latest]
+        } else {
+            queued
+        };
+        // each input gets the same share of the tick, so moving by their average is stepping through them in turn
+        if let Some(client) = server.clients.get_mut(&addr) {
+            let total: f32 = inputs.iter().map(direction).sum();
+            client.tick_direction = Some(total / inputs.len() as f32);
+        }
+        // nothing to mine until there's terrain
+        let terrain = match terrain.as_deref_mut() {
+            Some(terrain) => terrain,
+            None => continue,
+        };
+        // one block a tick however many inputs came in, or sending more of them would mine faster
+        for input in inputs.iter().filter(|input| input.mine) {
+            // the client thinks there's a block here, so it's out of date
+            if terrain.cell(input.block_x, input.block_y).is_none() {
+                debug!(
+                    "{} tried to mine empty block {}, {}",
+                    addr, input.block_x, input.block_y
+                );
+                continue;
+            }
+            if remove_block(input.block_x, input.block_y, terrain).is_ok() {
+                mined.push(addr);
+                terrain_changes.send(TerrainChanged::block(input.block_x, input.block_y));
+                break;
+            }
+        }
+    }
+
+    for addr in mined {
+        if let Some(client) = server.clients.get_mut(&addr) {
+            client.blocks_mined += 1;
+        }
+    }
+}
+
+/// Run this game tick's physics, split into physics_substeps steps
+/// inputs steer players once per tick, or before every step if inputs_every_substep is set
+/// TODO: gravity and collisions once players are simulated here instead of on the client
+fn simulate_physics(
+    mut server: ResMut<Server>,
+    input_map: Res<HashMap<SocketAddr, PlayerInput>>,
+    mut query: Query<(&NetworkId, &mut Transform, &mut Velocity)>,
+) {
+    let substeps = server.config.physics_substeps.max(1);
+    let dt = 1. / (server.config.game_tick_hz.max(1) * substeps) as f32;
+    let every_substep = server.config.inputs_every_substep;
+
+    // which entity each client's inputs steer and which way, the latest input if apply_inputs didn't say
+    let steering: HashMap<u64, f32> = server
+        .inputs_in_join_order(&input_map)
+        .into_iter()
+        .filter_map(|(addr, input)| {
+            let client = server.clients.get(&addr)?;
+            let direction = client.tick_direction.unwrap_or_else(|| direction(input));
+            Some((client.player_id?, direction))
+        })
+        .collect();
+    for client in server.clients.values_mut() {
+        client.tick_direction = None;
+    }
+
+    for step in 0..substeps {
+        for (id, mut transform, mut velocity) in query.iter_mut() {
+            if step == 0 || every_substep {
+                if let Some(direction) = steering.get(&id.0) {
+                    *velocity = steer(*direction, *velocity);
+                }
+            }
+            transform.translation.x += velocity.x * dt;
+            transform.translation.y += velocity.y * dt;
+        }
+    }
+    server.metrics.physics_steps += substeps;
+}
+
+/// Which way an input steers, -1 for left, 1 for right and 0 for neither (or both)
+fn direction(input: &PlayerInput) -> f32 {
+    (input.right as i8 - input.left as i8) as f32
+}
+
+/// Velocity a player ends up with after being steered in direction, see direction
+fn steer(direction: f32, velocity: Velocity) -> Velocity {
+    Velocity {
+        x: direction * PLAYER_SPEED,
+        y: velocity.y,
+    }
+}
+
+/// Shrink map down to floor (or what it holds, if that's more) once it is less than a quarter full
+/// anything fuller is left alone, it would likely only grow back
+fn shrink_if_sparse<K: Eq + std::hash::Hash, V>(map: &mut HashMap<K, V>, floor: usize) {
+    if map.capacity() > floor && map.len() * 4 < map.capacity() {
+        map.shrink_to(floor);
+    }
+}
+
+/// Drop the times (oldest first) that are more than window before now
+fn forget_before(times: &mut VecDeque<Instant>, now: Instant, window: Duration) {
+    while times
+        .front()
+        .is_some_and(|at| now.saturating_duration_since(*at) > window)
+    {
+        times.pop_front();
+    }
+}
+
+/// Whether message is a client's first, the ones it sends before it has heard anything back from us
+fn is_fresh_connect(message: &ClientToServer) -> bool {
+    message.header.last_received_sequence == 0
+        && message
+            .bodies
+            .iter()
+            .any(|b| matches!(b, ClientBodyElem::Codecs(_)))
+}
 
 /// Process a client's message and push new bodies to the next packet sent to the client
+/// returns the ids of reliable bodies that this message confirmed
 /// TODO: will probably need direct World access in the future
 fn compute_new_bodies(
     client: &mut ClientInfo,
-    message: ClientToServer,
+    mut message: ClientToServer,
+    server_sequence: u64,
     input_map: &mut HashMap<SocketAddr, PlayerInput>,
+    now: Instant,
+) -> Vec<u64> {
+    // TODO: just impl Display or Debug instead
+    let mut bodies_str = "".to_string();
+    for body in &message.bodies {
+        bodies_str.push_str(match body {
+            ClientBodyElem::Ping => "ping,",
+            ClientBodyElem::TokenPing(_) => "token_ping,",
+            ClientBodyElem::EntityAck(_) => "entity_ack,",
+            ClientBodyElem::Input(_) => "input,",
+            ClientBodyElem::Codecs(_) => "codecs,",
+            ClientBodyElem::TerrainAck(_) => "terrain_ack,",
+            ClientBodyElem::PredictionError { .. } => "prediction_error,",
+            ClientBodyElem::AdminCommand(_) => "admin_command,",
+            ClientBodyElem::ReceiveTime { .. } => "receive_time,",
+            ClientBodyElem::KeyExchange(_) => "key_exchange,",
+            ClientBodyElem::Encrypted(_) => "encrypted,",
+            ClientBodyElem::RequestChunks { .. } => "request_chunks,",
+            ClientBodyElem::Observe => "observe,",
+            ClientBodyElem::DesyncReport { .. } => "desync_report,",
+            ClientBodyElem::TerrainScale(_) => "terrain_scale,",
+            ClientBodyElem::Pong(_) => "pong,",
+            ClientBodyElem::ProtocolVersions { .. } => "protocol_versions,",
+            ClientBodyElem::PackedInput(_) => "packed_input,",
+            ClientBodyElem::MtuAck(_) => "mtu_ack,",
+            ClientBodyElem::ReconnectToken(_) => "reconnect_token,",
+            ClientBodyElem::Subscribe { .. } => "subscribe,",
+            ClientBodyElem::Name(_) => "name,",
+        });
+    }
+    info!(
+        "server got message from client @ {} with {} bodies: {}",
+        client.addr,
+        message.bodies.len(),
+        bodies_str
+    );
+
+    // missing sequence numbers mean packets from the client got lost (or are still on their way)
+    let client_seq = message.header.current_sequence;
+    match client.last_seen_client_seq {
+        Some(last_seen) if client_seq > last_seen + 1 => {
+            let gap = client_seq - last_seen - 1;
+            client.detected_loss += gap;
+            client.bandwidth.lost += gap;
+            debug!(
+                "{} skipped {} sequence number(s), {} to {}",
+                client.addr, gap, last_seen, client_seq
+            );
+        }
+        _ => {}
+    }
+    if client.last_seen_client_seq < Some(client_seq) {
+        client.last_seen_client_seq = Some(client_seq);
+    }
+
+    // this message is in-order
+    // TODO: whenever the clients send inputs, ignore any that are out of order
+    // i.e. only use the most recent input
+    let mut delivered = Vec::new();
+    if message.header.last_received_sequence > client.last_ack {
+        client.last_ack = message.header.last_received_sequence;
+        let waiting = client.waiting.min(client.bodies.len());
+        client.bodies.truncate(waiting);
+        client.note_acked(client.last_ack, now);
+
+        // forget about reliable bodies that are now confirmed
+        // every packet since first_sent carried them, so getting any of those means they arrived
+        let last_ack = client.last_ack;
+        client.reliable.retain(|r| {
+            let confirmed = r.first_sent.is_some_and(|seq| seq <= last_ack);
+            if confirmed {
+                delivered.push(r.elem.id);
+            }
+            !confirmed
+        });
+
+        // a delivered baseline counts as the client acking its terrain version
+        if let Some((id, version)) = client.pending_baseline {
+            if delivered.contains(&id) {
+                client.pending_baseline = None;
+                client.ack_terrain(version);
+            }
+        }
+
+        // hearing back from us is what finishes a handshake
+        if matches!(
+            client.state,
+            ConnectionState::Connecting | ConnectionState::Reconnecting
+        ) {
+            if let Err(e) = client.transition(ConnectionState::Connected) {
+                warn!("{}: {}", client.addr, e);
+            }
+        }
+
+        // reset its drop timer
+        client.reset_drop_timer();
+    } else {
+        // message out of oder
+    }
+
+    // a packed input is handled just like a full one from here on
+    for body in &mut message.bodies {
+        if let ClientBodyElem::PackedInput(packed) = body {
+            *body = ClientBodyElem::Input(packed.unpack());
+        }
+    }
+
+    // every packet the client sends on one of its ticks has the same sequence, and only one of them has inputs
+    // inputs in another with a sequence inputs already came in are a copy the network made
+    let repeated_inputs = client
+        .input_history
+        .iter()
+        .any(|(sequence, _)| *sequence == message.header.current_sequence);
+
+    // anything other than acks and pings means someone is there
+    if message
+        .bodies
+        .iter()
+        .any(|b| matches!(b, ClientBodyElem::Input(_)))
+    {
+        client.note_input(now);
+    }
+
+    // compute our responses
+    let mut body_elems: Vec<ServerBodyElem> = message
+        .bodies
+        .iter()
+        // match client bodies to server bodies
+        .filter_map(|elem| match elem {
+            ClientBodyElem::Ping => Some(ServerBodyElem::Pong(message.header.current_sequence)),
+            ClientBodyElem::TokenPing(token) => Some(ServerBodyElem::TokenPong {
+                token: *token,
+                server_seq: server_sequence,
+            }),
+            // observers only watch, they don't get to move anything
+            ClientBodyElem::Input(_) if client.observer => {
+                debug!("ignoring input from observer {}", client.addr);
+                None
+            }
+            ClientBodyElem::Input(_) if !client.state.takes_inputs() => {
+                debug!(
+                    "discarding input from {}, it's {:?}",
+                    client.addr, client.state
+                );
+                None
+            }
+            ClientBodyElem::Input(_) if repeated_inputs => {
+                debug!(
+                    "{} sent inputs for sequence {} again, ignoring them",
+                    client.addr, message.header.current_sequence
+                );
+                None
+            }
+            ClientBodyElem::Input(input) => {
+                // TODO: handle player input
+                info!("server storing current inputs to input hashmap");
+                //insert the players inputs into a hashmap that is a resource
+                let icopy = input.clone();
+                client.record_input(message.header.current_sequence, &icopy);
+                client.queue_input(message.header.current_sequence, icopy.clone());
+                input_map.insert(client.addr, icopy);
+                None
+            }
+            ClientBodyElem::TerrainAck(version) => {
+                client.ack_terrain(*version);
+                None
+            }
+            // already handled when the client joined
+            ClientBodyElem::Observe => None,
+            ClientBodyElem::DesyncReport {
+                tick,
+                client_checksum,
+            } => {
+                client.desync_report = Some((*tick, *client_checksum));
+                None
+            }
+            ClientBodyElem::TerrainScale(scale) => {
+                client.set_terrain_scale(*scale);
+                None
+            }
+            ClientBodyElem::Pong(token) => {
+                client.note_probe_echo(*token, now);
+                None
+            }
+            ClientBodyElem::ProtocolVersions { min, max } => {
+                match negotiate_version(SUPPORTED_PROTOCOL_VERSIONS, *min..=*max) {
+                    Some(version) => {
+                        if version != client.protocol_version {
+                            info!("speaking protocol version {} with {}", version, client.addr);
+                        }
+                        client.protocol_version = version;
+                        Some(ServerBodyElem::ProtocolVersion(version))
+                    }
+                    None => {
+                        client.version_mismatch = Some((*min, *max));
+                        None
+                    }
+                }
+            }
+            ClientBodyElem::RequestChunks { ids } => {
+                // ids that don't exist get thrown out by enqueue_terrain, this keeps how many there are bounded
+                let room = MAX_REQUESTED_CHUNKS.saturating_sub(client.requested_chunks.len());
+                if ids.len() > room {
+                    warn!(
+                        "{} asked for {} chunk(s), only taking {}",
+                        client.addr,
+                        ids.len(),
+                        room
+                    );
+                }
+                client.requested_chunks.extend(ids.iter().take(room));
+                None
+            }
+            ClientBodyElem::EntityAck(snapshot) => {
+                // acks can arrive out of order, only ever move forward
+                if client.entity_baseline < Some(*snapshot) {
+                    client.entity_baseline = Some(*snapshot);
+                    // deltas are never sent against anything older
+                    client.entity_views.retain(|id, _| id >= snapshot);
+                }
+                None
+            }
+            ClientBodyElem::PredictionError { magnitude } => {
+                client.prediction_error.add(*magnitude);
+                None
+            }
+            ClientBodyElem::ReceiveTime {
+                sent_at_micros,
+                received_at_micros,
+            } => {
+                client
+                    .one_way_delay
+                    .add(*sent_at_micros, *received_at_micros);
+                None
+            }
+            // both dealt with in get_one_message, see ClientInfo::decrypt
+            ClientBodyElem::KeyExchange(_) | ClientBodyElem::Encrypted(_) => None,
+            // turned into an Input above
+            ClientBodyElem::PackedInput(_) => None,
+            ClientBodyElem::MtuAck(size) => {
+                client.note_mtu_ack(*size);
+                None
+            }
+            ClientBodyElem::ReconnectToken(token) => {
+                client.reconnect_token = Some(*token);
+                client.player_token = Some(*token);
+                None
+            }
+            ClientBodyElem::Subscribe { mask } => {
+                if *mask != client.subscriptions {
+                    info!("{} subscribed to {:#x}", client.addr, mask);
+                }
+                client.subscriptions = *mask;
+                None
+            }
+            ClientBodyElem::Name(name) => {
+                let name = name.trim();
+                if name.is_empty()
+                    || name.chars().count() > MAX_NAME_LEN
+                    || name.chars().any(char::is_control)
+                {
+                    warn!(
+                        "{} asked for a name that isn't allowed: {:?}",
+                        client.addr, name
+                    );
+                } else if client.name.as_deref() != Some(name) {
+                    // who else is using it only matters once every client's been heard from
+                    client.name_claim = Some(name.to_string());
+                }
+                None
+            }
+            ClientBodyElem::AdminCommand(line) => {
+                // whether the client may run it depends on the server, so it gets checked later
+                client.admin_commands.push(line.clone());
+                None
+            }
+            ClientBodyElem::Codecs(offered) => {
+                // which ones get used depends on the server's config, see ClientInfo::negotiate_codecs
+                client.offered_codecs = Some(offered.clone());
+                None
+            }
+        })
+        .collect();
+
+    // info!(
+    //     "server responses += {}",
+    //     // debug format of all new elems
+    //     body_elems.iter().fold(String::new(), |mut acc, s| {
+    //         acc.push_str(&format!("({:?}) ", s));
+    //         acc
+    //     })
+    // );
+
+    // queue up our responses to be sent our in the next packet
+    client.bodies.append(&mut body_elems);
+
+    // only keep pongs that are in response to a ping newer than or equals to the client's last_ack
+    client.bodies.retain(|elem| match elem {
+        ServerBodyElem::Pong(seq) => *seq >= client.last_ack,
+        ServerBodyElem::TokenPong { server_seq, .. } => *server_seq >= client.last_ack,
+        ServerBodyElem::EntitySnapshot { .. } => true, // replaced every tick by the next one
+        ServerBodyElem::EntityDelta { .. } => true,
+        ServerBodyElem::GameSettings(_) => true,
+        ServerBodyElem::Terrain(_) => true, // always keep terrains
+        ServerBodyElem::MapChange(_) => true,
+        ServerBodyElem::TerrainChunks(_) => true,
+        ServerBodyElem::TerrainVersion(_) => true,
+        ServerBodyElem::ChangedBounds(_) => true,
+        ServerBodyElem::Disconnect(_) => true,
+        ServerBodyElem::Redirect { .. } => true,
+        ServerBodyElem::Compressed { .. } => true,
+        ServerBodyElem::KeyExchange(_) => true,
+        ServerBodyElem::Encrypted(_) => true,
+        ServerBodyElem::PlayerList(_) => true,
+        ServerBodyElem::Announcement(_) => true,
+        ServerBodyElem::Coarse { .. } => true,
+        ServerBodyElem::Ping(_) => true,
+        ServerBodyElem::ProtocolVersion(_) => true,
+        ServerBodyElem::MtuProbe { .. } => true,
+        ServerBodyElem::Restarting { .. } => true,
+    });
+
+    delivered
+}
+
+pub(super) fn send_all_messages(mut server: ResMut<Server>) {
+    // nothing would get through, check_socket is working on it
+    if server.socket_down() {
+        return;
+    }
+    let start = Instant::now();
+    let now = server.now();
+    server.check_tick_watchdog(now);
+    if server.config.probe_mtu {
+        server.probe_mtus(now);
+    }
+    let network_tick = server.network_tick;
+    server.network_tick += 1;
+
+    // anything still waiting from last tick is late already, get it out before the new messages
+    if !server.paced.is_empty() {
+        let late: Vec<(SocketAddr, ServerToClient)> = server.paced.drain(..).collect();
+        server.send_burst(late);
+    }
+    server.send_smoothed(now);
+
+    // loop over clients
+    let sequence = server.sequence;
+    let game_tick_hz = server.config.game_tick_hz;
+    let network_tick_micros = 1_000_000 / server.metrics.network_tick_hz.max(1);
+    let max_packets_per_second = server.config.max_packets_per_second;
+    let idle_after = server.config.idle_after;
+    let keepalive_interval = server.config.keepalive_interval;
+    let probe_interval = server.config.probe_interval;
+
+    // the world has to have been left alone for a second, so every change has had time to go out
+    let checksum_interval = server.config.checksum_interval_ticks;
+    let terrain_version = server.terrain_version;
+    let world_checksum = server
+        .world_checksum
+        .filter(|_| checksum_interval > 0 && network_tick.is_multiple_of(checksum_interval))
+        .filter(|c| sequence.saturating_sub(c.changed_at) >= game_tick_hz)
+        .filter(|_| server.dirty_chunks.is_empty())
+        .map(|c| c.checksum);
+    let mut checksums_sent = 0;
+
+    // once the total bandwidth cap is reached, only what matters most goes out until the next second
+    if now.saturating_duration_since(server.bandwidth_second_start) >= Duration::from_secs(1) {
+        server.bandwidth_second_start = now;
+        server.metrics.bytes_this_second = 0;
+    }
+    let over_bandwidth = server
+        .config
+        .max_bytes_per_second
+        .is_some_and(|cap| server.metrics.bytes_this_second >= cap);
+    if over_bandwidth {
+        server.metrics.bandwidth_capped_ticks += 1;
+    }
+    let fits = |body: &ServerBodyElem| !over_bandwidth || body.priority() == BodyPriority::High;
+    let mut messages = Vec::with_capacity(server.clients.len());
+    // only clients that got a message this tick have their bodies filtered
+    let mut sent: HashMap<SocketAddr, PacketContents> = HashMap::new();
+    let mut capped_sends = 0;
+    let mut keepalives = 0;
+    let mut skipped_keepalives = 0;
+    let Server {
+        clients,
+        middleware,
+        ..
+    } = &mut *server;
+    for (client_addr, client_info) in clients.iter_mut() {
+        client_info.check_idle(now, idle_after);
+
+        // slow clients skip most ticks
+        if !client_info.is_due(network_tick) {
+            continue;
+        }
+
+        // time the round trip ourselves, the client might not be sending anything to ack
+        if client_info.protocol_version >= PROBE_PROTOCOL_VERSION
+            && client_info.probe_due(now, probe_interval)
+        {
+            client_info.enqueue_probe(now);
+        }
+
+        // nothing to send, only a header goes out so the client knows we're still here
+        let keepalive = !client_info.has_queued();
+        if keepalive && !client_info.keepalive_due(now, keepalive_interval) {
+            skipped_keepalives += 1;
+            continue;
+        }
+
+        // coarse bandwidth limit, bodies stay queued until there's room
+        if !client_info.take_send_slot(now, max_packets_per_second) {
+            capped_sends += 1;
+            continue;
+        }
+        if keepalive {
+            keepalives += 1;
+        }
+
+        // reliable bodies the client doesn't want, or can't decode, would never be acked, so they're dropped instead of piling up
+        // anything else it doesn't want is left out, and thrown away with everything else that went out
+        let subscriptions = client_info.subscriptions;
+        let version = client_info.protocol_version;
+        client_info.reliable.retain(|r| {
+            r.elem.body.subscribed(subscriptions) && r.elem.body.min_version() <= version
+        });
+
+        // queues stay at full resolution and uncompressed, bodies are only shrunk for the packet they go out in
+        let codecs = client_info.codecs;
+        // clients that can't decode Coarse get full resolution whatever they asked for
+        let scale = match version {
+            v if v >= COARSE_PROTOCOL_VERSION => client_info.terrain_scale,
+            _ => 1,
+        };
+        // under a known MTU, bodies go out in the order they were queued until one doesn't fit, the rest wait for the next packet
+        // the first thing in the packet goes out no matter how big, so a body bigger than the MTU doesn't get stuck
+        // reliable bodies that already went out always go again, every packet since first_sent has to carry them
+        let mut room = client_info.mtu.map(|mtu| mtu.saturating_sub(MTU_OVERHEAD));
+        let mut first = true;
+        let mut full = false;
+        let mut under_mtu = |len: usize, resent: bool| {
+            let fits = match &mut room {
+                None => true,
+                Some(_) if resent => true,
+                Some(_) if full => false,
+                Some(left) => first || len <= *left,
+            };
+            if fits {
+                room = room.map(|left| left.saturating_sub(len));
+            } else {
+                full = true;
+            }
+            first = false;
+            fits
+        };
+        let reliable: Vec<ReliableElem> = client_info
+            .reliable
+            .iter()
+            .filter(|r| fits(&r.elem.body))
+            .map(|r| {
+                let elem = ReliableElem {
+                    id: r.elem.id,
+                    body: r.elem.body.shrink(scale, codecs),
+                };
+                (elem, r.first_sent.is_some())
+            })
+            .filter(|(elem, resent)| under_mtu(encoded_len(elem), *resent))
+            .map(|(elem, _)| elem)
+            .collect();
+        // which of the queued bodies went out, or were thrown away, see keep_body
+        let mut went_out = Vec::with_capacity(client_info.bodies.len());
+        let mut bodies = Vec::with_capacity(client_info.bodies.len());
+        for body in &client_info.bodies {
+            if !fits(body) {
+                went_out.push(false);
+                continue;
+            }
+            let shrunk = body.shrink(scale, codecs);
+            if !body.subscribed(subscriptions) || shrunk.min_version() > version {
+                went_out.push(true);
+            } else if under_mtu(encoded_len(&shrunk), false) {
+                went_out.push(true);
+                bodies.push(shrunk);
+            } else {
+                went_out.push(false);
+            }
+        }
+        let contents = PacketContents {
+            bodies: went_out,
+            reliable: reliable.iter().map(|r| r.id).collect(),
+        };
+        let message = ServerToClient {
+            // snapshots and terrain were all queued on this tick, right before sending
+            header: ServerHeader {
+                sequence,
+                snapshot_tick: sequence,
+                game_tick_hz,
+                send_interval_micros: client_info.send_interval_ticks * network_tick_micros,
+                // filled in when it's sent
+                sent_at_micros: 0,
+                world_checksum: world_checksum
+                    .filter(|_| client_info.terrain_synced(terrain_version)),
+            },
+            // anything left out stays queued, unless it gets made again anyway, see send_all_messages
+            bodies,
+            reliable,
+        };
+        // vetoed bodies stay queued, as if the client was skipped this tick
+        if !middleware.allow_outbound(*client_addr, &message) {
+            debug!("middleware vetoed a message to {}", client_addr);
+            continue;
+        }
+        let checksum_sent = message.header.world_checksum.is_some();
+        // so do ones that couldn't be encrypted
+        let message = match client_info.encrypt(message) {
+            Some(message) => message,
+            None => continue,
+        };
+        if checksum_sent {
+            checksums_sent += 1;
+        }
+        client_info.smoothing.interval =
+            Duration::from_micros(client_info.send_interval_ticks * network_tick_micros);
+        messages.push((*client_addr, message));
+        sent.insert(*client_addr, contents);
+    }
+    server.metrics.capped_sends += capped_sends;
+    server.metrics.checksums_sent += checksums_sent;
+    server.metrics.keepalives += keepalives;
+    server.metrics.skipped_keepalives += skipped_keepalives;
+
+    // early messages wait for send_smoothed_messages, they count as sent already
+    if server.config.smooth_sends {
+        let Server {
+            clients,
+            smoothed,
+            metrics,
+            ..
+        } = &mut *server;
+        let mut on_time = Vec::with_capacity(messages.len());
+        for (addr, message) in messages {
+            let at = clients
+                .get_mut(&addr)
+                .map_or(now, |client| client.smoothing.schedule(now));
+            if at <= now {
+                on_time.push((addr, message));
+            } else {
+                metrics.smoothed_sends += 1;
+                smoothed.push((at, addr, message));
+            }
+        }
+        messages = on_time;
+    }
+
+    if server.config.pacing {
+        // send the first share now, the rest get sent by send_paced_messages over the tick
+        server.paced_per_step = messages.len().div_ceil(PACING_STEPS as usize).max(1);
+        let first: Vec<(SocketAddr, ServerToClient)> = messages
+            .drain(..server.paced_per_step.min(messages.len()))
+            .collect();
+        server.metrics.paced_messages += messages.len() as u64;
+        server.paced.extend(messages);
+        server.send_burst(first);
+    } else {
+        server.send_burst(messages);
+    }
+
+    // filter out client bodies
+    for client_info in server.clients.values_mut() {
+        // skipped clients hold on to everything until their turn
+        let contents = match sent.remove(&client_info.addr) {
+            Some(contents) => contents,
+            None => continue,
+        };
+
+        // bodies that were left out go first next time
+        let (waiting, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut client_info.bodies)
+            .into_iter()
+            .zip(contents.bodies.into_iter().chain(std::iter::repeat(true)))
+            .filter(|(b, went_out)| keep_body(b, *went_out))
+            .partition(|(_, went_out)| !went_out);
+        client_info.waiting = waiting.len();
+        client_info.bodies = waiting.into_iter().chain(rest).map(|(b, _)| b).collect();
+
+        // remember when reliable bodies first went out
+        for pending in &mut client_info.reliable {
+            if contents.reliable.contains(&pending.elem.id) {
+                pending.first_sent.get_or_insert(sequence);
+            }
+        }
+    }
+
+    server.metrics.system_times.send_messages.record(start);
+}
+
+/// Which of a client's queued bodies made it into the packet it was sent, see send_all_messages
+struct PacketContents {
+    /// One for each of ClientInfo::bodies, whether it went out or was thrown away
+    bodies: Vec<bool>,
+    /// Ids of the reliable bodies that went out
+    reliable: Vec<u64>,
+}
+
+/// Whether a body stays queued after a packet went out to its client, went_out is whether it was in it
+/// bodies that were left out wait for the next packet, unless a newer one gets made anyway
+fn keep_body(body: &ServerBodyElem, went_out: bool) -> bool {
+    match body {
+        ServerBodyElem::Pong(_) => true, // keep pongs until we know they were received
+        ServerBodyElem::TokenPong { .. } => true,
+        ServerBodyElem::EntitySnapshot { .. } => false, // a newer one comes next tick
+        ServerBodyElem::EntityDelta { .. } => false,
+        ServerBodyElem::GameSettings(_) => false, // only ever sent reliably
+        ServerBodyElem::PlayerList(_) => false,
+        ServerBodyElem::Announcement(_) => false,
+        ServerBodyElem::Terrain(_) => false, // never keep old terrains
+        ServerBodyElem::MapChange(_) => false, // only ever sent reliably
+        // changes that never made it are resent until they're acked, see enqueue_terrain
+        ServerBodyElem::TerrainChunks(_) => false,
+        ServerBodyElem::TerrainVersion(_) => false,
+        ServerBodyElem::ChangedBounds(_) => false,
+        ServerBodyElem::Disconnect(_) => false, // sent on its own, see Server::disconnect
+        ServerBodyElem::Redirect { .. } => false, // only sent to non-clients
+        ServerBodyElem::Compressed { .. } => false, // only made while sending, see send_all_messages
+        ServerBodyElem::Coarse { .. } => false,
+        // a lost probe just never gets echoed, one that waited would make the round trip look longer
+        ServerBodyElem::Ping(_) => false,
+        ServerBodyElem::ProtocolVersion(_) => !went_out, // answered again if the client asks again
+        ServerBodyElem::MtuProbe { .. } => false, // only sent on its own, see Server::probe_mtus
+        ServerBodyElem::Restarting { .. } => false, // only ever sent reliably
+        ServerBodyElem::KeyExchange(_) => false,  // only added while sending
+        ServerBodyElem::Encrypted(_) => false,
+    }
+}
+
+/// Send the next share of messages held back by pacing
+fn send_paced_messages(mut server: ResMut<Server>) {
+    let count = server.paced_per_step.min(server.paced.len());
+    if count == 0 {
+        return;
+    }
+    let step: Vec<(SocketAddr, ServerToClient)> = server.paced.drain(..count).collect();
+    server.send_burst(step);
+}
+
+/// Send messages held back by smooth_sends once it's their turn
+/// runs every frame, so they go out as close to it as frames allow
+fn send_smoothed_messages(mut server: ResMut<Server>) {
+    let now = server.now();
+    server.send_smoothed(now);
+}
+
+/// Remember which chunks changed so enqueue_terrain only sends those
+/// runs every frame so that no events get missed, changes while there's no terrain are dropped
+fn collect_terrain_changes(
+    mut terrain_changes: EventReader<TerrainChanged>,
+    mut server: ResMut<Server>,
+    terrain: Option<Res<Terrain>>,
 ) {
-    // TODO: just impl Display or Debug instead
-    let mut bodies_str = "".to_string();
-    for body in &message.bodies {
-        bodies_str.push_str(match body {
-            ClientBodyElem::Ping => "ping,",
-            ClientBodyElem::Input(_) => "input,",
+    let terrain = match terrain {
+        Some(terrain) => terrain,
+        None => {
+            terrain_changes.clear();
+            return;
+        }
+    };
+    let world: &dyn WorldState = terrain.as_ref();
+    let chunk_count = world.region_count();
+    for change in terrain_changes.iter() {
+        // chunks past the end of the terrain don't exist, so there's nothing to send
+        let last = (*change.region.end()).min(chunk_count.saturating_sub(1));
+        if chunk_count > 0 && *change.region.start() <= last {
+            for chunk in *change.region.start()..=last {
+                let whole = BlockBounds::chunk(chunk);
+                let bounds = change
+                    .blocks
+                    .map_or(Some(whole), |blocks| blocks.intersection(whole));
+                if let Some(bounds) = bounds {
+                    let dirty = server.dirty_chunks.entry(chunk).or_insert(bounds);
+                    *dirty = dirty.union(bounds);
+                }
+            }
+        }
+    }
+}
+
+/// Where every networked entity is, sorted by entity id
+fn entity_states(query: &Query<(&NetworkId, &Transform)>) -> Vec<EntityState> {
+    let mut entities: Vec<EntityState> = query
+        .iter()
+        .map(|(id, transform)| EntityState {
+            id: id.0,
+            x: transform.translation.x,
+            y: transform.translation.y,
+        })
+        .collect();
+    entities.sort_by_key(|state| state.id);
+    entities
+}
+
+/// Remember where every entity ended up this game tick, see SnapshotHistory
+fn record_snapshot_history(mut server: ResMut<Server>, query: Query<(&NetworkId, &Transform)>) {
+    if server.config.snapshot_history == 0 {
+        return;
+    }
+    let tick = server.sequence;
+    server.snapshot_history.push(tick, entity_states(&query));
+}
+
+/// Add where every networked entity is to the next packet sent
+/// clients get what changed since the last snapshot they acked, or all of it if we don't have that one
+fn enqueue_entity_snapshot(mut server: ResMut<Server>, query: Query<(&NetworkId, &Transform)>) {
+    let entities = entity_states(&query);
+
+    // only a new snapshot when something actually changed
+    let server = &mut *server;
+    let snapshot = match &server.latest_snapshot {
+        Some((id, latest)) if *latest == entities => *id,
+        // nothing to send, and nothing the clients need to get rid of
+        None if entities.is_empty() => return,
+        _ => {
+            let id = server.next_snapshot_id;
+            server.next_snapshot_id += 1;
+            server.latest_snapshot = Some((id, entities));
+            id
+        }
+    };
+    let current = match &server.latest_snapshot {
+        Some((_, entities)) => entities,
+        None => return,
+    };
+    let view_radius = server.config.entity_view_radius;
+
+    for client in server.clients.values_mut() {
+        // only what's around the client's player, if it has one
+        let center = client
+            .player_id
+            .and_then(|id| current.iter().find(|state| state.id == id));
+        let view: Vec<EntityState> = match (view_radius, center) {
+            (Some(radius), Some(center)) => {
+                let radius = radius as f32;
+                current
+                    .iter()
+                    .filter(|state| {
+                        let (dx, dy) = (state.x - center.x, state.y - center.y);
+                        dx * dx + dy * dy <= radius * radius
+                    })
+                    .copied()
+                    .collect()
+            }
+            _ => current.clone(),
+        };
+
+        let base = client
+            .entity_baseline
+            .and_then(|baseline| Some((baseline, client.entity_views.get(&baseline)?)));
+        let body = match base {
+            Some((baseline, base)) => {
+                let (changed, removed) = entity_delta(base, &view);
+                // the client already has exactly this
+                if changed.is_empty() && removed.is_empty() {
+                    continue;
+                }
+                ServerBodyElem::EntityDelta {
+                    snapshot,
+                    baseline,
+                    changed,
+                    removed,
+                }
+            }
+            // never acked one, or it's so old we forgot it
+            None => ServerBodyElem::EntitySnapshot {
+                snapshot,
+                entities: view.clone(),
+            },
+        };
+
+        // an older snapshot that hasn't gone out yet is out of date
+        client.bodies.retain(|b| {
+            !matches!(
+                b,
+                ServerBodyElem::EntitySnapshot { .. } | ServerBodyElem::EntityDelta { .. }
+            )
+        });
+        client.bodies.push(body);
+        client.entity_views.insert(snapshot, view);
+        while client.entity_views.len() > ENTITY_SNAPSHOT_HISTORY {
+            client.entity_views.pop_first();
+        }
+    }
+}
+
+/// Keep the checksum of the world up to date, it only has to be worked out again when the terrain changes
+/// the last one stays while there's no terrain
+fn update_world_checksum(mut server: ResMut<Server>, terrain: Option<Res<Terrain>>) {
+    let terrain = match terrain {
+        Some(terrain) => terrain,
+        None => return,
+    };
+    if server.world_checksum.is_some() && !terrain.is_changed() {
+        return;
+    }
+    let checksum = terrain.checksum();
+    if server.world_checksum.map(|c| c.checksum) != Some(checksum) {
+        server.world_checksum = Some(WorldChecksum {
+            checksum,
+            changed_at: server.sequence,
+        });
+    }
+}
+
+/// Reliably send the game settings to new clients, and to everyone when they change
+fn enqueue_settings(mut server: ResMut<Server>) {
+    let server = &mut *server;
+    let settings = &server.settings;
+    for client in server.clients.values_mut() {
+        if client.settings_version >= Some(settings.version) {
+            continue;
+        }
+        // older settings that haven't made it yet are out of date
+        client
+            .reliable
+            .retain(|r| !matches!(r.elem.body, ServerBodyElem::GameSettings(_)));
+        client.enqueue_reliable(ServerBodyElem::GameSettings(settings.clone()));
+        client.settings_version = Some(settings.version);
+    }
+}
+
+/// Why a terrain can't go out to clients, see check_terrain
+#[derive(Debug)]
+enum TerrainCheckError {
+    Encode(bincode::error::EncodeError),
+    /// It encodes, but clients would throw it away
+    Decode(bincode::error::DecodeError),
+}
+
+impl std::fmt::Display for TerrainCheckError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TerrainCheckError::Encode(e) => write!(f, "unable to encode terrain: {}", e),
+            TerrainCheckError::Decode(e) => write!(f, "clients would reject terrain: {}", e),
+        }
+    }
+}
+
+/// Make sure a world can be encoded, and decoded again the way clients will
+/// much better to find out here than to have every packet carrying it fail to send
+/// returns the decoded copy and how big it was encoded
+fn check_terrain<W: WorldState>(world: &W) -> Result<(W, usize), TerrainCheckError> {
+    let encoded = world.encode().map_err(TerrainCheckError::Encode)?;
+    let decoded = W::restore(&encoded).map_err(TerrainCheckError::Decode)?;
+    Ok((decoded, encoded.len()))
+}
+
+/// Add the terrain to the next packet sent
+/// new clients get all of it, everyone else the chunks that changed since the last version they acked
+/// skipped while there's no terrain yet (it's still being generated, or loading it failed), warning once until there is
+/// TODO: use reference for terrain instead of clone?
+fn enqueue_terrain(
+    mut server: ResMut<Server>,
+    terrain: Option<Res<Terrain>>,
+    mut warned: Local<bool>,
+) {
+    let terrain = match terrain {
+        Some(terrain) => terrain,
+        None => {
+            if !*warned {
+                warn!("no terrain yet, not sending clients any until there is");
+                *warned = true;
+            }
+            return;
+        }
+    };
+    *warned = false;
+    let start = Instant::now();
+    queue_terrain(&mut server, &terrain);
+    server.metrics.system_times.enqueue_terrain.record(start);
+}
+
+/// Queue whatever terrain each client needs, see enqueue_terrain
+fn queue_terrain(server: &mut Server, terrain: &Terrain) {
+    // new clients need something to apply changes to, and can't afford to lose it
+    // clients that came back still having their terrain (see returning_baselines) only get what changed
+    if server
+        .clients
+        .values()
+        .any(|client| client.terrain_baselines.is_empty() || !client.terrain_welcomed)
+    {
+        let version = server.terrain_version;
+        match check_terrain(terrain) {
+            Ok((good, size)) => {
+                server.last_good_terrain = Some((good, version));
+                server.last_good_terrain_size = Some(size);
+            }
+            Err(e) => error!(
+                "terrain version {} can't be sent, new clients get the last good one instead: {}",
+                version, e
+            ),
+        }
+        let Server {
+            clients,
+            last_good_terrain,
+            chunk_versions,
+            ..
+        } = &mut *server;
+        match last_good_terrain {
+            Some((good, good_version)) => {
+                for client in clients.values_mut() {
+                    if !client.terrain_welcomed && !client.terrain_baselines.is_empty() {
+                        let base = *client.terrain_baselines.first().unwrap();
+                        let changed: Vec<&Chunk> = terrain
+                            .chunks
+                            .iter()
+                            .filter(|chunk| chunk_versions.get(&chunk.chunk_number) > Some(&base))
+                            .collect();
+                        // so much changed while it was gone that all of it is no bigger
+                        if encoded_len(&changed) < encoded_len(good) {
+                            info!(
+                                "{} came back with terrain version {}, only sending what changed",
+                                client.addr, base
+                            );
+                            client.terrain_welcomed = true;
+                            continue;
+                        }
+                        client.terrain_baselines.clear();
+                    }
+                    if client.terrain_baselines.is_empty() {
+                        client
+                            .enqueue_baseline(ServerBodyElem::Terrain(good.clone()), *good_version);
+                        client.terrain_welcomed = true;
+                        info!(
+                            "enqueued terrain baseline version {} for {}",
+                            good_version, client.addr
+                        );
+                    }
+                }
+            }
+            None => error!("no good terrain to send to new clients"),
+        }
+    }
+
+    // chunks clients asked for again go out straight away, not on the terrain interval
+    for client in server.clients.values_mut() {
+        if client.requested_chunks.is_empty() {
+            continue;
+        }
+        let requested = std::mem::take(&mut client.requested_chunks);
+        let chunks: Vec<Chunk> = terrain
+            .chunks
+            .iter()
+            .filter(|chunk| requested.contains(&chunk.chunk_number))
+            .cloned()
+            .collect();
+        if chunks.len() < requested.len() {
+            warn!(
+                "{} asked for {} chunk(s) that don't exist",
+                client.addr,
+                requested.len() - chunks.len()
+            );
+        }
+        debug!("resending {} chunk(s) to {}", chunks.len(), client.addr);
+        if !chunks.is_empty() {
+            client.bodies.push(ServerBodyElem::TerrainChunks(chunks));
+        }
+    }
+
+    // terrain changes slowly, so changes don't need to go out every tick
+    let tick = server.terrain_tick_counter;
+    server.terrain_tick_counter += 1;
+    if !tick.is_multiple_of(server.config.terrain_interval_ticks) {
+        return;
+    }
+
+    if !server.dirty_chunks.is_empty() {
+        server.terrain_version += 1;
+        let version = server.terrain_version;
+        let dirty = std::mem::take(&mut server.dirty_chunks);
+        for (chunk, bounds) in dirty {
+            server.chunk_versions.insert(chunk, version);
+            let history = server.chunk_bounds.entry(chunk).or_default();
+            history.push_back((version, bounds));
+            // still covers every change since the older version, and some from before it
+            if history.len() > CHANGED_BOUNDS_HISTORY {
+                let (_, oldest) = history.pop_front().unwrap();
+                history[0].1 = history[0].1.union(oldest);
+            }
+        }
+    }
+
+    // anyone behind gets everything since the oldest version they might have
+    let version = server.terrain_version;
+    let Server {
+        clients,
+        chunk_versions,
+        chunk_bounds,
+        ..
+    } = &mut *server;
+    for client in clients.values_mut() {
+        let base = match client.terrain_baselines.first() {
+            Some(base) if *base < version => *base,
+            _ => continue,
+        };
+        let chunks: Vec<Chunk> = terrain
+            .chunks
+            .iter()
+            .filter(|chunk| chunk_versions.get(&chunk.chunk_number) > Some(&base))
+            .cloned()
+            .collect();
+        debug!(
+            "enqueued {} changed chunk(s) for {}, version {} to {}",
+            chunks.len(),
+            client.addr,
+            base,
+            version
+        );
+        let bounds = changed_bounds(chunk_bounds, &chunks, base);
+        client.enqueue_terrain_delta(chunks, bounds, version);
+        client.terrain_baselines.insert(version);
+    }
+}
+
+/// Where blocks in chunks changed since version base, one bounds per chunk unless there are too many
+fn changed_bounds(
+    history: &BTreeMap<u64, VecDeque<(u64, BlockBounds)>>,
+    chunks: &[Chunk],
+    base: u64,
+) -> Vec<BlockBounds> {
+    let bounds: Vec<BlockBounds> = chunks
+        .iter()
+        .map(|chunk| {
+            history
+                .get(&chunk.chunk_number)
+                .and_then(|changes| {
+                    changes
+                        .iter()
+                        .filter(|(version, _)| *version > base)
+                        .map(|(_, bounds)| *bounds)
+                        .reduce(BlockBounds::union)
+                })
+                .unwrap_or_else(|| BlockBounds::chunk(chunk.chunk_number))
+        })
+        .collect();
+    if bounds.len() > MAX_CHANGED_BOUNDS {
+        return bounds
+            .into_iter()
+            .reduce(BlockBounds::union)
+            .into_iter()
+            .collect();
+    }
+    bounds
+}
+
+/// Debug log of what every client has queued up
+#[cfg(debug_assertions)]
+fn log_queue_depths(server: Res<Server>) {
+    for summary in server.queue_summaries() {
+        debug!("queue for {}", summary);
+    }
+}
+
+/// Speed the network tick up or slow it down to suit how many clients are connected
+fn tune_network_tick(mut server: ResMut<Server>, timesteps: Option<ResMut<FixedTimesteps>>) {
+    let hz = server.config.tuned_network_tick_hz(server.clients.len());
+    if hz != server.metrics.network_tick_hz {
+        info!(
+            "{} client(s) connected, network tick now {} Hz",
+            server.clients.len(),
+            hz
+        );
+        server.metrics.network_tick_hz = hz;
+    }
+
+    // the timesteps only show up once they've run, so keep checking until the change sticks
+    if let Some(mut timesteps) = timesteps {
+        let step = Duration::from_secs_f64(1. / hz as f64);
+        if let Some(info) = timesteps.get_mut(NETWORK_TICK_LABEL) {
+            info.step = step;
+        }
+        if let Some(info) = timesteps.get_mut(PACING_LABEL) {
+            info.step = step / PACING_STEPS as u32;
+        }
+    }
+}
+
+/// Apply every queued ClientMutation, in the order they were queued
+/// the one point in the network tick (after sending, before dropping) where clients come and go
+fn apply_client_mutations(
+    mut server: ResMut<Server>,
+    mut input_map: ResMut<HashMap<SocketAddr, PlayerInput>>,
+    mut disconnects: EventWriter<ClientDisconnected>,
+) {
+    let mutations = std::mem::take(&mut server.mutations);
+    for mutation in mutations {
+        if let Some(disconnect) = server.apply_mutation(mutation) {
+            input_map.remove(&disconnect.addr);
+            disconnects.send(disconnect);
+        }
+    }
+}
+
+/// Queue removing clients that haven't responded in a while, apply_client_mutations removes them right after
+fn drop_disconnected_clients(mut server: ResMut<Server>) {
+    // until_drop counts game ticks, this many go by every network tick
+    let game_ticks = server.game_ticks_per_network_tick();
+
+    // drop clients that haven't responded in a while, unless they only just connected
+    let start = Instant::now();
+    let now = server.now();
+    let timed_out: Vec<SocketAddr> = server
+        .clients
+        .values()
+        .filter(|client| client.until_drop < game_ticks && now >= client.grace_until)
+        .map(|client| client.addr)
+        .collect();
+    for address in timed_out {
+        warn!("dropping client {}", address);
+        // apply_client_mutations also forgets any input that came in this tick without an ack that would have kept it around
+        server.queue_mutation(ClientMutation::Remove(address, DisconnectReason::Timeout));
+    }
+
+    // loop through active clients
+    for client_info in server.clients.values_mut() {
+        client_info.until_drop = client_info.until_drop.saturating_sub(game_ticks);
+    }
+
+    server.prune_bans(now);
+    server.metrics.system_times.drop_clients.record(start);
+}
+
+/// unit tests
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::world::{CHUNK_HEIGHT, CHUNK_WIDTH, MAX_TERRAIN_CHUNKS};
+    use bevy::{ecs::schedule::IntoSystemDescriptor, log::Level, utils::tracing};
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    /// Creates a server on an in-process network, the network is returned so clients can join it
+    fn test_server() -> (Server, ChannelNetwork) {
+        test_server_with(ServerConfig::default())
+    }
+
+    /// Like test_server, with config instead of the defaults
+    pub(in crate::network) fn test_server_with(config: ServerConfig) -> (Server, ChannelNetwork) {
+        let network = ChannelNetwork::new();
+        let server_addr = SocketAddr::from((DEFAULT_SERVER_IP, DEFAULT_SERVER_PORT));
+        let server = Server::with_transport(Box::new(network.endpoint(server_addr)), config);
+        (server, network)
+    }
+
+    /// Creates a client endpoint on the network
+    pub(in crate::network) fn test_client(network: &ChannelNetwork, port: u16) -> ChannelTransport {
+        network.endpoint(SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    /// Runs one system once on the world
+    pub(in crate::network) fn run_system<Params>(
+        world: &mut World,
+        system: impl IntoSystemDescriptor<Params>,
+    ) {
+        let mut stage = SystemStage::single_threaded();
+        stage.add_system(system);
+        stage.run(world);
+    }
+
+    /// Sends held back by max_packets_per_second, for tests outside this module
+    pub(in crate::network) fn capped_sends(server: &Server) -> u64 {
+        server.metrics.capped_sends
+    }
+
+    /// Drop the welcome queued for every client, for tests about what comes after it
+    fn forget_welcomes(server: &mut Server) {
+        for client in server.clients.values_mut() {
+            client.reliable.retain(|pending| {
+                !matches!(
+                    pending.elem.body,
+                    ServerBodyElem::GameSettings(_)
+                        | ServerBodyElem::PlayerList(_)
+                        | ServerBodyElem::Announcement(_)
+                )
+            });
+        }
+    }
+
+    /// World with a server and everything its systems need
+    pub(in crate::network) fn test_world(server: Server) -> World {
+        let mut world = World::new();
+        world.insert_resource(server);
+        world.insert_resource(HashMap::<SocketAddr, PlayerInput>::new());
+        world.insert_resource(Terrain::empty());
+        world.insert_resource(Events::<AdminCommand>::default());
+        world.insert_resource(Events::<AdminRequest>::default());
+        world.insert_resource(Events::<ClientDisconnected>::default());
+        world.insert_resource(Events::<TerrainChanged>::default());
+        world.insert_resource(Events::<ReliableDelivered>::default());
+        world
+    }
+
+    /// Send a message to the server from a client endpoint, acknowledging the given sequence
+    fn client_send(client: &ChannelTransport, ack: u64, bodies: Vec<ClientBodyElem>) {
+        let message = ClientToServer::builder()
+            .ack(ack)
+            .sequence(ack)
+            .bodies(bodies)
+            .build();
+        let server_addr = SocketAddr::from((DEFAULT_SERVER_IP, DEFAULT_SERVER_PORT));
+        send_message(client, server_addr, message).unwrap();
+    }
+
+    /// Get the next message waiting at a client endpoint, if any
+    fn client_recv(client: &ChannelTransport) -> Option<ServerToClient> {
+        let mut buffer = [0u8; BUFFER_SIZE];
+        let (size, _) = client.recv_from(&mut buffer).ok()?;
+        Some(
+            bincode::decode_from_slice(&buffer[..size], BINCODE_CONFIG)
+                .unwrap()
+                .0,
+        )
+    }
+
+    #[test]
+    fn server_talks_over_channel_transport() {
+        let (mut server, network) = test_server();
+        let client = test_client(&network, 9000);
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+
+        let (info, message) = server.get_one_message().unwrap();
+        assert_eq!(info.addr, client.local_addr().unwrap());
+        assert!(matches!(message.bodies[..], [ClientBodyElem::Ping]));
+        assert!(matches!(
+            server.get_one_message(),
+            Err(ReceiveError::NoMessage)
+        ));
+
+        let reply = ServerToClient {
+            header: ServerHeader {
+                sequence: 7,
+                ..Default::default()
+            },
+            bodies: vec![ServerBodyElem::Pong(7)],
+            reliable: Vec::new(),
+        };
+        server
+            .send_message(client.local_addr().unwrap(), reply)
+            .unwrap();
+
+        let received = client_recv(&client).unwrap();
+        assert_eq!(received.header.sequence, 7);
+        assert!(matches!(received.bodies[..], [ServerBodyElem::Pong(7)]));
+    }
+
+    #[test]
+    fn map_change_survives_packet_drop() {
+        let (server, network) = test_server();
+        let dropped = test_client(&network, 9000);
+        let delivered = test_client(&network, 9001);
+        let mut world = test_world(server);
+
+        // both clients connect
+        client_send(&dropped, 0, vec![ClientBodyElem::Ping]);
+        client_send(&delivered, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        assert_eq!(world.resource::<Server>().clients.len(), 2);
+        forget_welcomes(&mut world.resource_mut::<Server>());
+
+        let new_map = Terrain::new(1);
+        world
+            .resource_mut::<Events<AdminCommand>>()
+            .send(AdminCommand::MapChange(new_map.clone()));
+        run_system(&mut world, handle_admin_commands);
+        assert_eq!(*world.resource::<Terrain>(), new_map);
+
+        // first packet only reaches one of the clients
+        run_system(&mut world, send_all_messages);
+        let first = client_recv(&delivered).unwrap();
+        assert!(matches!(
+            &first.reliable[..],
+            [ReliableElem { body: ServerBodyElem::MapChange(t), .. }] if *t == new_map
+        ));
+        assert!(client_recv(&dropped).is_some()); // lost on the way
+
+        // next packet carries the map change again
+        world.resource_mut::<Server>().sequence += 1;
+        run_system(&mut world, send_all_messages);
+        let second = client_recv(&dropped).unwrap();
+        assert!(matches!(
+            &second.reliable[..],
+            [ReliableElem { body: ServerBodyElem::MapChange(t), .. }] if *t == new_map
+        ));
+        assert_eq!(second.reliable[0].id, first.reliable[0].id);
+        client_recv(&delivered).unwrap();
+
+        // once acknowledged it stops being resent, but only for that client
+        client_send(&dropped, second.header.sequence, vec![]);
+        run_system(&mut world, server_handle_messages);
+        world.resource_mut::<Server>().sequence += 1;
+        run_system(&mut world, send_all_messages);
+        assert!(client_recv(&dropped).unwrap().reliable.is_empty());
+        assert_eq!(client_recv(&delivered).unwrap().reliable.len(), 1);
+    }
+
+    #[test]
+    fn dropped_client_reports_session_duration() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+
+        // pretend the client joined a while ago and has since gone quiet
+        {
+            let mut server = world.resource_mut::<Server>();
+            let info = server.clients.values_mut().next().unwrap();
+            info.connected_at -= Duration::from_secs(30);
+            info.grace_until = info.connected_at;
+            info.until_drop = 0;
+
+            let status = server.status();
+            assert_eq!(status.clients.len(), 1);
+            assert!(status.clients[0].session_duration >= Duration::from_secs(30));
+        }
+        run_system(&mut world, drop_disconnected_clients);
+        run_system(&mut world, apply_client_mutations);
+        assert!(world.resource::<Server>().clients.is_empty());
+
+        let events = world.resource::<Events<ClientDisconnected>>();
+        let mut reader = events.get_reader();
+        let disconnects: Vec<&ClientDisconnected> = reader.iter(events).collect();
+        assert_eq!(disconnects.len(), 1);
+        assert_eq!(disconnects[0].addr, client.local_addr().unwrap());
+        assert!(disconnects[0].session_duration >= Duration::from_secs(30));
+        assert!(disconnects[0].session_duration < Duration::from_secs(60));
+        assert_eq!(disconnects[0].reason, DisconnectReason::Timeout);
+
+        // the client gets told why
+        let last = client_recv(&client).unwrap();
+        assert!(matches!(
+            last.bodies[..],
+            [ServerBodyElem::Disconnect(DisconnectReason::Timeout)]
+        ));
+    }
+
+    #[test]
+    fn kicked_client_is_told_why() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        world.send_event(AdminCommand::Kick(client.local_addr().unwrap()));
+        run_system(&mut world, handle_admin_commands);
+        // still there until the mutations are applied
+        assert_eq!(world.resource::<Server>().clients.len(), 1);
+        run_system(&mut world, apply_client_mutations);
+        assert!(world.resource::<Server>().clients.is_empty());
+
+        let last = client_recv(&client).unwrap();
+        assert!(matches!(
+            last.bodies[..],
+            [ServerBodyElem::Disconnect(DisconnectReason::Kicked)]
+        ));
+
+        let events = world.resource::<Events<ClientDisconnected>>();
+        let mut reader = events.get_reader();
+        let disconnects: Vec<&ClientDisconnected> = reader.iter(events).collect();
+        assert_eq!(disconnects.len(), 1);
+        assert_eq!(disconnects[0].reason, DisconnectReason::Kicked);
+    }
+
+    #[test]
+    fn message_burst_is_spread_over_ticks() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+
+        for _ in 0..DEFAULT_MAX_MESSAGES_PER_TICK + 5 {
+            client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        }
+
+        // first tick only gets through the budget
+        run_system(&mut world, server_handle_messages);
+        let metrics = &world.resource::<Server>().metrics;
+        assert_eq!(
+            metrics.datagrams_received,
+            DEFAULT_MAX_MESSAGES_PER_TICK as u64
+        );
+        assert_eq!(metrics.deferred_ticks, 1);
+
+        // next tick picks up the rest without running out
+        run_system(&mut world, server_handle_messages);
+        let metrics = &world.resource::<Server>().metrics;
+        assert_eq!(
+            metrics.datagrams_received,
+            DEFAULT_MAX_MESSAGES_PER_TICK as u64 + 5
+        );
+        assert_eq!(metrics.deferred_ticks, 1);
+    }
+
+    #[test]
+    fn localhost_only_rejects_remote_senders() {
+        let (mut server, network) = test_server();
+        server.config.localhost_only = true;
+        let local = test_client(&network, 9000);
+        let remote = network.endpoint(SocketAddr::from(([192, 168, 1, 20], 9000)));
+
+        client_send(&remote, 0, vec![ClientBodyElem::Ping]);
+        assert!(matches!(
+            server.get_one_message(),
+            Err(ReceiveError::AddressNotAllowed)
+        ));
+        assert!(server.clients.is_empty());
+
+        client_send(&local, 0, vec![ClientBodyElem::Ping]);
+        assert!(server.get_one_message().is_ok());
+        assert!(server.clients.contains_key(&local.local_addr().unwrap()));
+    }
+
+    #[test]
+    fn terrain_throttled_but_pongs_every_tick() {
+        let (mut server, network) = test_server();
+        server.config.terrain_interval_ticks = 3;
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+        world.insert_resource(Terrain::new(1));
+
+        // pong stays queued since the client never acks it
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+
+        let mut had_terrain = Vec::new();
+        for _ in 0..6 {
+            // the world changes every tick
+            world.send_event(TerrainChanged::block_row(0));
+            run_system(&mut world, collect_terrain_changes);
+            run_system(&mut world, enqueue_terrain);
+            run_system(&mut world, send_all_messages);
+
+            let received = client_recv(&client).unwrap();
+            assert!(received
+                .bodies
+                .iter()
+                .any(|b| matches!(b, ServerBodyElem::Pong(_))));
+            had_terrain.push(
+                received
+                    .bodies
+                    .iter()
+                    .any(|b| matches!(b, ServerBodyElem::TerrainChunks(_))),
+            );
+        }
+        assert_eq!(had_terrain, [true, false, false, true, false, false]);
+    }
+
+    #[test]
+    fn queue_summary_counts_by_kind() {
+        let (mut server, network) = test_server();
+        let client = test_client(&network, 9000);
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        server.get_one_message().unwrap();
+        forget_welcomes(&mut server);
+
+        let info = server.clients.values_mut().next().unwrap();
+        info.bodies.push(ServerBodyElem::Pong(1));
+        info.bodies.push(ServerBodyElem::Terrain(Terrain::empty()));
+        info.enqueue_reliable(ServerBodyElem::MapChange(Terrain::empty()));
+
+        let summaries = server.queue_summaries();
+        assert_eq!(summaries.len(), 1);
+        let summary = &summaries[0];
+        assert_eq!(summary.addr, client.local_addr().unwrap());
+        assert_eq!(summary.bodies.get("pong"), Some(&1));
+        assert_eq!(summary.bodies.get("terrain"), Some(&1));
+        assert_eq!(summary.reliable.get("map_change"), Some(&1));
+        assert_eq!(summary.depth(), 3);
+    }
+
+    #[test]
+    fn spectator_gets_one_in_five_packets() {
+        let (server, network) = test_server();
+        let player = test_client(&network, 9000);
+        let spectator = test_client(&network, 9001);
+        let mut world = test_world(server);
+
+        client_send(&player, 0, vec![ClientBodyElem::Ping]);
+        client_send(&spectator, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        forget_welcomes(&mut world.resource_mut::<Server>());
+        world.send_event(AdminCommand::SetRole(
+            spectator.local_addr().unwrap(),
+            ClientRole::Spectator,
+        ));
+        run_system(&mut world, handle_admin_commands);
+
+        let mut player_packets = 0;
+        let mut spectator_packets = 0;
+        for _ in 0..10 {
+            run_system(&mut world, enqueue_terrain);
+            run_system(&mut world, send_all_messages);
+            run_system(&mut world, drop_disconnected_clients);
+            while let Some(message) = client_recv(&player) {
+                player_packets += 1;
+                client_send(&player, message.header.sequence, Vec::new());
+            }
+            run_system(&mut world, increase_tick);
+            run_system(&mut world, server_handle_messages);
+            while let Some(message) = client_recv(&spectator) {
+                spectator_packets += 1;
+                // the spectator still gets its terrain
+                assert!(matches!(
+                    message.reliable[..],
+                    [
+                        ReliableElem {
+                            body: ServerBodyElem::Terrain(_),
+                            ..
+                        },
+                        ..
+                    ]
+                ));
+            }
+        }
+        assert_eq!(player_packets, 10);
+        assert_eq!(spectator_packets, 2);
+
+        // the spectator hasn't acked anything for longer than a player may, but is still around
+        // (the player acked every packet)
+        assert_eq!(world.resource::<Server>().clients.len(), 2);
+    }
+
+    #[test]
+    fn pacing_spreads_sends_over_tick() {
+        let (mut server, network) = test_server();
+        server.config.pacing = true;
+        let clients: Vec<ChannelTransport> = (0..DEFAULT_MAX_CLIENTS as u16)
+            .map(|i| test_client(&network, 9000 + i))
+            .collect();
+        let mut world = test_world(server);
+        for client in &clients {
+            client_send(client, 0, vec![ClientBodyElem::Ping]);
+        }
+        run_system(&mut world, server_handle_messages);
+
+        // only the first share goes out with the network tick
+        run_system(&mut world, send_all_messages);
+        let received = |clients: &[ChannelTransport]| {
+            clients.iter().filter(|c| client_recv(c).is_some()).count()
+        };
+        let per_step = DEFAULT_MAX_CLIENTS.div_ceil(PACING_STEPS as usize);
+        assert_eq!(received(&clients), per_step);
+
+        // the rest trickle out over the following pieces
+        let mut total = per_step;
+        for _ in 1..PACING_STEPS {
+            run_system(&mut world, send_paced_messages);
+            total += received(&clients);
+        }
+        assert_eq!(total, DEFAULT_MAX_CLIENTS);
+
+        let metrics = &world.resource::<Server>().metrics;
+        assert_eq!(metrics.largest_send_burst, per_step as u64);
+        assert_eq!(
+            metrics.paced_messages,
+            (DEFAULT_MAX_CLIENTS - per_step) as u64
+        );
+    }
+
+    #[test]
+    fn watchdog_catches_late_network_tick() {
+        let (mut server, _network) = test_server();
+        server.config.tick_watchdog_threshold = Duration::from_secs(2);
+        let start = Instant::now();
+
+        // first tick has nothing to compare against, then one on time
+        server.check_tick_watchdog(start);
+        server.check_tick_watchdog(start + Duration::from_secs(1));
+        assert_eq!(server.metrics.missed_ticks, 0);
+
+        // server stalled for a while
+        server.check_tick_watchdog(start + Duration::from_secs(4));
+        assert_eq!(server.metrics.missed_ticks, 1);
+
+        // back to normal
+        server.check_tick_watchdog(start + Duration::from_secs(5));
+        assert_eq!(server.metrics.missed_ticks, 1);
+    }
+
+    #[test]
+    fn watchdog_runs_with_send_all_messages() {
+        let (mut server, _network) = test_server();
+        // pretend the previous network tick was a long time ago
+        server.last_network_tick_at = Some(Instant::now() - DEFAULT_TICK_WATCHDOG_THRESHOLD * 2);
+        let mut world = test_world(server);
+
+        run_system(&mut world, send_all_messages);
+        assert_eq!(world.resource::<Server>().metrics.missed_ticks, 1);
+    }
+
+    #[test]
+    fn conflicting_mines_go_to_first_joined_client() {
+        let (server, network) = test_server();
+        let first = test_client(&network, 9001);
+        let second = test_client(&network, 9000);
+        let mut world = test_world(server);
+        world.insert_resource(Terrain::new(1));
+
+        // find a block to fight over
+        let (block_x, block_y) = {
+            let terrain = world.resource::<Terrain>();
+            let y = (0..CHUNK_HEIGHT)
+                .find(|y| terrain.chunks[0].blocks[*y][0].is_some())
+                .unwrap();
+            (0, y)
+        };
+        let mine = ClientBodyElem::Input(PlayerInput {
+            left: false,
+            right: false,
+            jump: false,
+            mine: true,
+            block_x,
+            block_y,
+        });
+
+        client_send(&first, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        client_send(&second, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+
+        // inputs arrive in the opposite order that the clients joined in
+        // with an ack, inputs from clients still connecting aren't taken
+        client_send(&second, 1, vec![mine.clone()]);
+        client_send(&first, 1, vec![mine]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, apply_inputs);
+
+        let server = world.resource::<Server>();
+        let mined =
+            |client: &ChannelTransport| server.clients[&client.local_addr().unwrap()].blocks_mined;
+        assert_eq!(mined(&first), 1);
+        assert_eq!(mined(&second), 0);
+        assert!(world.resource::<Terrain>().chunks[0].blocks[block_y][block_x].is_none());
+    }
+
+    #[test]
+    fn packets_per_second_cap_is_respected() {
+        let (mut server, network) = test_server();
+        server.config.max_packets_per_second = 2;
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+
+        // network ticks much faster than normal, all well within a second
+        for _ in 0..5 {
+            run_system(&mut world, send_all_messages);
+        }
+        let mut received = 0;
+        while client_recv(&client).is_some() {
+            received += 1;
+        }
+        assert_eq!(received, 2);
+
+        let server = world.resource::<Server>();
+        assert_eq!(server.metrics.capped_sends, 3);
+        // the pong is still waiting to go out
+        let info = server.clients.values().next().unwrap();
+        assert!(matches!(info.bodies[..], [ServerBodyElem::Pong(_)]));
+    }
+
+    #[test]
+    fn send_slots_free_up_after_a_second() {
+        let mut info = ClientInfo::new(SocketAddr::from(([127, 0, 0, 1], 9000)), 0, Instant::now());
+        let start = Instant::now();
+
+        assert!(info.take_send_slot(start, 2));
+        assert!(info.take_send_slot(start + Duration::from_millis(500), 2));
+        assert!(!info.take_send_slot(start + Duration::from_millis(900), 2));
+
+        // the first send is now over a second old
+        assert!(info.take_send_slot(start + Duration::from_millis(1100), 2));
+        assert!(!info.take_send_slot(start + Duration::from_millis(1200), 2));
+    }
+
+    #[test]
+    fn only_changed_chunks_are_enqueued() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+        world.insert_resource(Terrain::new(3));
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        forget_welcomes(&mut world.resource_mut::<Server>());
+
+        // a new client gets the whole terrain, and no changes since nothing changed
+        run_system(&mut world, enqueue_terrain);
+        {
+            let server = world.resource::<Server>();
+            let info = server.clients.values().next().unwrap();
+            assert!(matches!(
+                info.reliable[..],
+                [
+                    PendingReliable {
+                        elem: ReliableElem {
+                            body: ServerBodyElem::Terrain(_),
+                            ..
+                        },
+                        ..
+                    },
+                    ..
+                ]
+            ));
+            assert!(!info
+                .bodies
+                .iter()
+                .any(|b| matches!(b, ServerBodyElem::TerrainChunks(_))));
+        }
+
+        // change something in the middle chunk, and somewhere that doesn't exist
+        world.send_event(TerrainChanged::block_row(CHUNK_HEIGHT + 5));
+        world.send_event(TerrainChanged {
+            region: 7..=9,
+            blocks: None,
+        });
+        run_system(&mut world, collect_terrain_changes);
+        run_system(&mut world, enqueue_terrain);
+
+        let server = world.resource::<Server>();
+        let info = server.clients.values().next().unwrap();
+        let sent: Vec<Vec<u64>> = info
+            .bodies
+            .iter()
+            .filter_map(|b| match b {
+                ServerBodyElem::TerrainChunks(chunks) => {
+                    Some(chunks.iter().map(|c| c.chunk_number).collect())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sent, [vec![1]]);
+        assert!(server.dirty_chunks.is_empty());
+        // the baseline doesn't get queued twice
+        let baselines = info
+            .reliable
+            .iter()
+            .filter(|r| matches!(r.elem.body, ServerBodyElem::Terrain(_)))
+            .count();
+        assert_eq!(baselines, 1);
+    }
+
+    #[test]
+    fn clients_map_never_resizes_up_to_capacity() {
+        let (mut server, _network) = test_server();
+        let capacity = server.clients.capacity();
+        let needed =
+            server.config.max_clients + server.config.max_observers + EXTRA_CLIENTS_CAPACITY;
+        assert!(capacity >= needed);
+
+        for i in 0..needed {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 9000 + i as u16));
+            server
+                .clients
+                .insert(addr, ClientInfo::new(addr, i as u64, Instant::now()));
+        }
+        assert_eq!(server.clients.capacity(), capacity);
+    }
+
+    #[test]
+    fn reliable_delivery_confirmed_once_after_retransmit() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        forget_welcomes(&mut world.resource_mut::<Server>());
+        let id = world
+            .resource_mut::<Server>()
+            .clients
+            .values_mut()
+            .next()
+            .unwrap()
+            .enqueue_reliable(ServerBodyElem::MapChange(Terrain::empty()));
+
+        // first packet is lost, second one is a retransmit that makes it
+        run_system(&mut world, send_all_messages);
+        client_recv(&client).unwrap();
+        run_system(&mut world, increase_tick);
+        run_system(&mut world, send_all_messages);
+        let retransmit = client_recv(&client).unwrap();
+        assert_eq!(retransmit.reliable[0].id, id);
+
+        let delivered = |world: &World| -> Vec<(SocketAddr, u64)> {
+            let events = world.resource::<Events<ReliableDelivered>>();
+            let mut reader = events.get_reader();
+            reader
+                .iter(events)
+                .map(|d| (d.client, d.message_id))
+                .collect()
+        };
+        assert!(delivered(&world).is_empty());
+
+        client_send(&client, retransmit.header.sequence, vec![]);
+        run_system(&mut world, server_handle_messages);
+        assert_eq!(delivered(&world), [(client.local_addr().unwrap(), id)]);
+
+        // later acks don't confirm it again
+        run_system(&mut world, increase_tick);
+        run_system(&mut world, send_all_messages);
+        let next = client_recv(&client).unwrap();
+        client_send(&client, next.header.sequence, vec![]);
+        run_system(&mut world, server_handle_messages);
+        assert_eq!(delivered(&world).len(), 1);
+    }
+
+    #[test]
+    fn idle_client_slows_down_until_input() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        world
+            .resource_mut::<Server>()
+            .clients
+            .values_mut()
+            .next()
+            .unwrap()
+            .last_input_time -= DEFAULT_IDLE_AFTER * 2;
+
+        // count packets over some network ticks, with the client acking everything
+        let run_ticks = |world: &mut World, ticks: usize| {
+            let mut received = 0;
+            for _ in 0..ticks {
+                run_system(world, send_all_messages);
+                run_system(world, drop_disconnected_clients);
+                while let Some(message) = client_recv(&client) {
+                    received += 1;
+                    client_send(&client, message.header.sequence, vec![]);
+                }
+                run_system(world, increase_tick);
+                run_system(world, server_handle_messages);
+            }
+            received
+        };
+
+        // acks alone don't count as being active
+        let ticks = IDLE_SEND_INTERVAL_TICKS as usize * 3;
+        assert_eq!(run_ticks(&mut world, ticks), 3);
+        assert_eq!(world.resource::<Server>().clients.len(), 1);
+
+        client_send(
+            &client,
+            0,
+            vec![ClientBodyElem::Input(PlayerInput {
+                left: true,
+                right: false,
+                jump: false,
+                mine: false,
+                block_x: 0,
+                block_y: 0,
+            })],
+        );
+        run_system(&mut world, server_handle_messages);
+        assert_eq!(run_ticks(&mut world, ticks), ticks);
+    }
+
+    #[test]
+    fn kicked_client_refused_until_ban_expires() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let addr = client.local_addr().unwrap();
+        let mut world = test_world(server);
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        world.send_event(AdminCommand::Kick(addr));
+        run_system(&mut world, handle_admin_commands);
+        run_system(&mut world, apply_client_mutations);
+        while client_recv(&client).is_some() {}
+
+        // comes straight back, gets told it was kicked
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        assert!(world.resource::<Server>().clients.is_empty());
+        let refusal = client_recv(&client).unwrap();
+        assert!(matches!(
+            refusal.bodies[..],
+            [ServerBodyElem::Disconnect(DisconnectReason::Kicked)]
+        ));
+        // but only the once, however much more comes from that address
+        for _ in 0..3 {
+            client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        }
+        run_system(&mut world, server_handle_messages);
+        assert!(client_recv(&client).is_none());
+
+        // once the window is over the ban gets cleaned up and the client is let back in
+        *world.resource_mut::<Server>().bans.get_mut(&addr).unwrap() = Instant::now();
+        run_system(&mut world, drop_disconnected_clients);
+        assert!(world.resource::<Server>().bans.is_empty());
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        assert!(world.resource::<Server>().clients.contains_key(&addr));
+    }
+
+    #[test]
+    fn server_uses_config_file_values() {
+        let path = std::env::temp_dir().join("game_server_config_test.toml");
+        std::fs::write(&path, "max_clients = 1\nbuffer_size = 1024\nkick_ban = 0\n").unwrap();
+        let config = ServerConfig::load(&path).unwrap();
+
+        let (server, network) = test_server_with(config);
+        assert_eq!(server.buffer.len(), 1024);
+        assert!(server.config.kick_ban.is_zero());
+
+        // only room for one
+        let first = test_client(&network, 9000);
+        let second = test_client(&network, 9001);
+        let mut world = test_world(server);
+        client_send(&first, 0, vec![ClientBodyElem::Ping]);
+        client_send(&second, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        assert_eq!(world.resource::<Server>().clients.len(), 1);
+        let refusal = client_recv(&second).unwrap();
+        assert!(matches!(
+            refusal.bodies[..],
+            [ServerBodyElem::Disconnect(DisconnectReason::ServerFull)]
+        ));
+    }
+
+    #[test]
+    fn two_clients_serviced_in_one_tick() {
+        let (server, network) = test_server();
+        let first = test_client(&network, 9000);
+        let second = test_client(&network, 9001);
+        let mut world = test_world(server);
+
+        client_send(&first, 0, vec![ClientBodyElem::Ping]);
+        client_send(&second, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        assert_eq!(world.resource::<Server>().clients.len(), 2);
+
+        run_system(&mut world, send_all_messages);
+        for client in [&first, &second] {
+            let message = client_recv(client).unwrap();
+            assert!(message
+                .bodies
+                .iter()
+                .any(|body| matches!(body, ServerBodyElem::Pong(_))));
+        }
+    }
+
+    #[test]
+    fn new_client_survives_timeout_during_grace() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+
+        // a normal timeout's worth of network ticks with no acks
+        let game_ticks = world.resource::<Server>().game_ticks_per_network_tick();
+        for _ in 0..FRAME_DIFFERENCE_BEFORE_DISCONNECT / game_ticks + 2 {
+            run_system(&mut world, drop_disconnected_clients);
+        }
+        assert_eq!(world.resource::<Server>().clients.len(), 1);
+
+        // once the grace period is over the normal timeout applies
+        world
+            .resource_mut::<Server>()
+            .clients
+            .values_mut()
+            .next()
+            .unwrap()
+            .grace_until = Instant::now();
+        run_system(&mut world, drop_disconnected_clients);
+        run_system(&mut world, apply_client_mutations);
+        assert!(world.resource::<Server>().clients.is_empty());
+    }
+
+    #[test]
+    fn full_server_redirects_new_clients() {
+        let (mut server, network) = test_server();
+        let target = SocketAddr::from(([127, 0, 0, 1], 9999));
+        server.config.max_clients = 1;
+        server.config.redirect_when_full = Some(target);
+
+        let first = test_client(&network, 9000);
+        let second = test_client(&network, 9001);
+        let mut world = test_world(server);
+        client_send(&first, 0, vec![ClientBodyElem::Ping]);
+        client_send(&second, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+
+        let redirect = client_recv(&second).unwrap();
+        assert!(matches!(
+            redirect.bodies[..],
+            [ServerBodyElem::Redirect { addr }] if addr == target
+        ));
+        let server = world.resource::<Server>();
+        assert_eq!(server.clients.len(), 1);
+        assert!(!server.clients.contains_key(&second.local_addr().unwrap()));
+    }
+
+    #[test]
+    fn terrain_compressed_only_for_clients_that_offer_it() {
+        let (server, network) = test_server();
+        let plain = test_client(&network, 9000);
+        let deflate = test_client(&network, 9001);
+        let mut world = test_world(server);
+        world.insert_resource(Terrain::new(2));
+
+        client_send(&plain, 0, vec![ClientBodyElem::Ping]);
+        client_send(
+            &deflate,
+            0,
+            vec![ClientBodyElem::Codecs(vec![Codec::Deflate])],
+        );
+        run_system(&mut world, server_handle_messages);
+        forget_welcomes(&mut world.resource_mut::<Server>());
+        run_system(&mut world, enqueue_terrain);
+        run_system(&mut world, send_all_messages);
+
+        let message = client_recv(&plain).unwrap();
+        assert!(matches!(
+            message.reliable[..],
+            [
+                ReliableElem {
+                    body: ServerBodyElem::Terrain(_),
+                    ..
+                },
+                ..
+            ]
+        ));
+
+        let message = client_recv(&deflate).unwrap();
+        let (codec, data) = match &message.reliable[..] {
+            [ReliableElem {
+                body: ServerBodyElem::Compressed { codec, data },
+                ..
+            }, ..] => (*codec, data),
+            other => panic!("expected compressed terrain, got {:?}", other),
+        };
+        assert_eq!(codec, Codec::Deflate);
+        let terrain = match ServerBodyElem::decompress(codec, data).unwrap() {
+            ServerBodyElem::Terrain(terrain) => terrain,
+            other => panic!("expected terrain, got {}", other),
+        };
+        assert_eq!(terrain.chunks.len(), 2);
+
+        // the queue itself is left alone
+        let server = world.resource::<Server>();
+        let info = &server.clients[&deflate.local_addr().unwrap()];
+        assert!(matches!(
+            info.reliable[0].elem.body,
+            ServerBodyElem::Terrain(_)
+        ));
+    }
+
+    #[test]
+    fn terrain_ack_frees_older_baselines() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+        world.insert_resource(Terrain::new(3));
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, enqueue_terrain);
+
+        // two rounds of changes, neither acked, so the second is relative to the baseline
+        let last_chunks = |world: &mut World| -> Vec<u64> {
+            let server = world.resource::<Server>();
+            let info = server.clients.values().next().unwrap();
+            info.bodies
+                .iter()
+                .rev()
+                .find_map(|b| match b {
+                    ServerBodyElem::TerrainChunks(chunks) => {
+                        Some(chunks.iter().map(|c| c.chunk_number).collect())
+                    }
+                    _ => None,
+                })
+                .unwrap()
+        };
+        world.send_event(TerrainChanged::block_row(0));
+        run_system(&mut world, collect_terrain_changes);
+        run_system(&mut world, enqueue_terrain);
+        assert_eq!(last_chunks(&mut world), [0]);
+        // every run_system reads events from the start, so get rid of the old one
+        world.resource_mut::<Events<TerrainChanged>>().clear();
+        world.send_event(TerrainChanged::block_row(CHUNK_HEIGHT + 5));
+        run_system(&mut world, collect_terrain_changes);
+        run_system(&mut world, enqueue_terrain);
+        assert_eq!(last_chunks(&mut world), [0, 1]);
+        {
+            let server = world.resource::<Server>();
+            let info = server.clients.values().next().unwrap();
+            assert_eq!(info.terrain_baselines, BTreeSet::from([0, 1, 2]));
+        }
+
+        // the client acks version 1, anything older is forgotten
+        client_send(&client, 0, vec![ClientBodyElem::TerrainAck(1)]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, enqueue_terrain);
+        let server = world.resource::<Server>();
+        let info = server.clients.values().next().unwrap();
+        assert_eq!(info.terrain_baselines, BTreeSet::from([1, 2]));
+
+        // and the next resend only needs what changed after 1
+        assert_eq!(last_chunks(&mut world), [1]);
+    }
+
+    #[test]
+    fn skipped_client_sequences_count_as_loss() {
+        let (mut server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut input_map = HashMap::new();
+
+        for sequence in [1, 2, 5, 3, 6] {
+            client_send(&client, sequence, vec![]);
+            let (info, message) = server.get_one_message().unwrap();
+            compute_new_bodies(info, message, 0, &mut input_map, Instant::now());
+        }
+
+        // 3 and 4 were missing when 5 showed up, 3 arriving late doesn't undo that
+        let info = server.clients.values().next().unwrap();
+        assert_eq!(info.detected_loss, 2);
+        assert_eq!(info.last_seen_client_seq, Some(6));
+    }
+
+    #[test]
+    fn dump_command_writes_recent_packets() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+
+        for ack in 0..3 {
+            client_send(&client, ack, vec![ClientBodyElem::Ping]);
+        }
+        run_system(&mut world, server_handle_messages);
+
+        let path = std::env::temp_dir().join("game_dump_command_test.log");
+        assert!(matches!(
+            parse_admin_command(&format!("dump {}", path.display())),
+            Ok(AdminCommand::DumpReplay(p)) if p == path
+        ));
+        world.send_event(AdminCommand::DumpReplay(path.clone()));
+        run_system(&mut world, handle_admin_commands);
+
+        let mut reader = ReplayReader::open(&path).unwrap();
+        let mut acks = Vec::new();
+        while let Some(frame) = reader.next_frame().unwrap() {
+            let (message, _): (ClientToServer, usize) =
+                bincode::decode_from_slice(&frame.data, BINCODE_CONFIG).unwrap();
+            acks.push(message.header.last_received_sequence);
+        }
+        assert_eq!(acks, [0, 1, 2]);
+    }
+
+    #[test]
+    fn prediction_errors_aggregated_and_clamped() {
+        let (mut server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut input_map = HashMap::new();
+
+        let reports = [2., 4., f32::NAN, -5., f32::INFINITY];
+        let bodies = reports
+            .iter()
+            .map(|magnitude| ClientBodyElem::PredictionError {
+                magnitude: *magnitude,
+            })
+            .collect();
+        client_send(&client, 1, bodies);
+        let (info, message) = server.get_one_message().unwrap();
+        compute_new_bodies(info, message, 0, &mut input_map, Instant::now());
+
+        // NaN is dropped, negative counts as perfect and infinity as the most we allow
+        let status = server.status();
+        let stats = status.clients[0].prediction_error;
+        assert_eq!(stats.reports, 4);
+        assert_eq!(stats.max, MAX_PREDICTION_ERROR);
+        assert_eq!(stats.average(), (6. + MAX_PREDICTION_ERROR as f64) / 4.);
+    }
+
+    #[test]
+    fn token_ping_echoes_token() {
+        let (mut server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut input_map = HashMap::new();
+
+        let token = 0xdead_beef_cafe;
+        client_send(
+            &client,
+            0,
+            vec![ClientBodyElem::TokenPing(token), ClientBodyElem::Ping],
+        );
+        let (info, message) = server.get_one_message().unwrap();
+        compute_new_bodies(info, message, 42, &mut input_map, Instant::now());
+
+        // version 1 pings still get the old pong
+        let info = server.clients.values().next().unwrap();
+        assert!(matches!(
+            info.bodies[..],
+            [
+                ServerBodyElem::TokenPong {
+                    token: t,
+                    server_seq: 42
+                },
+                ServerBodyElem::Pong(_)
+            ] if t == token
+        ));
+    }
+
+    #[test]
+    fn bandwidth_counters_roll_over() {
+        let (mut server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let addr = client.local_addr().unwrap();
+        let mut input_map = HashMap::new();
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        let (info, message) = server.get_one_message().unwrap();
+        compute_new_bodies(info, message, 0, &mut input_map, Instant::now());
+        let reply = ServerToClient {
+            header: ServerHeader {
+                sequence: 5,
+                ..Default::default()
+            },
+            bodies: vec![ServerBodyElem::Pong(0)],
+            reliable: Vec::new(),
+        };
+        server.send_message(addr, reply).unwrap();
+        client_recv(&client).unwrap();
+
+        // acking the packet gives us a round trip time
+        client_send(&client, 5, Vec::new());
+        let (info, message) = server.get_one_message().unwrap();
+        compute_new_bodies(info, message, 0, &mut input_map, Instant::now());
+
+        let info = server.clients.get_mut(&addr).unwrap();
+        assert!(info.rtt.is_some());
+        let sample = info.roll_bandwidth();
+        assert_eq!(sample.packets_received, 2);
+        assert_eq!(sample.packets_sent, 1);
+        assert!(sample.bytes_received > 0);
+        assert!(sample.bytes_sent > 0);
+
+        // the next second starts from nothing
+        assert_eq!(info.roll_bandwidth(), BandwidthSample::default());
+        client_send(&client, 5, Vec::new());
+        server.get_one_message().unwrap();
+        let info = server.clients.get_mut(&addr).unwrap();
+        assert_eq!(info.roll_bandwidth().packets_received, 1);
+    }
+
+    #[test]
+    fn entity_snapshot_reaches_client() {
+        let (mut server, network) = test_server();
+        let client = test_client(&network, 9000);
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        server.get_one_message().unwrap();
+        let mut world = test_world(server);
+        world
+            .spawn()
+            .insert(NetworkId(7))
+            .insert(Transform::from_xyz(3., -4., 0.));
+        world
+            .spawn()
+            .insert(NetworkId(2))
+            .insert(Transform::from_xyz(-1.5, 0., 0.));
+
+        run_system(&mut world, enqueue_entity_snapshot);
+        run_system(&mut world, send_all_messages);
+
+        let message = client_recv(&client).unwrap();
+        let entities = message
+            .bodies
+            .iter()
+            .find_map(|body| match body {
+                ServerBodyElem::EntitySnapshot { entities, .. } => Some(entities.clone()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            entities,
+            [
+                EntityState {
+                    id: 2,
+                    x: -1.5,
+                    y: 0.
+                },
+                EntityState {
+                    id: 7,
+                    x: 3.,
+                    y: -4.
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn entity_deltas_follow_acked_snapshots() {
+        let (mut server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let addr = client.local_addr().unwrap();
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        server.get_one_message().unwrap();
+        let mut world = test_world(server);
+        let moving = world
+            .spawn()
+            .insert(NetworkId(1))
+            .insert(Transform::from_xyz(0., 0., 0.))
+            .id();
+        let leaving = world
+            .spawn()
+            .insert(NetworkId(2))
+            .insert(Transform::from_xyz(5., 5., 0.))
+            .id();
+        let entity_body = |world: &mut World| {
+            let mut server = world.resource_mut::<Server>();
+            let client = server.clients.get_mut(&addr).unwrap();
+            let body = client.bodies.pop().unwrap();
+            client.bodies.clear();
+            body
+        };
+        let ack = |world: &mut World, snapshot| {
+            let mut server = world.resource_mut::<Server>();
+            let client = server.clients.get_mut(&addr).unwrap();
+            client.entity_baseline = Some(snapshot);
+        };
+
+        // nothing acked yet, so everything
+        run_system(&mut world, enqueue_entity_snapshot);
+        let base = match entity_body(&mut world) {
+            ServerBodyElem::EntitySnapshot { snapshot, entities } => {
+                assert_eq!(entities.len(), 2);
+                ack(&mut world, snapshot);
+                entities
+            }
+            body => panic!("expected a full snapshot, got {}", body),
+        };
+
+        // one moves, one goes away and a new one shows up
+        world.get_mut::<Transform>(moving).unwrap().translation.x = 3.;
+        world.despawn(leaving);
+        world
+            .spawn()
+            .insert(NetworkId(3))
+            .insert(Transform::from_xyz(-1., 2., 0.));
+        run_system(&mut world, enqueue_entity_snapshot);
+        let latest = match entity_body(&mut world) {
+            ServerBodyElem::EntityDelta {
+                snapshot,
+                baseline,
+                changed,
+                removed,
+            } => {
+                assert_eq!(baseline, 1);
+                assert_eq!(changed.iter().map(|s| s.id).collect::<Vec<_>>(), [1, 3]);
+                assert_eq!(removed, [2]);
+                // the client ends up with the same thing the server has
+                let applied = apply_entity_delta(&base, &changed, &removed);
+                assert_eq!(
+                    applied,
+                    world.resource::<Server>().clients[&addr].entity_views[&snapshot]
+                );
+                snapshot
+            }
+            body => panic!("expected a delta, got {}", body),
+        };
+
+        // up to date clients get nothing
+        ack(&mut world, latest);
+        run_system(&mut world, enqueue_entity_snapshot);
+        assert!(world.resource::<Server>().clients[&addr].bodies.is_empty());
+
+        // a baseline we've forgotten about means starting over
+        world
+            .resource_mut::<Server>()
+            .clients
+            .get_mut(&addr)
+            .unwrap()
+            .entity_views
+            .clear();
+        world.get_mut::<Transform>(moving).unwrap().translation.y = 1.;
+        run_system(&mut world, enqueue_entity_snapshot);
+        assert!(matches!(
+            entity_body(&mut world),
+            ServerBodyElem::EntitySnapshot { ref entities, .. } if entities.len() == 2
+        ));
+    }
+
+    #[test]
+    fn headers_carry_send_interval() {
+        let config = ServerConfig {
+            network_tick_hz: 4,
+            game_tick_hz: 60,
+            ..ServerConfig::default()
+        };
+        let (mut server, network) = test_server_with(config);
+        let player = test_client(&network, 9000);
+        let spectator = test_client(&network, 9001);
+        client_send(&player, 0, vec![ClientBodyElem::Ping]);
+        client_send(&spectator, 0, vec![ClientBodyElem::Ping]);
+        server.get_one_message().unwrap();
+        let (info, _) = server.get_one_message().unwrap();
+        info.set_role(ClientRole::Spectator);
+        server.sequence = 30;
+        let mut world = test_world(server);
+
+        run_system(&mut world, send_all_messages);
+
+        let header = client_recv(&player).unwrap().header;
+        assert_eq!(header.snapshot_tick, 30);
+        assert_eq!(header.game_tick_hz, 60);
+        assert_eq!(header.send_interval_micros, 250_000);
+        // spectators only get every fifth network tick
+        let header = client_recv(&spectator).unwrap().header;
+        assert_eq!(
+            header.send_interval_micros,
+            250_000 * SPECTATOR_SEND_INTERVAL_TICKS
+        );
+    }
+
+    #[test]
+    fn far_entities_culled_per_client() {
+        let config = ServerConfig {
+            entity_view_radius: Some(100),
+            ..ServerConfig::default()
+        };
+        let (mut server, network) = test_server_with(config);
+        let near = test_client(&network, 9000);
+        let far = test_client(&network, 9001);
+        client_send(&near, 0, vec![ClientBodyElem::Ping]);
+        client_send(&far, 0, vec![ClientBodyElem::Ping]);
+        server.get_one_message().unwrap().0.player_id = Some(1);
+        server.get_one_message().unwrap().0.player_id = Some(2);
+        let mut world = test_world(server);
+        for (id, x) in [(1, 0.), (2, 1000.), (3, 50.)] {
+            world
+                .spawn()
+                .insert(NetworkId(id))
+                .insert(Transform::from_xyz(x, 0., 0.));
+        }
+
+        run_system(&mut world, enqueue_entity_snapshot);
+
+        let seen = |world: &World, client: &ChannelTransport| -> Vec<u64> {
+            let server = world.resource::<Server>();
+            match server.clients[&client.local_addr().unwrap()].bodies.last() {
+                Some(ServerBodyElem::EntitySnapshot { entities, .. }) => {
+                    entities.iter().map(|state| state.id).collect()
+                }
+                _ => panic!("expected a full snapshot"),
+            }
+        };
+        // 3 is close to 1 but nowhere near 2
+        assert_eq!(seen(&world, &near), [1, 3]);
+        assert_eq!(seen(&world, &far), [2]);
+    }
+
+    #[test]
+    fn physics_substeps_each_game_tick() {
+        let config = ServerConfig {
+            game_tick_hz: 50,
+            physics_substeps: 4,
+            ..ServerConfig::default()
+        };
+        let (mut server, network) = test_server_with(config);
+        let client = test_client(&network, 9000);
+        let addr = client.local_addr().unwrap();
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        server.get_one_message().unwrap().0.player_id = Some(1);
+        let mut world = test_world(server);
+        let player = world
+            .spawn()
+            .insert(NetworkId(1))
+            .insert(Transform::default())
+            .insert(Velocity::default())
+            .id();
+        let drifting = world
+            .spawn()
+            .insert(NetworkId(2))
+            .insert(Transform::default())
+            .insert(Velocity { x: 0., y: -100. })
+            .id();
+        world
+            .resource_mut::<HashMap<SocketAddr, PlayerInput>>()
+            .insert(
+                addr,
+                PlayerInput {
+                    left: false,
+                    right: true,
+                    jump: false,
+                    mine: false,
+                    block_x: 0,
+                    block_y: 0,
+                },
+            );
+
+        for _ in 0..5 {
+            run_system(&mut world, simulate_physics);
+        }
+
+        // 5 ticks of 4 steps each, a tenth of a second at 50 ticks per second
+        assert_eq!(world.resource::<Server>().metrics.physics_steps, 20);
+        let position = |entity| world.get::<Transform>(entity).unwrap().translation;
+        assert!((position(player).x - PLAYER_SPEED / 10.).abs() < 1e-3);
+        assert!((position(drifting).y + 10.).abs() < 1e-3);
+        assert_eq!(position(drifting).x, 0.);
+    }
+
+    #[test]
+    fn list_clients_reports_connected_clients() {
+        let (mut server, network) = test_server();
+        let first = test_client(&network, 9000);
+        let second = test_client(&network, 9001);
+        for client in [&first, &second] {
+            client_send(client, 0, vec![ClientBodyElem::Ping]);
+            let (info, message) = server.get_one_message().unwrap();
+            compute_new_bodies(info, message, 0, &mut HashMap::new(), Instant::now());
+        }
+        forget_welcomes(&mut server);
+        assert!(matches!(
+            parse_admin_command("clients"),
+            Ok(AdminCommand::ListClients)
+        ));
+
+        let report = server.list_clients();
+        let addrs: Vec<SocketAddr> = report.clients.iter().map(|c| c.addr).collect();
+        assert_eq!(
+            addrs,
+            [first.local_addr().unwrap(), second.local_addr().unwrap()]
+        );
+        for (id, client) in report.clients.iter().enumerate() {
+            assert_eq!(client.id, id as u64);
+            // each one is waiting on its pong
+            assert_eq!(client.queued_bodies, 1);
+            assert_eq!(client.loss, 0);
+            assert_eq!(client.rtt, None);
+            assert!(client.session_duration < Duration::from_secs(5));
+        }
+        assert!(report.to_string().starts_with("2 client(s)"));
+
+        // gone clients are gone from the list too
+        server.disconnect(first.local_addr().unwrap(), DisconnectReason::Kicked);
+        let report = server.list_clients();
+        assert_eq!(report.clients.len(), 1);
+        assert_eq!(report.clients[0].id, 1);
+    }
+
+    #[test]
+    fn settings_sent_on_connect_and_change() {
+        let (mut server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let addr = client.local_addr().unwrap();
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        server.get_one_message().unwrap();
+        let mut world = test_world(server);
+        let queued_settings = |world: &World| -> Vec<GameSettings> {
+            world.resource::<Server>().clients[&addr]
+                .reliable
+                .iter()
+                .filter_map(|r| match &r.elem.body {
+                    ServerBodyElem::GameSettings(settings) => Some(settings.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        run_system(&mut world, enqueue_settings);
+        run_system(&mut world, enqueue_settings);
+        assert_eq!(queued_settings(&world), [GameSettings::default()]);
+
+        // changing a setting sends it out again, replacing the one that never made it
+        let command = parse_admin_command("set friendly_fire true").unwrap();
+        world.send_event(command);
+        run_system(&mut world, handle_admin_commands);
+        run_system(&mut world, enqueue_settings);
+        let settings = queued_settings(&world);
+        assert_eq!(settings.len(), 1);
+        assert_eq!(settings[0].version, 2);
+        assert_eq!(settings[0].values["friendly_fire"], "true");
+
+        // setting it to what it already is changes nothing
+        world.resource_mut::<Events<AdminCommand>>().clear();
+        world.send_event(parse_admin_command("set friendly_fire true").unwrap());
+        run_system(&mut world, handle_admin_commands);
+        assert_eq!(world.resource::<Server>().settings.version, 2);
+    }
+
+    #[test]
+    fn empty_datagram_ignored_without_slot() {
+        let (mut server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let server_addr = SocketAddr::from((DEFAULT_SERVER_IP, DEFAULT_SERVER_PORT));
+
+        // a real message first, so there's something left over in the buffer
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        server.get_one_message().unwrap();
+        server.clients.clear();
+
+        client.send_to(&[], server_addr).unwrap();
+        assert!(matches!(
+            server.get_one_message(),
+            Err(ReceiveError::EmptyDatagram)
+        ));
+        assert!(server.clients.is_empty());
+        assert_eq!(server.metrics.empty_datagrams, 1);
+    }
+
+    #[test]
+    fn message_with_too_many_bodies_dropped() {
+        let (mut server, network) = test_server_with(ServerConfig {
+            max_bodies_per_message: 4,
+            ..ServerConfig::default()
+        });
+        let client = test_client(&network, 9000);
+
+        // a handful of tiny bodies, well under any packet size limit
+        client_send(&client, 0, vec![ClientBodyElem::Ping; 5]);
+        assert!(matches!(
+            server.get_one_message(),
+            Err(ReceiveError::TooManyBodies(5))
+        ));
+        assert!(server.clients.is_empty());
+        assert_eq!(server.metrics.too_many_bodies, 1);
+
+        // right at the cap is fine
+        client_send(&client, 0, vec![ClientBodyElem::Ping; 4]);
+        assert!(server.get_one_message().is_ok());
+    }
+
+    #[test]
+    fn oversized_datagram_dropped_before_decoding() {
+        let (mut server, network) = test_server_with(ServerConfig {
+            max_message_size: 64,
+            ..ServerConfig::default()
+        });
+        let client = test_client(&network, 9000);
+
+        // a real message, padded out past the limit with a long admin command
+        client_send(
+            &client,
+            0,
+            vec![ClientBodyElem::AdminCommand("status".repeat(20))],
+        );
+        assert!(matches!(
+            server.get_one_message(),
+            Err(ReceiveError::Oversized { size, .. }) if size > 64
+        ));
+        assert!(server.clients.is_empty());
+        assert_eq!(server.metrics.oversized_messages, 1);
+        assert_eq!(server.metrics.decode_errors, 0);
+
+        // a normal one is still fine
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        assert!(server.get_one_message().is_ok());
+    }
+
+    #[test]
+    fn network_tick_rate_follows_client_count() {
+        let (server, network) = test_server_with(ServerConfig {
+            network_tick_hz: 30,
+            min_network_tick_hz: Some(10),
+            max_clients: 3,
+            ..ServerConfig::default()
+        });
+        let mut world = test_world(server);
+        let rate = |world: &World| world.resource::<Server>().metrics.network_tick_hz;
+
+        // full speed while there's hardly anyone around
+        run_system(&mut world, tune_network_tick);
+        assert_eq!(rate(&world), 30);
+
+        let clients: Vec<ChannelTransport> =
+            (0..3).map(|i| test_client(&network, 9000 + i)).collect();
+        for (joined, client) in clients.iter().enumerate() {
+            client_send(client, 0, vec![ClientBodyElem::Ping]);
+            run_system(&mut world, server_handle_messages);
+            run_system(&mut world, tune_network_tick);
+            assert_eq!(rate(&world), [30, 20, 10][joined]);
+        }
+
+        // timeouts and such go by the slower tick
+        assert_eq!(world.resource::<Server>().game_ticks_per_network_tick(), 6);
+
+        // and back up as they leave
+        world
+            .resource_mut::<Server>()
+            .clients
+            .remove(&clients[0].local_addr().unwrap());
+        run_system(&mut world, tune_network_tick);
+        assert_eq!(rate(&world), 20);
+    }
+
+    #[test]
+    fn rejection_sent_to_unknown_addr() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let addr = client.local_addr().unwrap();
+        assert!(!server.clients.contains_key(&addr));
+
+        let message = ServerToClient {
+            header: ServerHeader::default(),
+            bodies: vec![ServerBodyElem::Disconnect(DisconnectReason::ServerFull)],
+            reliable: Vec::new(),
+        };
+        assert!(server.send_to_addr(addr, message).unwrap() > 0);
+        let message = client_recv(&client).unwrap();
+        assert!(matches!(
+            message.bodies[..],
+            [ServerBodyElem::Disconnect(DisconnectReason::ServerFull)]
+        ));
+
+        // still not a client, so nothing was set up for it
+        assert!(server.clients.is_empty());
+    }
+
+    /// Subscriber that keeps the message of every event up to max_level
+    struct CaptureSubscriber {
+        max_level: Level,
+        messages: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl tracing::field::Visit for CaptureSubscriber {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.messages.lock().unwrap().push(format!("{:?}", value));
+            }
+        }
+    }
+
+    impl tracing::Subscriber for CaptureSubscriber {
+        fn register_callsite(
+            &self,
+            _metadata: &'static tracing::Metadata<'static>,
+        ) -> tracing::subscriber::Interest {
+            // other tests run with other levels, so don't let the answer get cached
+            tracing::subscriber::Interest::sometimes()
+        }
+
+        fn enabled(&self, metadata: &tracing::Metadata<'_>) -> bool {
+            *metadata.level() <= self.max_level
+        }
+
+        fn new_span(&self, _span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            event.record(&mut CaptureSubscriber {
+                max_level: self.max_level,
+                messages: self.messages.clone(),
+            });
+        }
+
+        fn enter(&self, _span: &tracing::span::Id) {}
+
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    #[test]
+    fn full_messages_traced_only_when_enabled() {
+        for (max_level, trace_messages, traced) in [
+            (Level::TRACE, true, true),
+            (Level::DEBUG, true, false),
+            (Level::TRACE, false, false),
+        ] {
+            let (mut server, network) = test_server();
+            if trace_messages {
+                // what ServerPlugin::new adds for ServerConfig::trace_messages
+                server.middleware.add_inbound(trace_inbound);
+                server.middleware.add_outbound(trace_outbound);
+            }
+            let client = test_client(&network, 9000);
+            let mut world = test_world(server);
+            let messages = Arc::new(Mutex::new(Vec::new()));
+            let subscriber = CaptureSubscriber {
+                max_level,
+                messages: messages.clone(),
+            };
+            tracing::subscriber::with_default(subscriber, || {
+                client_send(&client, 0, vec![ClientBodyElem::Ping]);
+                run_system(&mut world, server_handle_messages);
+                run_system(&mut world, send_all_messages);
+            });
+
+            let messages = messages.lock().unwrap();
+            let logged = |start| messages.iter().any(|m| m.starts_with(start));
+            assert_eq!(logged("server got ClientToServer"), traced);
+            assert_eq!(logged("server sending ServerToClient"), traced);
+            // everything else still gets through
+            assert!(!messages.is_empty());
+        }
+    }
+
+    #[test]
+    fn host_handed_off_when_host_leaves() {
+        let (server, network) = test_server_with(ServerConfig {
+            host_authority: true,
+            ..ServerConfig::default()
+        });
+        let mut world = test_world(server);
+        let host = test_client(&network, 9000);
+        let guest = test_client(&network, 9001);
+        let command = || vec![ClientBodyElem::AdminCommand("status".to_string())];
+        let commands_run = |world: &mut World| {
+            run_system(world, server_handle_messages);
+            world.resource_mut::<Events<AdminCommand>>().drain().count()
+        };
+
+        // first to connect is the host
+        client_send(&host, 0, vec![ClientBodyElem::Ping]);
+        client_send(&guest, 0, command());
+        assert_eq!(commands_run(&mut world), 0);
+        assert_eq!(world.resource::<Server>().host, host.local_addr().ok());
+        client_send(&host, 0, command());
+        assert_eq!(commands_run(&mut world), 1);
+
+        // the guest takes over once the host is gone
+        world
+            .resource_mut::<Server>()
+            .disconnect(host.local_addr().unwrap(), DisconnectReason::Kicked);
+        assert_eq!(world.resource::<Server>().host, guest.local_addr().ok());
+        client_send(&guest, 0, command());
+        assert_eq!(commands_run(&mut world), 1);
+
+        // even the host can't touch files on the server or stop it
+        let refused = std::env::temp_dir().join("game_host_dump_refused");
+        for line in [
+            format!("dump {}", refused.display()),
+            format!(
+                "inputs {} {}",
+                host.local_addr().unwrap(),
+                refused.display()
+            ),
+            "map /etc/passwd".to_string(),
+            "stop".to_string(),
+            "restart".to_string(),
+        ] {
+            client_send(&guest, 0, vec![ClientBodyElem::AdminCommand(line)]);
+            assert_eq!(commands_run(&mut world), 0);
+        }
+        assert!(!refused.exists());
+        client_send(
+            &guest,
+            0,
+            vec![ClientBodyElem::AdminCommand("map surface".to_string())],
+        );
+        assert_eq!(commands_run(&mut world), 1);
+    }
+
+    #[test]
+    fn one_way_delay_from_receive_times() {
+        let (mut server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let report = |sent_at_micros, received_at_micros| ClientBodyElem::ReceiveTime {
+            sent_at_micros,
+            received_at_micros,
+        };
+        client_send(
+            &client,
+            0,
+            vec![
+                report(1_000_000, 1_030_000),
+                report(2_000_000, 2_050_000),
+                // the client's clock is behind ours, no use for working out a delay
+                report(3_000_000, 2_999_000),
+            ],
+        );
+        let (info, message) = server.get_one_message().unwrap();
+        compute_new_bodies(info, message, 0, &mut HashMap::new(), Instant::now());
+
+        let stats = server.clients.values().next().unwrap().one_way_delay;
+        assert_eq!(stats.reports, 2);
+        assert_eq!(stats.skewed, 1);
+        assert_eq!(stats.average(), Some(Duration::from_millis(40)));
+        assert_eq!(stats.max, Duration::from_millis(50));
+
+        // packets going out get stamped, so the client has something to report
+        server.send_single_body(client.local_addr().unwrap(), ServerBodyElem::Pong(0));
+        let sent_at = client_recv(&client).unwrap().header.sent_at_micros;
+        assert!(sent_at > 0 && sent_at <= unix_micros());
+    }
+
+    #[test]
+    fn bind_retried_with_backoff() {
+        let mut attempts = 0;
+        let mut waits = Vec::new();
+        let bound = bind_with_retry(
+            || {
+                attempts += 1;
+                if attempts <= 2 {
+                    Err(std::io::Error::from(std::io::ErrorKind::AddrInUse))
+                } else {
+                    Ok(attempts)
+                }
+            },
+            4,
+            Duration::from_millis(10),
+            |wait| waits.push(wait),
+        );
+        assert_eq!(bound.unwrap(), 3);
+        assert_eq!(
+            waits,
+            [Duration::from_millis(10), Duration::from_millis(20)]
+        );
+
+        // out of retries, the last error comes back instead of a panic
+        let mut waits = Vec::new();
+        let bound: std::io::Result<()> = bind_with_retry(
+            || Err(std::io::Error::from(std::io::ErrorKind::AddrInUse)),
+            2,
+            Duration::from_millis(10),
+            |wait| waits.push(wait),
+        );
+        assert_eq!(bound.unwrap_err().kind(), std::io::ErrorKind::AddrInUse);
+        assert_eq!(waits.len(), 2);
+    }
+
+    #[test]
+    fn bodies_encrypted_after_key_exchange() {
+        let (server, network) = test_server_with(ServerConfig {
+            encryption: true,
+            ..ServerConfig::default()
+        });
+        let mut world = test_world(server);
+        let client = test_client(&network, 9000);
+        let key_pair = KeyPair::generate();
+
+        // the server answers the key exchange in the clear
+        client_send(
+            &client,
+            0,
+            vec![ClientBodyElem::KeyExchange(key_pair.public_key())],
+        );
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, send_all_messages);
+        let reply = client_recv(&client).unwrap();
+        let server_public = reply
+            .bodies
+            .iter()
+            .find_map(|body| match body {
+                ServerBodyElem::KeyExchange(server_public) => Some(*server_public),
+                _ => None,
+            })
+            .unwrap();
+        let mut key = key_pair.session_key(server_public);
+        // what client_send puts in the header, which the sealed bodies are bound to
+        let header = |ack| {
+            ClientToServer::builder()
+                .ack(ack)
+                .sequence(ack)
+                .build()
+                .header
+                .aad()
+        };
+
+        // once we encrypt, so does the server
+        let ping = seal_bodies(
+            &mut key,
+            Toward::Server,
+            &header(1),
+            vec![ClientBodyElem::TokenPing(7)],
+        )
+        .unwrap();
+        client_send(&client, 1, vec![ClientBodyElem::Encrypted(ping.clone())]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, send_all_messages);
+        let reply = client_recv(&client).unwrap();
+        let sealed = match &reply.bodies[..] {
+            [ServerBodyElem::Encrypted(sealed)] => sealed,
+            other => panic!("expected one encrypted body, got {:?}", other),
+        };
+        let (bodies, _reliable): (Vec<ServerBodyElem>, Vec<ReliableElem>) =
+            open_bodies(&mut key, Toward::Client, &reply.header.aad(), sealed).unwrap();
+        assert!(bodies
+            .iter()
+            .any(|body| matches!(body, ServerBodyElem::TokenPong { token: 7, .. })));
+
+        // a message that can't be encrypted isn't sent at all, and what was in it isn't counted as sent
+        let set_fail_seals = |world: &mut World, fail: bool| {
+            let mut server = world.resource_mut::<Server>();
+            let info = server.clients.values_mut().next().unwrap();
+            info.session.as_mut().unwrap().fail_seals = fail;
+        };
+        set_fail_seals(&mut world, true);
+        world
+            .resource_mut::<Server>()
+            .clients
+            .values_mut()
+            .next()
+            .unwrap()
+            .enqueue_reliable(ServerBodyElem::Announcement("hi".to_string()));
+        run_system(&mut world, send_all_messages);
+        assert!(client_recv(&client).is_none());
+        {
+            let server = world.resource::<Server>();
+            let info = server.clients.values().next().unwrap();
+            assert!(info.reliable.iter().all(|r| r.first_sent.is_none()));
+        }
+        set_fail_seals(&mut world, false);
+        run_system(&mut world, send_all_messages);
+        let reply = client_recv(&client).unwrap();
+        let sealed = match &reply.bodies[..] {
+            [ServerBodyElem::Encrypted(sealed)] => sealed,
+            other => panic!("expected one encrypted body, got {:?}", other),
+        };
+        let (_bodies, reliable): (Vec<ServerBodyElem>, Vec<ReliableElem>) =
+            open_bodies(&mut key, Toward::Client, &reply.header.aad(), sealed).unwrap();
+        assert!(reliable
+            .iter()
+            .any(|r| matches!(&r.body, ServerBodyElem::Announcement(text) if text == "hi")));
+
+        // anything else is refused, without taking the server down
+        let mut server = world.resource_mut::<Server>();
+        let mut wrong_key = KeyPair::generate().session_key(server_public);
+        // past the counter the server already opened, so it's the key that gets it refused
+        wrong_key.seal(Toward::Server, &[], &[]);
+        let sealed = seal_bodies(
+            &mut wrong_key,
+            Toward::Server,
+            &header(2),
+            vec![ClientBodyElem::TokenPing(8)],
+        );
+        client_send(&client, 2, vec![ClientBodyElem::Encrypted(sealed.unwrap())]);
+        assert!(matches!(
+            server.get_one_message(),
+            Err(ReceiveError::Decrypt(CryptoError::Rejected))
+        ));
+        // bodies that did open once, again, or with a different header
+        client_send(&client, 1, vec![ClientBodyElem::Encrypted(ping)]);
+        assert!(matches!(
+            server.get_one_message(),
+            Err(ReceiveError::Decrypt(CryptoError::Replayed))
+        ));
+        let sealed = seal_bodies(
+            &mut key,
+            Toward::Server,
+            &header(2),
+            vec![ClientBodyElem::TokenPing(9)],
+        );
+        client_send(&client, 3, vec![ClientBodyElem::Encrypted(sealed.unwrap())]);
+        assert!(matches!(
+            server.get_one_message(),
+            Err(ReceiveError::Decrypt(CryptoError::Rejected))
+        ));
+        client_send(&client, 2, vec![ClientBodyElem::Ping]);
+        assert!(matches!(
+            server.get_one_message(),
+            Err(ReceiveError::Unencrypted)
+        ));
+    }
+
+    #[test]
+    fn keepalive_sent_once_per_interval() {
+        let (server, network) = test_server_with(ServerConfig {
+            keepalive_interval: Duration::from_secs(1),
+            ..ServerConfig::default()
+        });
+        let mut world = test_world(server);
+        let client = test_client(&network, 9000);
+        let addr = client.local_addr().unwrap();
+        client_send(&client, 0, vec![]);
+        run_system(&mut world, server_handle_messages);
+        let empty_client = |world: &mut World, last_sent_at| {
+            let mut server = world.resource_mut::<Server>();
+            let info = server.clients.get_mut(&addr).unwrap();
+            info.bodies.clear();
+            info.reliable.clear();
+            info.last_sent_at = last_sent_at;
+        };
+
+        // nothing queued, so just a header
+        empty_client(&mut world, None);
+        run_system(&mut world, send_all_messages);
+        let keepalive = client_recv(&client).unwrap();
+        assert!(keepalive.bodies.is_empty() && keepalive.reliable.is_empty());
+
+        // and not again until the interval is up
+        run_system(&mut world, send_all_messages);
+        assert!(client_recv(&client).is_none());
+        empty_client(&mut world, Some(Instant::now() - Duration::from_secs(2)));
+        run_system(&mut world, send_all_messages);
+        assert!(client_recv(&client).is_some());
+
+        // a late paced message already went out this tick, another empty one would be redundant
+        empty_client(&mut world, Some(Instant::now() - Duration::from_secs(2)));
+        world
+            .resource_mut::<Server>()
+            .paced
+            .push_back((addr, keepalive));
+        run_system(&mut world, send_all_messages);
+        assert!(client_recv(&client).is_some());
+        assert!(client_recv(&client).is_none());
+
+        // anything queued goes out no matter the interval
+        world
+            .resource_mut::<Server>()
+            .clients
+            .get_mut(&addr)
+            .unwrap()
+            .bodies
+            .push(ServerBodyElem::Pong(3));
+        run_system(&mut world, send_all_messages);
+        let bodies = client_recv(&client).unwrap().bodies;
+        assert!(matches!(bodies[..], [ServerBodyElem::Pong(3)]));
+
+        let server = world.resource::<Server>();
+        assert_eq!(server.metrics.keepalives, 2);
+        assert_eq!(server.metrics.skipped_keepalives, 2);
+    }
+
+    #[test]
+    fn client_mutations_apply_in_queued_order() {
+        let (server, network) = test_server();
+        let first = test_client(&network, 9000);
+        let second = test_client(&network, 9001);
+        let (a, b) = (first.local_addr().unwrap(), second.local_addr().unwrap());
+        let mut world = test_world(server);
+        for client in [&first, &second] {
+            client_send(client, 0, vec![ClientBodyElem::Ping]);
+        }
+        run_system(&mut world, server_handle_messages);
+        world
+            .resource_mut::<HashMap<SocketAddr, PlayerInput>>()
+            .insert(
+                a,
+                PlayerInput {
+                    left: true,
+                    right: false,
+                    jump: false,
+                    mine: false,
+                    block_x: 0,
+                    block_y: 0,
+                },
+            );
+
+        assert!(matches!(
+            parse_admin_command("name 127.0.0.1:9001 big miner"),
+            Ok(AdminCommand::SetName(addr, name)) if addr == b && name == "big miner"
+        ));
+        world.send_event(AdminCommand::SetName(a, "first".to_string()));
+        world.send_event(AdminCommand::Kick(a));
+        run_system(&mut world, handle_admin_commands);
+        {
+            let mut server = world.resource_mut::<Server>();
+            // too late for a, it's gone by then
+            server.queue_mutation(ClientMutation::UpdateName(a, "late".to_string()));
+            // a comes back as a brand new client
+            server.queue_mutation(ClientMutation::Add(Box::new(ClientInfo::new(
+                a,
+                5,
+                Instant::now(),
+            ))));
+            server.queue_mutation(ClientMutation::UpdateName(b, "big miner".to_string()));
+            // nothing changes until they're applied
+            assert_eq!(server.clients[&a].join_number, 0);
+            assert_eq!(server.clients[&a].name, None);
+        }
+        run_system(&mut world, apply_client_mutations);
+
+        let server = world.resource::<Server>();
+        assert!(server.mutations.is_empty());
+        assert_eq!(server.clients[&a].join_number, 5);
+        assert_eq!(server.clients[&a].name, None);
+        assert_eq!(server.clients[&b].name.as_deref(), Some("big miner"));
+        assert!(server.list_clients().to_string().contains("(big miner)"));
+        assert!(world
+            .resource::<HashMap<SocketAddr, PlayerInput>>()
+            .is_empty());
+        let events = world.resource::<Events<ClientDisconnected>>();
+        let mut reader = events.get_reader();
+        let disconnects: Vec<&ClientDisconnected> = reader.iter(events).collect();
+        assert_eq!(disconnects.len(), 1);
+        assert_eq!(
+            (disconnects[0].addr, disconnects[0].reason),
+            (a, DisconnectReason::Kicked)
+        );
+    }
+
+    #[test]
+    fn new_clients_are_answered_before_mutations_apply_and_timeouts_wait_for_them() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let addr = client.local_addr().unwrap();
+        let mut world = test_world(server);
+
+        // admitted by the message that got it in, so the very next send answers it
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        assert!(world.resource::<Server>().clients.contains_key(&addr));
+        run_system(&mut world, send_all_messages);
+        let reply = client_recv(&client).unwrap();
+        assert!(reply
+            .bodies
+            .iter()
+            .any(|body| matches!(body, ServerBodyElem::Pong(_))));
+
+        // timing out goes through the queue with everything else
+        {
+            let mut server = world.resource_mut::<Server>();
+            let info = server.clients.get_mut(&addr).unwrap();
+            info.grace_until = Instant::now();
+            info.until_drop = 0;
+        }
+        run_system(&mut world, drop_disconnected_clients);
+        assert!(world.resource::<Server>().clients.contains_key(&addr));
+        run_system(&mut world, apply_client_mutations);
+        assert!(world.resource::<Server>().clients.is_empty());
+    }
+
+    #[test]
+    fn kick_over_admin_socket() {
+        let (server, network) = test_server();
+        let player = test_client(&network, 9000);
+        let admin = test_client(&network, 9100);
+        let admin_addr = SocketAddr::from(([127, 0, 0, 1], 9200));
+        let mut world = test_world(server);
+        world.insert_resource(AdminSocket::with_transport(
+            Box::new(network.endpoint(admin_addr)),
+            Some("sekrit".to_string()),
+            BUFFER_SIZE,
+        ));
+        client_send(&player, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+
+        let command = |line: &str| {
+            admin.send_to(line.as_bytes(), admin_addr).unwrap();
+        };
+        let reply = || {
+            let mut buffer = [0u8; BUFFER_SIZE];
+            let (size, _) = admin.recv_from(&mut buffer).unwrap();
+            String::from_utf8(buffer[..size].to_vec()).unwrap()
+        };
+
+        // no token, no kick
+        command("kick 127.0.0.1:9000");
+        run_system(&mut world, read_admin_socket);
+        assert_eq!(reply(), "error: wrong or missing token");
+
+        // a wrong token of the right length or a prefix of the right one is refused the same way
+        command("sekrig clients");
+        command("sekri clients");
+        run_system(&mut world, read_admin_socket);
+        assert_eq!(reply(), "error: wrong or missing token");
+        assert_eq!(reply(), "error: wrong or missing token");
+
+        // replies wait for the command to have run and say how it went
+        command("sekrit clients");
+        command("sekrit kick 127.0.0.1:9000");
+        command("sekrit kick 127.0.0.1:9001");
+        run_system(&mut world, read_admin_socket);
+        assert!(admin.recv_from(&mut [0u8; BUFFER_SIZE]).is_err());
+        run_system(&mut world, handle_admin_commands);
+        assert!(reply().starts_with("1 client(s)\n    #0 127.0.0.1:9000"));
+        assert_eq!(reply(), "kicking 127.0.0.1:9000");
+        assert_eq!(reply(), "error: no client at 127.0.0.1:9001");
+        run_system(&mut world, apply_client_mutations);
+        assert!(world.resource::<Server>().clients.is_empty());
+        let last = client_recv(&player).unwrap();
+        assert!(matches!(
+            last.bodies[..],
+            [ServerBodyElem::Disconnect(DisconnectReason::Kicked)]
+        ));
+    }
+
+    #[test]
+    fn bandwidth_cap_defers_terrain_but_not_pongs() {
+        let (server, network) = test_server_with(ServerConfig {
+            max_bytes_per_second: Some(1000),
+            ..ServerConfig::default()
+        });
+        let mut world = test_world(server);
+        let client = test_client(&network, 9000);
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        {
+            let mut server = world.resource_mut::<Server>();
+            forget_welcomes(&mut server);
+            // someone else used up this second already
+            server.metrics.bytes_this_second = 1000;
+            let info = server.clients.values_mut().next().unwrap();
+            info.enqueue_baseline(ServerBodyElem::Terrain(Terrain::empty()), 0);
+            info.bodies.push(ServerBodyElem::TerrainVersion(0));
+        }
+
+        run_system(&mut world, send_all_messages);
+        let capped = client_recv(&client).unwrap();
+        assert!(matches!(capped.bodies[..], [ServerBodyElem::Pong(_)]));
+        assert!(capped.reliable.is_empty());
+        {
+            let server = world.resource::<Server>();
+            assert_eq!(server.metrics.bandwidth_capped_ticks, 1);
+            let info = server.clients.values().next().unwrap();
+            // still waiting to go out for the first time
+            assert_eq!(info.reliable[0].first_sent, None);
+        }
+
+        // a new second, everything goes
+        world.resource_mut::<Server>().bandwidth_second_start -= Duration::from_secs(1);
+        run_system(&mut world, send_all_messages);
+        let full = client_recv(&client).unwrap();
+        assert!(matches!(
+            full.reliable[..],
+            [ReliableElem {
+                body: ServerBodyElem::Terrain(_),
+                ..
+            }]
+        ));
+        let server = world.resource::<Server>();
+        assert_eq!(server.metrics.bandwidth_capped_ticks, 1);
+        // only this tick's packet counts towards the new second
+        assert!(server.metrics.bytes_this_second > 0 && server.metrics.bytes_this_second < 1000);
+    }
+
+    #[test]
+    fn requested_chunks_resent_next_tick() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+        world.insert_resource(Terrain::new(5));
+        client_send(&client, 0, vec![]);
+        run_system(&mut world, server_handle_messages);
+        // already has the whole terrain
+        run_system(&mut world, enqueue_terrain);
+        let bodies = |world: &mut World| {
+            let mut server = world.resource_mut::<Server>();
+            let info = server.clients.values_mut().next().unwrap();
+            info.reliable.clear();
+            std::mem::take(&mut info.bodies)
+        };
+        bodies(&mut world);
+
+        // 99 doesn't exist, and way too many ids only gets the first few looked at
+        client_send(
+            &client,
+            0,
+            vec![ClientBodyElem::RequestChunks {
+                ids: vec![3, 1, 99, 3],
+            }],
+        );
+        client_send(
+            &client,
+            0,
+            vec![ClientBodyElem::RequestChunks {
+                ids: (100..1000).collect(),
+            }],
+        );
+        run_system(&mut world, server_handle_messages);
+        assert_eq!(
+            world
+                .resource::<Server>()
+                .clients
+                .values()
+                .next()
+                .unwrap()
+                .requested_chunks
+                .len(),
+            MAX_REQUESTED_CHUNKS
+        );
+        run_system(&mut world, enqueue_terrain);
+        let resent: Vec<ChunkId> = bodies(&mut world)
+            .iter()
+            .flat_map(|body| match body {
+                ServerBodyElem::TerrainChunks(chunks) => {
+                    chunks.iter().map(|chunk| chunk.chunk_number).collect()
+                }
+                _ => Vec::new(),
+            })
+            .collect();
+        assert_eq!(resent, [1, 3]);
+
+        // only once
+        run_system(&mut world, enqueue_terrain);
+        assert!(bodies(&mut world).is_empty());
+    }
+
+    #[test]
+    fn input_history_keeps_latest_inputs_in_order() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let addr = client.local_addr().unwrap();
+        let mut world = test_world(server);
+        let input = |block_x| PlayerInput {
+            left: false,
+            right: true,
+            jump: false,
+            mine: true,
+            block_x,
+            block_y: 7,
+        };
+
+        let total = INPUT_HISTORY_LEN + 3;
+        for sequence in 0..total {
+            client_send(
+                &client,
+                sequence as u64,
+                vec![ClientBodyElem::Input(input(sequence))],
+            );
+            let mut server = world.resource_mut::<Server>();
+            let (info, message) = server.get_one_message().unwrap();
+            compute_new_bodies(info, message, 0, &mut HashMap::new(), Instant::now());
+        }
+
+        // only the newest ones, oldest first
+        let server = world.resource::<Server>();
+        let history = &server.clients[&addr].input_history;
+        assert_eq!(history.len(), INPUT_HISTORY_LEN);
+        assert!(history
+            .iter()
+            .zip(3..total)
+            .all(|((sequence, input), expected)| {
+                *sequence == expected as u64 && input.block_x == expected
+            }));
+
+        let path = std::env::temp_dir().join("game_input_history_test.txt");
+        assert!(matches!(
+            parse_admin_command(&format!("inputs {} {}", addr, path.display())),
+            Ok(AdminCommand::DumpInputs(a, p)) if a == addr && p == path
+        ));
+        world.send_event(AdminCommand::DumpInputs(addr, path.clone()));
+        run_system(&mut world, handle_admin_commands);
+        let dumped = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = dumped.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(lines.len(), INPUT_HISTORY_LEN);
+        assert_eq!(lines[0], "3 0 1 0 1 3 7");
+    }
+
+    #[test]
+    fn joining_spawns_a_player_and_leaving_despawns_it() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let addr = client.local_addr().unwrap();
+        let mut world = test_world(server);
+        let right = ClientBodyElem::Input(PlayerInput {
+            left: false,
+            right: true,
+            jump: false,
+            mine: false,
+            block_x: 0,
+            block_y: 0,
+        });
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+
+        let id = world.resource::<Server>().clients[&addr].player_id.unwrap();
+        let mut players = world.query::<(&NetworkId, &Transform, &Velocity)>();
+        assert_eq!(players.iter(&world).count(), 1);
+        assert_eq!(players.single(&world).0 .0, id);
+
+        // it's the one the client's inputs move
+        client_send(&client, 1, vec![right]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, apply_inputs);
+        run_system(&mut world, simulate_physics);
+        let (_, transform, velocity) = players.single(&world);
+        assert_eq!(velocity.x, PLAYER_SPEED);
+        assert!(transform.translation.x > 0.);
+
+        // and it's gone with the client
+        world
+            .resource_mut::<Server>()
+            .queue_mutation(ClientMutation::Remove(addr, DisconnectReason::Kicked));
+        run_system(&mut world, apply_client_mutations);
+        run_system(&mut world, despawn_departed_players);
+        assert_eq!(players.iter(&world).count(), 0);
+    }
+
+    #[test]
+    fn observers_get_snapshots_but_their_inputs_are_ignored() {
+        let (server, network) = test_server();
+        let players: Vec<ChannelTransport> = (0..DEFAULT_MAX_CLIENTS as u16)
+            .map(|i| test_client(&network, 9000 + i))
+            .collect();
+        let observer = test_client(&network, 9100);
+        let extra = test_client(&network, 9101);
+        let mut world = test_world(server);
+        let input = || {
+            ClientBodyElem::Input(PlayerInput {
+                left: true,
+                right: false,
+                jump: true,
+                mine: true,
+                block_x: 1,
+                block_y: 2,
+            })
+        };
+
+        // the server is full of players, observers still fit
+        for player in &players {
+            client_send(player, 0, vec![ClientBodyElem::Ping]);
+        }
+        client_send(&observer, 0, vec![ClientBodyElem::Observe, input()]);
+        client_send(&observer, 0, vec![input()]);
+        client_send(&extra, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+
+        let addr = observer.local_addr().unwrap();
+        assert!(world.resource::<Server>().clients[&addr].observer);
+        assert!(!world
+            .resource::<HashMap<SocketAddr, PlayerInput>>()
+            .contains_key(&addr));
+        assert!(world.resource::<Server>().clients[&addr]
+            .player_id
+            .is_none());
+        assert!(matches!(
+            client_recv(&extra).unwrap().bodies[..],
+            [ServerBodyElem::Disconnect(DisconnectReason::ServerFull)]
+        ));
+
+        run_system(&mut world, enqueue_entity_snapshot);
+        run_system(&mut world, send_all_messages);
+        let mut snapshot = None;
+        while let Some(message) = client_recv(&observer) {
+            snapshot = snapshot.or(message.bodies.into_iter().find_map(|body| match body {
+                ServerBodyElem::EntitySnapshot { entities, .. } => Some(entities),
+                _ => None,
+            }));
+        }
+        // every player, and nothing for the observer
+        assert_eq!(snapshot.unwrap().len(), players.len());
+    }
+
+    #[test]
+    fn unsendable_terrain_falls_back_to_last_good_one() {
+        let (server, network) = test_server();
+        let first = test_client(&network, 9000);
+        let second = test_client(&network, 9001);
+        let mut world = test_world(server);
+        world.insert_resource(Terrain::new(2));
+        let good = world.resource::<Terrain>().clone();
+        client_send(&first, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, enqueue_terrain);
+        // the size status reports comes from the check, the terrain isn't encoded again for it
+        let size = world.resource::<Server>().last_good_terrain_size;
+        assert_eq!(size, Some(good.encode().unwrap().len()));
+
+        // more chunks than clients accept
+        let mut bad = Terrain::empty();
+        bad.chunks = (0..=MAX_TERRAIN_CHUNKS)
+            .map(|chunk_number| Chunk {
+                blocks: [[None; CHUNK_WIDTH]; CHUNK_HEIGHT],
+                rendered: false,
+                chunk_number,
+            })
+            .collect();
+        assert!(matches!(
+            check_terrain(&bad),
+            Err(TerrainCheckError::Decode(_))
+        ));
+        // an admin can't switch to it either
+        world.send_event(AdminCommand::MapChange(bad.clone()));
+        run_system(&mut world, handle_admin_commands);
+        assert_eq!(*world.resource::<Terrain>(), good);
+
+        world.insert_resource(bad);
+        client_send(&second, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        forget_welcomes(&mut world.resource_mut::<Server>());
+        run_system(&mut world, enqueue_terrain);
+
+        let server = world.resource::<Server>();
+        let info = &server.clients[&second.local_addr().unwrap()];
+        assert!(matches!(
+            &info.reliable[..],
+            [pending, ..] if matches!(&pending.elem.body, ServerBodyElem::Terrain(t) if *t == good)
+        ));
+    }
+
+    #[test]
+    fn ack_latency_from_delayed_acks() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 9000));
+        let mut info = ClientInfo::new(addr, 0, Instant::now());
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        for sequence in 1..=3 {
+            info.note_sent(sequence, 100, start + ms(10 * sequence));
+        }
+
+        // 1 is acked 50ms after it went out, 2 never is, 3 after 20ms
+        info.note_acked(1, start + ms(60));
+        info.note_acked(3, start + ms(50));
+        // acking something already forgotten changes nothing
+        info.note_acked(2, start + ms(70));
+        assert_eq!(
+            info.ack_latency,
+            AckLatencyStats {
+                acks: 2,
+                total: ms(70),
+                min: ms(20),
+                max: ms(50),
+            }
+        );
+        assert_eq!(info.ack_latency.average(), Some(ms(35)));
+        assert_eq!(info.rtt, Some(ms(20)));
+
+        let other = AckLatencyStats {
+            acks: 1,
+            total: ms(5),
+            min: ms(5),
+            max: ms(5),
+        };
+        let merged = info.ack_latency.merge(other);
+        assert_eq!((merged.acks, merged.min, merged.max), (3, ms(5), ms(50)));
+        assert_eq!(AckLatencyStats::default().merge(other), other);
+    }
+
+    #[test]
+    fn second_connect_from_known_address_follows_policy() {
+        for policy in [DuplicateConnect::Reconnect, DuplicateConnect::Reject] {
+            let (mut server, network) = test_server_with(ServerConfig {
+                duplicate_connect: policy,
+                ..ServerConfig::default()
+            });
+            let client = test_client(&network, 9000);
+            let addr = client.local_addr().unwrap();
+            let connect = || vec![ClientBodyElem::Codecs(vec![Codec::Deflate])];
+
+            // connects, then hears back and acks
+            client_send(&client, 0, connect());
+            let (info, message) = server.get_one_message().unwrap();
+            compute_new_bodies(info, message, 0, &mut HashMap::new(), Instant::now());
+            client_send(&client, 1, vec![ClientBodyElem::Ping]);
+            let (info, message) = server.get_one_message().unwrap();
+            compute_new_bodies(info, message, 0, &mut HashMap::new(), Instant::now());
+            assert_eq!(server.clients[&addr].last_ack, 1);
+            let info = server.clients.get_mut(&addr).unwrap();
+            info.name = Some("miner".to_string());
+            info.player_id = Some(3);
+
+            // then restarts on the same port
+            client_send(&client, 0, connect());
+            let result = server.get_one_message().map(|(info, _)| info.join_number);
+            match policy {
+                DuplicateConnect::Reconnect => {
+                    // still the same player, so it keeps its place in the join order
+                    assert_eq!(result.unwrap(), 0);
+                    let info = &server.clients[&addr];
+                    assert_eq!(info.last_ack, 0);
+                    assert_eq!(info.name.as_deref(), Some("miner"));
+                    assert_eq!(info.player_id, Some(3));
+                }
+                DuplicateConnect::Reject => {
+                    assert!(matches!(result, Err(ReceiveError::DuplicateConnect)));
+                    assert_eq!(server.clients[&addr].join_number, 0);
+                    assert_eq!(server.clients[&addr].last_ack, 1);
+                }
+            }
+            assert_eq!(server.clients.len(), 1);
+        }
+    }
+
+    #[test]
+    fn system_times_recorded() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+        world.insert_resource(Terrain::new(1));
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, enqueue_terrain);
+        run_system(&mut world, send_all_messages);
+        run_system(&mut world, drop_disconnected_clients);
+
+        let times = world.resource::<Server>().status().metrics.system_times;
+        for timing in [
+            times.handle_messages,
+            times.enqueue_terrain,
+            times.send_messages,
+            times.drop_clients,
+        ] {
+            assert_eq!(timing.runs, 1);
+            assert!(timing.total > Duration::ZERO);
+            assert_eq!(timing.max, timing.total);
+        }
+        assert!(times.to_string().starts_with("handle messages avg "));
+    }
+
+    #[test]
+    fn broken_socket_is_rebound_or_given_up_on() {
+        // 192.0.2.0/24 is set aside for documentation, this machine can't bind it
+        for (bind_addr, recovers) in [
+            (SocketAddr::from(([127, 0, 0, 1], 0)), true),
+            (SocketAddr::from(([192, 0, 2, 1], 9)), false),
+        ] {
+            let server = Server::with_transport(
+                Box::new(ClosedTransport),
+                ServerConfig {
+                    bind_addr,
+                    bind_retries: 2,
+                    bind_retry_delay: Duration::ZERO,
+                    ..ServerConfig::default()
+                },
+            );
+            let mut world = test_world(server);
+            world.insert_resource(Events::<SocketFailed>::default());
+            world.insert_resource(Events::<AppExit>::default());
+
+            for _ in 0..SOCKET_ERRORS_BEFORE_REBIND + 5 {
+                run_system(&mut world, server_handle_messages);
+                run_system(&mut world, check_socket);
+            }
+
+            let server = world.resource::<Server>();
+            let failures: Vec<(SocketAddr, u32)> = world
+                .resource::<Events<SocketFailed>>()
+                .iter_current_update_events()
+                .map(|failure| (failure.addr, failure.attempts))
+                .collect();
+            if recovers {
+                assert!(server.transport.local_addr().is_ok());
+                assert_eq!(server.socket_errors, 0);
+                assert!(failures.is_empty());
+                assert!(world
+                    .get_resource::<NextState<states::server::GameState>>()
+                    .is_none());
+            } else {
+                // one bind and two retries, then it stops, once
+                assert!(server.socket_failed);
+                assert_eq!(failures, [(bind_addr, 3)]);
+                assert_eq!(world.resource::<Events<AppExit>>().len(), 1);
+                assert!(matches!(
+                    world.get_resource::<NextState<states::server::GameState>>(),
+                    Some(NextState(states::server::GameState::Stopped))
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn new_client_welcomed_in_order() {
+        let (mut server, network) = test_server();
+        let first = test_client(&network, 9000);
+        client_send(&first, 0, vec![ClientBodyElem::Ping]);
+        server.get_one_message().unwrap();
+        let mut world = test_world(server);
+        run_system(&mut world, enqueue_terrain);
+        run_system(&mut world, enqueue_settings);
+        world
+            .resource_mut::<Server>()
+            .apply_mutation(ClientMutation::UpdateName(
+                first.local_addr().unwrap(),
+                "alice".to_string(),
+            ));
+
+        let second = test_client(&network, 9001);
+        client_send(&second, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, enqueue_terrain);
+        run_system(&mut world, enqueue_settings);
+        run_system(&mut world, send_all_messages);
+        client_recv(&first).unwrap();
+
+        let message = client_recv(&second).unwrap();
+        let kinds: Vec<&str> = message.reliable.iter().map(|r| r.body.kind()).collect();
+        assert_eq!(
+            kinds,
+            ["game_settings", "player_list", "announcement", "terrain"]
+        );
+        assert!(message.bodies.iter().all(|b| !matches!(
+            b,
+            ServerBodyElem::TerrainChunks(_) | ServerBodyElem::TerrainVersion(_)
+        )));
+        match &message.reliable[1].body {
+            ServerBodyElem::PlayerList(players) => {
+                let names: Vec<String> = players.iter().map(|p| p.to_string()).collect();
+                assert_eq!(names, ["alice", "player #1"]);
+            }
+            other => panic!("expected the player list, got {}", other),
+        }
+        assert!(matches!(
+            &message.reliable[2].body,
+            ServerBodyElem::Announcement(text) if text == DEFAULT_WELCOME_MESSAGE
+        ));
+    }
+
+    #[test]
+    fn new_client_welcomed_without_terrain() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+        world.remove_resource::<Terrain>();
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, enqueue_terrain);
+        run_system(&mut world, send_all_messages);
+        let message = client_recv(&client).unwrap();
+        let kinds: Vec<&str> = message.reliable.iter().map(|r| r.body.kind()).collect();
+        assert_eq!(kinds, ["game_settings", "player_list", "announcement"]);
+
+        // the terrain follows once there is one, without welcoming it again
+        world.insert_resource(Terrain::new(1));
+        run_system(&mut world, enqueue_terrain);
+        run_system(&mut world, enqueue_settings);
+        let server = world.resource::<Server>();
+        let info = server.clients.values().next().unwrap();
+        let kinds: Vec<&str> = info.reliable.iter().map(|r| r.elem.body.kind()).collect();
+        assert_eq!(
+            kinds,
+            ["game_settings", "player_list", "announcement", "terrain"]
+        );
+    }
+
+    #[test]
+    fn altered_client_world_reports_desync() {
+        let (server, network) = test_server_with(ServerConfig {
+            checksum_interval_ticks: 1,
+            resync_on_desync: true,
+            ..ServerConfig::default()
+        });
+        let client = test_client(&network, 9000);
+        let client_addr = client.local_addr().unwrap();
+        let mut world = test_world(server);
+        world.insert_resource(Terrain::new(1));
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, update_world_checksum);
+        run_system(&mut world, enqueue_terrain);
+        run_system(&mut world, send_all_messages);
+        let welcome = client_recv(&client).unwrap();
+        // still getting the terrain, so its world can't be expected to match yet
+        assert_eq!(welcome.header.world_checksum, None);
+
+        client_send(&client, welcome.header.sequence, vec![]);
+        run_system(&mut world, server_handle_messages);
+        // nothing has changed for a while
+        world.resource_mut::<Server>().sequence += DEFAULT_GAME_TICK_HZ;
+        run_system(&mut world, send_all_messages);
+        let message = client_recv(&client).unwrap();
+        let terrain = world.resource::<Terrain>().clone();
+        assert_eq!(message.header.world_checksum, Some(terrain.checksum()));
+        assert!(desync_report(&message.header, &terrain).is_none());
+
+        // a client that mined a block the server doesn't know about
+        let mut altered = terrain.clone();
+        let y = (0..CHUNK_HEIGHT)
+            .find(|y| altered.cell(0, *y).is_some())
+            .unwrap();
+        remove_block(0, y, &mut altered).unwrap();
+        let report = desync_report(&message.header, &altered).unwrap();
+        assert!(matches!(
+            report,
+            ClientBodyElem::DesyncReport { tick, client_checksum }
+                if tick == message.header.snapshot_tick && client_checksum == altered.checksum()
+        ));
+
+        client_send(&client, message.header.sequence, vec![report]);
+        run_system(&mut world, server_handle_messages);
+        let server = world.resource::<Server>();
+        assert_eq!(server.metrics.desyncs, 1);
+        let info = &server.clients[&client_addr];
+        assert_eq!(info.desyncs, 1);
+        // and it gets the whole terrain again
+        assert!(info.terrain_baselines.is_empty());
+    }
+
+    #[test]
+    fn terrain_and_entities_use_their_own_codecs() {
+        let (server, network) = test_server_with(ServerConfig {
+            terrain_codec: Codec::Uncompressed,
+            entity_codec: Codec::Deflate,
+            ..ServerConfig::default()
+        });
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+        world.insert_resource(Terrain::new(1));
+
+        // the only entity is the client's own player
+        client_send(
+            &client,
+            0,
+            vec![ClientBodyElem::Codecs(vec![Codec::Deflate])],
+        );
+        run_system(&mut world, server_handle_messages);
+        assert_eq!(
+            world.resource::<Server>().clients[&client.local_addr().unwrap()].codecs,
+            PayloadCodecs {
+                terrain: Codec::Uncompressed,
+                entities: Codec::Deflate,
+            }
+        );
+        forget_welcomes(&mut world.resource_mut::<Server>());
+        run_system(&mut world, enqueue_terrain);
+        run_system(&mut world, enqueue_entity_snapshot);
+        run_system(&mut world, send_all_messages);
+
+        // both in the same packet, only the entities compressed
+        let message = client_recv(&client).unwrap();
+        assert!(matches!(
+            message.reliable[0].body,
+            ServerBodyElem::Terrain(_)
+        ));
+        let (codec, data) = message
+            .bodies
+            .iter()
+            .find_map(|b| match b {
+                ServerBodyElem::Compressed { codec, data } => Some((*codec, data)),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(codec, Codec::Deflate);
+        assert!(matches!(
+            ServerBodyElem::decompress(codec, data).unwrap(),
+            ServerBodyElem::EntitySnapshot { entities, .. } if entities.len() == 1
+        ));
+    }
+
+    #[test]
+    fn middleware_veto_stops_message_before_handling() {
+        let (mut server, network) = test_server();
+        let vetoed = Arc::new(AtomicU64::new(0));
+        let counter = vetoed.clone();
+        server.middleware.add_inbound(move |_, message| {
+            let input = message
+                .bodies
+                .iter()
+                .any(|b| matches!(b, ClientBodyElem::Input(_)));
+            if input {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+            !input
+        });
+        let client = test_client(&network, 9000);
+        let addr = client.local_addr().unwrap();
+        let mut world = test_world(server);
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        client_send(
+            &client,
+            0,
+            vec![
+                ClientBodyElem::TokenPing(5),
+                ClientBodyElem::Input(PlayerInput {
+                    left: true,
+                    right: false,
+                    jump: false,
+                    mine: false,
+                    block_x: 0,
+                    block_y: 0,
+                }),
+            ],
+        );
+        run_system(&mut world, server_handle_messages);
+
+        assert_eq!(vetoed.load(Ordering::Relaxed), 1);
+        assert!(world
+            .resource::<HashMap<SocketAddr, PlayerInput>>()
+            .is_empty());
+        // the whole message went, not just the input
+        let bodies = &world.resource::<Server>().clients[&addr].bodies;
+        assert!(bodies.iter().any(|b| matches!(b, ServerBodyElem::Pong(_))));
+        assert!(!bodies
+            .iter()
+            .any(|b| matches!(b, ServerBodyElem::TokenPong { .. })));
+    }
+
+    #[test]
+    fn inputs_arriving_on_drop_tick_are_not_stored() {
+        let input = PlayerInput {
+            left: true,
+            right: false,
+            jump: false,
+            mine: false,
+            block_x: 0,
+            block_y: 0,
+        };
+        let run = |config: ServerConfig| {
+            let (server, network) = test_server_with(config);
+            let client = test_client(&network, 9000);
+            let addr = client.local_addr().unwrap();
+            let mut world = test_world(server);
+            client_send(&client, 0, vec![ClientBodyElem::Ping]);
+            run_system(&mut world, server_handle_messages);
+
+            // kicked during the game tick, the input comes in before the network tick removes it
+            // without a ban, so it isn't refused on arrival
+            world.send_event(AdminCommand::Kick(addr));
+            run_system(&mut world, handle_admin_commands);
+            client_send(&client, 1, vec![ClientBodyElem::Input(input.clone())]);
+            run_system(&mut world, server_handle_messages);
+            let stored = world
+                .resource::<HashMap<SocketAddr, PlayerInput>>()
+                .contains_key(&addr);
+            run_system(&mut world, apply_client_mutations);
+            assert!(world.resource::<Server>().clients.is_empty());
+            assert!(world
+                .resource::<HashMap<SocketAddr, PlayerInput>>()
+                .is_empty());
+            stored
+        };
+        let no_ban = ServerConfig {
+            kick_ban: Duration::ZERO,
+            ..ServerConfig::default()
+        };
+        assert!(!run(no_ban.clone()));
+        assert!(run(ServerConfig {
+            inputs_while_dropping: true,
+            ..no_ban
+        }));
+
+        // inputs from before it was kicked stop being applied right away too
+        let run = |config: ServerConfig| {
+            let (server, network) = test_server_with(config);
+            let client = test_client(&network, 9000);
+            let addr = client.local_addr().unwrap();
+            let mut world = test_world(server);
+            client_send(&client, 0, vec![ClientBodyElem::Ping]);
+            run_system(&mut world, server_handle_messages);
+            world
+                .resource_mut::<Server>()
+                .clients
+                .get_mut(&addr)
+                .unwrap()
+                .state = ConnectionState::Connected;
+            client_send(&client, 1, vec![ClientBodyElem::Input(input.clone())]);
+            run_system(&mut world, server_handle_messages);
+            assert!(world
+                .resource::<HashMap<SocketAddr, PlayerInput>>()
+                .contains_key(&addr));
+            world.send_event(AdminCommand::Kick(addr));
+            run_system(&mut world, handle_admin_commands);
+            world
+                .resource::<HashMap<SocketAddr, PlayerInput>>()
+                .contains_key(&addr)
+        };
+        assert!(!run(ServerConfig::default()));
+        assert!(run(ServerConfig {
+            inputs_while_dropping: true,
+            ..ServerConfig::default()
+        }));
+
+        // timed out on the same tick an input with a stale ack came in
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+        client_send(&client, 1, vec![ClientBodyElem::Input(input)]);
+        run_system(&mut world, server_handle_messages);
+        {
+            let mut server = world.resource_mut::<Server>();
+            let info = server.clients.values_mut().next().unwrap();
+            info.grace_until = info.connected_at;
+            info.until_drop = 0;
+        }
+        assert_eq!(
+            world.resource::<HashMap<SocketAddr, PlayerInput>>().len(),
+            1
+        );
+        run_system(&mut world, drop_disconnected_clients);
+        run_system(&mut world, apply_client_mutations);
+        assert!(world.resource::<Server>().clients.is_empty());
+        assert!(world
+            .resource::<HashMap<SocketAddr, PlayerInput>>()
+            .is_empty());
+    }
+
+    #[test]
+    fn client_message_builder_fills_in_header_and_bodies() {
+        let input = PlayerInput {
+            left: false,
+            right: true,
+            jump: true,
+            mine: false,
+            block_x: 3,
+            block_y: 4,
+        };
+        let message = ClientToServer::builder()
+            .ack(7)
+            .body(ClientBodyElem::Ping)
+            .input(input)
+            .build();
+        assert_eq!(message.header.last_received_sequence, 7);
+        assert_eq!(message.header.current_sequence, 0);
+        assert!(matches!(
+            message.bodies[..],
+            [
+                ClientBodyElem::Ping,
+                ClientBodyElem::Input(PlayerInput {
+                    right: true,
+                    jump: true,
+                    block_x: 3,
+                    ..
+                })
+            ]
+        ));
+
+        let message = ClientToServer::builder()
+            .ack(2)
+            .sequence(9)
+            .token_ping(5)
+            .bodies([ClientBodyElem::TerrainAck(1), ClientBodyElem::EntityAck(4)])
+            .body(ClientBodyElem::Observe)
+            .build();
+        assert_eq!(
+            (
+                message.header.current_sequence,
+                message.header.last_received_sequence
+            ),
+            (9, 2)
+        );
+        assert!(matches!(
+            message.bodies[..],
+            [
+                ClientBodyElem::TokenPing(5),
+                ClientBodyElem::TerrainAck(1),
+                ClientBodyElem::EntityAck(4),
+                ClientBodyElem::Observe
+            ]
+        ));
+
+        // nothing but the ack is still a message the server handles
+        let message = ClientToServer::builder().ack(0).build();
+        assert!(message.bodies.is_empty());
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let server_addr = SocketAddr::from((DEFAULT_SERVER_IP, DEFAULT_SERVER_PORT));
+        send_message(&client, server_addr, message).unwrap();
+        let mut world = test_world(server);
+        run_system(&mut world, server_handle_messages);
+        assert_eq!(world.resource::<Server>().clients.len(), 1);
+    }
+
+    #[test]
+    fn low_res_client_gets_smaller_terrain() {
+        let (server, network) = test_server();
+        let full = test_client(&network, 9000);
+        let low = test_client(&network, 9001);
+        let mut world = test_world(server);
+        world.insert_resource(Terrain::new(2));
+        client_send(&full, 0, vec![ClientBodyElem::Ping]);
+        let offer = ClientBodyElem::ProtocolVersions {
+            min: MIN_PROTOCOL_VERSION,
+            max: COARSE_PROTOCOL_VERSION,
+        };
+        client_send(&low, 0, vec![offer, ClientBodyElem::TerrainScale(4)]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, enqueue_terrain);
+        run_system(&mut world, send_all_messages);
+
+        // size of the terrain a client got and the chunks it ends up with
+        let receive = |client: &ChannelTransport| {
+            let mut buffer = [0u8; BUFFER_SIZE];
+            let (size, _) = client.recv_from(&mut buffer).unwrap();
+            let message: ServerToClient =
+                bincode::decode_from_slice(&buffer[..size], BINCODE_CONFIG)
+                    .unwrap()
+                    .0;
+            let terrain = message
+                .reliable
+                .into_iter()
+                .find_map(|r| match r.body {
+                    ServerBodyElem::Terrain(t) => Some(t),
+                    ServerBodyElem::Coarse { kind, chunks } => {
+                        match ServerBodyElem::upsample(kind, &chunks) {
+                            Some(ServerBodyElem::Terrain(t)) => Some(t),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                })
+                .unwrap();
+            (size, terrain)
+        };
+        let (full_size, full_terrain) = receive(&full);
+        let (low_size, low_terrain) = receive(&low);
+        assert!(low_size < full_size, "{} vs {}", low_size, full_size);
+        assert_eq!(full_terrain.chunks, world.resource::<Terrain>().chunks);
+        assert_eq!(low_terrain.chunks.len(), 2);
+        assert_ne!(low_terrain.chunks, full_terrain.chunks);
+
+        // changes go out at the same resolution as the baseline they apply to
+        let low_addr = low.local_addr().unwrap();
+        let changed = world.resource::<Terrain>().chunks[0].clone();
+        {
+            let mut server = world.resource_mut::<Server>();
+            let info = server.clients.get_mut(&low_addr).unwrap();
+            assert!(!info.terrain_synced(0));
+            info.bodies
+                .push(ServerBodyElem::TerrainChunks(vec![changed]));
+        }
+        run_system(&mut world, send_all_messages);
+        let message = client_recv(&low).unwrap();
+        // this client never offered any codecs, so it's not compressed
+        assert!(message.bodies.iter().any(|body| matches!(
+            body,
+            ServerBodyElem::Coarse { kind: CoarseKind::TerrainChunks, chunks } if chunks[0].scale == 4
+        )));
+
+        // a scale that doesn't split chunks evenly is ignored, a new one means a new baseline
+        let mut server = world.resource_mut::<Server>();
+        let info = server.clients.get_mut(&low_addr).unwrap();
+        info.set_terrain_scale(3);
+        assert_eq!(info.terrain_scale, 4);
+        info.set_terrain_scale(2);
+        assert_eq!(info.terrain_scale, 2);
+        assert!(info.terrain_baselines.is_empty());
+    }
+
+    #[test]
+    fn back_to_back_receives_dont_share_the_buffer() {
+        let (mut server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let long = "a".repeat(200);
+        client_send(&client, 1, vec![ClientBodyElem::AdminCommand(long.clone())]);
+        client_send(
+            &client,
+            2,
+            vec![ClientBodyElem::AdminCommand("b".to_string())],
+        );
+
+        let (_, first) = server.get_one_message().unwrap();
+        let (_, second) = server.get_one_message().unwrap();
+        // the second packet is shorter, the end of the first is still sitting in the buffer
+        let tail = &long.as_bytes()[100..];
+        assert!(server.buffer.windows(tail.len()).any(|w| w == tail));
+        assert!(matches!(
+            &first.bodies[..],
+            [ClientBodyElem::AdminCommand(command)] if *command == long
+        ));
+        assert!(matches!(
+            &second.bodies[..],
+            [ClientBodyElem::AdminCommand(command)] if command == "b"
+        ));
+        assert_eq!(
+            (
+                first.header.current_sequence,
+                second.header.current_sequence
+            ),
+            (1, 2)
+        );
+    }
+
+    #[test]
+    fn server_probe_echo_gives_rtt_sample() {
+        let config = ServerConfig {
+            probe_interval: Duration::from_secs(60),
+            ..ServerConfig::default()
+        };
+        let (server, network) = test_server_with(config);
+        let client = test_client(&network, 9000);
+        let addr = client.local_addr().unwrap();
+        let mut world = test_world(server);
+        // clients that don't say otherwise might not understand the server's pings
+        let versions = ClientBodyElem::ProtocolVersions {
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        };
+        client_send(&client, 0, vec![versions]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, send_all_messages);
+
+        let message = client_recv(&client).unwrap();
+        let token = message
+            .bodies
+            .iter()
+            .find_map(|body| match body {
+                ServerBodyElem::Ping(token) => Some(*token),
+                _ => None,
+            })
+            .unwrap();
+        // not due again for a while
+        run_system(&mut world, send_all_messages);
+        let message = client_recv(&client).unwrap();
+        assert!(!message
+            .bodies
+            .iter()
+            .any(|body| matches!(body, ServerBodyElem::Ping(_))));
+
+        // the echo doesn't ack anything new, so only the probe can time it
+        assert_eq!(world.resource::<Server>().clients[&addr].rtt, None);
+        client_send(&client, 0, vec![ClientBodyElem::Pong(token)]);
+        run_system(&mut world, server_handle_messages);
+        let info = &world.resource::<Server>().clients[&addr];
+        assert!(info.rtt.is_some());
+        assert_eq!(info.ack_latency.acks, 1);
+        assert!(info.probes.is_empty());
+
+        // echoing it again doesn't count twice
+        client_send(&client, 0, vec![ClientBodyElem::Pong(token)]);
+        run_system(&mut world, server_handle_messages);
+        assert_eq!(
+            world.resource::<Server>().clients[&addr].ack_latency.acks,
+            1
+        );
+    }
+
+    #[test]
+    fn server_refuses_to_start_with_a_directory_save_file() {
+        let start = |config: ServerConfig| {
+            let mut world = World::new();
+            world.insert_resource(Events::<AppExit>::default());
+            world.insert_resource(Events::<AdminCommand>::default());
+            run_system(
+                &mut world,
+                move |commands: Commands,
+                      exit: EventWriter<AppExit>,
+                      admin_commands: EventWriter<AdminCommand>| {
+                    create_server(
+                        commands,
+                        exit,
+                        admin_commands,
+                        &config,
+                        &Middleware::default(),
+                    )
+                },
+            );
+            (
+                world.contains_resource::<Server>(),
+                !world.resource::<Events<AppExit>>().is_empty(),
+            )
+        };
+        let config = ServerConfig {
+            bind_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            save_file: std::env::temp_dir(),
+            bind_retries: 0,
+            ..ServerConfig::default()
+        };
+        assert_eq!(start(config.clone()), (false, true));
+
+        // nothing is ever saved, so it doesn't matter where to
+        let (started, exited) = start(ServerConfig {
+            persist: false,
+            ..config
         });
+        assert!(started && !exited);
     }
-    info!(
-        "server got message from client @ {} with {} bodies: {}",
-        client.addr,
-        message.bodies.len(),
-        bodies_str
-    );
 
-    // this message is in-order
-    // TODO: whenever the clients send inputs, ignore any that are out of order
-    // i.e. only use the most recent input
-    if message.header.last_received_sequence > client.last_ack {
-        client.last_ack = message.header.last_received_sequence;
-        client.bodies.clear();
+    /// ServerToClient as a version 8 client has it, decoding fails on anything newer
+    #[derive(bincode::Decode)]
+    struct V8ServerToClient {
+        _header: ServerHeader,
+        bodies: Vec<V8ServerBodyElem>,
+        reliable: Vec<V8ReliableElem>,
+    }
 
-        // reset its drop timer
-        client.until_drop = FRAME_DIFFERENCE_BEFORE_DISCONNECT;
-    } else {
-        // message out of oder
+    #[derive(bincode::Decode)]
+    struct V8ReliableElem {
+        _id: u64,
+        _body: V8ServerBodyElem,
     }
 
-    // compute our responses
-    let mut body_elems: Vec<ServerBodyElem> = message
-        .bodies
-        .iter()
-        // match client bodies to server bodies
-        .filter_map(|elem| match elem {
-            ClientBodyElem::Ping => Some(ServerBodyElem::Pong(message.header.current_sequence)),
-            ClientBodyElem::Input(input) => {
-                // TODO: handle player input
-                info!("server storing current inputs to input hashmap");
-                //insert the players inputs into a hashmap that is a resource
-                let icopy = input.clone();
-                input_map.insert(client.addr, icopy);
-                None
+    #[derive(bincode::Decode, Debug)]
+    #[allow(dead_code)] // only ever decoded
+    enum V8ServerBodyElem {
+        Pong(u64),
+        Terrain(Terrain),
+        MapChange(Terrain),
+        TerrainChunks(Vec<Chunk>),
+        TerrainVersion(u64),
+        Disconnect(V8DisconnectReason),
+        Redirect {
+            addr: SocketAddr,
+        },
+        Compressed {
+            codec: Codec,
+            data: Vec<u8>,
+        },
+        TokenPong {
+            token: u64,
+            server_seq: u64,
+        },
+        EntitySnapshot {
+            snapshot: u64,
+            entities: Vec<EntityState>,
+        },
+        EntityDelta {
+            snapshot: u64,
+            baseline: u64,
+            changed: Vec<EntityState>,
+            removed: Vec<u64>,
+        },
+        GameSettings(GameSettings),
+        KeyExchange([u8; PUBLIC_KEY_LEN]),
+        Encrypted(Vec<u8>),
+        PlayerList(Vec<PlayerListEntry>),
+        Announcement(String),
+        Coarse {
+            kind: CoarseKind,
+            chunks: Vec<crate::world::CoarseChunk>,
+        },
+    }
+
+    #[derive(bincode::Decode, Debug, PartialEq, Eq)]
+    enum V8DisconnectReason {
+        Timeout,
+        Kicked,
+        ServerFull,
+        ServerShutdown,
+        UnsupportedVersion,
+    }
+
+    /// Next message to client, decoded the way a version 8 client would
+    fn v8_client_recv(client: &ChannelTransport) -> Option<V8ServerToClient> {
+        let mut buffer = [0u8; BUFFER_SIZE];
+        let (size, _) = client.recv_from(&mut buffer).ok()?;
+        Some(
+            bincode::decode_from_slice(&buffer[..size], BINCODE_CONFIG)
+                .unwrap()
+                .0,
+        )
+    }
+
+    #[test]
+    fn older_client_gets_highest_shared_version() {
+        assert_eq!(negotiate_version(7..=10, 5..=8), Some(8));
+        assert_eq!(negotiate_version(7..=10, 9..=12), Some(10));
+        assert_eq!(negotiate_version(7..=10, 1..=6), None);
+
+        let config = ServerConfig {
+            probe_interval: Duration::from_secs(60),
+            ..ServerConfig::default()
+        };
+        let (server, network) = test_server_with(config);
+        let older = test_client(&network, 9000);
+        let ancient = test_client(&network, 9001);
+        let mut world = test_world(server);
+        let offer = |min, max| ClientBodyElem::ProtocolVersions { min, max };
+        client_send(&older, 0, vec![offer(MIN_PROTOCOL_VERSION, 8)]);
+        client_send(&ancient, 0, vec![offer(1, MIN_PROTOCOL_VERSION - 1)]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, send_all_messages);
+
+        let older_addr = older.local_addr().unwrap();
+        assert_eq!(
+            world.resource::<Server>().clients[&older_addr].protocol_version,
+            8
+        );
+        // version 8 came before ProtocolVersion and the server's own pings, so it gets neither
+        let message = v8_client_recv(&older).unwrap();
+        assert!(!message.reliable.is_empty());
+        for _ in 0..3 {
+            run_system(&mut world, send_all_messages);
+            while v8_client_recv(&older).is_some() {}
+        }
+        // nor a reason it can't decode
+        world
+            .resource_mut::<Server>()
+            .disconnect(older_addr, DisconnectReason::Replaced);
+        let message = v8_client_recv(&older).unwrap();
+        assert!(matches!(
+            message.bodies[..],
+            [V8ServerBodyElem::Disconnect(V8DisconnectReason::Kicked)]
+        ));
+
+        run_system(&mut world, apply_client_mutations);
+        assert!(world.resource::<Server>().clients.is_empty());
+        let disconnect = std::iter::from_fn(|| client_recv(&ancient))
+            .flat_map(|message| message.bodies)
+            .find(|body| matches!(body, ServerBodyElem::Disconnect(_)));
+        assert!(matches!(
+            disconnect,
+            Some(ServerBodyElem::Disconnect(
+                DisconnectReason::UnsupportedVersion
+            ))
+        ));
+    }
+
+    #[test]
+    fn metrics_csv_gets_a_row_every_interval() {
+        let dir = std::env::temp_dir().join(format!("game_metrics_rows_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metrics.csv");
+        let _ = std::fs::remove_file(&path);
+
+        let (mut server, network) = test_server();
+        let server_addr = SocketAddr::from((DEFAULT_SERVER_IP, DEFAULT_SERVER_PORT));
+        server.metrics_csv = Some(MetricsCsv::open(&path).unwrap());
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+
+        for interval in 1..=3 {
+            client_send(&client, interval, vec![ClientBodyElem::Ping]);
+            if interval == 2 {
+                client.send_to(&[0xff; 8], server_addr).unwrap();
             }
-        })
-        .collect();
+            run_system(&mut world, server_handle_messages);
+            run_system(&mut world, send_all_messages);
+            run_system(&mut world, write_metrics_csv);
+        }
 
-    // info!(
-    //     "server responses += {}",
-    //     // debug format of all new elems
-    //     body_elems.iter().fold(String::new(), |mut acc, s| {
-    //         acc.push_str(&format!("({:?}) ", s));
-    //         acc
-    //     })
-    // );
+        let text = std::fs::read_to_string(&path).unwrap();
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some(METRICS_CSV_HEADER));
+        let columns = METRICS_CSV_HEADER.split(',').collect::<Vec<&str>>();
+        let rows = lines
+            .map(|line| {
+                let values = line.split(',').map(|v| v.parse::<u64>().unwrap());
+                columns
+                    .iter()
+                    .copied()
+                    .zip(values)
+                    .collect::<HashMap<&str, u64>>()
+            })
+            .collect::<Vec<HashMap<&str, u64>>>();
+        assert_eq!(rows.len(), 3);
+        for (row, packets) in rows.iter().zip(1..) {
+            assert_eq!(row.len(), columns.len());
+            assert_eq!(row["clients"], 1);
+            assert_eq!(row["packets_in"], packets);
+            assert!(row["bytes_out"] > 0);
+        }
+        // totals only go up
+        assert!(rows[2]["bytes_in"] > rows[0]["bytes_in"]);
+        assert_eq!(rows[0]["decode_errors"], 0);
+        assert_eq!(rows[2]["decode_errors"], 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-    // queue up our responses to be sent our in the next packet
-    client.bodies.append(&mut body_elems);
+    #[test]
+    fn inputs_for_a_despawned_player_are_ignored_or_kept() {
+        for keep in [MissingPlayerInputs::Ignore, MissingPlayerInputs::Keep] {
+            let config = ServerConfig {
+                missing_player_inputs: keep,
+                ..ServerConfig::default()
+            };
+            let (mut server, network) = test_server_with(config);
+            let client = test_client(&network, 9000);
+            let addr = client.local_addr().unwrap();
+            client_send(&client, 0, vec![ClientBodyElem::Ping]);
+            server.get_one_message().unwrap().0.player_id = Some(1);
+            let mut world = test_world(server);
+            world.insert_resource(Terrain::new(1));
+            let (block_x, block_y) = {
+                let terrain = world.resource::<Terrain>();
+                let y = (0..CHUNK_HEIGHT)
+                    .find(|y| terrain.chunks[0].blocks[*y][0].is_some())
+                    .unwrap();
+                (0, y)
+            };
+            world
+                .resource_mut::<HashMap<SocketAddr, PlayerInput>>()
+                .insert(
+                    addr,
+                    PlayerInput {
+                        left: false,
+                        right: true,
+                        jump: false,
+                        mine: true,
+                        block_x,
+                        block_y,
+                    },
+                );
 
-    // only keep pongs that are in response to a ping newer than or equals to the client's last_ack
-    client.bodies.retain(|elem| match elem {
-        ServerBodyElem::Pong(seq) => *seq >= client.last_ack,
-        ServerBodyElem::Terrain(_) => true, // always keep terrains
-    });
-}
+            // the player died, nothing should panic and nothing gets mined
+            run_system(&mut world, apply_inputs);
+            run_system(&mut world, simulate_physics);
+            assert_eq!(world.resource::<Server>().clients[&addr].blocks_mined, 0);
+            assert!(world.resource::<Terrain>().chunks[0].blocks[block_y][0].is_some());
+            let kept = world
+                .resource::<HashMap<SocketAddr, PlayerInput>>()
+                .contains_key(&addr);
+            assert_eq!(kept, keep == MissingPlayerInputs::Keep);
 
-fn send_all_messages(mut server: ResMut<Server>) {
-    // loop over clients
-    for (client_addr, client_info) in &server.clients {
-        let message = ServerToClient {
-            header: ServerHeader {
-                sequence: server.sequence,
-            },
-            bodies: client_info.bodies.clone(),
+            // once it respawns a kept input carries on where it left off
+            let player = world
+                .spawn()
+                .insert(NetworkId(1))
+                .insert(Transform::default())
+                .insert(Velocity::default())
+                .id();
+            run_system(&mut world, apply_inputs);
+            run_system(&mut world, simulate_physics);
+            let mined = world.resource::<Server>().clients[&addr].blocks_mined;
+            let velocity = *world.entity(player).get::<Velocity>().unwrap();
+            if keep == MissingPlayerInputs::Keep {
+                assert_eq!(mined, 1);
+                assert_eq!(velocity.x, PLAYER_SPEED);
+            } else {
+                assert_eq!(mined, 0);
+                assert_eq!(velocity.x, 0.);
+            }
+        }
+    }
+
+    #[test]
+    fn snapshot_history_rewinds_to_past_ticks() {
+        let (mut server, _network) = test_server();
+        server.snapshot_history = SnapshotHistory::new(3);
+        let mut world = test_world(server);
+        let player = world
+            .spawn()
+            .insert(NetworkId(1))
+            .insert(Transform::default())
+            .id();
+        for tick in 10..15 {
+            world.resource_mut::<Server>().sequence = tick;
+            world
+                .entity_mut(player)
+                .get_mut::<Transform>()
+                .unwrap()
+                .translation
+                .x = tick as f32;
+            run_system(&mut world, record_snapshot_history);
+        }
+
+        let history = &world.resource::<Server>().snapshot_history;
+        // only the last 3 ticks are left
+        assert_eq!(history.snapshots.len(), 3);
+        assert!(history.at_tick(11).is_none());
+        let (taken, entities) = history.at_tick(13).unwrap();
+        assert_eq!(taken, 13);
+        assert_eq!(
+            entities,
+            [EntityState {
+                id: 1,
+                x: 13.,
+                y: 0.
+            }]
+        );
+        // ticks we haven't got yet get the newest one
+        assert_eq!(history.at_tick(99).unwrap().0, 14);
+        assert!(matches!(
+            parse_admin_command("rewind 13"),
+            Ok(AdminCommand::Rewind(13))
+        ));
+    }
+
+    #[test]
+    fn client_goes_through_connection_states_in_order() {
+        use ConnectionState::*;
+        let leaving = Disconnecting { keep_inputs: false };
+        for (from, to) in [
+            (Connecting, Connected),
+            (Connected, Reconnecting),
+            (Reconnecting, Connected),
+        ] {
+            assert!(from.can_become(to));
+        }
+        for from in [Connecting, Connected, Reconnecting] {
+            assert!(from.can_become(leaving));
+        }
+        for (from, to) in [
+            (Connecting, Reconnecting),
+            (Connected, Connecting),
+            (leaving, Connected),
+            (leaving, leaving),
+        ] {
+            assert!(!from.can_become(to));
+        }
+
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let addr = client.local_addr().unwrap();
+        let mut world = test_world(server);
+        let input = PlayerInput {
+            left: true,
+            right: false,
+            jump: false,
+            mine: false,
+            block_x: 0,
+            block_y: 0,
+        };
+        let state = |world: &World| world.resource::<Server>().clients[&addr].state;
+
+        // it hasn't heard from us yet, so its input isn't taken
+        client_send(&client, 0, vec![ClientBodyElem::Input(input.clone())]);
+        run_system(&mut world, server_handle_messages);
+        assert_eq!(state(&world), Connecting);
+        assert!(world
+            .resource::<HashMap<SocketAddr, PlayerInput>>()
+            .is_empty());
+
+        client_send(&client, 1, vec![ClientBodyElem::Input(input)]);
+        run_system(&mut world, server_handle_messages);
+        assert_eq!(state(&world), Connected);
+        assert!(world
+            .resource::<HashMap<SocketAddr, PlayerInput>>()
+            .contains_key(&addr));
+
+        // restarting on the same port does the handshake again
+        client_send(&client, 0, vec![ClientBodyElem::Codecs(Vec::new())]);
+        run_system(&mut world, server_handle_messages);
+        assert_eq!(state(&world), Reconnecting);
+
+        // a kicked client can't be brought back, only given a new slot
+        world.send_event(AdminCommand::Kick(addr));
+        run_system(&mut world, handle_admin_commands);
+        assert_eq!(state(&world), leaving);
+        let mut server = world.resource_mut::<Server>();
+        let info = server.clients.get_mut(&addr).unwrap();
+        assert_eq!(
+            info.transition(Connected),
+            Err(InvalidTransition {
+                from: leaving,
+                to: Connected
+            })
+        );
+        assert_eq!(info.state, leaving);
+    }
+
+    #[test]
+    fn reconnects_past_the_cap_are_refused() {
+        use ConnectionState::*;
+        let (mut server, network) = test_server_with(ServerConfig {
+            max_reconnects: Some(2),
+            ..ServerConfig::default()
+        });
+        let client = test_client(&network, 9000);
+        let addr = client.local_addr().unwrap();
+        let connect = || vec![ClientBodyElem::Codecs(vec![Codec::Deflate])];
+        let reconnect = |server: &mut Server| {
+            // gets going, then restarts on the same port
+            client_send(&client, 1, vec![ClientBodyElem::Ping]);
+            let (info, message) = server.get_one_message().unwrap();
+            compute_new_bodies(info, message, 0, &mut HashMap::new(), Instant::now());
+            client_send(&client, 0, connect());
+            server.get_one_message().map(|(info, _)| info.state)
+        };
+
+        client_send(&client, 0, connect());
+        server.get_one_message().unwrap();
+        assert_eq!(reconnect(&mut server).unwrap(), Reconnecting);
+        assert_eq!(reconnect(&mut server).unwrap(), Reconnecting);
+        // the third one in the window gets ignored, the old slot stays
+        assert!(matches!(
+            reconnect(&mut server),
+            Err(ReceiveError::DuplicateConnect)
+        ));
+        assert_eq!(server.clients[&addr].state, Connected);
+
+        // once the old reconnects age out it's allowed again
+        let later = Instant::now() + server.config.reconnect_window * 2;
+        server.prune_bans(later);
+        assert!(server.reconnects.is_empty());
+        assert_eq!(reconnect(&mut server).unwrap(), Reconnecting);
+    }
+
+    #[test]
+    fn reliable_bodies_resent_while_stopping() {
+        let (mut server, network) = test_server();
+        server.config.shutdown_drain = Duration::from_millis(250);
+        let clock = FakeClock::new();
+        server.clock = Box::new(clock.clone());
+        let start = clock.now();
+        let client = test_client(&network, 9000);
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        server.get_one_message().unwrap();
+        let mut world = test_world(server);
+        assert!(matches!(
+            parse_admin_command("stop"),
+            Ok(AdminCommand::Stop)
+        ));
+
+        // nobody acks, so the goodbye keeps going out until the drain window is up
+        run_system(&mut world, destroy_server);
+        let messages: Vec<ServerToClient> = std::iter::from_fn(|| client_recv(&client)).collect();
+        let is_goodbye = |body: &ServerBodyElem| match body {
+            ServerBodyElem::Announcement(text) => text == DEFAULT_SHUTDOWN_MESSAGE,
+            _ => false,
         };
+        let goodbyes = messages
+            .iter()
+            .filter(|message| message.reliable.iter().any(|r| is_goodbye(&r.body)))
+            .count();
+        // at the start and every DRAIN_RESEND_INTERVAL after, all on the server's clock
+        assert_eq!(goodbyes, 3);
+        assert_eq!(clock.now() - start, Duration::from_millis(250));
+        assert!(matches!(
+            messages.last().unwrap().bodies[..],
+            [ServerBodyElem::Disconnect(DisconnectReason::ServerShutdown)]
+        ));
+    }
 
-        // form message via borrow before consuming it
-        let success_msg = format!("server sent message to {:?}", client_info.addr);
-        match server.send_message(*client_addr, message) {
-            Ok(_) => info!("{}", success_msg),
-            Err(e) => error!("server unable to send message: {:?}", e),
+    #[test]
+    fn packed_inputs_round_trip_and_are_smaller() {
+        let mining = PlayerInput {
+            left: true,
+            right: false,
+            jump: true,
+            mine: true,
+            block_x: 300,
+            block_y: 7,
+        };
+        let walking = PlayerInput {
+            mine: false,
+            block_x: 12,
+            ..mining.clone()
+        };
+        let size =
+            |body: ClientBodyElem| bincode::encode_to_vec(body, BINCODE_CONFIG).unwrap().len();
+        for input in [&mining, &walking] {
+            let packed = PackedInput::pack(input);
+            let unpacked = packed.unpack();
+            assert_eq!(
+                (unpacked.left, unpacked.right, unpacked.jump, unpacked.mine),
+                (input.left, input.right, input.jump, input.mine)
+            );
+            assert!(
+                size(ClientBodyElem::PackedInput(packed))
+                    < size(ClientBodyElem::Input(input.clone()))
+            );
         }
+        // the block only matters while mining
+        let unpacked = PackedInput::pack(&mining).unpack();
+        assert_eq!((unpacked.block_x, unpacked.block_y), (300, 7));
+        let unpacked = PackedInput::pack(&walking).unpack();
+        assert_eq!((unpacked.block_x, unpacked.block_y), (0, 0));
+
+        // the server stores it like any other input
+        let (server, network) = test_server();
+        let client = test_client(&network, 1);
+        let mut world = test_world(server);
+        client_send(
+            &client,
+            1,
+            vec![ClientBodyElem::PackedInput(PackedInput::pack(&mining))],
+        );
+        run_system(&mut world, server_handle_messages);
+        let stored =
+            &world.resource::<HashMap<SocketAddr, PlayerInput>>()[&client.local_addr().unwrap()];
+        assert_eq!(
+            (stored.mine, stored.block_x, stored.block_y),
+            (true, 300, 7)
+        );
     }
 
-    // filter out client bodies
-    for client_info in server.clients.values_mut() {
-        client_info.bodies.retain(|b| match b {
-            ServerBodyElem::Pong(_) => true, // keep pongs until we know they were received
-            ServerBodyElem::Terrain(_) => false, // never keep old terrains
+    #[test]
+    fn compacting_gives_back_room_after_clients_leave() {
+        let (mut server, _network) = test_server();
+        let mut input_map = HashMap::new();
+        let reserved = server.clients.capacity();
+        let now = Instant::now();
+        for port in 0..500 {
+            let addr = SocketAddr::from(([10, 0, 0, 1], port));
+            server.admit_client(addr, false, false);
+            server.bans.insert(addr, now);
+            input_map.insert(
+                addr,
+                PlayerInput {
+                    left: false,
+                    right: false,
+                    jump: false,
+                    mine: false,
+                    block_x: 0,
+                    block_y: 0,
+                },
+            );
+        }
+        // a full house shouldn't be touched
+        assert_eq!(server.compact(&mut input_map), 0);
+
+        let addrs: Vec<SocketAddr> = server.clients.keys().copied().collect();
+        for addr in addrs {
+            server.clients.remove(&addr);
+            input_map.remove(&addr);
+        }
+        server.prune_bans(now + Duration::from_secs(1));
+        assert!(server.clients.capacity() > reserved);
+
+        assert!(server.compact(&mut input_map) > 0);
+        // back to what it started with, not below
+        assert_eq!(server.clients.capacity(), reserved);
+        assert_eq!(server.bans.capacity(), 0);
+        assert!(input_map.capacity() <= reserved);
+        // nothing more to give back
+        assert_eq!(server.compact(&mut input_map), 0);
+    }
+
+    #[test]
+    fn probed_mtu_caps_packet_size() {
+        let (server, network) = test_server_with(ServerConfig {
+            probe_mtu: true,
+            ..ServerConfig::default()
         });
+        let client = test_client(&network, 9000);
+        let old = test_client(&network, 9001);
+        let mut world = test_world(server);
+        let versions = ClientBodyElem::ProtocolVersions {
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        };
+        client_send(&client, 0, vec![versions]);
+        client_send(&old, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, send_all_messages);
+
+        // every probe is padded out to its size, clients too old for them don't get any
+        let mut buffer = [0u8; BUFFER_SIZE];
+        let mut probed = Vec::new();
+        while let Ok((len, _)) = client.recv_from(&mut buffer) {
+            let message: ServerToClient = decode_into_owned(&buffer[..len]).unwrap();
+            if let [ServerBodyElem::MtuProbe { size, .. }] = message.bodies[..] {
+                assert!(len <= size as usize && len + 2 >= size as usize);
+                probed.push(size);
+            }
+        }
+        assert_eq!(probed, MTU_PROBE_SIZES);
+        while let Some(message) = client_recv(&old) {
+            assert!(!message
+                .bodies
+                .iter()
+                .any(|b| matches!(b, ServerBodyElem::MtuProbe { .. })));
+        }
+
+        // the biggest one got lost on the way
+        client_send(
+            &client,
+            1,
+            vec![ClientBodyElem::MtuAck(1200), ClientBodyElem::MtuAck(1400)],
+        );
+        run_system(&mut world, server_handle_messages);
+        let addr = client.local_addr().unwrap();
+        let old_addr = old.local_addr().unwrap();
+        let mut server = world.resource_mut::<Server>();
+        server.probe_mtus(Instant::now() + MTU_PROBE_TIMEOUT);
+        assert_eq!(server.clients[&addr].mtu, Some(1400));
+        assert_eq!(server.clients[&old_addr].mtu, None);
+
+        let announcement = || ServerBodyElem::Announcement("x".repeat(100));
+        for target in [addr, old_addr] {
+            let info = server.clients.get_mut(&target).unwrap();
+            info.bodies
+                .extend(std::iter::repeat_with(announcement).take(30));
+        }
+        run_system(&mut world, send_all_messages);
+        let len = client.recv_from(&mut buffer).unwrap().0;
+        assert!(len <= 1400);
+        let announcements = |message: ServerToClient| {
+            message
+                .bodies
+                .iter()
+                .filter(|b| matches!(b, ServerBodyElem::Announcement(_)))
+                .count()
+        };
+        let sent = announcements(decode_into_owned(&buffer[..len]).unwrap());
+        assert!(sent > 0 && sent < 30);
+        assert_eq!(announcements(client_recv(&old).unwrap()), 30);
     }
-}
 
-/// Add the terrain to the next packet sent
-/// TODO: convert to delta and baseline
-/// TODO: use reference for terrain instead of clone?
-fn enqueue_terrain(mut server: ResMut<Server>, terrain: Res<Terrain>) {
-    for client in server.clients.values_mut() {
-        client.bodies.push(ServerBodyElem::Terrain(terrain.clone()));
-        info!("enqueued terrain");
+    #[test]
+    fn body_bigger_than_the_mtu_goes_out_alone() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let addr = client.local_addr().unwrap();
+        let mut world = test_world(server);
+        let versions = ClientBodyElem::ProtocolVersions {
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        };
+        client_send(&client, 0, vec![versions]);
+        run_system(&mut world, server_handle_messages);
+        {
+            let mut server = world.resource_mut::<Server>();
+            forget_welcomes(&mut server);
+            let info = server.clients.get_mut(&addr).unwrap();
+            info.mtu = Some(1200);
+            info.enqueue_reliable(ServerBodyElem::Announcement("x".repeat(2000)));
+            info.enqueue_reliable(ServerBodyElem::Announcement("after".to_string()));
+        }
+        let receive = || {
+            let mut buffer = [0u8; BUFFER_SIZE];
+            let len = client.recv_from(&mut buffer).unwrap().0;
+            let message: ServerToClient = decode_into_owned(&buffer[..len]).unwrap();
+            let texts: Vec<usize> = message
+                .reliable
+                .iter()
+                .filter_map(|r| match &r.body {
+                    ServerBodyElem::Announcement(text) => Some(text.len()),
+                    _ => None,
+                })
+                .collect();
+            let answered = message
+                .bodies
+                .iter()
+                .any(|b| matches!(b, ServerBodyElem::ProtocolVersion(_)));
+            (len, texts, answered, message.header.sequence)
+        };
+
+        // the big one goes out on its own, everything else waits for room
+        run_system(&mut world, send_all_messages);
+        let (len, texts, answered, sequence) = receive();
+        assert!(len > 1200);
+        assert_eq!(texts, [2000]);
+        assert!(!answered);
+
+        // it keeps going out until it's acked, still on its own
+        run_system(&mut world, send_all_messages);
+        let (_, texts, answered, _) = receive();
+        assert_eq!(texts, [2000]);
+        assert!(!answered);
+
+        // and then what waited goes out, acking didn't throw it away
+        client_send(&client, sequence, vec![]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, send_all_messages);
+        let (len, texts, answered, _) = receive();
+        assert_eq!(texts, ["after".len()]);
+        assert!(len <= 1200);
+        assert!(answered);
     }
-}
 
-fn drop_disconnected_clients(mut server: ResMut<Server>) {
-    // drop clients that haven't responded in a while
-    server.clients.retain(|address, client| {
-        let keep = client.until_drop >= GAME_TICK_HZ;
-        if !keep {
-            warn!("dropping client {}", address);
+    #[test]
+    fn reconnect_token_from_before_a_restart_restores_the_player() {
+        let dir = std::env::temp_dir().join(format!("game_restart_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let network = ChannelNetwork::new();
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], DEFAULT_SERVER_PORT));
+        let config = ServerConfig {
+            persist: true,
+            save_file: dir.join("world.sav"),
+            ..ServerConfig::default()
+        };
+        let launch = |world: &mut World| {
+            let server =
+                Server::with_transport(Box::new(network.endpoint(server_addr)), config.clone());
+            world.insert_resource(server);
+        };
+        let client = test_client(&network, 9000);
+        let addr = client.local_addr().unwrap();
+        let hello = || ClientBodyElem::ProtocolVersions {
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        };
+
+        let mut world = test_world(test_server().0);
+        launch(&mut world);
+        client_send(&client, 0, vec![hello()]);
+        run_system(&mut world, server_handle_messages);
+        {
+            let mut server = world.resource_mut::<Server>();
+            let info = server.clients.get_mut(&addr).unwrap();
+            info.name = Some("digger".to_string());
+            assert_eq!(info.player_id, Some(0));
         }
+        let mut player = world.query::<&mut Transform>();
+        player.single_mut(&mut world).translation = Vec3::new(64., -96., 0.);
+        world.send_event(AdminCommand::Restart(3));
+        run_system(&mut world, handle_admin_commands);
+        run_system(&mut world, send_all_messages);
+        let token = std::iter::from_fn(|| client_recv(&client))
+            .flat_map(|message| message.reliable)
+            .find_map(|r| match r.body {
+                ServerBodyElem::Restarting {
+                    reconnect_token,
+                    after_seconds: 3,
+                } => Some(reconnect_token),
+                _ => None,
+            })
+            .unwrap();
 
-        keep
-    });
+        // started again, the client is someone new until it brings the right token
+        // and so is anyone else who joins, without taking its old id
+        let mut world = test_world(test_server().0);
+        launch(&mut world);
+        assert!(world
+            .resource_mut::<Server>()
+            .resume_restart(Instant::now()));
+        assert!(!world
+            .resource_mut::<Server>()
+            .resume_restart(Instant::now()));
+        let other = test_client(&network, 9001);
+        client_send(&other, 0, vec![hello()]);
+        run_system(&mut world, server_handle_messages);
+        client_send(&client, 0, vec![ClientBodyElem::ReconnectToken(token + 1)]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, restore_returning_players);
+        let server = world.resource::<Server>();
+        assert_eq!(
+            server.clients[&other.local_addr().unwrap()].player_id,
+            Some(1)
+        );
+        assert_eq!(server.clients[&addr].player_id, Some(2));
 
-    // loop through active clients
-    for client_info in server.clients.values_mut() {
-        client_info.until_drop -= GAME_TICK_HZ;
+        client_send(
+            &client,
+            1,
+            vec![hello(), ClientBodyElem::ReconnectToken(token)],
+        );
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, restore_returning_players);
+        run_system(&mut world, apply_client_mutations);
+        let server = world.resource::<Server>();
+        assert_eq!(server.clients[&addr].player_id, Some(0));
+        assert_eq!(server.clients[&addr].name.as_deref(), Some("digger"));
+        // the one it had in the meantime is now the old one
+        let mut players = world.query::<(&NetworkId, &Transform)>();
+        let mut players: Vec<(u64, (f32, f32))> = players
+            .iter(&world)
+            .map(|(id, t)| (id.0, (t.translation.x, t.translation.y)))
+            .collect();
+        players.sort_by_key(|(id, _)| *id);
+        assert_eq!(players, [(0, (64., -96.)), (1, (0., 0.))]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn client_subscribed_to_pongs_never_gets_terrain() {
+        let (server, network) = test_server();
+        let dashboard = test_client(&network, 9000);
+        let player = test_client(&network, 9001);
+        let mut world = test_world(server);
+        world.insert_resource(Terrain::new(2));
+        let pongs_only = ClientBodyElem::Subscribe {
+            mask: BodyCategory::Pongs.bit(),
+        };
+        client_send(&dashboard, 0, vec![pongs_only, ClientBodyElem::Ping]);
+        client_send(&player, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        for _ in 0..3 {
+            run_system(&mut world, enqueue_terrain);
+            run_system(&mut world, send_all_messages);
+        }
+
+        let received = |client: &ChannelTransport| {
+            std::iter::from_fn(|| client_recv(client))
+                .flat_map(|m| m.reliable.into_iter().map(|r| r.body).chain(m.bodies))
+                .map(|body| body.category())
+                .collect::<Vec<Option<BodyCategory>>>()
+        };
+        let dashboard = received(&dashboard);
+        assert!(dashboard.contains(&Some(BodyCategory::Pongs)));
+        assert!(!dashboard.contains(&Some(BodyCategory::Terrain)));
+        assert!(received(&player).contains(&Some(BodyCategory::Terrain)));
+        // and nothing it'll never get is left waiting to be acked
+        let server = world.resource::<Server>();
+        assert!(server
+            .clients
+            .values()
+            .filter(|c| c.subscriptions != SUBSCRIBE_ALL)
+            .all(|c| c.reliable.is_empty()));
+    }
+
+    #[test]
+    fn fake_clock_drops_client_right_when_grace_ends() {
+        let (mut server, network) = test_server();
+        let clock = FakeClock::new();
+        server.clock = Box::new(clock.clone());
+        server.config.connect_grace = Duration::from_secs(5);
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+
+        // out of ticks straight away, only the grace period keeps it around
+        world
+            .resource_mut::<Server>()
+            .clients
+            .values_mut()
+            .next()
+            .unwrap()
+            .until_drop = 0;
+        clock.advance(Duration::from_secs(5) - Duration::from_millis(1));
+        run_system(&mut world, drop_disconnected_clients);
+        assert_eq!(world.resource::<Server>().clients.len(), 1);
+
+        clock.advance(Duration::from_millis(1));
+        run_system(&mut world, drop_disconnected_clients);
+        run_system(&mut world, apply_client_mutations);
+        assert!(world.resource::<Server>().clients.is_empty());
+    }
+
+    #[test]
+    fn unsent_terrain_changes_are_replaced_not_piled_up() {
+        let (mut server, network) = test_server();
+        server.config.terrain_interval_ticks = 1;
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+        world.insert_resource(Terrain::new(3));
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, enqueue_terrain);
+
+        // two rounds of changes with nothing sent in between
+        world.send_event(TerrainChanged::block_row(5));
+        run_system(&mut world, collect_terrain_changes);
+        run_system(&mut world, enqueue_terrain);
+        world.send_event(TerrainChanged::block_row(CHUNK_HEIGHT + 5));
+        run_system(&mut world, collect_terrain_changes);
+        run_system(&mut world, enqueue_terrain);
+
+        let server = world.resource::<Server>();
+        let info = server.clients.values().next().unwrap();
+        let terrain: Vec<&ServerBodyElem> = info
+            .bodies
+            .iter()
+            .filter(|b| {
+                matches!(
+                    b,
+                    ServerBodyElem::TerrainChunks(_) | ServerBodyElem::TerrainVersion(_)
+                )
+            })
+            .collect();
+        assert!(matches!(
+            terrain[..],
+            [
+                ServerBodyElem::TerrainChunks(chunks),
+                ServerBodyElem::TerrainVersion(2)
+            ] if chunks.iter().map(|c| c.chunk_number).eq([0, 1])
+        ));
+    }
+
+    #[test]
+    fn name_collisions_follow_policy() {
+        for (policy, same_player) in [
+            (NameCollision::Suffix, false),
+            (NameCollision::Suffix, true),
+            (NameCollision::Reject, false),
+            (NameCollision::Reject, true),
+            (NameCollision::Kick, false),
+            (NameCollision::Kick, true),
+        ] {
+            let (mut server, network) = test_server();
+            server.config.name_collision = policy;
+            let first = test_client(&network, 9000);
+            let second = test_client(&network, 9001);
+            let (a, b) = (first.local_addr().unwrap(), second.local_addr().unwrap());
+            let mut world = test_world(server);
+            // the same player comes back with the token it connected with the first time
+            let join = |token| {
+                let mut bodies = vec![
+                    ClientBodyElem::ProtocolVersions {
+                        min: MIN_PROTOCOL_VERSION,
+                        max: PROTOCOL_VERSION,
+                    },
+                    ClientBodyElem::Name(" miner ".to_string()),
+                ];
+                bodies.push(ClientBodyElem::ReconnectToken(token));
+                bodies
+            };
+            let second_token = if same_player { 1 } else { 2 };
+            for (client, token) in [(&first, 1), (&second, second_token)] {
+                client_send(client, 0, join(token));
+                run_system(&mut world, server_handle_messages);
+                run_system(&mut world, apply_client_mutations);
+            }
+
+            let server = world.resource::<Server>();
+            let name = |addr| server.clients.get(&addr).and_then(|c| c.name.as_deref());
+            let gone = match (policy, same_player) {
+                (NameCollision::Suffix, true) => {
+                    assert_eq!(name(a), Some("miner (2)"));
+                    assert_eq!(name(b), Some("miner"));
+                    None
+                }
+                // taking someone else's name is only ever allowed with their token
+                (NameCollision::Suffix | NameCollision::Kick, false) => {
+                    assert_eq!(name(a), Some("miner"));
+                    assert_eq!(name(b), Some("miner (2)"));
+                    None
+                }
+                (NameCollision::Reject, _) => {
+                    assert_eq!(name(a), Some("miner"));
+                    assert!(!server.clients.contains_key(&b));
+                    Some((&second, DisconnectReason::NameTaken))
+                }
+                (NameCollision::Kick, true) => {
+                    assert!(!server.clients.contains_key(&a));
+                    assert_eq!(name(b), Some("miner"));
+                    Some((&first, DisconnectReason::Replaced))
+                }
+            };
+            // whoever had to go is told why
+            if let Some((client, reason)) = gone {
+                assert!(std::iter::from_fn(|| client_recv(client)).any(|m| m
+                    .bodies
+                    .iter()
+                    .any(|b| matches!(b, ServerBodyElem::Disconnect(r) if *r == reason))));
+            }
+        }
+    }
+
+    #[test]
+    fn slow_tick_sends_overrun_with_its_label() {
+        let (mut server, _network) = test_server();
+        server.config.game_tick_hz = 100;
+        let mut world = test_world(server);
+        world.init_resource::<TickTimers>();
+        world.insert_resource(Events::<TickOverrun>::default());
+        let run_tick = |world: &mut World, took: Duration| {
+            run_system(world, |timers: ResMut<TickTimers>| {
+                start_tick(timers, GAME_TICK_LABEL)
+            });
+            run_system(world, move || std::thread::sleep(took));
+            run_system(
+                world,
+                |timers: ResMut<TickTimers>,
+                 server: Res<Server>,
+                 overruns: EventWriter<TickOverrun>| {
+                    finish_tick(timers, server, overruns, GAME_TICK_LABEL)
+                },
+            );
+            world
+                .resource_mut::<Events<TickOverrun>>()
+                .drain()
+                .collect::<Vec<TickOverrun>>()
+        };
+
+        // well inside the 10ms budget
+        assert!(run_tick(&mut world, Duration::ZERO).is_empty());
+
+        let overruns = run_tick(&mut world, Duration::from_millis(25));
+        assert_eq!(overruns.len(), 1);
+        assert_eq!(overruns[0].label, GAME_TICK_LABEL);
+        assert_eq!(overruns[0].budget, Duration::from_millis(10));
+        assert!(overruns[0].elapsed >= Duration::from_millis(25));
+    }
+
+    #[test]
+    fn smoothed_sends_even_out_after_a_stall() {
+        // returns when every packet went out, for ticks that ran at these offsets
+        let send_times = |smooth_sends: bool| {
+            let (mut server, network) = test_server();
+            let clock = FakeClock::new();
+            let start = clock.now();
+            server.clock = Box::new(clock.clone());
+            server.config.smooth_sends = smooth_sends;
+            server.metrics.network_tick_hz = 20;
+            let client = test_client(&network, 9000);
+            let mut world = test_world(server);
+            client_send(&client, 0, vec![ClientBodyElem::Ping]);
+            run_system(&mut world, server_handle_messages);
+
+            // when the client's latest packet went out, and how many it's had
+            let last_sent = |world: &World| {
+                let client = world.resource::<Server>().clients.values().next().unwrap();
+                (client.bandwidth.packets_sent, client.last_sent_at)
+            };
+
+            // a 30ms stall, then a tick straight after to catch up
+            let mut sent = Vec::new();
+            for tick_at in [0, 50, 130, 130, 200, 250, 300] {
+                // frames are every 10ms
+                while clock.now() < start + Duration::from_millis(tick_at) {
+                    clock.advance(Duration::from_millis(10));
+                    run_system(&mut world, send_smoothed_messages);
+                    sent.push(last_sent(&world));
+                }
+                run_system(&mut world, send_all_messages);
+                sent.push(last_sent(&world));
+            }
+            sent.dedup_by_key(|(packets, _)| *packets);
+            let sent: Vec<Instant> = sent.into_iter().filter_map(|(_, at)| at).collect();
+            let gaps: Vec<u128> = sent.windows(2).map(|w| (w[1] - w[0]).as_millis()).collect();
+            let jitter = world
+                .resource::<Server>()
+                .clients
+                .values()
+                .next()
+                .unwrap()
+                .smoothing
+                .jitter;
+            (gaps, jitter)
+        };
+
+        // the catch-up tick's packet goes out a whole tick early, then the next one's late
+        let (gaps, rough) = send_times(false);
+        assert_eq!(gaps, [50, 80, 0, 70, 50, 50]);
+        // held back to its turn, and back to 50ms from then on
+        let (gaps, smooth) = send_times(true);
+        assert_eq!(gaps, [50, 80, 20, 50, 50, 50]);
+        assert!(smooth < rough, "{:?} vs {:?}", smooth, rough);
+    }
+
+    #[test]
+    fn changed_bounds_tightly_enclose_changes() {
+        let (mut server, network) = test_server();
+        server.config.terrain_interval_ticks = 1;
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+        world.insert_resource(Terrain::new(3));
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, enqueue_terrain);
+        world
+            .resource_mut::<Server>()
+            .clients
+            .values_mut()
+            .next()
+            .unwrap()
+            .protocol_version = CHANGED_BOUNDS_PROTOCOL_VERSION;
+
+        // two blocks in the first chunk, then one in the second and somewhere in the third
+        world.send_event(TerrainChanged::block(3, 5));
+        world.send_event(TerrainChanged::block(10, 2));
+        run_system(&mut world, collect_terrain_changes);
+        run_system(&mut world, enqueue_terrain);
+        world.send_event(TerrainChanged::block(7, CHUNK_HEIGHT + 9));
+        world.send_event(TerrainChanged::block_row(2 * CHUNK_HEIGHT));
+        run_system(&mut world, collect_terrain_changes);
+        run_system(&mut world, enqueue_terrain);
+
+        // the client hasn't acked the first changes, so it gets both, with only one set of bounds
+        let server = world.resource::<Server>();
+        let info = server.clients.values().next().unwrap();
+        let bounds: Vec<&Vec<BlockBounds>> = info
+            .bodies
+            .iter()
+            .filter_map(|b| match b {
+                ServerBodyElem::ChangedBounds(bounds) => Some(bounds),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            bounds,
+            [&vec![
+                BlockBounds {
+                    min_x: 3,
+                    min_y: 2,
+                    max_x: 10,
+                    max_y: 5
+                },
+                BlockBounds::block(7, CHUNK_HEIGHT + 9),
+                BlockBounds::chunk(2),
+            ]]
+        );
+        let i = info
+            .bodies
+            .iter()
+            .position(|b| matches!(b, ServerBodyElem::ChangedBounds(_)))
+            .unwrap();
+        assert!(matches!(
+            info.bodies[i + 1..],
+            [
+                ServerBodyElem::TerrainChunks(_),
+                ServerBodyElem::TerrainVersion(_),
+                ..
+            ]
+        ));
+    }
+
+    #[test]
+    fn terrain_waits_until_there_is_some() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+        world.remove_resource::<Terrain>();
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, enqueue_terrain);
+        run_system(&mut world, enqueue_terrain);
+        let has_baseline = |world: &World| {
+            world
+                .resource::<Server>()
+                .clients
+                .values()
+                .next()
+                .unwrap()
+                .reliable
+                .iter()
+                .any(|r| matches!(r.elem.body, ServerBodyElem::Terrain(_)))
+        };
+        assert!(!has_baseline(&world));
+
+        world.insert_resource(Terrain::new(2));
+        run_system(&mut world, enqueue_terrain);
+        assert!(has_baseline(&world));
+    }
+
+    #[test]
+    fn inputs_between_game_ticks_all_applied_in_order() {
+        let (server, network) = test_server();
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+        world.insert_resource(Terrain::new(1));
+        let player = world
+            .spawn()
+            .insert(NetworkId(1))
+            .insert(Transform::default())
+            .insert(Velocity::default())
+            .id();
+
+        // three blocks to mine, one after the other
+        let blocks: Vec<(usize, usize)> = {
+            let terrain = world.resource::<Terrain>();
+            (0..CHUNK_WIDTH)
+                .filter_map(|x| {
+                    (0..CHUNK_HEIGHT)
+                        .find(|y| terrain.chunks[0].blocks[*y][x].is_some())
+                        .map(|y| (x, y))
+                })
+                .take(3)
+                .collect()
+        };
+        let mine = |(block_x, block_y): (usize, usize), right: bool| {
+            ClientBodyElem::Input(PlayerInput {
+                left: false,
+                right,
+                jump: false,
+                mine: true,
+                block_x,
+                block_y,
+            })
+        };
+
+        client_send(&client, 0, vec![ClientBodyElem::Ping]);
+        run_system(&mut world, server_handle_messages);
+        world
+            .resource_mut::<Server>()
+            .clients
+            .values_mut()
+            .next()
+            .unwrap()
+            .player_id = Some(1);
+
+        // three inputs in their own packets before the next game tick, the second one late
+        // and the first one twice, the network doubled it
+        let server_addr = SocketAddr::from((DEFAULT_SERVER_IP, DEFAULT_SERVER_PORT));
+        for (sequence, block, right) in [
+            (2, blocks[1], false),
+            (4, blocks[2], true),
+            (2, blocks[1], false),
+            (3, blocks[0], true),
+        ] {
+            let message = ClientToServer::builder()
+                .ack(1)
+                .sequence(sequence)
+                .body(mine(block, right))
+                .build();
+            send_message(&client, server_addr, message).unwrap();
+        }
+        run_system(&mut world, server_handle_messages);
+        {
+            let server = world.resource::<Server>();
+            let info = server.clients.values().next().unwrap();
+            let queued: Vec<u64> = info.queued_inputs.iter().map(|(seq, _)| *seq).collect();
+            assert_eq!(queued, [2, 3, 4]);
+        }
+
+        // the block the first input is after is already gone, so the second one mines and the third is too late
+        remove_block(
+            blocks[1].0,
+            blocks[1].1,
+            &mut world.resource_mut::<Terrain>(),
+        )
+        .unwrap();
+        run_system(&mut world, apply_inputs);
+        run_system(&mut world, simulate_physics);
+
+        let events = world.resource::<Events<TerrainChanged>>();
+        let changed: Vec<TerrainChanged> = events.get_reader().iter(events).cloned().collect();
+        assert_eq!(changed, [TerrainChanged::block(blocks[0].0, blocks[0].1)]);
+        let server = world.resource::<Server>();
+        let info = server.clients.values().next().unwrap();
+        assert_eq!(info.blocks_mined, 1);
+        assert!(info.queued_inputs.is_empty());
+        // two of the three inputs went right, for a third of the tick each
+        let velocity = *world.entity(player).get::<Velocity>().unwrap();
+        assert_eq!(velocity.x, PLAYER_SPEED * 2. / 3.);
+    }
+
+    #[test]
+    fn quick_reconnect_gets_changes_instead_of_all_the_terrain() {
+        let (mut server, network) = test_server();
+        server.config.terrain_interval_ticks = 1;
+        let client = test_client(&network, 9000);
+        let mut world = test_world(server);
+        world.insert_resource(Terrain::new(3));
+        let connect = |terrain_version: Option<u64>| {
+            let mut bodies = vec![ClientBodyElem::Codecs(vec![Codec::Deflate])];
+            bodies.extend(terrain_version.map(ClientBodyElem::TerrainAck));
+            bodies
+        };
+        let full_terrains = |world: &World| {
+            world
+                .resource::<Server>()
+                .clients
+                .values()
+                .next()
+                .unwrap()
+                .reliable
+                .iter()
+                .filter(|r| matches!(r.elem.body, ServerBodyElem::Terrain(_)))
+                .count()
+        };
+
+        // gets the whole terrain, then some changes, and acks both
+        client_send(&client, 0, connect(None));
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, enqueue_terrain);
+        run_system(&mut world, send_all_messages);
+        let sequence = client_recv(&client).unwrap().header.sequence;
+        world.send_event(TerrainChanged::block_row(5));
+        run_system(&mut world, collect_terrain_changes);
+        run_system(&mut world, enqueue_terrain);
+        let version = world.resource::<Server>().terrain_version;
+        client_send(&client, sequence, vec![ClientBodyElem::TerrainAck(version)]);
+        run_system(&mut world, server_handle_messages);
+
+        // restarts its connection, still having that terrain, and something changes meanwhile
+        client_send(&client, 0, connect(Some(version)));
+        run_system(&mut world, server_handle_messages);
+        world.resource_mut::<Events<TerrainChanged>>().clear();
+        world.send_event(TerrainChanged::block_row(CHUNK_HEIGHT + 5));
+        run_system(&mut world, collect_terrain_changes);
+        run_system(&mut world, enqueue_terrain);
+        assert_eq!(full_terrains(&world), 0);
+        {
+            let server = world.resource::<Server>();
+            let info = server.clients.values().next().unwrap();
+            assert_eq!(info.join_number, 0);
+            assert!(info.welcomed);
+            assert!(info.bodies.iter().any(|b| matches!(
+                b,
+                ServerBodyElem::TerrainChunks(chunks) if chunks.iter().map(|c| c.chunk_number).eq([1])
+            )));
+        }
+
+        // coming back with an unheard of version gets it all again
+        run_system(&mut world, send_all_messages);
+        let sequence = client_recv(&client).unwrap().header.sequence;
+        client_send(&client, sequence, vec![]);
+        run_system(&mut world, server_handle_messages);
+        client_send(&client, 0, connect(Some(version + 100)));
+        run_system(&mut world, server_handle_messages);
+        run_system(&mut world, enqueue_terrain);
+        assert_eq!(full_terrains(&world), 1);
+    }
+
+    /// App with the server binary's networking and saving but not its WorldPlugin, so there's no terrain until a test adds it
+    /// on a port of its own, saving to save_file, ticking fast enough to get somewhere in a test
+    fn whole_server(save_file: PathBuf) -> App {
+        whole_server_with(ServerPlugin::new(whole_server_config(save_file)))
+    }
+
+    /// Config for whole_server
+    fn whole_server_config(save_file: PathBuf) -> ServerConfig {
+        ServerConfig {
+            bind_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            game_tick_hz: 200,
+            network_tick_hz: 100,
+            save_file,
+            ..ServerConfig::default()
+        }
+    }
+
+    /// Like whole_server, with plugin as the ServerPlugin
+    fn whole_server_with(plugin: ServerPlugin) -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugin(states::server::StatePlugin)
+            .add_plugin(plugin)
+            .add_plugin(crate::save::server::SaveLoadPlugin);
+        app
+    }
+
+    #[test]
+    fn middleware_added_to_the_plugin_sees_messages() {
+        let save_file = std::env::temp_dir().join("game_plugin_middleware_test.sav");
+        let (inbound, outbound) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+        let (seen_in, seen_out) = (inbound.clone(), outbound.clone());
+        let plugin = ServerPlugin::new(whole_server_config(save_file))
+            .add_inbound(move |_, _| {
+                seen_in.fetch_add(1, Ordering::Relaxed);
+                true
+            })
+            .add_outbound(move |_, _| {
+                seen_out.fetch_add(1, Ordering::Relaxed);
+                true
+            });
+        let mut app = whole_server_with(plugin);
+        app.insert_resource(Terrain::empty());
+        app.update();
+        let server_addr = app
+            .world
+            .resource::<Server>()
+            .transport
+            .local_addr()
+            .unwrap();
+
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let ping = ClientToServer::builder()
+            .ack(0)
+            .sequence(0)
+            .body(ClientBodyElem::Ping)
+            .build();
+        client
+            .send_to(
+                &bincode::encode_to_vec(ping, BINCODE_CONFIG).unwrap(),
+                server_addr,
+            )
+            .unwrap();
+        for _ in 0..10 {
+            std::thread::sleep(Duration::from_millis(15));
+            app.update();
+        }
+        assert_eq!(inbound.load(Ordering::Relaxed), 1);
+        assert!(outbound.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn whole_server_stops_without_terrain() {
+        let save_file = std::env::temp_dir().join("game_whole_server_stop_test.sav");
+        let _ = std::fs::remove_file(&save_file);
+        let mut app = whole_server(save_file.clone());
+        app.update();
+        assert!(app.world.get_resource::<Terrain>().is_none());
+
+        // the last save on the way out has nothing to write
+        app.world
+            .insert_resource(NextState(states::server::GameState::Stopped));
+        app.update();
+        assert_eq!(
+            app.world
+                .resource::<CurrentState<states::server::GameState>>()
+                .0,
+            states::server::GameState::Stopped
+        );
+        assert!(!save_file.exists());
+    }
+
+    #[test]
+    fn whole_server_runs_without_terrain_until_there_is_some() {
+        let save_file = std::env::temp_dir().join("game_whole_server_test.sav");
+        let _ = std::fs::remove_file(&save_file);
+        let mut app = whole_server(save_file);
+        app.update();
+        let server_addr = app
+            .world
+            .resource::<Server>()
+            .transport
+            .local_addr()
+            .unwrap();
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_nonblocking(true).unwrap();
+        let mut sequence = 0;
+        let mut run = |app: &mut App| -> Vec<ServerToClient> {
+            let mut received = Vec::new();
+            for _ in 0..10 {
+                let message = ClientToServer::builder()
+                    .ack(sequence)
+                    .sequence(sequence)
+                    .body(ClientBodyElem::Input(PlayerInput {
+                        left: false,
+                        right: false,
+                        jump: false,
+                        mine: true,
+                        block_x: 3,
+                        block_y: 3,
+                    }))
+                    .build();
+                client
+                    .send_to(
+                        &bincode::encode_to_vec(message, BINCODE_CONFIG).unwrap(),
+                        server_addr,
+                    )
+                    .unwrap();
+                std::thread::sleep(Duration::from_millis(15));
+                app.update();
+                let mut buffer = [0u8; BUFFER_SIZE];
+                while let Ok((size, _)) = client.recv_from(&mut buffer) {
+                    let message: ServerToClient = decode_into_owned(&buffer[..size]).unwrap();
+                    sequence = message.header.sequence;
+                    received.push(message);
+                }
+            }
+            received
+        };
+        let has_terrain = |messages: &[ServerToClient]| {
+            messages.iter().any(|message| {
+                message
+                    .reliable
+                    .iter()
+                    .any(|r| matches!(r.body, ServerBodyElem::Terrain(_)))
+            })
+        };
+
+        // every system in the plugin gets through its ticks, it's just that none of the terrain goes out
+        let before = run(&mut app);
+        assert!(!before.is_empty());
+        assert!(!has_terrain(&before));
+        assert_eq!(app.world.resource::<Server>().clients.len(), 1);
+
+        app.world.insert_resource(Terrain::new(1));
+        assert!(has_terrain(&run(&mut app)));
     }
 }
@@ -0,0 +1,489 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+#[cfg(test)]
+use std::collections::HashMap;
+
+/// How long the receive thread sleeps when the socket has nothing for it
+const RECEIVE_THREAD_IDLE: Duration = Duration::from_millis(1);
+
+/// Longest the receive thread waits between retries while the socket keeps failing, see is_transient
+const RECEIVE_THREAD_MAX_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Anything that can send and receive whole datagrams, e.g. a UDP socket
+/// Must be non-blocking: `recv_from` returns `WouldBlock` whenever nothing is waiting
+pub(super) trait Transport: Send + Sync + std::fmt::Debug {
+    /// Send one datagram to target
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize>;
+
+    /// Receive one datagram into buf, returns its size and who sent it
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    /// The address other endpoints can reach us at
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Datagrams that arrived but were thrown away because we couldn't keep up
+    fn dropped_datagrams(&self) -> u64 {
+        0
+    }
+}
+
+/// Transport over a real UDP socket
+#[derive(Debug)]
+pub(super) struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    /// Binds the socket
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+
+        // we want nonblocking sockets!
+        socket.set_nonblocking(true)?;
+
+        Ok(UdpTransport { socket })
+    }
+
+    /// Binds the socket, then ties it to the network interface called device if there is one
+    pub fn bind_on(addr: SocketAddr, device: Option<&str>) -> io::Result<Self> {
+        let transport = Self::bind(addr)?;
+        if let Some(device) = device {
+            bind_to_device(&transport.socket, device)?;
+        }
+        Ok(transport)
+    }
+}
+
+/// Only send and receive through the network interface called device, e.g. eth0
+#[cfg(target_os = "linux")]
+fn bind_to_device(socket: &UdpSocket, device: &str) -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt::BindToDevice};
+    use std::os::unix::io::AsRawFd;
+
+    setsockopt(
+        socket.as_raw_fd(),
+        BindToDevice,
+        &std::ffi::OsString::from(device),
+    )
+    .map_err(|e| {
+        io::Error::new(
+            io::Error::from(e).kind(),
+            format!("unable to bind to device {}: {}", device, e),
+        )
+    })
+}
+
+/// SO_BINDTODEVICE is Linux only, elsewhere pick the interface by its address instead
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device(_socket: &UdpSocket, device: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("binding to device {} only works on Linux", device),
+    ))
+}
+
+impl Transport for UdpTransport {
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(buf, target)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+/// Transport that never receives anything and throws away everything sent, see Server::inject_datagram
+#[derive(Debug)]
+pub(super) struct NullTransport;
+
+impl Transport for NullTransport {
+    fn send_to(&self, buf: &[u8], _target: SocketAddr) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::from(([0, 0, 0, 0], 0)))
+    }
+}
+
+/// Errors a UDP socket gives now and then without anything being wrong with it
+/// e.g. ICMP port unreachable on some platforms, anything else means the socket itself is in trouble
+pub(super) fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+    )
+}
+
+/// Stands in for a socket that has been closed, sending and receiving always fail
+#[derive(Debug)]
+pub(super) struct ClosedTransport;
+
+impl Transport for ClosedTransport {
+    fn send_to(&self, _buf: &[u8], _target: SocketAddr) -> io::Result<usize> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+
+    fn recv_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+}
+
+/// A datagram along with who sent it
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// Reads another transport on its own thread, queueing datagrams until they're asked for
+/// so packets keep being taken off the socket even when the game tick runs late
+/// Stops the thread when dropped
+#[derive(Debug)]
+pub(super) struct ThreadedTransport {
+    /// Sending still happens straight on the caller's thread
+    inner: Arc<dyn Transport>,
+    /// Datagrams read by the thread, oldest first, along with any errors that weren't transient
+    receiver: Mutex<mpsc::Receiver<io::Result<Datagram>>>,
+    /// Set to tell the thread to finish up
+    stop: Arc<AtomicBool>,
+    /// Datagrams the thread threw away because the queue was full
+    dropped: Arc<AtomicU64>,
+    /// None once the thread has been joined
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ThreadedTransport {
+    /// Start reading inner on a new thread, holding at most capacity datagrams
+    /// when the queue is full new datagrams are dropped, the same as a full socket buffer would
+    pub fn spawn(
+        inner: Box<dyn Transport>,
+        capacity: usize,
+        buffer_size: usize,
+    ) -> io::Result<Self> {
+        let inner: Arc<dyn Transport> = Arc::from(inner);
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let stop = Arc::new(AtomicBool::new(false));
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        let thread = {
+            let inner = inner.clone();
+            let stop = stop.clone();
+            let dropped = dropped.clone();
+            thread::Builder::new()
+                .name("socket receive".to_string())
+                .spawn(move || {
+                    let mut buffer = vec![0u8; buffer_size];
+                    // doubled for every transient error in a row, so one that won't clear up doesn't spin
+                    let mut backoff = RECEIVE_THREAD_IDLE;
+                    while !stop.load(Ordering::Relaxed) {
+                        match inner.recv_from(&mut buffer) {
+                            Ok((size, from)) => {
+                                backoff = RECEIVE_THREAD_IDLE;
+                                match sender.try_send(Ok((buffer[..size].to_vec(), from))) {
+                                    Ok(()) => {}
+                                    Err(mpsc::TrySendError::Full(_)) => {
+                                        dropped.fetch_add(1, Ordering::Relaxed);
+                                    }
+                                    // nobody is reading anymore
+                                    Err(mpsc::TrySendError::Disconnected(_)) => return,
+                                }
+                            }
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                                backoff = RECEIVE_THREAD_IDLE;
+                                thread::sleep(RECEIVE_THREAD_IDLE);
+                            }
+                            Err(e) if is_transient(&e) => {
+                                thread::sleep(backoff);
+                                backoff = (backoff * 2).min(RECEIVE_THREAD_MAX_BACKOFF);
+                            }
+                            // whoever reads us decides what to do about it, a full queue will get more later
+                            Err(e) => {
+                                if let Err(mpsc::TrySendError::Disconnected(_)) =
+                                    sender.try_send(Err(e))
+                                {
+                                    return;
+                                }
+                                thread::sleep(RECEIVE_THREAD_IDLE);
+                            }
+                        }
+                    }
+                })?
+        };
+
+        Ok(Self {
+            inner,
+            receiver: Mutex::new(receiver),
+            stop,
+            dropped,
+            thread: Some(thread),
+        })
+    }
+
+    /// Stop the thread and wait for it, datagrams it already queued can still be received
+    pub fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Transport for ThreadedTransport {
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.inner.send_to(buf, target)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.receiver.lock().unwrap().try_recv() {
+            Ok(Err(e)) => Err(e),
+            Ok(Ok((datagram, sender_addr))) => {
+                // anything that doesn't fit in buf is discarded, same as reading the socket
+                let size = datagram.len().min(buf.len());
+                buf[..size].copy_from_slice(&datagram[..size]);
+                Ok((size, sender_addr))
+            }
+            Err(_) => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn dropped_datagrams(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for ThreadedTransport {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// In-process "network" that connects ChannelTransports together without any real sockets
+/// Cloning gives another handle to the same network
+/// TODO: use for single-process host+play once there is a way to run server and client in one app
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub(super) struct ChannelNetwork {
+    endpoints: Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Datagram>>>>,
+}
+
+#[cfg(test)]
+impl ChannelNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a transport that can be reached at addr on this network
+    pub fn endpoint(&self, addr: SocketAddr) -> ChannelTransport {
+        let (sender, receiver) = mpsc::channel();
+        self.endpoints.lock().unwrap().insert(addr, sender);
+
+        ChannelTransport {
+            addr,
+            network: self.clone(),
+            receiver: Mutex::new(receiver),
+        }
+    }
+}
+
+/// Transport over an in-process channel, see ChannelNetwork
+#[cfg(test)]
+#[derive(Debug)]
+pub(super) struct ChannelTransport {
+    /// Our address on the network
+    addr: SocketAddr,
+    /// Used to find the other endpoints when sending
+    network: ChannelNetwork,
+    /// Incoming datagrams
+    receiver: Mutex<mpsc::Receiver<Datagram>>,
+}
+
+#[cfg(test)]
+impl Transport for ChannelTransport {
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        // just like UDP, sending to an address nobody listens on silently goes nowhere
+        if let Some(sender) = self.network.endpoints.lock().unwrap().get(&target) {
+            let _ = sender.send((buf.to_vec(), self.addr));
+        }
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.receiver.lock().unwrap().try_recv() {
+            Ok((datagram, sender_addr)) => {
+                // just like UDP, anything that doesn't fit in buf is discarded
+                let size = datagram.len().min(buf.len());
+                buf[..size].copy_from_slice(&datagram[..size]);
+                Ok((size, sender_addr))
+            }
+            Err(_) => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+/// unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_transport_delivers_in_order() {
+        let network = ChannelNetwork::new();
+        let a = network.endpoint(SocketAddr::from(([127, 0, 0, 1], 1000)));
+        let b = network.endpoint(SocketAddr::from(([127, 0, 0, 1], 1001)));
+
+        a.send_to(&[1, 2, 3], b.local_addr().unwrap()).unwrap();
+        a.send_to(&[4], b.local_addr().unwrap()).unwrap();
+
+        let mut buf = [0u8; 8];
+        let (size, from) = b.recv_from(&mut buf).unwrap();
+        assert_eq!(
+            (&buf[..size], from),
+            (&[1u8, 2, 3][..], a.local_addr().unwrap())
+        );
+        let (size, _) = b.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..size], &[4]);
+
+        // nothing left, and nothing was sent to a
+        assert_eq!(
+            b.recv_from(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert_eq!(
+            a.recv_from(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    /// Receive from transport, giving its thread up to a second to catch up
+    fn recv_waiting(transport: &dyn Transport, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+        for _ in 0..1000 {
+            match transport.recv_from(buf) {
+                Ok(received) => return Some(received),
+                Err(_) => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn threaded_transport_keeps_order() {
+        let network = ChannelNetwork::new();
+        let a = network.endpoint(SocketAddr::from(([127, 0, 0, 1], 1000)));
+        let b_addr = SocketAddr::from(([127, 0, 0, 1], 1001));
+        let b = ThreadedTransport::spawn(Box::new(network.endpoint(b_addr)), 256, 8).unwrap();
+
+        for i in 0..100u8 {
+            a.send_to(&[i], b_addr).unwrap();
+        }
+        let mut buf = [0u8; 8];
+        for i in 0..100u8 {
+            let (size, from) = recv_waiting(&b, &mut buf).unwrap();
+            assert_eq!((&buf[..size], from), (&[i][..], a.local_addr().unwrap()));
+        }
+        assert_eq!(b.dropped_datagrams(), 0);
+    }
+
+    #[test]
+    fn threaded_transport_drops_when_full_and_shuts_down() {
+        let network = ChannelNetwork::new();
+        let a = network.endpoint(SocketAddr::from(([127, 0, 0, 1], 1000)));
+        let b_addr = SocketAddr::from(([127, 0, 0, 1], 1001));
+        let mut b = ThreadedTransport::spawn(Box::new(network.endpoint(b_addr)), 2, 8).unwrap();
+
+        for i in 0..5u8 {
+            a.send_to(&[i], b_addr).unwrap();
+        }
+        for _ in 0..1000 {
+            if b.dropped_datagrams() == 3 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(b.dropped_datagrams(), 3);
+
+        // the oldest ones made it, and are still there after shutting down
+        b.shutdown();
+        assert!(b.thread.is_none());
+        a.send_to(&[9], b_addr).unwrap();
+        let mut buf = [0u8; 8];
+        for i in 0..2u8 {
+            let (size, _) = b.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..size], &[i]);
+        }
+        // nothing is read once the thread is gone
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(
+            b.recv_from(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    /// Every receive is refused, as a socket that was sent to a closed port can keep doing
+    #[derive(Debug, Default)]
+    struct RefusedTransport {
+        receives: Arc<AtomicU64>,
+    }
+
+    impl Transport for RefusedTransport {
+        fn send_to(&self, buf: &[u8], _target: SocketAddr) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn recv_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.receives.fetch_add(1, Ordering::Relaxed);
+            Err(io::ErrorKind::ConnectionRefused.into())
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            Ok(SocketAddr::from(([127, 0, 0, 1], 1000)))
+        }
+    }
+
+    #[test]
+    fn threaded_transport_backs_off_on_errors_that_keep_happening() {
+        let refused = RefusedTransport::default();
+        let receives = refused.receives.clone();
+        let mut transport = ThreadedTransport::spawn(Box::new(refused), 8, 8).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        transport.shutdown();
+
+        // 1 + 2 + 4 + ... then every 50ms, rather than as fast as the thread can go
+        let receives = receives.load(Ordering::Relaxed);
+        assert!((2..=15).contains(&receives), "{}", receives);
+        // and none of them were passed on, they're transient
+        let mut buf = [0u8; 8];
+        assert_eq!(
+            transport.recv_from(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+}
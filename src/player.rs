@@ -19,16 +19,16 @@ use crate::{
     CharacterCamera, WIN_H, WIN_W,
 };
 
-const PLAYER_ASSET: &str = "Ferris.png";
-const PLAYER_SIZE: f32 = 32.;
+pub const PLAYER_ASSET: &str = "Ferris.png";
+pub const PLAYER_SIZE: f32 = 32.;
 const PLAYER_START_COORDS: (u64, u64) = (0, 0);
-const PLAYER_SPEED: f32 = 500.;
+pub const PLAYER_SPEED: f32 = 500.;
 const PLAYER_JUMP_DURATION: f32 = 0.3; //seconds
 const PLAYER_MINE_DURATION: f32 = 2.; //seconds
 const PLAYER_MINE_RADIUS: f32 = 3.; //number of blocks
 const GRAVITY: f32 = -350.0;
 const CAMERA_BOUNDS_SIZE: [f32; 2] = [1000., 500.];
-const PLAYER_Z: f32 = 2.0;
+pub const PLAYER_Z: f32 = 2.0;
 
 #[derive(Component)]
 pub struct Player;
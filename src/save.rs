@@ -1,16 +1,17 @@
 use bevy::prelude::*;
 use bincode::{Decode, Encode};
 use std::{
-    fs::{create_dir_all, read, File},
+    fs::{create_dir_all, read, remove_file, rename, File},
     io::Write,
     path::{Path, PathBuf},
+    sync::{Mutex, TryLockError},
 };
 
 use crate::{
     network::BINCODE_CONFIG,
     player::Player,
     states,
-    world::{RenderedBlock, Terrain},
+    world::{RenderedBlock, Terrain, CHUNK_HEIGHT, CHUNK_WIDTH},
     CharacterCamera,
 };
 
@@ -30,6 +31,9 @@ pub fn default_save_path_server() -> PathBuf {
         .join(DEFAULT_SAVE_FILE_SERVER)
 }
 
+/// Held while a save is being written, so two saves never write over each other
+static SAVE_LOCK: Mutex<()> = Mutex::new(());
+
 pub mod client {
 
     use super::*;
@@ -66,6 +70,12 @@ pub mod server {
                     .run_in_state(states::server::GameState::Running)
                     .label("save_server"),
             );
+            // one last save so nothing since the last autosave is lost
+            // unless the server never started, the save file could belong to whoever has the port
+            app.add_exit_system(
+                states::server::GameState::Running,
+                save_server.run_if_resource_exists::<network::server::Server>(),
+            );
         }
     }
 }
@@ -86,39 +96,228 @@ pub struct LoadFile {
     terrain: Terrain,
 }
 
-fn save_server(terrain: Res<Terrain>) {
+#[derive(Debug)]
+pub enum LoadError {
+    /// Unable to read the file
+    IoError(std::io::Error),
+    /// The file isn't a valid save file
+    DecodeError(bincode::error::DecodeError),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::IoError(e) => write!(f, "could not read save file, {}", e),
+            LoadError::DecodeError(e) => write!(f, "unable to decode save file: {}", e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SaveError {
+    /// Unable to write the file
+    IoError(std::io::Error),
+    /// The world couldn't be encoded
+    EncodeError(bincode::error::EncodeError),
+}
+
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveError::IoError(e) => write!(f, "could not write save file, {}", e),
+            SaveError::EncodeError(e) => write!(f, "unable to encode terrain, {}", e),
+        }
+    }
+}
+
+/// Why saves to a path are never going to work, see check_save_path
+#[derive(Debug)]
+pub enum SavePathError {
+    /// The path is a directory, there's nowhere to put the file
+    IsDirectory(PathBuf),
+    /// Nothing can be written where the file goes
+    Unwritable(PathBuf, std::io::Error),
+}
+
+impl std::fmt::Display for SavePathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SavePathError::IsDirectory(path) => write!(
+                f,
+                "save file {} is a directory, point it at a file instead",
+                path.display()
+            ),
+            SavePathError::Unwritable(path, e) => {
+                write!(f, "save file {} can't be written, {}", path.display(), e)
+            }
+        }
+    }
+}
+
+/// What happened to a save that didn't fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveOutcome {
+    /// The file was written
+    Saved,
+    /// Another save was already running, that one covers this one
+    Skipped,
+}
+
+/// Encodes the world and writes it to path, only one save runs at a time
+/// the file is written next to path and renamed over it, so nobody ever reads half a save
+pub fn write_save_file(
+    path: &Path,
+    player_coords: (u64, u64),
+    terrain: &Terrain,
+) -> Result<SaveOutcome, SaveError> {
+    let _guard = match SAVE_LOCK.try_lock() {
+        Ok(guard) => guard,
+        Err(TryLockError::WouldBlock) => return Ok(SaveOutcome::Skipped),
+        // a save panicked, the lock doesn't protect any data so carry on
+        Err(TryLockError::Poisoned(e)) => e.into_inner(),
+    };
+
     let save_file = SaveFile {
-        player_coords: (0, 0), // dummy value
-        terrain: terrain.as_ref(),
+        player_coords,
+        terrain,
     };
     // try to encode, allocating a vec
     // in a real packet, we should use a pre-allocated array and encode into its slice
-    match bincode::encode_to_vec(save_file, BINCODE_CONFIG) {
-        Ok(encoded_vec) => {
-            // creates the savedata folder if it is missing
-            if let Err(e) = create_dir_all(DEFAULT_SAVE_DIR) {
-                error!("unable to create save dir, {}", e);
-                return;
-            }
-            // else it was successful
-
-            // open file in write-mode
-            match File::create(default_save_path_server()) {
-                Ok(mut file) => {
-                    // write the bytes to file
-                    match file.write_all(&encoded_vec) {
-                        Ok(_) => info!("saved to file!"),
-                        Err(e) => error!("could not write to save file, {}", e),
-                    }
-                }
-                Err(e) => {
-                    error!("could not create save file, {}", e);
-                }
-            }
-        }
-        Err(e) => {
-            error!("unable to encode terrain, {}", e);
+    let encoded_vec =
+        bincode::encode_to_vec(save_file, BINCODE_CONFIG).map_err(SaveError::EncodeError)?;
+
+    // creates the savedata folder if it is missing
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir).map_err(SaveError::IoError)?;
+    }
+
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = PathBuf::from(temp_name);
+    let mut file = File::create(&temp_path).map_err(SaveError::IoError)?;
+    file.write_all(&encoded_vec).map_err(SaveError::IoError)?;
+    // make sure it's all on disk before it replaces the old save
+    file.sync_all().map_err(SaveError::IoError)?;
+    rename(&temp_path, path).map_err(SaveError::IoError)?;
+    Ok(SaveOutcome::Saved)
+}
+
+/// Make sure write_save_file can write to path, before there's any progress to lose
+/// writes (and removes) a small file next to it the same way a save would, the save itself isn't touched
+pub fn check_save_path(path: &Path) -> Result<(), SavePathError> {
+    if path.is_dir() {
+        return Err(SavePathError::IsDirectory(path.to_path_buf()));
+    }
+    let unwritable = |e| SavePathError::Unwritable(path.to_path_buf(), e);
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir).map_err(unwritable)?;
+    }
+    let mut probe_name = path.as_os_str().to_owned();
+    probe_name.push(".check");
+    let probe_path = PathBuf::from(probe_name);
+    File::create(&probe_path)
+        .and_then(|mut file| file.write_all(b"check"))
+        .map_err(unwritable)?;
+    remove_file(&probe_path).map_err(unwritable)
+}
+
+/// Decodes the contents of a save file
+fn decode_load_file(encoded: &[u8]) -> Result<LoadFile, LoadError> {
+    let (load, _size): (LoadFile, usize) =
+        bincode::decode_from_slice(encoded, BINCODE_CONFIG).map_err(LoadError::DecodeError)?;
+    Ok(load)
+}
+
+/// Reads a save file and returns just its terrain
+pub fn load_terrain(path: &Path) -> Result<Terrain, LoadError> {
+    let encoded_vec = read(path).map_err(LoadError::IoError)?;
+    Ok(decode_load_file(&encoded_vec)?.terrain)
+}
+
+/// Summary of a save file, for looking at one without running the game
+#[derive(Debug)]
+pub struct SaveInfo {
+    /// Size of the file in bytes
+    file_size: usize,
+    /// FNV-1a hash of the whole file
+    checksum: u64,
+    player_coords: (u64, u64),
+    chunks: usize,
+    /// Blocks that aren't air
+    solid_blocks: usize,
+}
+
+impl std::fmt::Display for SaveInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "file size: {} bytes", self.file_size)?;
+        writeln!(f, "checksum: {:016x}", self.checksum)?;
+        writeln!(
+            f,
+            "terrain: {} chunk(s), {}x{} blocks, {} solid",
+            self.chunks,
+            CHUNK_WIDTH,
+            self.chunks * CHUNK_HEIGHT,
+            self.solid_blocks
+        )?;
+        writeln!(f, "player: {:?}", self.player_coords)?;
+        // neither of these are saved yet
+        writeln!(f, "sequence: not stored")?;
+        write!(f, "seed: not stored")
+    }
+}
+
+impl SaveInfo {
+    pub fn chunks(&self) -> usize {
+        self.chunks
+    }
+}
+
+/// 64-bit FNV-1a hash, good enough to tell if two save files differ
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Reads a save file the same way the game does and summarizes it
+pub fn inspect_save_file(path: &Path) -> Result<SaveInfo, LoadError> {
+    let encoded_vec = read(path).map_err(LoadError::IoError)?;
+    let load = decode_load_file(&encoded_vec)?;
+    let solid_blocks = load
+        .terrain
+        .chunks
+        .iter()
+        .flat_map(|chunk| chunk.blocks.iter().flatten())
+        .filter(|block| block.is_some())
+        .count();
+
+    Ok(SaveInfo {
+        file_size: encoded_vec.len(),
+        checksum: checksum(&encoded_vec),
+        player_coords: load.player_coords,
+        chunks: load.terrain.chunks.len(),
+        solid_blocks,
+    })
+}
+
+fn save_server(terrain: Option<Res<Terrain>>, server: Option<Res<crate::network::server::Server>>) {
+    let path = match server.as_ref().and_then(|server| server.save_file()) {
+        Some(path) => path,
+        None => return,
+    };
+    // nothing to save until there's a world, and an old save is better than an empty one
+    let terrain = match terrain {
+        Some(terrain) => terrain,
+        None => {
+            info!("no terrain yet, not saving");
+            return;
         }
+    };
+    // dummy player coords, the server has no single player
+    match write_save_file(path, (0, 0), terrain.as_ref()) {
+        Ok(SaveOutcome::Saved) => info!("saved to file!"),
+        Ok(SaveOutcome::Skipped) => info!("already saving, skipped"),
+        Err(e) => error!("{}", e),
     }
 }
 
@@ -141,40 +340,14 @@ pub fn f5_save_to_file(
     let x_block_index = (transform.translation.x / 32.) as u64;
     let y_block_index = -(transform.translation.y / 32.) as u64;
 
-    // the struct to serialize
-    let save_file = SaveFile {
-        player_coords: (x_block_index, y_block_index),
-        terrain: terrain.as_ref(),
-    };
-
-    // try to encode, allocating a vec
-    // in a real packet, we should use a pre-allocated array and encode into its slice
-    match bincode::encode_to_vec(save_file, BINCODE_CONFIG) {
-        Ok(encoded_vec) => {
-            // creates the savedata folder if it is missing
-            if let Err(e) = create_dir_all(DEFAULT_SAVE_DIR) {
-                error!("unable to create save dir, {}", e);
-                return;
-            }
-            // else it was successful
-
-            // open file in write-mode
-            match File::create(default_save_path()) {
-                Ok(mut file) => {
-                    // write the bytes to file
-                    match file.write_all(&encoded_vec) {
-                        Ok(_) => warn!("saved to file!"),
-                        Err(e) => error!("could not write to save file, {}", e),
-                    }
-                }
-                Err(e) => {
-                    error!("could not create save file, {}", e);
-                }
-            }
-        }
-        Err(e) => {
-            error!("unable to encode terrain, {}", e);
-        }
+    match write_save_file(
+        &default_save_path(),
+        (x_block_index, y_block_index),
+        terrain.as_ref(),
+    ) {
+        Ok(SaveOutcome::Saved) => warn!("saved to file!"),
+        Ok(SaveOutcome::Skipped) => warn!("already saving, skipped"),
+        Err(e) => error!("{}", e),
     }
 }
 
@@ -224,3 +397,108 @@ pub fn f6_load_from_file(
         }
     }
 }
+
+/// unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a save file with the given terrain to a temporary path
+    fn write_test_save(name: &str, terrain: &Terrain) -> PathBuf {
+        let save_file = SaveFile {
+            player_coords: (3, 4),
+            terrain,
+        };
+        let encoded = bincode::encode_to_vec(save_file, BINCODE_CONFIG).unwrap();
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, encoded).unwrap();
+        path
+    }
+
+    #[test]
+    fn inspect_reports_terrain() {
+        let terrain = Terrain::new(2);
+        let path = write_test_save("game_inspect_test.sav", &terrain);
+
+        let info = inspect_save_file(&path).unwrap();
+        assert_eq!(info.chunks, 2);
+        assert_eq!(info.player_coords, (3, 4));
+        assert_eq!(info.file_size, read(&path).unwrap().len());
+        assert!(info.solid_blocks > 0);
+        assert!(info.solid_blocks <= 2 * CHUNK_WIDTH * CHUNK_HEIGHT);
+
+        // same file, same checksum
+        assert_eq!(inspect_save_file(&path).unwrap().checksum, info.checksum);
+    }
+
+    #[test]
+    fn inspect_rejects_corrupt_file() {
+        let path = std::env::temp_dir().join("game_inspect_corrupt.sav");
+        std::fs::write(&path, [0xff; 16]).unwrap();
+
+        assert!(matches!(
+            inspect_save_file(&path),
+            Err(LoadError::DecodeError(_))
+        ));
+    }
+
+    #[test]
+    fn back_to_back_saves_leave_a_complete_file() {
+        let terrain = Terrain::new(2);
+        let path = std::env::temp_dir().join("game_concurrent_save.sav");
+
+        let outcomes: Vec<SaveOutcome> = std::thread::scope(|s| {
+            let saves: Vec<_> = (0..2)
+                .map(|_| s.spawn(|| write_save_file(&path, (3, 4), &terrain).unwrap()))
+                .collect();
+            saves.into_iter().map(|save| save.join().unwrap()).collect()
+        });
+        // one of them may have been skipped, but at least one wrote the file
+        assert!(outcomes.contains(&SaveOutcome::Saved));
+
+        let info = inspect_save_file(&path).unwrap();
+        assert_eq!(info.chunks, 2);
+        assert_eq!(info.player_coords, (3, 4));
+        // nothing left over from writing it
+        let mut temp_name = path.as_os_str().to_owned();
+        temp_name.push(".tmp");
+        assert!(!PathBuf::from(temp_name).exists());
+
+        // once the first is done, the next save goes through
+        assert_eq!(
+            write_save_file(&path, (5, 6), &terrain).unwrap(),
+            SaveOutcome::Saved
+        );
+        assert_eq!(inspect_save_file(&path).unwrap().player_coords, (5, 6));
+    }
+
+    #[test]
+    fn save_path_check_tells_directories_from_unwritable_paths() {
+        let dir = std::env::temp_dir().join("game_save_path_check");
+        create_dir_all(&dir).unwrap();
+        assert!(matches!(
+            check_save_path(&dir),
+            Err(SavePathError::IsDirectory(_))
+        ));
+
+        // a file where the save's directory should be
+        let file = dir.join("not_a_dir");
+        std::fs::write(&file, b"").unwrap();
+        let err = check_save_path(&file.join("server.sav")).unwrap_err();
+        assert!(matches!(err, SavePathError::Unwritable(..)));
+        assert!(err.to_string().contains("can't be written"), "{}", err);
+        assert!(check_save_path(&dir)
+            .unwrap_err()
+            .to_string()
+            .contains("is a directory"));
+
+        // a fine path is left as it was
+        let good = dir.join("nested").join("server.sav");
+        check_save_path(&good).unwrap();
+        assert!(!good.exists());
+        assert_eq!(
+            std::fs::read_dir(good.parent().unwrap()).unwrap().count(),
+            0
+        );
+    }
+}
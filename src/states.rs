@@ -8,6 +8,8 @@ pub mod server {
     #[derive(Debug, Clone, Eq, PartialEq, Hash)]
     pub enum GameState {
         Running,
+        /// The server couldn't start, nothing runs while the app exits
+        Stopped,
     }
 
     /// Initial GameState
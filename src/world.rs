@@ -1,13 +1,15 @@
 use crate::{
     network::BINCODE_CONFIG,
     procedural_functions::{
-        self, dist_to_vein, generate_random_cave, generate_random_vein, generate_random_vein_count, generate_perlin_noise
+        self, dist_to_vein, generate_perlin_noise, generate_random_cave, generate_random_vein,
+        generate_random_vein_count,
     },
     save, states,
 };
 use bevy::prelude::*;
 use std::fs::*;
 use std::io::Write;
+use std::ops::RangeInclusive;
 
 use bincode::{BorrowDecode, Decode, Encode};
 use rand::Rng;
@@ -21,6 +23,13 @@ const BASE_SEED: u64 = 82981925813;
 /// Decrease for bigger caves
 const PERLIN_CAVE_THRESHOLD: f32 = 1.75;
 
+/// Largest number of chunks a decoded terrain may claim to have
+/// A chunk is over 100KB in memory, so this keeps a bad save file or packet from allocating gigabytes
+pub const MAX_TERRAIN_CHUNKS: u64 = 1024;
+
+/// Largest number of veins a decoded terrain may claim to have (about 16 get generated per chunk)
+pub const MAX_TERRAIN_VEINS: u64 = MAX_TERRAIN_CHUNKS * 64;
+
 pub mod client {
     use super::*;
     pub struct WorldPlugin;
@@ -88,7 +97,8 @@ fn destroy_world(mut commands: Commands, query: Query<Entity, With<RenderedBlock
 
 /// Represents all chunks in the game world
 /// Should be a global resource
-#[derive(Encode, Decode, Debug, PartialEq, Clone)]
+/// Decode is implemented by hand so that oversized terrains get rejected before allocating
+#[derive(Encode, Debug, PartialEq, Clone)]
 pub struct Terrain {
     /// Vector of chunks, each one contains its own chunk_number
     /// TODO: potentially convert into a symbol table for faster lookups?
@@ -112,14 +122,9 @@ impl Terrain {
             }
         }
 
-        let chunks = (0..num_chunks)
-            .map(|d| Chunk::new(d, &veins))
-            .collect();
+        let chunks = (0..num_chunks).map(|d| Chunk::new(d, &veins)).collect();
 
-        Terrain {
-            veins,
-            chunks,
-        }
+        Terrain { veins, chunks }
     }
 
     /// Creates a terrain with no chunks
@@ -129,6 +134,260 @@ impl Terrain {
             veins: Vec::new(),
         }
     }
+
+    /// Creates a terrain out of chunks made somewhere else, without any veins
+    pub fn from_chunks(chunks: Vec<Chunk>) -> Terrain {
+        Terrain {
+            chunks,
+            veins: Vec::new(),
+        }
+    }
+}
+
+/// Decode the length of a Vec (as written by its Encode), rejecting anything longer than max
+fn decode_bounded_len<D: bincode::de::Decoder>(
+    decoder: &mut D,
+    max: u64,
+    what: &str,
+) -> Result<usize, bincode::error::DecodeError> {
+    let len = u64::decode(decoder)?;
+    if len > max {
+        return Err(bincode::error::DecodeError::OtherString(format!(
+            "terrain has {} {}, more than the maximum of {}",
+            len, what, max
+        )));
+    }
+    Ok(len as usize)
+}
+
+impl Decode for Terrain {
+    fn decode<D: bincode::de::Decoder>(
+        decoder: &mut D,
+    ) -> Result<Self, bincode::error::DecodeError> {
+        // fields must be decoded in the same order that the derived Encode writes them
+        let num_chunks = decode_bounded_len(decoder, MAX_TERRAIN_CHUNKS, "chunks")?;
+        let mut chunks = Vec::with_capacity(num_chunks);
+        for _ in 0..num_chunks {
+            chunks.push(Chunk::decode(decoder)?);
+        }
+
+        let num_veins = decode_bounded_len(decoder, MAX_TERRAIN_VEINS, "veins")?;
+        let mut veins = Vec::with_capacity(num_veins);
+        for _ in 0..num_veins {
+            veins.push(Vein::decode(decoder)?);
+        }
+
+        Ok(Terrain { chunks, veins })
+    }
+}
+
+impl<'de> BorrowDecode<'de> for Terrain {
+    fn borrow_decode<D: bincode::de::BorrowDecoder<'de>>(
+        decoder: &mut D,
+    ) -> Result<Self, bincode::error::DecodeError> {
+        Self::decode(decoder)
+    }
+}
+
+/// Sent whenever something changes blocks in the terrain
+/// Lets the server resend just the chunks that changed instead of the whole terrain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TerrainChanged {
+    /// Chunk numbers that changed
+    pub region: RangeInclusive<u64>,
+    /// Blocks that changed, None if it could be any of the region's
+    pub blocks: Option<BlockBounds>,
+}
+
+impl TerrainChanged {
+    /// A change to the chunk holding global block row y
+    pub fn block_row(y: usize) -> Self {
+        let chunk_number = (y / CHUNK_HEIGHT) as u64;
+        TerrainChanged {
+            region: chunk_number..=chunk_number,
+            blocks: None,
+        }
+    }
+
+    /// A change to just the block at x, y
+    pub fn block(x: usize, y: usize) -> Self {
+        TerrainChanged {
+            blocks: Some(BlockBounds::block(x, y)),
+            ..TerrainChanged::block_row(y)
+        }
+    }
+}
+
+/// Rectangle of blocks from min to max inclusive, in the same global coordinates remove_block takes
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockBounds {
+    pub min_x: usize,
+    pub min_y: usize,
+    pub max_x: usize,
+    pub max_y: usize,
+}
+
+impl BlockBounds {
+    pub fn block(x: usize, y: usize) -> Self {
+        BlockBounds {
+            min_x: x,
+            min_y: y,
+            max_x: x,
+            max_y: y,
+        }
+    }
+
+    /// Every block in chunk chunk_number
+    pub fn chunk(chunk_number: u64) -> Self {
+        let top = chunk_number as usize * CHUNK_HEIGHT;
+        BlockBounds {
+            min_x: 0,
+            min_y: top,
+            max_x: CHUNK_WIDTH - 1,
+            max_y: top + CHUNK_HEIGHT - 1,
+        }
+    }
+
+    /// Smallest bounds with both in them
+    pub fn union(self, other: Self) -> Self {
+        BlockBounds {
+            min_x: self.min_x.min(other.min_x),
+            min_y: self.min_y.min(other.min_y),
+            max_x: self.max_x.max(other.max_x),
+            max_y: self.max_y.max(other.max_y),
+        }
+    }
+
+    /// The blocks in both, None if there aren't any
+    pub fn intersection(self, other: Self) -> Option<Self> {
+        let bounds = BlockBounds {
+            min_x: self.min_x.max(other.min_x),
+            min_y: self.min_y.max(other.min_y),
+            max_x: self.max_x.min(other.max_x),
+            max_y: self.max_y.min(other.max_y),
+        };
+        (bounds.min_x <= bounds.max_x && bounds.min_y <= bounds.max_y).then_some(bounds)
+    }
+
+    /// x and y within chunk chunk_number (as Chunk::blocks is indexed) of every block in both
+    pub fn cells_in_chunk(&self, chunk_number: u64) -> impl Iterator<Item = (usize, usize)> {
+        let top = chunk_number as usize * CHUNK_HEIGHT;
+        let cells = self.intersection(BlockBounds::chunk(chunk_number));
+        cells.into_iter().flat_map(move |cells| {
+            (cells.min_y..=cells.max_y)
+                .flat_map(move |y| (cells.min_x..=cells.max_x).map(move |x| (x, y - top)))
+        })
+    }
+}
+
+/// What the networking layer needs from a world, so other kinds of world can reuse the sync code
+/// worlds are split into regions (chunks for Terrain) numbered from 0, which get diffed and sent one at a time
+pub trait WorldState: Send + Sync {
+    /// Width and height in blocks
+    fn dimensions(&self) -> (usize, usize);
+
+    /// Block at x, y, None if it's empty or outside the world
+    fn cell(&self, x: usize, y: usize) -> Option<BlockType>;
+
+    /// How many regions the world is split into
+    fn region_count(&self) -> u64;
+
+    /// One region ready to send, None if there's no such region
+    fn encode_region(&self, region: u64) -> Option<Vec<u8>>;
+
+    /// The whole world ready to send
+    fn encode(&self) -> Result<Vec<u8>, bincode::error::EncodeError>;
+
+    /// Put a world back together from what encode made of it
+    fn restore(encoded: &[u8]) -> Result<Self, bincode::error::DecodeError>
+    where
+        Self: Sized;
+
+    /// Regions that are different in other, including ones only one of the two has
+    fn diff(&self, other: &dyn WorldState) -> Vec<u64> {
+        (0..self.region_count().max(other.region_count()))
+            .filter(|region| self.encode_region(*region) != other.encode_region(*region))
+            .collect()
+    }
+
+    /// Cheap checksum of every block, doesn't depend on the order regions are stored in
+    /// two worlds with the same blocks always get the same one, wherever they were made
+    fn checksum(&self) -> u64 {
+        let (width, height) = self.dimensions();
+        let mut checksum = 0u64;
+        for y in 0..height {
+            for x in 0..width {
+                if let Some(block_type) = self.cell(x, y) {
+                    checksum = checksum.wrapping_add(cell_checksum(x, y, block_type));
+                }
+            }
+        }
+        checksum
+    }
+}
+
+/// One block's part of WorldState::checksum, mixed so that moving or swapping blocks changes the sum
+fn cell_checksum(x: usize, y: usize, block_type: BlockType) -> u64 {
+    // splitmix64 finalizer
+    let mut z =
+        ((y as u64) << 32 | (x as u64) << 8 | block_type as u64).wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+impl WorldState for Terrain {
+    fn dimensions(&self) -> (usize, usize) {
+        (CHUNK_WIDTH, self.chunks.len() * CHUNK_HEIGHT)
+    }
+
+    fn cell(&self, x: usize, y: usize) -> Option<BlockType> {
+        let chunk_number = (y / CHUNK_HEIGHT) as u64;
+        let chunk = self
+            .chunks
+            .iter()
+            .find(|c| c.chunk_number == chunk_number)?;
+        chunk.blocks[y % CHUNK_HEIGHT]
+            .get(x)?
+            .map(|block| block.block_type)
+    }
+
+    fn region_count(&self) -> u64 {
+        self.chunks.len() as u64
+    }
+
+    fn encode_region(&self, region: u64) -> Option<Vec<u8>> {
+        let chunk = self.chunks.iter().find(|c| c.chunk_number == region)?;
+        bincode::encode_to_vec(chunk, BINCODE_CONFIG).ok()
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, bincode::error::EncodeError> {
+        bincode::encode_to_vec(self, BINCODE_CONFIG)
+    }
+
+    fn restore(encoded: &[u8]) -> Result<Self, bincode::error::DecodeError> {
+        bincode::decode_from_slice(encoded, BINCODE_CONFIG).map(|(terrain, _)| terrain)
+    }
+
+    /// Same as the default, without looking up the chunk for every block
+    fn checksum(&self) -> u64 {
+        let mut checksum = 0u64;
+        for chunk in &self.chunks {
+            let first_row = chunk.chunk_number as usize * CHUNK_HEIGHT;
+            for (y, row) in chunk.blocks.iter().enumerate() {
+                for (x, block) in row.iter().enumerate() {
+                    if let Some(block) = block {
+                        checksum = checksum.wrapping_add(cell_checksum(
+                            x,
+                            first_row + y,
+                            block.block_type,
+                        ));
+                    }
+                }
+            }
+        }
+        checksum
+    }
 }
 
 /// Represents a chunk of blocks; stored in the Terrain resource
@@ -259,7 +518,6 @@ impl Chunk {
                     block_type = BlockType::CaveVoid;
                 }
 
-
                 if block_type != BlockType::CaveVoid {
                     c.blocks[y][x] = Some(Block {
                         block_type,
@@ -511,6 +769,78 @@ impl BiomeType {
     }
 }
 
+/// Whether chunks can be downsampled by scale, it has to split them into whole squares
+pub fn valid_terrain_scale(scale: u8) -> bool {
+    let scale = scale as usize;
+    scale > 0 && CHUNK_WIDTH.is_multiple_of(scale) && CHUNK_HEIGHT.is_multiple_of(scale)
+}
+
+impl Chunk {
+    /// This chunk at 1/scale the resolution each way, every scale by scale square of blocks
+    /// becomes whatever there is most of in it, empty space included
+    /// scale has to be valid_terrain_scale
+    pub fn downsample(&self, scale: u8) -> CoarseChunk {
+        let s = scale as usize;
+        let mut blocks = Vec::with_capacity((CHUNK_WIDTH / s) * (CHUNK_HEIGHT / s));
+        for y in (0..CHUNK_HEIGHT).step_by(s) {
+            for x in (0..CHUNK_WIDTH).step_by(s) {
+                let mut counts: Vec<(Option<BlockType>, usize)> = Vec::new();
+                for row in &self.blocks[y..y + s] {
+                    for block in &row[x..x + s] {
+                        let block_type = block.map(|b| b.block_type);
+                        match counts.iter_mut().find(|(t, _)| *t == block_type) {
+                            Some((_, count)) => *count += 1,
+                            None => counts.push((block_type, 1)),
+                        }
+                    }
+                }
+                // ties go to whichever came first
+                let most = counts.iter().rev().max_by_key(|(_, count)| *count);
+                blocks.push(most.and_then(|(block_type, _)| *block_type));
+            }
+        }
+        CoarseChunk {
+            chunk_number: self.chunk_number,
+            scale,
+            blocks,
+        }
+    }
+}
+
+/// A chunk at a lower resolution, for clients that can't afford all of it, see Chunk::downsample
+#[derive(Encode, Decode, Debug, PartialEq, Clone)]
+pub struct CoarseChunk {
+    pub chunk_number: u64,
+    /// Every block stands for a scale by scale square of the chunk
+    pub scale: u8,
+    /// Row by row, starting at the top of the chunk
+    pub blocks: Vec<Option<BlockType>>,
+}
+
+impl CoarseChunk {
+    /// Back to a whole chunk, with every block in a square the same
+    /// None if scale and the number of blocks don't make up a chunk
+    pub fn upsample(&self) -> Option<Chunk> {
+        let s = self.scale as usize;
+        if !valid_terrain_scale(self.scale)
+            || self.blocks.len() != (CHUNK_WIDTH / s) * (CHUNK_HEIGHT / s)
+        {
+            return None;
+        }
+        let mut chunk = Chunk {
+            blocks: [[None; CHUNK_WIDTH]; CHUNK_HEIGHT],
+            rendered: false,
+            chunk_number: self.chunk_number,
+        };
+        for (y, row) in chunk.blocks.iter_mut().enumerate() {
+            for (x, block) in row.iter_mut().enumerate() {
+                *block = self.blocks[(y / s) * (CHUNK_WIDTH / s) + x / s].map(Block::new);
+            }
+        }
+        Some(chunk)
+    }
+}
+
 /// _Not_ a component; stored in a Chunk
 #[derive(Copy, Clone, Debug)]
 pub struct Block {
@@ -727,6 +1057,29 @@ pub fn destroy_block(
     commands: &mut Commands,
     terrain: &mut Terrain,
 ) -> Result<Block, DestroyBlockError> {
+    let mut block = remove_block(x, y, terrain)?;
+
+    match block.entity {
+        Some(entity) => {
+            // info!("despawning sprite for block at ({}, {})", x, y);
+            commands.entity(entity).despawn();
+        }
+        None => {
+            warn!("block at ({}, {}) exists but had no entity attached!", x, y);
+        }
+    };
+
+    // unlink entity
+    block.entity = None;
+
+    // give the block back to the caller
+    // TODO: maybe give a different data type?
+    Ok(block)
+}
+
+/// Remove a block's data at a global position, leaving its sprite (if any) alone
+/// Used directly by the server, which doesn't render blocks
+pub fn remove_block(x: usize, y: usize, terrain: &mut Terrain) -> Result<Block, DestroyBlockError> {
     let chunk_number = y / CHUNK_HEIGHT;
     let block_y_in_chunk = y % CHUNK_HEIGHT;
 
@@ -740,39 +1093,10 @@ pub fn destroy_block(
     for chunk in &mut terrain.chunks {
         if chunk.chunk_number == (chunk_number as u64) {
             // we have found our chunk
-            let block_opt = &mut chunk.blocks[block_y_in_chunk][x];
-
-            match block_opt {
-                Some(block) => {
-                    match block.entity {
-                        Some(entity) => {
-                            // info!("despawning sprite for block at ({}, {})", x, y);
-                            commands.entity(entity).despawn();
-                        }
-                        None => {
-                            warn!("block at ({}, {}) exists but had no entity attached!", x, y);
-                        }
-                    };
-
-                    // unlink entity
-                    block.entity = None;
-
-                    // clone block data so we can give it to the caller
-                    let clone = block.clone();
-
-                    // remove the block from our data array
-                    // original block is dropped here
-                    *block_opt = None;
-
-                    // give the clone back to the caller
-                    // TODO: maybe give a different data type?
-                    return Ok(clone);
-                }
-                None => {
-                    // warn!("no block exists at ({}, {})", x, y);
-                    return Err(DestroyBlockError::BlockDoesntExist);
-                }
-            }
+            // remove the block from our data array, giving it to the caller
+            return chunk.blocks[block_y_in_chunk][x]
+                .take()
+                .ok_or(DestroyBlockError::BlockDoesntExist);
         }
     }
 
@@ -976,15 +1300,30 @@ mod tests {
         assert_eq!(original, decoded);
     }
 
+    #[test]
+    fn decode_oversized_terrain_fails() {
+        // a terrain claiming a huge number of chunks, without any of the chunk data
+        let encoded = bincode::encode_to_vec(u64::MAX, BINCODE_CONFIG).unwrap();
+        let decoded = bincode::decode_from_slice::<Terrain, _>(&encoded, BINCODE_CONFIG);
+        assert!(matches!(
+            decoded,
+            Err(bincode::error::DecodeError::OtherString(_))
+        ));
+
+        // just over the limit is rejected too
+        let encoded = bincode::encode_to_vec(MAX_TERRAIN_CHUNKS + 1, BINCODE_CONFIG).unwrap();
+        let decoded = bincode::decode_from_slice::<Terrain, _>(&encoded, BINCODE_CONFIG);
+        assert!(decoded.is_err());
+    }
+
     #[test]
     fn size_sanity_check() {
         let block_size = bincode::encode_to_vec(Block::new(BlockType::Limestone), BINCODE_CONFIG)
             .unwrap()
             .len();
-        let chunk_size =
-            bincode::encode_to_vec(Chunk::new(0, &Vec::new(),), BINCODE_CONFIG)
-                .unwrap()
-                .len();
+        let chunk_size = bincode::encode_to_vec(Chunk::new(0, &Vec::new()), BINCODE_CONFIG)
+            .unwrap()
+            .len();
         let terrain_size = bincode::encode_to_vec(Terrain::new(1), BINCODE_CONFIG)
             .unwrap()
             .len();
@@ -992,4 +1331,153 @@ mod tests {
         assert!(terrain_size > block_size);
         assert!(chunk_size > block_size);
     }
+
+    /// Smallest world that isn't a Terrain, one column of blocks per region
+    #[derive(Debug, PartialEq)]
+    struct HeightMap {
+        heights: Vec<usize>,
+    }
+
+    impl WorldState for HeightMap {
+        fn dimensions(&self) -> (usize, usize) {
+            (
+                self.heights.len(),
+                self.heights.iter().copied().max().unwrap_or(0),
+            )
+        }
+
+        fn cell(&self, x: usize, y: usize) -> Option<BlockType> {
+            (y < *self.heights.get(x)?).then_some(BlockType::Sand)
+        }
+
+        fn region_count(&self) -> u64 {
+            self.heights.len() as u64
+        }
+
+        fn encode_region(&self, region: u64) -> Option<Vec<u8>> {
+            let height = self.heights.get(region as usize)?;
+            bincode::encode_to_vec(*height as u64, BINCODE_CONFIG).ok()
+        }
+
+        fn encode(&self) -> Result<Vec<u8>, bincode::error::EncodeError> {
+            let heights: Vec<u64> = self.heights.iter().map(|h| *h as u64).collect();
+            bincode::encode_to_vec(heights, BINCODE_CONFIG)
+        }
+
+        fn restore(encoded: &[u8]) -> Result<Self, bincode::error::DecodeError> {
+            let (heights, _): (Vec<u64>, _) = bincode::decode_from_slice(encoded, BINCODE_CONFIG)?;
+            Ok(HeightMap {
+                heights: heights.into_iter().map(|h| h as usize).collect(),
+            })
+        }
+    }
+
+    #[test]
+    fn world_state_diffs_regions() {
+        // terrains diff by chunk
+        let terrain = Terrain::new(3);
+        let mut mined = terrain.clone();
+        let (width, height) = mined.dimensions();
+        assert_eq!((width, height), (CHUNK_WIDTH, 3 * CHUNK_HEIGHT));
+        let y = (CHUNK_HEIGHT..2 * CHUNK_HEIGHT)
+            .find(|y| mined.cell(0, *y).is_some())
+            .unwrap();
+        remove_block(0, y, &mut mined).unwrap();
+        assert_eq!(mined.cell(0, y), None);
+        assert_eq!(terrain.diff(&mined), [1]);
+        assert!(terrain.diff(&terrain).is_empty());
+
+        // and anything else works the same way, through the trait alone
+        let worlds: Vec<Box<dyn WorldState>> = vec![
+            Box::new(HeightMap {
+                heights: vec![1, 2, 3],
+            }),
+            Box::new(HeightMap {
+                heights: vec![1, 5],
+            }),
+        ];
+        assert_eq!(worlds[0].dimensions(), (3, 3));
+        assert_eq!(worlds[0].cell(1, 1), Some(BlockType::Sand));
+        assert_eq!(worlds[0].cell(1, 2), None);
+        assert_eq!(worlds[0].diff(worlds[1].as_ref()), [1, 2]);
+        assert!(worlds[1].encode().unwrap().len() < worlds[0].encode().unwrap().len());
+        let heights = HeightMap {
+            heights: vec![4, 0, 2],
+        };
+        assert_eq!(
+            HeightMap::restore(&heights.encode().unwrap()).unwrap(),
+            heights
+        );
+
+        // an empty terrain is different in every region a heightmap has
+        assert_eq!(Terrain::empty().diff(worlds[1].as_ref()), [0, 1]);
+    }
+
+    #[test]
+    fn checksum_follows_blocks_not_chunk_order() {
+        let terrain = Terrain::new(2);
+        let mut reordered = terrain.clone();
+        reordered.chunks.reverse();
+        assert_eq!(terrain.checksum(), reordered.checksum());
+        assert_ne!(terrain.checksum(), Terrain::empty().checksum());
+
+        let y = (0..CHUNK_HEIGHT)
+            .find(|y| terrain.cell(0, *y).is_some())
+            .unwrap();
+        let mut mined = terrain.clone();
+        remove_block(0, y, &mut mined).unwrap();
+        assert_ne!(terrain.checksum(), mined.checksum());
+
+        // the faster Terrain version agrees with going through the trait one block at a time
+        let heights = HeightMap {
+            heights: vec![1, 2, 3],
+        };
+        let mut sand = Terrain::empty();
+        sand.chunks.push(Chunk {
+            blocks: [[None; CHUNK_WIDTH]; CHUNK_HEIGHT],
+            rendered: false,
+            chunk_number: 0,
+        });
+        for (x, height) in heights.heights.iter().enumerate() {
+            for y in 0..*height {
+                sand.chunks[0].blocks[y][x] = Some(Block::new(BlockType::Sand));
+            }
+        }
+        assert_eq!(sand.checksum(), heights.checksum());
+    }
+
+    #[test]
+    fn downsampled_chunk_keeps_most_common_blocks() {
+        let mut chunk = Chunk {
+            blocks: [[None; CHUNK_WIDTH]; CHUNK_HEIGHT],
+            rendered: false,
+            chunk_number: 3,
+        };
+        // three of the top left square are iron, the rest of the chunk is empty
+        for (x, y) in [(0, 0), (1, 0), (0, 1)] {
+            chunk.blocks[y][x] = Some(Block::new(BlockType::Iron));
+        }
+        // one coal in the next square over isn't enough
+        chunk.blocks[0][2] = Some(Block::new(BlockType::Coal));
+
+        let coarse = chunk.downsample(2);
+        assert_eq!(coarse.blocks.len(), CHUNK_WIDTH * CHUNK_HEIGHT / 4);
+        assert_eq!(coarse.blocks[0], Some(BlockType::Iron));
+        assert_eq!(coarse.blocks[1], None);
+
+        let upsampled = coarse.upsample().unwrap();
+        assert_eq!(upsampled.chunk_number, 3);
+        assert_eq!(upsampled.blocks[1][1], Some(Block::new(BlockType::Iron)));
+        assert_eq!(upsampled.blocks[0][2], None);
+
+        // full resolution changes nothing
+        assert_eq!(chunk.downsample(1).upsample().unwrap(), chunk);
+        assert!(!valid_terrain_scale(0));
+        assert!(!valid_terrain_scale(3));
+        let bad = CoarseChunk {
+            blocks: vec![None; 5],
+            ..coarse
+        };
+        assert_eq!(bad.upsample(), None);
+    }
 }